@@ -0,0 +1,133 @@
+#[macro_use]
+extern crate criterion;
+
+// the submit path has no server state, so its source is benchmarked as is
+#[path = "../src/submit.rs"]
+mod submit;
+
+use criterion::{black_box, Criterion};
+use rand::thread_rng;
+use snarkvm::{
+    console::account::{Address, PrivateKey},
+    prelude::{CanaryV0, Environment, ToBytes},
+    synthesizer::{CoinbasePuzzle, EpochChallenge, PuzzleConfig, UniversalSRS},
+    utilities::Uniform,
+};
+use snarkvm_algorithms::polycommit::kzg10::{KZGCommitment, KZGProof};
+use snarkvm_curves::{PairingEngine, ProjectiveCurve};
+use submit::{decode_submit, proof_difficulty, verify_solution};
+
+type Curve = <CanaryV0 as Environment>::PairingCurve;
+
+/// Degree the pool verifies proofs at
+const DEGREE: u32 = (1 << 13) - 1;
+
+/// Hex fields of a `mining.submit` as a prover sends them. The job id is passed on as is.
+struct Submit {
+    nonce: String,
+    commitment: String,
+    proof: String,
+}
+
+/// What the server verifies a solution against.
+struct Verifier {
+    coinbase_puzzle: CoinbasePuzzle<CanaryV0>,
+    epoch_challenge: EpochChallenge<CanaryV0>,
+    address: Address<CanaryV0>,
+}
+
+/// A verifier and a solution that passes it. Loads the universal SRS, which takes a while.
+fn solved_submit() -> (Verifier, Submit) {
+    let rng = &mut thread_rng();
+    let srs = UniversalSRS::<CanaryV0>::load().unwrap();
+    let coinbase_puzzle = CoinbasePuzzle::<CanaryV0>::trim(&srs, PuzzleConfig { degree: DEGREE }).unwrap();
+    let epoch_number = rand::random::<u32>();
+    let epoch_challenge = EpochChallenge::new(epoch_number, Default::default(), DEGREE).unwrap();
+    let address = Address::try_from(PrivateKey::<CanaryV0>::new(rng).unwrap()).unwrap();
+    let nonce = rand::random::<u64>();
+    let solution = coinbase_puzzle.prove(&epoch_challenge, address, nonce, None).unwrap();
+    let submit = Submit {
+        nonce: hex::encode(nonce.to_le_bytes()),
+        commitment: hex::encode(solution.commitment().to_bytes_le().unwrap()),
+        proof: hex::encode(solution.proof().to_bytes_le().unwrap()),
+    };
+    let verifier = Verifier {
+        coinbase_puzzle,
+        epoch_challenge,
+        address,
+    };
+    (verifier, submit)
+}
+
+/// Well formed fields that don't make a solution.
+fn fake_submit() -> Submit {
+    let rng = &mut thread_rng();
+    let commitment = KZGCommitment::<Curve>(<Curve as PairingEngine>::G1Projective::rand(rng).to_affine());
+    let proof = KZGProof::<Curve> {
+        w: <Curve as PairingEngine>::G1Projective::rand(rng).to_affine(),
+        random_v: None,
+    };
+    Submit {
+        nonce: hex::encode(rand::random::<u64>().to_le_bytes()),
+        commitment: hex::encode(commitment.to_bytes_le().unwrap()),
+        proof: hex::encode(proof.to_bytes_le().unwrap()),
+    }
+}
+
+/// The work a share costs: `Connection::serve` decodes it into the fields of a
+/// `ServerMessage::ProverSubmit`, the server computes its difficulty and verifies it if enabled.
+fn process(buffer: &mut Vec<u8>, submit: &Submit, verifier: Option<&Verifier>) -> Result<u64, &'static str> {
+    let (nonce_bytes, commitment, proof) = decode_submit(buffer, &submit.nonce, &submit.commitment, &submit.proof)?;
+    let nonce = u64::from_le_bytes(nonce_bytes);
+    let difficulty = proof_difficulty(&commitment).map_err(|_| "invalid solution")?;
+    if let Some(verifier) = verifier {
+        verify_solution(
+            &verifier.coinbase_puzzle,
+            &verifier.epoch_challenge,
+            verifier.address,
+            nonce,
+            &commitment,
+            &proof,
+        )
+        .map_err(|e| e.message())?;
+    }
+    Ok(difficulty)
+}
+
+fn submit_benchmark(c: &mut Criterion) {
+    let mut buffer = Vec::new();
+    let (verifier, valid) = solved_submit();
+    assert!(process(&mut buffer, &valid, Some(&verifier)).is_ok());
+    c.bench_function("submit_valid", |b| {
+        b.iter(|| process(&mut buffer, black_box(&valid), None))
+    });
+    c.bench_function("submit_valid_verified", |b| {
+        b.iter(|| process(&mut buffer, black_box(&valid), Some(&verifier)))
+    });
+
+    let bad_hex = Submit {
+        commitment: "zz".repeat(valid.commitment.len() / 2),
+        ..fake_submit()
+    };
+    c.bench_function("submit_bad_hex", |b| {
+        b.iter(|| process(&mut buffer, black_box(&bad_hex), Some(&verifier)))
+    });
+
+    // decodes as hex but isn't a point on the curve
+    let bad_point = Submit {
+        commitment: "ff".repeat(valid.commitment.len() / 2),
+        ..fake_submit()
+    };
+    c.bench_function("submit_bad_point", |b| {
+        b.iter(|| process(&mut buffer, black_box(&bad_point), Some(&verifier)))
+    });
+
+    // decodes fine, fails only at verification
+    let bad_proof = fake_submit();
+    c.bench_function("submit_bad_proof", |b| {
+        b.iter(|| process(&mut buffer, black_box(&bad_proof), Some(&verifier)))
+    });
+}
+
+criterion_group!(benches, submit_benchmark);
+criterion_main!(benches);
@@ -1,13 +1,65 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
     hash::Hash,
     time::{Duration, Instant},
 };
 
+use parking_lot::Mutex;
+
+/// Access order of the keys, oldest first, so the least recently used one is found without a scan.
+struct Recency<K> {
+    clock: u64,
+    ticks: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Eq + Hash + Clone> Recency<K> {
+    fn new() -> Self {
+        Recency {
+            clock: 0,
+            ticks: Default::default(),
+            order: Default::default(),
+        }
+    }
+
+    /// Marks `key` as the most recently used one.
+    fn touch(&mut self, key: &K) {
+        let tick = self.clock;
+        self.clock += 1;
+        if let Some(previous) = self.ticks.insert(key.clone(), tick) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(tick, key.clone());
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn oldest(&self) -> Option<K> {
+        self.order.values().next().cloned()
+    }
+
+    fn clear(&mut self) {
+        self.ticks.clear();
+        self.order.clear();
+    }
+}
+
 pub struct Cache<K: Eq + Hash + Clone, V: Clone> {
     duration: Duration,
     instants: HashMap<K, Instant>,
     values: HashMap<K, V>,
+    /// Per-key overrides of `duration`
+    ttls: HashMap<K, Duration>,
+    /// Least recently used keys are evicted past this many entries
+    capacity: Option<usize>,
+    /// Only tracked with a capacity, behind a lock so `get` can record accesses through a shared
+    /// reference
+    recency: Mutex<Recency<K>>,
 }
 
 impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
@@ -16,19 +68,255 @@ impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
             duration,
             instants: Default::default(),
             values: Default::default(),
+            ttls: Default::default(),
+            capacity: None,
+            recency: Mutex::new(Recency::new()),
+        }
+    }
+
+    /// Like `new`, but holds at most `max_entries`, evicting the least recently used key.
+    pub fn with_capacity(duration: Duration, max_entries: usize) -> Self {
+        Cache {
+            capacity: Some(max_entries.max(1)),
+            ..Self::new(duration)
         }
     }
 
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.recency.get_mut().oldest();
+        if let Some(key) = oldest {
+            self.remove(&key);
+        }
+    }
+
+    fn ttl(&self, key: &K) -> Duration {
+        self.ttls.get(key).copied().unwrap_or(self.duration)
+    }
+
     pub fn get(&self, key: K) -> Option<V> {
         let instant = self.instants.get(&key)?;
-        if instant.elapsed() > self.duration {
+        if instant.elapsed() > self.ttl(&key) {
             return None;
         }
+        if self.capacity.is_some() {
+            self.recency.lock().touch(&key);
+        }
         self.values.get(&key).cloned()
     }
 
     pub fn set(&mut self, key: K, value: V) {
+        if let Some(capacity) = self.capacity {
+            while !self.values.contains_key(&key) && self.values.len() >= capacity {
+                self.evict_least_recently_used();
+            }
+            self.recency.get_mut().touch(&key);
+        }
+        self.ttls.remove(&key);
         self.values.insert(key.clone(), value);
         self.instants.insert(key, Instant::now());
     }
+
+    /// Like `set`, but the entry lives for `ttl` instead of the cache's duration.
+    pub fn set_with_ttl(&mut self, key: K, value: V, ttl: Duration) {
+        self.set(key.clone(), value);
+        self.ttls.insert(key, ttl);
+    }
+
+    /// Returns the cached value, or computes and caches it. A hit doesn't extend the entry's life.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, f: F) -> V {
+        match self.try_get_or_insert_with(key, || Ok::<_, Infallible>(f())) {
+            Ok(value) => value,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Returns the cached value, or computes and caches it. Errors are returned without being cached.
+    pub fn try_get_or_insert_with<E>(&mut self, key: K, f: impl FnOnce() -> Result<V, E>) -> Result<V, E> {
+        if let Some(value) = self.get(key.clone()) {
+            return Ok(value);
+        }
+        let value = f()?;
+        self.set(key, value.clone());
+        Ok(value)
+    }
+
+    /// Invalidates `key`, returning its value even if it had already expired.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.instants.remove(key);
+        self.ttls.remove(key);
+        self.recency.get_mut().remove(key);
+        self.values.remove(key)
+    }
+
+    /// Drops the expired entries and returns how many. `get` only skips them, so caches that are
+    /// written more than read should call this periodically to reclaim the memory.
+    pub fn purge_expired(&mut self) -> usize {
+        let duration = self.duration;
+        let values = &mut self.values;
+        let ttls = &mut self.ttls;
+        let recency = self.recency.get_mut();
+        let before = values.len();
+        self.instants.retain(|k, instant| {
+            let keep = instant.elapsed() <= ttls.get(k).copied().unwrap_or(duration);
+            if !keep {
+                values.remove(k);
+                ttls.remove(k);
+                recency.remove(k);
+            }
+            keep
+        });
+        before - values.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.instants.clear();
+        self.ttls.clear();
+        self.recency.get_mut().clear();
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &V) -> bool) {
+        let instants = &mut self.instants;
+        let ttls = &mut self.ttls;
+        let recency = self.recency.get_mut();
+        self.values.retain(|k, v| {
+            let keep = f(k, v);
+            if !keep {
+                instants.remove(k);
+                ttls.remove(k);
+                recency.remove(k);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = Cache::with_capacity(Duration::from_secs(60), 2);
+        cache.set("a", 1);
+        cache.set("b", 2);
+        // reading "a" makes "b" the least recently used
+        assert_eq!(cache.get("a"), Some(1));
+        cache.set("c", 3);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("c"), Some(3));
+    }
+
+    #[test]
+    fn overwrite_at_capacity_evicts_nothing() {
+        let mut cache = Cache::with_capacity(Duration::from_secs(60), 2);
+        cache.set("a", 1);
+        cache.set("b", 2);
+        cache.set("a", 3);
+        assert_eq!(cache.get("a"), Some(3));
+        assert_eq!(cache.get("b"), Some(2));
+    }
+
+    #[test]
+    fn purge_drops_expired_entries() {
+        let mut cache = Cache::new(Duration::from_millis(20));
+        cache.set("a", 1);
+        cache.set("b", 2);
+        std::thread::sleep(Duration::from_millis(40));
+
+        assert_eq!(cache.purge_expired(), 2);
+        assert!(cache.values.is_empty());
+        assert!(cache.instants.is_empty());
+        assert_eq!(cache.purge_expired(), 0);
+    }
+
+    #[test]
+    fn clear_empties_everything() {
+        let mut cache = Cache::new(Duration::from_secs(60));
+        cache.set("a", 1);
+        cache.set_with_ttl("b", 2, Duration::from_secs(120));
+        cache.clear();
+        assert_eq!(cache.get("a"), None);
+        assert!(cache.values.is_empty());
+        assert!(cache.instants.is_empty());
+        assert!(cache.ttls.is_empty());
+    }
+
+    #[test]
+    fn retain_keeps_matching_entries() {
+        let mut cache = Cache::new(Duration::from_secs(60));
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            cache.set(key, value);
+        }
+        cache.retain(|_, value| value % 2 == 1);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), Some(3));
+        assert_eq!(cache.values.len(), 2);
+        assert!(cache.values.keys().all(|key| cache.instants.contains_key(key)));
+        assert_eq!(cache.instants.len(), 2);
+    }
+
+    #[test]
+    fn failed_computation_is_not_cached() {
+        let mut cache = Cache::new(Duration::from_secs(60));
+        assert_eq!(
+            cache.try_get_or_insert_with("a", || Err("unavailable")),
+            Err("unavailable")
+        );
+        assert_eq!(cache.get("a"), None);
+
+        assert_eq!(cache.try_get_or_insert_with("a", || Ok::<_, &str>(1)), Ok(1));
+        assert_eq!(cache.try_get_or_insert_with("a", || Err("unavailable")), Ok(1));
+    }
+
+    #[test]
+    fn removed_key_is_recomputed() {
+        let mut cache = Cache::new(Duration::from_secs(60));
+        cache.set("a", 1);
+        assert_eq!(cache.remove(&"a"), Some(1));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.remove(&"a"), None);
+        assert_eq!(cache.get_or_insert_with("a", || 2), 2);
+    }
+
+    #[test]
+    fn remove_returns_expired_value() {
+        let mut cache = Cache::new(Duration::from_millis(10));
+        cache.set("a", 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.remove(&"a"), Some(1));
+    }
+
+    #[test]
+    fn per_entry_ttl_outlives_global_duration() {
+        let mut cache = Cache::new(Duration::from_millis(10));
+        cache.set("short", 1);
+        cache.set_with_ttl("long", 2, Duration::from_secs(60));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("short"), None);
+        assert_eq!(cache.get("long"), Some(2));
+    }
+
+    #[test]
+    fn set_clears_ttl_override() {
+        let mut cache = Cache::new(Duration::from_millis(10));
+        cache.set_with_ttl("a", 1, Duration::from_secs(60));
+        cache.set("a", 2);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("a"), None);
+    }
+
+    #[test]
+    fn hit_does_not_refresh_the_entry() {
+        let mut cache = Cache::new(Duration::from_millis(30));
+        assert_eq!(cache.get_or_insert_with("a", || 1), 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get_or_insert_with("a", || 2), 1);
+        // the hit didn't extend the entry, so it expires 30ms after it was computed
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get_or_insert_with("a", || 3), 3);
+    }
 }
@@ -1,47 +1,185 @@
 use std::{
     collections::VecDeque,
-    time::{Duration, Instant},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, task::JoinHandle, time::interval};
+
+/// How events are turned into a speed.
+enum Mode {
+    /// Flat average of the events within the interval
+    Window {
+        storage: parking_lot::RwLock<VecDeque<(Instant, u64)>>,
+        interval: Duration,
+    },
+    /// Exponentially weighted moving average, an event's weight halves every half life
+    Ewma { state: RwLock<Ewma>, half_life: Duration },
+}
+
+struct Ewma {
+    /// Rate per second as of `updated`
+    rate: f64,
+    updated: Instant,
+}
+
+impl Ewma {
+    fn decayed(&self, half_life: Duration, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.rate * 0.5f64.powf(elapsed / half_life.as_secs_f64())
+    }
+}
+
+/// Drops the events that have left the window.
+fn prune(storage: &mut VecDeque<(Instant, u64)>, interval: Duration) {
+    while storage.front().is_some_and(|t| t.0.elapsed() > interval) {
+        storage.pop_front();
+    }
+}
+
+/// Window contents saved across restarts. `Instant`s don't survive a process, so each event is
+/// stored with its age at the time of the snapshot.
+#[derive(Serialize, Deserialize)]
+pub struct SpeedometerState {
+    /// (age, value), oldest first
+    events: Vec<(Duration, u64)>,
+    /// When the snapshot was taken, to age the events by the time until they are restored
+    taken: SystemTime,
+}
+
+/// Stops the task started by `Speedometer::spawn_pruner` when dropped.
+pub struct PrunerHandle(JoinHandle<()>);
+
+impl Drop for PrunerHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
 
 pub struct Speedometer {
-    storage: RwLock<VecDeque<(Instant, u64)>>,
-    interval: Duration,
+    mode: Mode,
     cached: bool,
     cache_interval: Option<Duration>,
     cache_instant: Option<Instant>,
     cache_value: f64,
+    /// Highest speed computed so far and when
+    peak: RwLock<(f64, Instant)>,
 }
 
 impl Speedometer {
     pub fn init(interval: Duration) -> Self {
         Self {
-            storage: RwLock::new(VecDeque::new()),
-            interval,
+            mode: Mode::Window {
+                storage: parking_lot::RwLock::new(VecDeque::new()),
+                interval,
+            },
             cached: false,
             cache_interval: None,
             cache_instant: None,
             cache_value: 0.0,
+            peak: RwLock::new((0.0, Instant::now())),
         }
     }
 
     pub fn init_with_cache(interval: Duration, cache_interval: Duration) -> Self {
         Self {
-            storage: RwLock::new(VecDeque::new()),
-            interval,
+            mode: Mode::Window {
+                storage: parking_lot::RwLock::new(VecDeque::new()),
+                interval,
+            },
             cached: true,
             cache_interval: Some(cache_interval),
             cache_instant: Some(Instant::now() - cache_interval),
             cache_value: 0.0,
+            peak: RwLock::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Smooths the speed with an exponentially weighted moving average instead of averaging over
+    /// a window, so it doesn't jump when events enter or leave the window.
+    pub fn init_ewma(half_life: Duration) -> Self {
+        Self {
+            mode: Mode::Ewma {
+                state: RwLock::new(Ewma {
+                    rate: 0.0,
+                    updated: Instant::now(),
+                }),
+                half_life,
+            },
+            cached: false,
+            cache_interval: None,
+            cache_instant: None,
+            cache_value: 0.0,
+            peak: RwLock::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Rebuilds a speedometer from a snapshot, dropping the events that expired in the meantime.
+    pub fn restore(state: SpeedometerState, interval: Duration) -> Self {
+        let now = Instant::now();
+        let since_snapshot = state.taken.elapsed().unwrap_or_default();
+        let events = state
+            .events
+            .into_iter()
+            .map(|(age, value)| (age + since_snapshot, value))
+            .filter(|(age, _)| *age <= interval)
+            .filter_map(|(age, value)| Some((now.checked_sub(age)?, value)))
+            .collect();
+        Self {
+            mode: Mode::Window {
+                storage: parking_lot::RwLock::new(events),
+                interval,
+            },
+            ..Self::init(interval)
+        }
+    }
+
+    /// Window contents for `restore`; empty in EWMA mode, which doesn't keep the events.
+    pub fn snapshot(&self) -> SpeedometerState {
+        let events = match &self.mode {
+            Mode::Window { storage, .. } => {
+                let now = Instant::now();
+                let storage = storage.read();
+                storage
+                    .iter()
+                    .map(|(at, value)| (now.duration_since(*at), *value))
+                    .collect()
+            }
+            Mode::Ewma { .. } => vec![],
+        };
+        SpeedometerState {
+            events,
+            taken: SystemTime::now(),
         }
     }
 
     pub async fn event(&self, value: u64) {
-        let mut storage = self.storage.write().await;
-        storage.push_back((Instant::now(), value));
-        while storage.front().map_or(false, |t| t.0.elapsed() > self.interval) {
-            storage.pop_front();
+        self.event_at(value, Instant::now()).await
+    }
+
+    /// Records an event that happened at `at`, e.g. when replaying shares after a restart. Events
+    /// may arrive out of order; they are kept sorted so the window still prunes from the front.
+    pub async fn event_at(&self, value: u64, at: Instant) {
+        match &self.mode {
+            Mode::Window { storage, interval } => {
+                let mut storage = storage.write();
+                let position = storage.partition_point(|t| t.0 <= at);
+                storage.insert(position, (at, value));
+                prune(&mut storage, *interval);
+            }
+            Mode::Ewma { state, half_life } => {
+                let mut state = state.write().await;
+                // the weights of the exponential kernel integrate to 1 with this scale
+                let weight = value as f64 * 2f64.ln() / half_life.as_secs_f64();
+                if at >= state.updated {
+                    state.rate = state.decayed(*half_life, at) + weight;
+                    state.updated = at;
+                } else {
+                    let age = state.updated.duration_since(at).as_secs_f64();
+                    state.rate += weight * 0.5f64.powf(age / half_life.as_secs_f64());
+                }
+            }
         }
     }
 
@@ -49,22 +187,280 @@ impl Speedometer {
         if self.cached && self.cache_instant.unwrap().elapsed() < self.cache_interval.unwrap() {
             return self.cache_value;
         }
-        let mut storage = self.storage.write().await;
-        while storage.front().map_or(false, |t| t.0.elapsed() > self.interval) {
-            storage.pop_front();
-        }
-        drop(storage);
-        let events = self.storage.read().await.iter().fold(0, |acc, t| acc + t.1);
-        let speed = events as f64 / self.interval.as_secs_f64();
+        let speed = match &self.mode {
+            Mode::Window { storage, interval } => {
+                let mut storage = storage.write();
+                prune(&mut storage, *interval);
+                let events = storage.iter().fold(0, |acc, t| acc + t.1);
+                events as f64 / interval.as_secs_f64()
+            }
+            Mode::Ewma { state, half_life } => state.read().await.decayed(*half_life, Instant::now()),
+        };
         if self.cached {
             self.cache_instant = Some(Instant::now());
             self.cache_value = speed;
         }
+        let mut peak = self.peak.write().await;
+        if speed > peak.0 {
+            *peak = (speed, Instant::now());
+        }
         speed
     }
 
+    /// Highest speed returned by `speed()` and when it was computed.
+    ///
+    /// Only fresh computations are considered; a read served from the cache repeats a value that
+    /// was already accounted for. The peak survives `reset()`, use `reset_peak()` to clear it.
+    pub async fn peak(&self) -> (f64, Instant) {
+        *self.peak.read().await
+    }
+
+    pub async fn reset_peak(&self) {
+        *self.peak.write().await = (0.0, Instant::now());
+    }
+
+    /// Drops expired events every `tick`, so a quiet speedometer doesn't hold on to them until
+    /// its next read. The task also ends once the speedometer itself is dropped.
+    pub fn spawn_pruner(self: Arc<Self>, tick: Duration) -> PrunerHandle {
+        let speedometer = Arc::downgrade(&self);
+        drop(self);
+        PrunerHandle(tokio::spawn(async move {
+            let mut ticker = interval(tick);
+            loop {
+                ticker.tick().await;
+                match speedometer.upgrade() {
+                    Some(speedometer) => speedometer.prune().await,
+                    None => return,
+                }
+            }
+        }))
+    }
+
+    async fn prune(&self) {
+        if let Mode::Window { storage, interval } = &self.mode {
+            prune(&mut storage.write(), *interval);
+        }
+    }
+
+    /// Summed value of the events in the window, 0 in EWMA mode.
+    pub async fn window_sum(&self) -> u64 {
+        match &self.mode {
+            Mode::Window { storage, interval } => {
+                let mut storage = storage.write();
+                prune(&mut storage, *interval);
+                storage.iter().map(|t| t.1).sum()
+            }
+            Mode::Ewma { .. } => 0,
+        }
+    }
+
+    /// Number of events in the window, 0 in EWMA mode.
+    pub async fn window_len(&self) -> usize {
+        match &self.mode {
+            Mode::Window { storage, interval } => {
+                let mut storage = storage.write();
+                prune(&mut storage, *interval);
+                storage.len()
+            }
+            Mode::Ewma { .. } => 0,
+        }
+    }
+
+    /// Coefficient of variation of the intervals between events in the window.
+    ///
+    /// Evenly spaced events give a value near 0 and a Poisson process near 1; `None` until there
+    /// are at least three events, and always in EWMA mode, which doesn't keep the events.
+    pub async fn interval_variation(&self) -> Option<f64> {
+        let storage = match &self.mode {
+            Mode::Window { storage, interval } => {
+                let mut storage = storage.write();
+                prune(&mut storage, *interval);
+                storage
+            }
+            Mode::Ewma { .. } => return None,
+        };
+        let intervals = storage
+            .iter()
+            .zip(storage.iter().skip(1))
+            .map(|(a, b)| b.0.duration_since(a.0).as_secs_f64())
+            .collect::<Vec<_>>();
+        if intervals.len() < 2 {
+            return None;
+        }
+        let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+        if mean == 0.0 {
+            return None;
+        }
+        let variance = intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+        Some(variance.sqrt() / mean)
+    }
+
+    /// The `p`-th percentile (0.0 to 1.0) of the gaps between consecutive events in the window,
+    /// using the nearest rank. `None` with fewer than two events and in EWMA mode.
+    pub async fn gap_percentile(&self, p: f64) -> Option<Duration> {
+        let mut gaps = match &self.mode {
+            Mode::Window { storage, interval } => {
+                let mut storage = storage.write();
+                prune(&mut storage, *interval);
+                storage
+                    .iter()
+                    .zip(storage.iter().skip(1))
+                    .map(|(a, b)| b.0.duration_since(a.0))
+                    .collect::<Vec<_>>()
+            }
+            Mode::Ewma { .. } => return None,
+        };
+        if gaps.is_empty() {
+            return None;
+        }
+        gaps.sort_unstable();
+        let rank = (p.clamp(0.0, 1.0) * (gaps.len() - 1) as f64).round() as usize;
+        Some(gaps[rank])
+    }
+
     #[allow(dead_code)]
     pub async fn reset(&self) {
-        self.storage.write().await.clear();
+        match &self.mode {
+            Mode::Window { storage, .. } => storage.write().clear(),
+            Mode::Ewma { state, .. } => {
+                let mut state = state.write().await;
+                state.rate = 0.0;
+                state.updated = Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn short_window_reacts_faster() {
+        let mut short = Speedometer::init(Duration::from_millis(100));
+        let mut long = Speedometer::init(Duration::from_secs(60));
+        for _ in 0..10 {
+            short.event(100).await;
+            long.event(100).await;
+        }
+        assert!(short.speed().await > long.speed().await);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(short.speed().await, 0.0);
+        assert!(long.speed().await > 0.0);
+    }
+
+    #[tokio::test]
+    async fn interval_variation_ignores_expired_events() {
+        let speedometer = Speedometer::init(Duration::from_millis(100));
+        for _ in 0..5 {
+            speedometer.event(1).await;
+        }
+        assert!(speedometer.interval_variation().await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(speedometer.interval_variation().await, None);
+    }
+
+    #[tokio::test]
+    async fn restore_drops_events_expired_since_snapshot() {
+        let speedometer = Speedometer::init(Duration::from_millis(100));
+        speedometer.event(100).await;
+        let state = speedometer.snapshot();
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let mut restored = Speedometer::restore(state, Duration::from_millis(100));
+        assert_eq!(restored.speed().await, 0.0);
+    }
+
+    #[tokio::test]
+    async fn ewma_halves_every_half_life() {
+        let half_life = Duration::from_secs(10);
+        let mut fresh = Speedometer::init_ewma(half_life);
+        let mut old = Speedometer::init_ewma(half_life);
+        fresh.event(1000).await;
+        old.event_at(1000, Instant::now() - half_life).await;
+
+        let fresh = fresh.speed().await;
+        assert!((fresh - 1000.0 * 2f64.ln() / 10.0).abs() < 1.0);
+        assert!((old.speed().await / fresh - 0.5).abs() < 0.01);
+    }
+
+    #[tokio::test]
+    async fn window_totals_skip_expired_events() {
+        let speedometer = Speedometer::init(Duration::from_millis(100));
+        speedometer
+            .event_at(5, Instant::now() - Duration::from_millis(200))
+            .await;
+        speedometer.event(10).await;
+        speedometer.event(20).await;
+        assert_eq!(speedometer.window_sum().await, 30);
+        assert_eq!(speedometer.window_len().await, 2);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(speedometer.window_sum().await, 0);
+        assert_eq!(speedometer.window_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn peak_survives_reset() {
+        let mut speedometer = Speedometer::init(Duration::from_secs(1));
+        speedometer.event(10).await;
+        assert_eq!(speedometer.speed().await, 10.0);
+        speedometer.reset().await;
+        assert_eq!(speedometer.speed().await, 0.0);
+        assert_eq!(speedometer.peak().await.0, 10.0);
+
+        speedometer.reset_peak().await;
+        assert_eq!(speedometer.peak().await.0, 0.0);
+    }
+
+    #[tokio::test]
+    async fn out_of_order_events_stay_sorted() {
+        let speedometer = Speedometer::init(Duration::from_secs(10));
+        let now = Instant::now();
+        for (seconds_ago, value) in [(1, 1), (3, 3), (2, 2), (20, 20)] {
+            speedometer
+                .event_at(value, now - Duration::from_secs(seconds_ago))
+                .await;
+        }
+        let values = match &speedometer.mode {
+            Mode::Window { storage, .. } => storage.read().iter().map(|t| t.1).collect::<Vec<_>>(),
+            Mode::Ewma { .. } => unreachable!(),
+        };
+        assert_eq!(values, [3, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn pruner_drops_expired_events_and_ends_with_the_speedometer() {
+        let speedometer = Arc::new(Speedometer::init(Duration::from_millis(50)));
+        speedometer.event(1).await;
+        let pruner = speedometer.clone().spawn_pruner(Duration::from_millis(10));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        match &speedometer.mode {
+            Mode::Window { storage, .. } => assert!(storage.read().is_empty()),
+            Mode::Ewma { .. } => unreachable!(),
+        }
+
+        drop(speedometer);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(pruner.0.is_finished());
+    }
+
+    #[tokio::test]
+    async fn gap_percentiles() {
+        let speedometer = Speedometer::init(Duration::from_secs(60));
+        let now = Instant::now();
+        speedometer.event_at(1, now - Duration::from_secs(10)).await;
+        assert_eq!(speedometer.gap_percentile(0.5).await, None);
+        // gaps of 1, 2, 3 and 4 seconds
+        for seconds_ago in [9, 7, 4, 0] {
+            speedometer.event_at(1, now - Duration::from_secs(seconds_ago)).await;
+        }
+        assert_eq!(speedometer.gap_percentile(0.0).await, Some(Duration::from_secs(1)));
+        assert_eq!(speedometer.gap_percentile(0.5).await, Some(Duration::from_secs(3)));
+        assert_eq!(speedometer.gap_percentile(1.0).await, Some(Duration::from_secs(4)));
+        assert_eq!(speedometer.gap_percentile(2.0).await, Some(Duration::from_secs(4)));
     }
 }
@@ -1,86 +1,315 @@
+#[cfg(feature = "db")]
+use std::env;
 use std::{
     collections::{HashMap, VecDeque},
-    fs::create_dir_all,
-    path::PathBuf,
-    sync::{atomic::AtomicBool, Arc},
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, write, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, Error};
 use cache::Cache;
+use clap::ValueEnum;
 use dirs::home_dir;
-use parking_lot::RwLock;
-use savefile::{load_file, save_file};
+#[cfg(feature = "db")]
+use futures::{
+    future::join_all,
+    stream::{self, Stream, StreamExt},
+};
+use parking_lot::{Mutex, RwLock};
+use savefile::{load_file, save_file, SavefileError};
 use savefile_derive::Savefile;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use snarkvm::prelude::{PuzzleCommitment, CanaryV0};
+use snarkvm::{
+    console::account::Address,
+    prelude::{Network, PuzzleCommitment, CanaryV0},
+};
+#[cfg(feature = "db")]
+use tokio::{sync::Notify, time::timeout};
 use tokio::{
     sync::{
+        broadcast,
         mpsc::{channel, Sender},
         RwLock as TokioRwLock,
     },
     task,
     time::sleep,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 #[cfg(feature = "db")]
-use crate::db::DB;
+use crate::{
+    db::{self, BreakerStats, DB},
+    error::{PoolError, PoolResult},
+};
 use crate::{
     accounting::AccountingMessage::{NewShare, NewSolution},
+    util,
     AccountingMessage::{Exit, SetN},
 };
 
 trait PayoutModel {
     fn add_share(&mut self, share: Share);
+
+    /// Shares a solution found by `finder` is split by.
+    fn solution_shares(&self, finder: &str) -> HashMap<String, u64>;
+}
+
+/// How solution rewards are split.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PayoutScheme {
+    /// Among the owners of the last N share value
+    Pplns,
+    /// All to the address that found the solution
+    Solo,
+}
+
+struct Solo;
+
+impl PayoutModel for Solo {
+    fn add_share(&mut self, _share: Share) {}
+
+    fn solution_shares(&self, finder: &str) -> HashMap<String, u64> {
+        HashMap::from([(finder.to_string(), 1)])
+    }
 }
 
-#[derive(Clone, Savefile)]
+#[derive(Clone, Savefile, Serialize, Deserialize)]
 struct Share {
     value: u64,
     owner: String,
+    /// Sequence number assigned when the share is added to the PPLNS queue
+    #[savefile_versions = "1.."]
+    #[serde(default)]
+    id: u64,
+    /// Unix timestamp in seconds, 0 for shares recorded before ids were introduced
+    #[savefile_versions = "1.."]
+    #[serde(default)]
+    timestamp: u64,
 }
 
 impl Share {
     pub fn init(value: u64, owner: String) -> Self {
-        Share { value, owner }
+        let timestamp = util::now();
+        Share {
+            value,
+            owner,
+            id: 0,
+            timestamp,
+        }
     }
 }
 
+/// Version of the binary PPLNS state, bumped when fields are added
+const STATE_VERSION: u32 = 1;
+
+/// Records that shares were imported with `--import-state`, so a restart doesn't import them again
+const IMPORT_MARKER_FILE: &str = "imported";
+
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Savefile)]
+#[derive(Clone, Savefile, Serialize, Deserialize)]
 struct PPLNS {
     queue: VecDeque<Share>,
     current_n: Arc<RwLock<u64>>,
     n: Arc<RwLock<u64>>,
+    #[savefile_versions = "1.."]
+    #[serde(default)]
+    next_share_id: u64,
+}
+
+/// On-disk format of the PPLNS state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StateFormat {
+    /// savefile binary format
+    Binary,
+    /// Pretty-printed JSON, for inspecting or repairing the state by hand
+    Json,
+}
+
+impl StateFormat {
+    fn file_name(&self) -> &'static str {
+        match self {
+            StateFormat::Binary => "state",
+            StateFormat::Json => "state.json",
+        }
+    }
+}
+
+/// Version of the state directory layout, bumped when state written by older builds must not be
+/// picked up
+const STATE_DIR_VERSION: u32 = 1;
+
+/// Directory the state was kept in before it was tied to the network, moved over once
+const LEGACY_STATE_DIR: &str = ".aleo_pool_testnet3_2";
+
+/// Records the network the state in a directory belongs to
+const NETWORK_FILE: &str = "network";
+
+/// Short name of the network the pool is built for, e.g. `canary_v0` for "Aleo Canary (v0)".
+fn network_name() -> String {
+    CanaryV0::NAME
+        .trim_start_matches("Aleo")
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Directory the pool keeps its state in, derived from the network it is built for.
+pub fn state_dir() -> Result<PathBuf, Error> {
+    state_dir_in(home_dir())
+}
+
+fn state_dir_in(home: Option<PathBuf>) -> Result<PathBuf, Error> {
+    let home = home.ok_or_else(|| anyhow!("No home directory found to keep the pool state in"))?;
+    Ok(home.join(format!(".aleo_pool_{}_{}", network_name(), STATE_DIR_VERSION)))
+}
+
+/// Creates the state directory and refuses to use it if its state belongs to another network.
+fn init_state_dir() -> Result<(), Error> {
+    let dir = state_dir()?;
+    create_dir_all(&dir)?;
+    let network_path = dir.join(NETWORK_FILE);
+    if !network_path.exists() {
+        let legacy_dir = home_dir().map(|home| home.join(LEGACY_STATE_DIR));
+        // builds before the network was part of the directory name served the same network
+        if let Some(legacy_dir) = legacy_dir.filter(|dir| dir.exists()) {
+            info!("Moving state from {} to {}", legacy_dir.display(), dir.display());
+            for entry in read_dir(&legacy_dir)? {
+                let entry = entry?;
+                let target = dir.join(entry.file_name());
+                if target.exists() {
+                    warn!(
+                        "Not moving {}, {} exists already",
+                        entry.path().display(),
+                        target.display()
+                    );
+                    continue;
+                }
+                rename(entry.path(), target)?;
+            }
+        }
+        write(&network_path, CanaryV0::ID.to_string())?;
+        return Ok(());
+    }
+    check_network(&dir)
+}
+
+/// Fails if the state in `dir` was written for another network.
+fn check_network(dir: &Path) -> Result<(), Error> {
+    let network = CanaryV0::ID.to_string();
+    let found = read_to_string(dir.join(NETWORK_FILE))?;
+    if found.trim() != network {
+        return Err(anyhow!(
+            "State in {} belongs to network {}, not {} ({})",
+            dir.display(),
+            found.trim(),
+            network,
+            CanaryV0::NAME
+        ));
+    }
+    Ok(())
 }
 
 impl PPLNS {
-    pub fn load() -> Self {
-        let home = home_dir();
-        if home.is_none() {
-            panic!("No home directory found");
+    pub fn load(format: StateFormat) -> Result<Self, Error> {
+        let dir = state_dir()?;
+        create_dir_all(&dir)?;
+        PPLNS::load_from(&dir, format)
+    }
+
+    fn load_from(dir: &Path, format: StateFormat) -> Result<Self, Error> {
+        let db_path = dir.join(format.file_name());
+        if format == StateFormat::Json && !db_path.exists() {
+            // carry over the binary state when switching to JSON
+            return PPLNS::load_from(dir, StateFormat::Binary);
         }
-        create_dir_all(home.as_ref().unwrap().join(".aleo_pool_testnet3_2")).unwrap();
-        let db_path = home.unwrap().join(".aleo_pool_testnet3_2/state");
         if !db_path.exists() {
-            return PPLNS {
+            return Ok(PPLNS {
                 queue: VecDeque::new(),
                 current_n: Default::default(),
                 n: Default::default(),
-            };
+                next_share_id: 0,
+            });
+        }
+        let mut pplns: PPLNS = match format {
+            // savefile migrates states of older versions, anything else is unreadable by this build
+            StateFormat::Binary => match load_file::<PPLNS, PathBuf>(db_path.clone(), STATE_VERSION) {
+                Ok(pplns) => pplns,
+                // a state this build can't read is set aside, other errors (e.g. I/O) may go away
+                Err(
+                    e @ (SavefileError::WrongVersion { .. }
+                    | SavefileError::IncompatibleSchema { .. }
+                    | SavefileError::ShortRead
+                    | SavefileError::InvalidUtf8 { .. }
+                    | SavefileError::InvalidChar),
+                ) => {
+                    let unreadable_path = db_path.with_extension(format!("unreadable-{}", util::now()));
+                    rename(&db_path, &unreadable_path)?;
+                    error!(
+                        "Failed to load PPLNS state ({}), moved it to {} and starting with an empty state",
+                        e,
+                        unreadable_path.display()
+                    );
+                    return PPLNS::load_from(dir, format);
+                }
+                Err(e) => return Err(anyhow!("Failed to load PPLNS state: {}", e)),
+            },
+            StateFormat::Json => serde_json::from_reader(BufReader::new(File::open(db_path)?))?,
+        };
+        if pplns.next_share_id == 0 && !pplns.queue.is_empty() {
+            // state from before share ids, number the queued shares in order
+            info!("Assigning ids to {} shares from old state", pplns.queue.len());
+            for (id, share) in pplns.queue.iter_mut().enumerate() {
+                share.id = id as u64;
+            }
+            pplns.next_share_id = pplns.queue.len() as u64;
+        }
+        Ok(pplns)
+    }
+
+    pub fn save(&self, format: StateFormat) -> std::result::Result<(), Error> {
+        self.save_to(&state_dir()?, format)
+    }
+
+    fn save_to(&self, dir: &Path, format: StateFormat) -> std::result::Result<(), Error> {
+        let db_path = dir.join(format.file_name());
+        match format {
+            StateFormat::Binary => {
+                save_file(db_path, STATE_VERSION, self).map_err(|e| anyhow!("Failed to save PPLNS state: {}", e))
+            }
+            StateFormat::Json => {
+                // write to a temporary file first so a crash never leaves a truncated state behind
+                let tmp_path = db_path.with_extension("json.tmp");
+                let mut writer = BufWriter::new(File::create(&tmp_path)?);
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writer.flush()?;
+                rename(tmp_path, db_path)?;
+                Ok(())
+            }
         }
-        load_file::<PPLNS, PathBuf>(db_path, 0).unwrap()
     }
 
-    pub fn save(&self) -> std::result::Result<(), Error> {
-        let home = home_dir();
-        if home.is_none() {
-            panic!("No home directory found");
+    /// How much of the PPLNS window is filled, 0 when the window size is not known yet.
+    pub fn fill_ratio(&self) -> f64 {
+        let n = *self.n.read();
+        if n == 0 {
+            return 0.0;
         }
-        let db_path = home.unwrap().join(".aleo_pool_testnet3_2/state");
-        save_file(db_path, 0, self).map_err(|e| anyhow!("Failed to save PPLNS state: {}", e))
+        (*self.current_n.read() as f64 / n as f64).min(1.0)
+    }
+
+    /// `current_n` and the share value of every address, read together so they add up.
+    fn round_shares(&self) -> (u64, HashMap<String, u64>) {
+        let current_n = self.current_n.read();
+        (*current_n, Accounting::pplns_to_provers_shares(self).1)
     }
 
     pub fn set_n(&mut self, n: u64) {
@@ -89,60 +318,519 @@ impl PPLNS {
         let mut self_n = self.n.write();
         if n < *self_n {
             while *current_n > n {
-                let share = self.queue.pop_front().unwrap();
-                *current_n -= share.value;
+                match self.queue.pop_front() {
+                    Some(share) => *current_n = current_n.saturating_sub(share.value),
+                    None => break,
+                }
             }
         }
         *self_n = n;
         debug!("set_n took {} us", start.elapsed().as_micros());
     }
+
+    /// Adds imported balances as shares, logging them to `wal`. Returns the round value after the
+    /// import and the window size, which is `n` if the state doesn't have one yet.
+    fn import(&mut self, balances: &[(String, u64)], n: Option<u64>, wal: &mut ShareWal) -> Result<(u64, u64), Error> {
+        let persisted_n = *self.n.read();
+        let n = match (persisted_n, n) {
+            (0, Some(n)) => n,
+            (0, None) => return Err(anyhow!("The PPLNS window size is unknown, pass it with --import-n")),
+            (n, _) => n,
+        };
+        let total = balances
+            .iter()
+            .try_fold(*self.current_n.read(), |total, (_, shares)| total.checked_add(*shares))
+            .ok_or_else(|| anyhow!("Imported shares overflow"))?;
+        // anything beyond n would push the imported shares straight out of the window
+        if total > n {
+            return Err(anyhow!(
+                "Imported shares bring the round to {} which exceeds n = {}",
+                total,
+                n
+            ));
+        }
+        if persisted_n == 0 {
+            wal.append(&WalEntry::SetN(n));
+            self.set_n(n);
+        }
+        for (address, shares) in balances {
+            let share = Share::init(*shares, address.clone());
+            wal.append(&WalEntry::Share(share.clone()));
+            self.add_share(share);
+        }
+        Ok((total, n))
+    }
+}
+
+/// Caps a share's value at `max` before it enters the PPLNS queue.
+fn clamp_share_value(value: u64, max: u64, owner: &str) -> u64 {
+    if value > max {
+        warn!("Clamping share value {} from {} to {}", value, owner, max);
+        return max;
+    }
+    value
+}
+
+/// Reads a JSON object of `{address: shares}` to import, sorted by address.
+fn read_balances(path: &str) -> Result<Vec<(String, u64)>, Error> {
+    let balances: HashMap<String, u64> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let mut balances = balances.into_iter().collect::<Vec<_>>();
+    balances.sort_unstable();
+    for (address, shares) in &balances {
+        if address.parse::<Address<CanaryV0>>().is_err() {
+            return Err(anyhow!("Invalid address {} in {}", address, path));
+        }
+        if *shares == 0 {
+            return Err(anyhow!("Address {} has no shares in {}", address, path));
+        }
+    }
+    Ok(balances)
 }
 
 impl PayoutModel for PPLNS {
-    fn add_share(&mut self, share: Share) {
+    fn add_share(&mut self, mut share: Share) {
         let start = Instant::now();
-        self.queue.push_back(share.clone());
         let mut current_n = self.current_n.write();
         let self_n = self.n.read();
-        *current_n += share.value;
+        // the round value has to stay the exact sum of the queue to be trimmed share by share, so a
+        // share it can't hold is dropped rather than saturating it
+        *current_n = match current_n.checked_add(share.value) {
+            Some(sum) => sum,
+            None => {
+                warn!(
+                    "Dropping share of value {} from {}, the PPLNS round value would overflow",
+                    share.value, share.owner
+                );
+                return;
+            }
+        };
+        share.id = self.next_share_id;
+        self.next_share_id += 1;
+        self.queue.push_back(share);
         while *current_n > *self_n {
-            let share = self.queue.pop_front().unwrap();
-            *current_n -= share.value;
+            match self.queue.pop_front() {
+                Some(share) => *current_n = current_n.saturating_sub(share.value),
+                None => break,
+            }
         }
         debug!("add_share took {} us", start.elapsed().as_micros());
         debug!("n: {} / {}", *current_n, self_n);
     }
+
+    fn solution_shares(&self, _finder: &str) -> HashMap<String, u64> {
+        Accounting::pplns_to_provers_shares(self).1
+    }
+}
+
+/// A PPLNS change recorded in the share log.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum WalEntry {
+    Share(Share),
+    SetN(u64),
+}
+
+/// Append-only log of the PPLNS changes since the state was last saved, replayed after a crash.
+struct ShareWal {
+    file: Option<File>,
+}
+
+impl ShareWal {
+    fn path() -> Result<PathBuf, Error> {
+        Ok(state_dir()?.join("pplns.wal"))
+    }
+
+    /// Applies the logged changes left by a previous run. Returns the number of changes.
+    fn replay(pplns: &mut PPLNS) -> Result<usize, Error> {
+        ShareWal::replay_from(&ShareWal::path()?, pplns)
+    }
+
+    fn replay_from(path: &Path, pplns: &mut PPLNS) -> Result<usize, Error> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let mut replayed = 0;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let entry = match serde_json::from_str::<WalEntry>(&line?) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // a crash during a write leaves the last entry truncated
+                    warn!("Stopping share log replay at an unreadable entry: {}", e);
+                    break;
+                }
+            };
+            match entry {
+                WalEntry::Share(share) => pplns.add_share(share),
+                WalEntry::SetN(n) => pplns.set_n(n),
+            }
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+
+    fn open(enabled: bool) -> Result<Self, Error> {
+        let file = match enabled {
+            true => Some(ShareWal::open_file(&ShareWal::path()?)?),
+            false => None,
+        };
+        Ok(ShareWal { file })
+    }
+
+    fn open_file(path: &Path) -> Result<File, Error> {
+        Ok(OpenOptions::new().create(true).append(true).open(path)?)
+    }
+
+    fn append(&mut self, entry: &WalEntry) {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let result = serde_json::to_vec(entry).map_err(Error::from).and_then(|mut line| {
+            line.push(b'\n');
+            file.write_all(&line).map_err(Error::from)
+        });
+        if let Err(e) = result {
+            error!("Failed to append to the share log: {}", e);
+        }
+    }
+
+    /// Empties the log once the state it covers has been saved.
+    fn truncate(&mut self) -> Result<(), Error> {
+        if let Some(file) = &self.file {
+            file.set_len(0)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
 struct Null {}
 
+/// Number of shares accepted since the last solution, regardless of their value.
+#[derive(Default)]
+struct RoundShares(AtomicU64);
+
+impl RoundShares {
+    fn record(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Starts a new round, returning the count of the one that ended.
+    fn reset(&self) -> u64 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
 pub enum AccountingMessage {
     NewShare(String, u64),
     SetN(u64),
-    NewSolution(PuzzleCommitment<CanaryV0>),
+    /// (commitment, address of the prover that found it)
+    NewSolution(PuzzleCommitment<CanaryV0>, String),
     Exit,
 }
 
 #[cfg(feature = "db")]
 static PAY_INTERVAL: Duration = Duration::from_secs(60);
 
+/// Attempts at saving a solution in one run, the rest are left for the next start
+#[cfg(feature = "db")]
+const MAX_SAVE_ATTEMPTS: u32 = 10;
+
+/// How long saving pending solutions may hold up an exit
+#[cfg(feature = "db")]
+static EXIT_SAVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A solution not yet written to the database.
+#[cfg(feature = "db")]
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingSolution {
+    commitment: String,
+    shares: HashMap<String, u64>,
+    /// The database refused the solution itself; it is kept for manual recovery but not retried
+    #[serde(default)]
+    refused: bool,
+    /// Failed attempts in this run
+    #[serde(skip)]
+    attempts: u32,
+}
+
+#[cfg(feature = "db")]
+impl PendingSolution {
+    fn retryable(&self) -> bool {
+        !self.refused && self.attempts < MAX_SAVE_ATTEMPTS
+    }
+}
+
+/// What an attempt at saving a pending solution came to.
+#[cfg(feature = "db")]
+enum SaveOutcome {
+    Saved,
+    /// The solution was saved by an earlier attempt
+    Duplicate,
+    /// Worth retrying, e.g. the database is unreachable
+    Transient(Error),
+    /// The database refused the solution itself
+    Refused(Error),
+}
+
+#[cfg(feature = "db")]
+impl SaveOutcome {
+    fn of(result: Result<(), Error>) -> Self {
+        match result {
+            Ok(()) => SaveOutcome::Saved,
+            Err(e) if db::is_duplicate(&e) => SaveOutcome::Duplicate,
+            Err(e) if db::is_transient(&e) => SaveOutcome::Transient(e),
+            Err(e) => SaveOutcome::Refused(e),
+        }
+    }
+}
+
+/// Solutions waiting to be written to the database, kept in the state directory so neither a
+/// database outage nor a restart loses them.
+#[cfg(feature = "db")]
+struct PendingSolutions {
+    path: PathBuf,
+    solutions: Mutex<Vec<PendingSolution>>,
+    added: Notify,
+}
+
+#[cfg(feature = "db")]
+impl PendingSolutions {
+    fn load() -> Result<Self, Error> {
+        PendingSolutions::load_from(state_dir()?.join("pending_solutions.json"))
+    }
+
+    fn load_from(path: PathBuf) -> Result<Self, Error> {
+        let solutions: Vec<PendingSolution> = match path.exists() {
+            true => serde_json::from_reader(BufReader::new(File::open(&path)?))?,
+            false => vec![],
+        };
+        let waiting = solutions.iter().filter(|solution| solution.retryable()).count();
+        if waiting > 0 {
+            info!("{} solutions from a previous run are waiting to be saved", waiting);
+        }
+        Ok(PendingSolutions {
+            path,
+            solutions: Mutex::new(solutions),
+            added: Notify::new(),
+        })
+    }
+
+    fn persist(&self, solutions: &[PendingSolution]) {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let result = File::create(&tmp_path).map_err(Error::from).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer(&mut writer, solutions)?;
+            writer.flush()?;
+            rename(&tmp_path, &self.path)?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Failed to persist pending solutions: {}", e);
+        }
+    }
+
+    fn add(&self, commitment: String, shares: HashMap<String, u64>) {
+        let mut solutions = self.solutions.lock();
+        solutions.push(PendingSolution {
+            commitment,
+            shares,
+            refused: false,
+            attempts: 0,
+        });
+        self.persist(&solutions);
+        drop(solutions);
+        self.added.notify_one();
+    }
+
+    /// Solutions to try saving, in the order they were found.
+    fn waiting(&self) -> Vec<PendingSolution> {
+        self.solutions
+            .lock()
+            .iter()
+            .filter(|solution| solution.retryable())
+            .cloned()
+            .collect()
+    }
+
+    /// Tries to save the pending solutions in order, stopping at the first transient failure.
+    async fn save(&self, database: &DB) {
+        for solution in self.waiting() {
+            let result = database.save_solution(&solution.commitment, &solution.shares).await;
+            if !self.record(&solution.commitment, SaveOutcome::of(result)) {
+                return;
+            }
+        }
+    }
+
+    /// Updates the solution after an attempt at saving it. Returns false if saving should stop
+    /// for now.
+    fn record(&self, commitment: &str, outcome: SaveOutcome) -> bool {
+        let mut solutions = self.solutions.lock();
+        let index = match solutions.iter().position(|pending| pending.commitment == commitment) {
+            Some(index) => index,
+            // saved concurrently on exit
+            None => return true,
+        };
+        match outcome {
+            SaveOutcome::Saved => {
+                info!("Recorded solution {}", commitment);
+                solutions.remove(index);
+            }
+            SaveOutcome::Duplicate => {
+                info!("Solution {} was recorded already", commitment);
+                solutions.remove(index);
+            }
+            SaveOutcome::Transient(e) => {
+                let pending = &mut solutions[index];
+                pending.attempts += 1;
+                if pending.retryable() {
+                    warn!("Failed to save solution {}, retrying: {}", commitment, e);
+                } else {
+                    error!(
+                        "Failed to save solution {} {} times, leaving it for the next start: {}",
+                        commitment, pending.attempts, e
+                    );
+                }
+                return false;
+            }
+            SaveOutcome::Refused(e) => {
+                error!(
+                    "Database refused solution {}, keeping it in the pending solutions for manual recovery: {}",
+                    commitment, e
+                );
+                solutions[index].refused = true;
+            }
+        }
+        self.persist(&solutions);
+        true
+    }
+}
+
+/// A solution the validator confirmed, as announced to API clients.
+#[derive(Clone, Serialize)]
+pub struct ConfirmedSolution {
+    pub height: u32,
+    pub reward: u64,
+}
+
+/// The most recent solution found by the pool, with its check result once known.
+#[derive(Clone, Serialize)]
+pub struct LastSolution {
+    pub commitment: String,
+    /// Unix timestamp in seconds of when it was recorded
+    pub timestamp: u64,
+    /// `None` until the solution has been checked
+    pub valid: Option<bool>,
+    pub height: Option<u32>,
+    pub reward: Option<u64>,
+}
+
+impl LastSolution {
+    fn recorded(commitment: String) -> Self {
+        LastSolution {
+            commitment,
+            timestamp: util::now(),
+            valid: None,
+            height: None,
+            reward: None,
+        }
+    }
+
+    /// Takes in the check result of `commitment` if it is this solution.
+    #[cfg(feature = "db")]
+    fn checked(&mut self, commitment: &str, confirmed: Option<&ConfirmedSolution>) {
+        if self.commitment != commitment {
+            return;
+        }
+        self.valid = Some(confirmed.is_some());
+        if let Some(solution) = confirmed {
+            self.height = Some(solution.height);
+            self.reward = Some(solution.reward);
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub struct Accounting {
     pplns: Arc<TokioRwLock<PPLNS>>,
     #[cfg(feature = "db")]
     database: Arc<DB>,
+    #[cfg(feature = "db")]
+    pending_solutions: Arc<PendingSolutions>,
+    #[cfg(feature = "db")]
+    solution_check_endpoints: Vec<String>,
+    /// Number of endpoints that must agree on a solution, 1 to use the first that responds
+    #[cfg(feature = "db")]
+    solution_check_quorum: usize,
+    /// Number of pending solutions the payout loop checks at once
+    #[cfg(feature = "db")]
+    solution_check_concurrency: usize,
     sender: Sender<AccountingMessage>,
     round_cache: TokioRwLock<Cache<Null, (u32, HashMap<String, u64>)>>,
+    round_shares: Arc<RoundShares>,
+    confirmed_solutions: broadcast::Sender<ConfirmedSolution>,
+    last_solution: Arc<RwLock<Option<LastSolution>>>,
+    /// Solutions are still recorded while paused, they are paid once payouts resume
+    payouts_paused: AtomicBool,
+    state_format: StateFormat,
+    wal: Arc<Mutex<ShareWal>>,
     exit_lock: Arc<AtomicBool>,
 }
 
 impl Accounting {
-    pub fn init() -> Arc<Accounting> {
+    /// Share values above `max_share_value` are clamped before they enter the PPLNS queue. With
+    /// `share_log`, every PPLNS change is logged as it happens so a crash loses none of them.
+    pub fn init(
+        state_format: StateFormat,
+        max_share_value: u64,
+        payout_scheme: PayoutScheme,
+        share_log: bool,
+    ) -> Result<Arc<Accounting>, Error> {
         #[cfg(feature = "db")]
         let database = Arc::new(DB::init());
+        #[cfg(feature = "db")]
+        let solution_check_endpoints = env::var("SOLUTION_CHECK_ENDPOINTS")
+            .unwrap_or_else(|_| "http://127.0.0.1:8001".to_string())
+            .split(',')
+            .map(|endpoint| endpoint.trim().trim_end_matches('/').to_string())
+            .filter(|endpoint| !endpoint.is_empty())
+            .collect::<Vec<_>>();
+        #[cfg(feature = "db")]
+        let solution_check_quorum = env::var("SOLUTION_CHECK_QUORUM")
+            .map(|quorum| quorum.parse::<usize>().expect("Invalid solution check quorum"))
+            .unwrap_or(1);
+        #[cfg(feature = "db")]
+        let solution_check_concurrency = env::var("SOLUTION_CHECK_CONCURRENCY")
+            .map(|concurrency| {
+                concurrency
+                    .parse::<usize>()
+                    .expect("Invalid solution check concurrency")
+            })
+            .unwrap_or(4)
+            .max(1);
+        #[cfg(feature = "db")]
+        assert!(
+            solution_check_quorum <= solution_check_endpoints.len(),
+            "Solution check quorum exceeds the number of endpoints"
+        );
 
-        let pplns = Arc::new(TokioRwLock::new(PPLNS::load()));
+        init_state_dir()?;
+        #[cfg(feature = "db")]
+        let pending_solutions = Arc::new(PendingSolutions::load()?);
+        let mut pplns = PPLNS::load(state_format)?;
+        let replayed = ShareWal::replay(&mut pplns)?;
+        if replayed > 0 {
+            info!("Recovered {} PPLNS changes from the share log", replayed);
+            pplns.save(state_format)?;
+            remove_file(ShareWal::path()?)?;
+        }
+        let pplns = Arc::new(TokioRwLock::new(pplns));
+        let wal = Arc::new(Mutex::new(ShareWal::open(share_log)?));
 
         let (sender, mut receiver) = channel(1024);
 
@@ -150,41 +838,89 @@ impl Accounting {
             pplns,
             #[cfg(feature = "db")]
             database,
+            #[cfg(feature = "db")]
+            pending_solutions,
+            #[cfg(feature = "db")]
+            solution_check_endpoints,
+            #[cfg(feature = "db")]
+            solution_check_quorum,
+            #[cfg(feature = "db")]
+            solution_check_concurrency,
             sender,
             round_cache: TokioRwLock::new(Cache::new(Duration::from_secs(10))),
+            round_shares: Default::default(),
+            confirmed_solutions: broadcast::channel(16).0,
+            last_solution: Default::default(),
+            payouts_paused: AtomicBool::new(false),
+            state_format,
+            wal,
             exit_lock: Arc::new(AtomicBool::new(false)),
         };
 
         let pplns = accounting.pplns.clone();
         #[cfg(feature = "db")]
         let database = accounting.database.clone();
+        #[cfg(feature = "db")]
+        let pending_solutions = accounting.pending_solutions.clone();
         let exit_lock = accounting.exit_lock.clone();
+        let round_shares = accounting.round_shares.clone();
+        let last_solution = accounting.last_solution.clone();
+        let wal = accounting.wal.clone();
         task::spawn(async move {
             while let Some(request) = receiver.recv().await {
                 match request {
                     NewShare(address, value) => {
-                        pplns.write().await.add_share(Share::init(value, address.clone()));
+                        let value = clamp_share_value(value, max_share_value, &address);
+                        let share = Share::init(value, address.clone());
+                        match payout_scheme {
+                            PayoutScheme::Pplns => {
+                                // logged under the state lock so a save never misses a logged share
+                                let mut pplns = pplns.write().await;
+                                wal.lock().append(&WalEntry::Share(share.clone()));
+                                pplns.add_share(share);
+                            }
+                            PayoutScheme::Solo => Solo.add_share(share),
+                        }
+                        round_shares.record();
                         debug!("Recorded share from {} with value {}", address, value);
                     }
                     SetN(n) => {
-                        pplns.write().await.set_n(n);
+                        let mut pplns = pplns.write().await;
+                        wal.lock().append(&WalEntry::SetN(n));
+                        pplns.set_n(n);
                         debug!("Set N to {}", n);
                     }
-                    NewSolution(commitment) => {
-                        let pplns = pplns.read().await.clone();
-                        let (_, address_shares) = Accounting::pplns_to_provers_shares(&pplns);
+                    NewSolution(commitment, finder) => {
+                        let shares = round_shares.reset();
+                        info!("Solution {} found by {} after {} shares", commitment, finder, shares);
+                        last_solution
+                            .write()
+                            .replace(LastSolution::recorded(commitment.to_string()));
+                        let address_shares = match payout_scheme {
+                            PayoutScheme::Pplns => pplns.read().await.solution_shares(&finder),
+                            PayoutScheme::Solo => Solo.solution_shares(&finder),
+                        };
 
+                        // saved in the background so an unavailable database doesn't hold up share recording
                         #[cfg(feature = "db")]
-                        if let Err(e) = database.save_solution(commitment, address_shares).await {
-                            error!("Failed to save block reward : {}", e);
-                        } else {
-                            info!("Recorded solution {}", commitment);
-                        }
+                        pending_solutions.add(commitment.to_string(), address_shares);
+                        #[cfg(not(feature = "db"))]
+                        let _ = address_shares;
                     }
                     Exit => {
                         receiver.close();
-                        let _ = pplns.read().await.save();
-                        exit_lock.store(true, std::sync::atomic::Ordering::SeqCst);
+                        let pplns = pplns.read().await;
+                        if pplns.save(state_format).is_ok() {
+                            let _ = wal.lock().truncate();
+                        }
+                        // whatever isn't saved in time stays in the state directory for the next start
+                        #[cfg(feature = "db")]
+                        let saved = timeout(EXIT_SAVE_TIMEOUT, pending_solutions.save(&database)).await;
+                        #[cfg(feature = "db")]
+                        if saved.is_err() {
+                            warn!("Timed out saving pending solutions, they are saved on the next start");
+                        }
+                        exit_lock.store(true, Ordering::SeqCst);
                     }
                 }
             }
@@ -192,30 +928,52 @@ impl Accounting {
 
         // backup pplns
         let pplns = accounting.pplns.clone();
+        let wal = accounting.wal.clone();
         task::spawn(async move {
             loop {
                 sleep(Duration::from_secs(60)).await;
-                if let Err(e) = pplns.read().await.save() {
+                let pplns = pplns.read().await;
+                if let Err(e) = pplns.save(state_format) {
                     error!("Unable to backup pplns: {}", e);
+                } else if let Err(e) = wal.lock().truncate() {
+                    error!("Unable to truncate the share log: {}", e);
                 }
             }
         });
 
         let res = Arc::new(accounting);
 
+        // solution saving routine, woken up by new solutions
+        #[cfg(feature = "db")]
+        {
+            let database = res.database.clone();
+            let pending_solutions = res.pending_solutions.clone();
+            task::spawn(async move {
+                loop {
+                    pending_solutions.save(&database).await;
+                    let _ = timeout(PAY_INTERVAL, pending_solutions.added.notified()).await;
+                }
+            });
+        }
+
         // payout routine
         #[cfg(feature = "db")]
         task::spawn(Accounting::payout_loop(res.clone()));
 
-        res
+        Ok(res)
     }
 
     pub fn sender(&self) -> Sender<AccountingMessage> {
         self.sender.clone()
     }
 
+    /// Number of messages waiting to be processed by the accounting task.
+    pub fn channel_depth(&self) -> usize {
+        util::channel_depth(&self.sender)
+    }
+
     pub async fn wait_for_exit(&self) {
-        while !self.exit_lock.load(std::sync::atomic::Ordering::SeqCst) {
+        while !self.exit_lock.load(Ordering::SeqCst) {
             sleep(Duration::from_millis(100)).await;
         }
     }
@@ -236,84 +994,985 @@ impl Accounting {
         (address_shares.len() as u32, address_shares)
     }
 
+    /// Share value of every address in the current round along with the round's `current_n`.
+    pub async fn round_shares_by_address(&self) -> (u64, HashMap<String, u64>) {
+        self.pplns.read().await.round_shares()
+    }
+
+    /// Seeds the PPLNS queue from a JSON object of `{address: shares}`, e.g. exported from another
+    /// pool. Returns the number of imported addresses, `None` if a previous run already imported.
+    ///
+    /// `n` bounds the import while the window size isn't known yet, which is the case until the
+    /// first epoch on a fresh state.
+    pub async fn import_state(&self, path: &str, n: Option<u64>) -> Result<Option<usize>, Error> {
+        let marker_path = state_dir()?.join(IMPORT_MARKER_FILE);
+        if marker_path.exists() {
+            warn!(
+                "Not importing {}, shares were already imported ({})",
+                path,
+                read_to_string(&marker_path)?.trim()
+            );
+            return Ok(None);
+        }
+        let balances = read_balances(path)?;
+        let mut pplns = self.pplns.write().await;
+        let (total, n) = {
+            let mut wal = self.wal.lock();
+            let imported = pplns.import(&balances, n, &mut wal)?;
+            pplns.save(self.state_format)?;
+            wal.truncate()?;
+            imported
+        };
+        // written only once the import is saved, so a crash before it imports again into the old state
+        write(&marker_path, format!("{} at {}", path, util::now()))?;
+        info!(
+            "Imported shares of {} addresses from {}, round is at {} / {}",
+            balances.len(),
+            path,
+            total,
+            n
+        );
+        Ok(Some(balances.len()))
+    }
+
+    /// Shares of the address in the current round.
+    pub async fn address_shares(&self, address: String) -> Value {
+        let shares = self
+            .pplns
+            .read()
+            .await
+            .queue
+            .iter()
+            .filter(|share| share.owner == address)
+            .map(|share| {
+                json!({
+                    "id": share.id,
+                    "timestamp": share.timestamp,
+                    "value": share.value,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!(shares)
+    }
+
+    /// Current PPLNS window size, 0 until it is first set.
+    pub async fn n(&self) -> u64 {
+        *self.pplns.read().await.n.read()
+    }
+
+    pub async fn fill_ratio(&self) -> f64 {
+        self.pplns.read().await.fill_ratio()
+    }
+
     pub async fn current_round(&self) -> Value {
         let pplns = self.pplns.clone().read().await.clone();
-        let cache = self.round_cache.read().await.get(Null {});
-        let (provers, shares) = match cache {
-            Some(cache) => cache,
-            None => {
-                let result = Accounting::pplns_to_provers_shares(&pplns);
-                self.round_cache.write().await.set(Null {}, result.clone());
-                result
-            }
+        // readers share the lock on a hit, only a miss waits for the write lock
+        let cached = self.round_cache.read().await.get(Null {});
+        let (provers, shares) = match cached {
+            Some(round) => round,
+            None => self
+                .round_cache
+                .write()
+                .await
+                .get_or_insert_with(Null {}, || Accounting::pplns_to_provers_shares(&pplns)),
         };
         json!({
             "n": pplns.n,
             "current_n": pplns.current_n,
+            "fill_ratio": pplns.fill_ratio(),
+            "round_shares": self.round_shares.count(),
             "provers": provers,
             "shares": shares,
         })
     }
 
+    pub async fn address_balance(&self, address: String) -> Result<Value, Error> {
+        let current_round_shares: u64 = self
+            .pplns
+            .read()
+            .await
+            .queue
+            .iter()
+            .filter(|share| share.owner == address)
+            .map(|share| share.value)
+            .sum();
+
+        #[cfg(feature = "db")]
+        let (pending_balance, lifetime_paid) = self.database.get_balance(&address).await?;
+        #[cfg(not(feature = "db"))]
+        let (pending_balance, lifetime_paid) = (0u64, 0u64);
+
+        Ok(json!({
+            "current_round_shares": current_round_shares,
+            "pending_balance": pending_balance,
+            "lifetime_paid": lifetime_paid,
+        }))
+    }
+
+    #[cfg(feature = "db")]
+    pub fn database_breaker(&self) -> BreakerStats {
+        self.database.breaker_stats()
+    }
+
+    pub fn payouts_paused(&self) -> bool {
+        self.payouts_paused.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether payouts were paused before.
+    pub fn set_payouts_paused(&self, paused: bool) -> bool {
+        let was_paused = self.payouts_paused.swap(paused, Ordering::SeqCst);
+        if was_paused != paused {
+            info!("Payouts {}", if paused { "paused" } else { "resumed" });
+        }
+        was_paused
+    }
+
+    pub fn last_solution(&self) -> Option<LastSolution> {
+        self.last_solution.read().clone()
+    }
+
+    /// Solutions confirmed by the validator from now on.
+    pub fn subscribe_solutions(&self) -> broadcast::Receiver<ConfirmedSolution> {
+        self.confirmed_solutions.subscribe()
+    }
+
     #[cfg(feature = "db")]
-    async fn check_solution(&self, commitment: &String) -> Result<bool> {
+    async fn check_solution(&self, commitment: &String) -> PoolResult<bool> {
+        let result = &if self.solution_check_quorum > 1 {
+            self.query_solution_quorum(commitment).await?
+        } else {
+            Accounting::query_solution_failover(&self.solution_check_endpoints, commitment).await?
+        };
+        let confirmed = Accounting::confirmed_solution(result)?;
+        match &confirmed {
+            Some(solution) => {
+                self.database
+                    .set_solution_valid(commitment, true, Some(solution.height), Some(solution.reward))
+                    .await?;
+                // fails only when nobody is listening
+                let _ = self.confirmed_solutions.send(solution.clone());
+            }
+            None => self.database.set_solution_valid(commitment, false, None, None).await?,
+        }
+        if let Some(last_solution) = self.last_solution.write().as_mut() {
+            last_solution.checked(commitment, confirmed.as_ref());
+        }
+        Ok(confirmed.is_some())
+    }
+
+    /// The solution's height and reward if the answer confirms it.
+    #[cfg(feature = "db")]
+    fn confirmed_solution(answer: &Value) -> PoolResult<Option<ConfirmedSolution>> {
+        if answer.is_null() {
+            return Ok(None);
+        }
+        let height = answer["height"]
+            .as_u64()
+            .ok_or(PoolError::InvalidSolutionResponse("height"))? as u32;
+        let reward = answer["reward"]
+            .as_u64()
+            .ok_or(PoolError::InvalidSolutionResponse("reward"))?;
+        Ok(Some(ConfirmedSolution { height, reward }))
+    }
+
+    /// Uses the first endpoint that responds.
+    #[cfg(feature = "db")]
+    async fn query_solution_failover(endpoints: &[String], commitment: &String) -> PoolResult<Value> {
+        let client = reqwest::Client::new();
+        for endpoint in endpoints {
+            match Accounting::query_solution(&client, endpoint, commitment).await {
+                Ok(value) => {
+                    debug!("Checked solution {} using {}", commitment, endpoint);
+                    return Ok(value);
+                }
+                Err(e) => warn!("Solution check endpoint {} failed: {}", endpoint, e),
+            }
+        }
+        Err(PoolError::SolutionCheckUnavailable(endpoints.len()))
+    }
+
+    /// Queries all endpoints and returns the response at least `solution_check_quorum` of them agree on.
+    #[cfg(feature = "db")]
+    async fn query_solution_quorum(&self, commitment: &String) -> PoolResult<Value> {
         let client = reqwest::Client::new();
+        let results = join_all(
+            self.solution_check_endpoints
+                .iter()
+                .map(|endpoint| Accounting::query_solution(&client, endpoint, commitment)),
+        )
+        .await;
+        Accounting::quorum_answer(
+            commitment,
+            self.solution_check_endpoints.iter().zip(results).collect(),
+            self.solution_check_quorum,
+        )
+    }
+
+    /// What an answer says about the solution: `None` if it isn't confirmed, otherwise its height and
+    /// reward. Endpoints may differ in any other field.
+    #[cfg(feature = "db")]
+    fn solution_confirmation(answer: &Value) -> Option<(Option<u64>, Option<u64>)> {
+        if answer.is_null() {
+            return None;
+        }
+        Some((answer["height"].as_u64(), answer["reward"].as_u64()))
+    }
+
+    /// Groups the answers by their confirmation and returns one of the largest group if it reaches
+    /// the quorum.
+    #[cfg(feature = "db")]
+    fn quorum_answer(commitment: &str, results: Vec<(&String, PoolResult<Value>)>, quorum: usize) -> PoolResult<Value> {
+        let endpoint_count = results.len();
+        let mut answers: Vec<(Value, Vec<&String>)> = vec![];
+        for (endpoint, result) in results {
+            match result {
+                Ok(value) => {
+                    let confirmation = Accounting::solution_confirmation(&value);
+                    match answers
+                        .iter_mut()
+                        .find(|(answer, _)| Accounting::solution_confirmation(answer) == confirmation)
+                    {
+                        Some((_, endpoints)) => endpoints.push(endpoint),
+                        None => answers.push((value, vec![endpoint])),
+                    }
+                }
+                Err(e) => warn!("Solution check endpoint {} failed: {}", endpoint, e),
+            }
+        }
+        if answers.len() > 1 {
+            error!("Validators disagree on solution {}:", commitment);
+            for (answer, endpoints) in &answers {
+                error!("  {:?} answered {}", endpoints, answer);
+            }
+        }
 
-        let result = &client
-            .get(format!("http://127.0.0.1:8001/commitment?commitment={}", commitment))
+        match answers.into_iter().max_by_key(|(_, endpoints)| endpoints.len()) {
+            Some((answer, endpoints)) if endpoints.len() >= quorum => Ok(answer),
+            Some((_, endpoints)) => Err(PoolError::SolutionCheckQuorum(endpoints.len(), quorum)),
+            None => Err(PoolError::SolutionCheckUnavailable(endpoint_count)),
+        }
+    }
+
+    #[cfg(feature = "db")]
+    async fn query_solution(client: &reqwest::Client, endpoint: &str, commitment: &String) -> PoolResult<Value> {
+        Ok(client
+            .get(format!("{}/commitment?commitment={}", endpoint, commitment))
             .send()
             .await?
             .json::<Value>()
-            .await?;
-        let is_valid = result.as_null().is_none();
-        if is_valid {
-            self.database
-                .set_solution_valid(
-                    commitment,
-                    true,
-                    Some(result["height"].as_u64().ok_or_else(|| anyhow!("height"))? as u32),
-                    Some(result["reward"].as_u64().ok_or_else(|| anyhow!("reward"))?),
-                )
-                .await?;
-        } else {
-            self.database.set_solution_valid(commitment, false, None, None).await?;
+            .await?)
+    }
+
+    /// Checks up to `concurrency` solutions at a time, yielding the results in the given order.
+    #[cfg(feature = "db")]
+    fn check_in_order<'a, F, Fut>(
+        solutions: Vec<(i32, String)>,
+        concurrency: usize,
+        check: F,
+    ) -> impl Stream<Item = (i32, String, PoolResult<bool>)> + 'a
+    where
+        F: Fn(String) -> Fut + 'a,
+        Fut: std::future::Future<Output = PoolResult<bool>> + 'a,
+    {
+        stream::iter(solutions)
+            .map(move |(id, commitment)| {
+                let valid = check(commitment.clone());
+                async move { (id, commitment, valid.await) }
+            })
+            .buffered(concurrency)
+    }
+
+    /// Checks the solutions and pays the valid ones in order, stopping at the first error or
+    /// once payouts are paused. Returns the ids of the paid solutions.
+    #[cfg(feature = "db")]
+    async fn pay_in_order<F, Fut, P, PayFut>(
+        solutions: Vec<(i32, String)>,
+        concurrency: usize,
+        paused: &AtomicBool,
+        check: F,
+        pay: P,
+    ) -> Vec<i32>
+    where
+        F: Fn(String) -> Fut,
+        Fut: std::future::Future<Output = PoolResult<bool>>,
+        P: Fn(i32) -> PayFut,
+        PayFut: std::future::Future<Output = anyhow::Result<()>>,
+    {
+        let mut paid = vec![];
+        let mut checks = Accounting::check_in_order(solutions, concurrency, check);
+        while let Some((id, commitment, valid)) = checks.next().await {
+            let valid = match valid {
+                Ok(valid) => valid,
+                Err(e) => {
+                    error!("Unable to check solution: {}", e);
+                    break;
+                }
+            };
+            if !valid {
+                continue;
+            }
+            if paused.load(Ordering::SeqCst) {
+                debug!("Payouts paused, leaving solution {} for later", commitment);
+                break;
+            }
+            match pay(id).await {
+                Ok(_) => {
+                    info!("Paid solution {}", commitment);
+                    paid.push(id);
+                }
+                Err(e) => {
+                    error!("Unable to pay solution {}: {}", id, e);
+                    break;
+                }
+            }
         }
-        Ok(is_valid)
+        paid
     }
 
     #[cfg(feature = "db")]
     async fn payout_loop(self: Arc<Accounting>) {
-        'forever: loop {
-            info!("Running payout loop");
-            let blocks = self.database.get_should_pay_solutions().await;
-            if blocks.is_err() {
-                error!("Unable to get should pay blocks: {}", blocks.unwrap_err());
+        loop {
+            if self.payouts_paused() {
+                debug!("Payouts are paused");
                 sleep(PAY_INTERVAL).await;
                 continue;
             }
-            for (id, commitment) in blocks.unwrap() {
-                let valid = self.check_solution(&commitment).await;
-                if valid.is_err() {
-                    error!("Unable to check solution: {}", valid.unwrap_err());
-                    sleep(PAY_INTERVAL).await;
-                    continue 'forever;
-                }
-                let valid = valid.unwrap();
-                if valid {
-                    match self.database.pay_solution(id).await {
-                        Ok(_) => {
-                            info!("Paid solution {}", commitment);
-                        }
-                        Err(e) => {
-                            error!("Unable to pay solution {}: {}", id, e);
-                            sleep(PAY_INTERVAL).await;
-                            continue 'forever;
-                        }
-                    }
+            info!("Running payout loop");
+            match self.database.get_should_pay_solutions().await {
+                Ok(blocks) => {
+                    // checked concurrently but paid in order
+                    let accounting = &self;
+                    Accounting::pay_in_order(
+                        blocks,
+                        self.solution_check_concurrency,
+                        &self.payouts_paused,
+                        |commitment| async move { accounting.check_solution(&commitment).await },
+                        |id| async move { accounting.database.pay_solution(id).await },
+                    )
+                    .await;
                 }
+                Err(e) => error!("Unable to get should pay blocks: {}", e),
             }
 
             sleep(PAY_INTERVAL).await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use snarkvm::console::account::PrivateKey;
+
+    use super::*;
+
+    #[test]
+    fn round_shares_add_up_to_current_n() {
+        let mut pplns = PPLNS {
+            queue: VecDeque::new(),
+            current_n: Default::default(),
+            n: Arc::new(RwLock::new(100)),
+            next_share_id: 0,
+        };
+        for (value, owner) in [(30, "a"), (40, "b"), (20, "a"), (50, "c")] {
+            pplns.add_share(Share::init(value, owner.to_string()));
+        }
+
+        let (current_n, shares) = pplns.round_shares();
+        assert_eq!(shares.values().sum::<u64>(), current_n);
+        assert!(current_n <= 100);
+        assert_eq!(shares.get("a"), Some(&20));
+    }
+
+    #[test]
+    fn wal_recovers_shares_since_the_checkpoint() {
+        let path = std::env::temp_dir().join(format!("pplns-{}.wal", std::process::id()));
+        let _ = remove_file(&path);
+        let mut wal = ShareWal {
+            file: Some(ShareWal::open_file(&path).unwrap()),
+        };
+        let mut pplns = PPLNS {
+            queue: VecDeque::new(),
+            current_n: Default::default(),
+            n: Arc::new(RwLock::new(100)),
+            next_share_id: 0,
+        };
+        pplns.add_share(Share::init(30, "a".to_string()));
+
+        // the saved state, after which the log starts over
+        let checkpoint = PPLNS {
+            queue: pplns.queue.clone(),
+            current_n: Arc::new(RwLock::new(*pplns.current_n.read())),
+            n: Arc::new(RwLock::new(*pplns.n.read())),
+            next_share_id: pplns.next_share_id,
+        };
+        wal.truncate().unwrap();
+        for entry in [
+            WalEntry::Share(Share::init(40, "b".to_string())),
+            WalEntry::SetN(80),
+            WalEntry::Share(Share::init(20, "c".to_string())),
+        ] {
+            wal.append(&entry);
+            match entry {
+                WalEntry::Share(share) => pplns.add_share(share),
+                WalEntry::SetN(n) => pplns.set_n(n),
+            }
+        }
+        // the crash cut the last write short
+        wal.file.as_mut().unwrap().write_all(b"{\"share\":{\"val").unwrap();
+        drop(wal);
+
+        let mut recovered = checkpoint;
+        assert_eq!(ShareWal::replay_from(&path, &mut recovered).unwrap(), 3);
+        assert_eq!(recovered.round_shares(), pplns.round_shares());
+        assert_eq!(*recovered.n.read(), 80);
+        assert_eq!(recovered.next_share_id, pplns.next_share_id);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn state_dir_follows_the_network() {
+        assert_eq!(network_name(), "canary_v0");
+        let dir = state_dir().unwrap();
+        assert_eq!(
+            dir.file_name().unwrap().to_str().unwrap(),
+            format!(".aleo_pool_canary_v0_{}", STATE_DIR_VERSION)
+        );
+    }
+
+    #[test]
+    fn state_of_another_network_is_refused() {
+        let dir = std::env::temp_dir().join(format!("aleo-pool-network-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(dir.join(NETWORK_FILE), (CanaryV0::ID + 1).to_string()).unwrap();
+        let error = check_network(&dir).unwrap_err();
+        assert!(error.to_string().contains("belongs to network"));
+        write(dir.join(NETWORK_FILE), CanaryV0::ID.to_string()).unwrap();
+        assert!(check_network(&dir).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "db")]
+    fn quorum_of(answers: &[Value], quorum: usize) -> PoolResult<Value> {
+        let endpoints = (0..answers.len())
+            .map(|i| format!("http://validator{}", i))
+            .collect::<Vec<_>>();
+        Accounting::quorum_answer(
+            "commitment",
+            endpoints.iter().zip(answers.iter().cloned().map(Ok)).collect(),
+            quorum,
+        )
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn unanimous_valid() {
+        let answer = json!({"height": 10, "reward": 500});
+        let result = quorum_of(&[answer.clone(), answer.clone(), answer], 3).unwrap();
+        assert_eq!(result["height"], 10);
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn quorum_met_with_one_dissent() {
+        let answers = [
+            json!({"height": 10, "reward": 500, "block_hash": "ab1a"}),
+            json!({"height": 10, "reward": 500, "block_hash": "ab1b"}),
+            Value::Null,
+        ];
+        let result = quorum_of(&answers, 2).unwrap();
+        assert_eq!(result["reward"], 500);
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn quorum_not_met() {
+        let answers = [
+            json!({"height": 10, "reward": 500}),
+            json!({"height": 11, "reward": 500}),
+            Value::Null,
+        ];
+        assert!(matches!(
+            quorum_of(&answers, 2),
+            Err(PoolError::SolutionCheckQuorum(1, 2))
+        ));
+    }
+
+    fn empty_pplns(n: u64) -> PPLNS {
+        PPLNS {
+            queue: VecDeque::new(),
+            current_n: Default::default(),
+            n: Arc::new(RwLock::new(n)),
+            next_share_id: 0,
+        }
+    }
+
+    fn state_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aleo-pool-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn json_state_round_trips() {
+        let dir = state_test_dir("json");
+        let mut pplns = empty_pplns(100);
+        for (value, owner) in [(10, "a"), (20, "b")] {
+            pplns.add_share(Share::init(value, owner.to_string()));
+        }
+        pplns.save_to(&dir, StateFormat::Json).unwrap();
+
+        let loaded = PPLNS::load_from(&dir, StateFormat::Json).unwrap();
+        let shares = |pplns: &PPLNS| {
+            pplns
+                .queue
+                .iter()
+                .map(|share| (share.id, share.value, share.owner.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shares(&loaded), shares(&pplns));
+        assert_eq!(*loaded.n.read(), 100);
+        assert_eq!(*loaded.current_n.read(), 30);
+        assert_eq!(loaded.next_share_id, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edited_json_state_is_loaded() {
+        let dir = state_test_dir("json-edited");
+        let mut pplns = empty_pplns(100);
+        pplns.add_share(Share::init(10, "a".to_string()));
+        pplns.save_to(&dir, StateFormat::Json).unwrap();
+
+        // an operator moves the share to another owner
+        let path = dir.join(StateFormat::Json.file_name());
+        let edited = read_to_string(&path).unwrap().replace("\"a\"", "\"c\"");
+        write(&path, edited).unwrap();
+
+        let loaded = PPLNS::load_from(&dir, StateFormat::Json).unwrap();
+        assert_eq!(loaded.queue[0].owner, "c");
+        assert_eq!(loaded.queue[0].value, 10);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fill_ratio_of_partial_and_full_windows() {
+        let mut pplns = empty_pplns(100);
+        pplns.add_share(Share::init(25, "a".to_string()));
+        assert_eq!(pplns.fill_ratio(), 0.25);
+        for _ in 0..4 {
+            pplns.add_share(Share::init(25, "b".to_string()));
+        }
+        assert_eq!(pplns.fill_ratio(), 1.0);
+        assert_eq!(empty_pplns(0).fill_ratio(), 0.0);
+    }
+
+    #[test]
+    fn shares_of_a_state_without_ids_are_numbered_in_order() {
+        let dir = state_test_dir("legacy");
+        let mut legacy = empty_pplns(100);
+        legacy.next_share_id = 7;
+        for (value, owner) in [(10, "a"), (20, "b"), (30, "a")] {
+            legacy.add_share(Share::init(value, owner.to_string()));
+        }
+        // version 0 states have neither share ids nor the next id
+        save_file(dir.join(StateFormat::Binary.file_name()), 0, &legacy).unwrap();
+
+        let pplns = PPLNS::load_from(&dir, StateFormat::Binary).unwrap();
+        let ids = pplns.queue.iter().map(|share| share.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(pplns.next_share_id, 3);
+        assert_eq!(pplns.queue.back().unwrap().value, 30);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Serves `answer` to every solution check it receives.
+    #[cfg(feature = "db")]
+    async fn fake_validator(answer: Value) -> String {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let body = answer.to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+                     close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        endpoint
+    }
+
+    /// An endpoint nothing listens on.
+    #[cfg(feature = "db")]
+    fn unreachable_validator() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[cfg(feature = "db")]
+    #[tokio::test]
+    async fn solution_check_fails_over_to_a_secondary() {
+        let endpoints = vec![
+            unreachable_validator(),
+            fake_validator(json!({"height": 10, "reward": 500})).await,
+        ];
+        let answer = Accounting::query_solution_failover(&endpoints, &"commitment".to_string())
+            .await
+            .unwrap();
+        assert_eq!(answer["height"], 10);
+    }
+
+    #[cfg(feature = "db")]
+    #[tokio::test]
+    async fn solution_check_fails_when_all_endpoints_are_down() {
+        let endpoints = vec![unreachable_validator(), unreachable_validator()];
+        assert!(matches!(
+            Accounting::query_solution_failover(&endpoints, &"commitment".to_string()).await,
+            Err(PoolError::SolutionCheckUnavailable(2))
+        ));
+    }
+
+    #[test]
+    fn round_shares_reset_on_solution() {
+        let round_shares = RoundShares::default();
+        for _ in 0..3 {
+            round_shares.record();
+        }
+        assert_eq!(round_shares.count(), 3);
+        assert_eq!(round_shares.reset(), 3);
+        assert_eq!(round_shares.count(), 0);
+        round_shares.record();
+        assert_eq!(round_shares.count(), 1);
+    }
+
+    #[test]
+    fn share_ids_are_monotonic_and_survive_a_save() {
+        let dir = state_test_dir("share-ids");
+        let mut pplns = empty_pplns(50);
+        // the window drops the oldest shares but never reuses their ids
+        for value in [10, 20, 30, 15, 5] {
+            pplns.add_share(Share::init(value, "a".to_string()));
+        }
+        let ids = pplns.queue.iter().map(|share| share.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 3, 4]);
+        pplns.save_to(&dir, StateFormat::Binary).unwrap();
+
+        let mut loaded = PPLNS::load_from(&dir, StateFormat::Binary).unwrap();
+        let loaded_ids = loaded.queue.iter().map(|share| share.id).collect::<Vec<_>>();
+        assert_eq!(loaded_ids, ids);
+        loaded.add_share(Share::init(1, "b".to_string()));
+        assert_eq!(loaded.queue.back().unwrap().id, 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn address() -> String {
+        Address::try_from(PrivateKey::<CanaryV0>::new(&mut thread_rng()).unwrap())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn imported_balances_make_up_the_round() {
+        let dir = state_test_dir("import");
+        let (a, b) = (address(), address());
+        let path = dir.join("balances.json");
+        let balances = HashMap::from([(a.clone(), 300), (b.clone(), 100)]);
+        write(&path, serde_json::to_string(&balances).unwrap()).unwrap();
+        let balances = read_balances(path.to_str().unwrap()).unwrap();
+
+        let mut pplns = empty_pplns(0);
+        let mut wal = ShareWal { file: None };
+        assert_eq!(pplns.import(&balances, Some(1000), &mut wal).unwrap(), (400, 1000));
+        let (current_n, shares) = pplns.round_shares();
+        assert_eq!(current_n, 400);
+        assert_eq!(shares.get(&a), Some(&300));
+        assert_eq!(shares.get(&b), Some(&100));
+        // more than the window holds would push the imported shares out again
+        assert!(pplns.import(&[(a, 700)], None, &mut wal).is_err());
+        assert_eq!(*pplns.current_n.read(), 400);
+
+        write(&path, json!({ "aleo1invalid": 10 }).to_string()).unwrap();
+        assert!(read_balances(path.to_str().unwrap()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn confirmation_carries_height_and_reward() {
+        let solution = Accounting::confirmed_solution(&json!({"height": 10, "reward": 500}))
+            .unwrap()
+            .unwrap();
+        assert_eq!((solution.height, solution.reward), (10, 500));
+        assert!(Accounting::confirmed_solution(&Value::Null).unwrap().is_none());
+        assert!(matches!(
+            Accounting::confirmed_solution(&json!({"height": 10})),
+            Err(PoolError::InvalidSolutionResponse("reward"))
+        ));
+    }
+
+    #[test]
+    fn no_home_directory_is_an_error() {
+        let error = state_dir_in(None).unwrap_err();
+        assert_eq!(error.to_string(), "No home directory found to keep the pool state in");
+        let dir = state_dir_in(Some(PathBuf::from("/home/pool"))).unwrap();
+        assert!(dir.starts_with("/home/pool"));
+    }
+
+    #[test]
+    fn oversized_share_value_is_clamped() {
+        assert_eq!(clamp_share_value(u64::MAX, 1_000, "a"), 1_000);
+        assert_eq!(clamp_share_value(1_000, 1_000, "a"), 1_000);
+        assert_eq!(clamp_share_value(10, 1_000, "a"), 10);
+    }
+
+    #[test]
+    fn round_value_never_wraps() {
+        let mut pplns = empty_pplns(u64::MAX);
+        pplns.add_share(Share::init(u64::MAX - 10, "a".to_string()));
+        // would wrap the round value, so it isn't credited
+        pplns.add_share(Share::init(20, "b".to_string()));
+        assert_eq!(*pplns.current_n.read(), u64::MAX - 10);
+        assert_eq!(pplns.queue.len(), 1);
+        pplns.add_share(Share::init(10, "b".to_string()));
+        assert_eq!(*pplns.current_n.read(), u64::MAX);
+        assert_eq!(pplns.round_shares().0, u64::MAX);
+    }
+
+    #[test]
+    fn solo_solution_credits_only_the_finder() {
+        let mut pplns = empty_pplns(100);
+        let mut solo = Solo;
+        for (value, owner) in [(30, "a"), (40, "b")] {
+            pplns.add_share(Share::init(value, owner.to_string()));
+            solo.add_share(Share::init(value, owner.to_string()));
+        }
+        assert_eq!(solo.solution_shares("b"), HashMap::from([("b".to_string(), 1)]));
+        // the same shares are split under PPLNS
+        assert_eq!(pplns.solution_shares("b").len(), 2);
+    }
+
+    fn wal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pplns-{}-{}.wal", name, std::process::id()));
+        let _ = remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn wal_replay_stops_at_a_truncated_line() {
+        let path = wal_path("truncated");
+        let mut wal = ShareWal {
+            file: Some(ShareWal::open_file(&path).unwrap()),
+        };
+        wal.append(&WalEntry::Share(Share::init(10, "a".to_string())));
+        wal.append(&WalEntry::Share(Share::init(20, "b".to_string())));
+        let file = wal.file.as_mut().unwrap();
+        file.write_all(b"{\"share\":{\"value\":3\n").unwrap();
+        // nothing after the damage is trusted
+        wal.append(&WalEntry::Share(Share::init(30, "c".to_string())));
+        drop(wal);
+
+        let mut pplns = empty_pplns(100);
+        assert_eq!(ShareWal::replay_from(&path, &mut pplns).unwrap(), 2);
+        let (current_n, shares) = pplns.round_shares();
+        assert_eq!(current_n, 30);
+        assert_eq!(shares.get("c"), None);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wal_replay_applies_entries_in_order() {
+        let path = wal_path("order");
+        let mut wal = ShareWal {
+            file: Some(ShareWal::open_file(&path).unwrap()),
+        };
+        wal.append(&WalEntry::Share(Share::init(40, "a".to_string())));
+        // raising n before the next shares keeps "a" in the window
+        wal.append(&WalEntry::SetN(200));
+        wal.append(&WalEntry::Share(Share::init(40, "b".to_string())));
+        wal.append(&WalEntry::Share(Share::init(40, "c".to_string())));
+        drop(wal);
+
+        let mut pplns = empty_pplns(50);
+        assert_eq!(ShareWal::replay_from(&path, &mut pplns).unwrap(), 4);
+        let (current_n, shares) = pplns.round_shares();
+        assert_eq!(current_n, 120);
+        assert_eq!(shares.get("a"), Some(&40));
+        assert_eq!(*pplns.n.read(), 200);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_binary_state_is_set_aside() {
+        let dir = state_test_dir("unreadable");
+        let path = dir.join(StateFormat::Binary.file_name());
+        let mut newer = empty_pplns(100);
+        newer.add_share(Share::init(10, "a".to_string()));
+        // written by a later build
+        save_file(&path, STATE_VERSION + 1, &newer).unwrap();
+
+        let pplns = PPLNS::load_from(&dir, StateFormat::Binary).unwrap();
+        assert!(pplns.queue.is_empty());
+        assert!(!path.exists());
+        let set_aside = read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(set_aside.len(), 1);
+        assert!(set_aside[0].contains(".unreadable-"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_that_cannot_be_read_for_now_is_kept() {
+        let dir = state_test_dir("io-error");
+        // reading a directory fails with an I/O error, not a format error
+        let path = dir.join(StateFormat::Binary.file_name());
+        create_dir_all(&path).unwrap();
+        assert!(PPLNS::load_from(&dir, StateFormat::Binary).is_err());
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn older_state_version_is_migrated_in_place() {
+        let dir = state_test_dir("older");
+        let path = dir.join(StateFormat::Binary.file_name());
+        let mut older = empty_pplns(100);
+        older.add_share(Share::init(10, "a".to_string()));
+        save_file(&path, STATE_VERSION - 1, &older).unwrap();
+
+        let pplns = PPLNS::load_from(&dir, StateFormat::Binary).unwrap();
+        assert_eq!(pplns.queue.len(), 1);
+        assert_eq!(*pplns.n.read(), 100);
+        // nothing was set aside
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "db")]
+    fn pending_solutions_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aleo-pool-{}-{}", name, std::process::id()));
+        create_dir_all(&dir).unwrap();
+        dir.join("pending_solutions.json")
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn transient_failure_keeps_the_solution_for_the_next_start() {
+        let path = pending_solutions_path("transient");
+        let pending = PendingSolutions::load_from(path.clone()).unwrap();
+        pending.add("commitment1".to_string(), HashMap::from([("a".to_string(), 10)]));
+        pending.add("commitment2".to_string(), HashMap::new());
+        for _ in 0..MAX_SAVE_ATTEMPTS {
+            assert!(!pending.record("commitment1", SaveOutcome::Transient(anyhow!("connection refused"))));
+        }
+        // out of attempts for this run, the later solution is tried next
+        assert_eq!(pending.waiting()[0].commitment, "commitment2");
+        assert!(pending.record("commitment2", SaveOutcome::Saved));
+        drop(pending);
+
+        let reloaded = PendingSolutions::load_from(path.clone()).unwrap();
+        let waiting = reloaded.waiting();
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].commitment, "commitment1");
+        assert_eq!(waiting[0].shares.get("a"), Some(&10));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn refused_solution_is_kept_but_not_retried() {
+        let path = pending_solutions_path("refused");
+        let pending = PendingSolutions::load_from(path.clone()).unwrap();
+        pending.add("commitment".to_string(), HashMap::new());
+        assert!(pending.record("commitment", SaveOutcome::Refused(anyhow!("check constraint"))));
+        assert!(pending.waiting().is_empty());
+        drop(pending);
+
+        let reloaded = PendingSolutions::load_from(path.clone()).unwrap();
+        assert!(reloaded.waiting().is_empty());
+        let solutions = reloaded.solutions.lock();
+        assert_eq!(solutions.len(), 1);
+        assert!(solutions[0].refused);
+        drop(solutions);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn last_solution_reflects_its_confirmation() {
+        let mut last_solution = LastSolution::recorded("commitment".to_string());
+        assert!(last_solution.timestamp > 0);
+        let confirmed = Accounting::confirmed_solution(&json!({"height": 10, "reward": 500})).unwrap();
+        // the check of an earlier solution doesn't apply
+        last_solution.checked("earlier", confirmed.as_ref());
+        assert_eq!(last_solution.valid, None);
+
+        last_solution.checked("commitment", confirmed.as_ref());
+        let json = serde_json::to_value(&last_solution).unwrap();
+        assert_eq!(json["commitment"], "commitment");
+        assert_eq!(json["valid"], true);
+        assert_eq!(json["height"], 10);
+        assert_eq!(json["reward"], 500);
+
+        let mut rejected = LastSolution::recorded("other".to_string());
+        rejected.checked("other", None);
+        assert_eq!((rejected.valid, rejected.height), (Some(false), None));
+    }
+
+    #[cfg(feature = "db")]
+    #[tokio::test]
+    async fn paused_payouts_pay_nothing_until_resumed() {
+        let solutions = (0..3).map(|id| (id, format!("commitment{}", id))).collect::<Vec<_>>();
+        let paused = AtomicBool::new(true);
+        let pay_calls = &AtomicU64::new(0);
+        let pay = move |_: i32| async move {
+            pay_calls.fetch_add(1, Ordering::SeqCst);
+            anyhow::Ok(())
+        };
+        let check = |_: String| async { PoolResult::Ok(true) };
+
+        // solutions keep being recorded meanwhile, recording doesn't look at the flag
+        let paid = Accounting::pay_in_order(solutions.clone(), 2, &paused, check, pay).await;
+        assert!(paid.is_empty());
+        assert_eq!(pay_calls.load(Ordering::SeqCst), 0);
+
+        paused.store(false, Ordering::SeqCst);
+        let paid = Accounting::pay_in_order(solutions, 2, &paused, check, pay).await;
+        assert_eq!(paid, vec![0, 1, 2]);
+        assert_eq!(pay_calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[cfg(feature = "db")]
+    #[tokio::test]
+    async fn solutions_are_checked_concurrently_and_paid_once() {
+        let solutions = (0..10).map(|id| (id, format!("commitment{}", id))).collect::<Vec<_>>();
+        let started = Instant::now();
+        let checked = Accounting::check_in_order(solutions, 10, |commitment| async move {
+            sleep(Duration::from_millis(100)).await;
+            Ok(commitment != "commitment3")
+        })
+        .collect::<Vec<_>>()
+        .await;
+        // one at a time the checks would take a second
+        assert!(started.elapsed() < Duration::from_millis(500));
+        let paid = checked
+            .into_iter()
+            .filter(|(.., valid)| *valid.as_ref().unwrap())
+            .map(|(id, ..)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(paid, vec![0, 1, 2, 4, 5, 6, 7, 8, 9]);
+    }
+}
@@ -1,59 +1,454 @@
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use serde_json::json;
+use aleo_stratum::{codec::MAX_MESSAGE_LEN, message::StratumMessage};
+use anyhow::anyhow;
+use futures::{stream, Stream, StreamExt};
+use rustls_pemfile::Item;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use snarkvm::{console::account::Address, prelude::CanaryV0};
-use tokio::task;
-use tracing::info;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, broadcast::error::RecvError},
+    task,
+    time::{interval, timeout},
+};
+use tokio_rustls::{
+    rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor,
+};
+use tracing::{debug, error, info};
 use warp::{
-    addr::remote,
+    filters::BoxedFilter,
     get,
     head,
+    hyper::{
+        server::conn::Http,
+        service::{service_fn, Service},
+    },
     path,
+    post,
     reply,
-    reply::{json, Json},
-    serve,
+    reply::{json, Json, Response},
+    sse,
+    sse::Event,
     Filter,
     Reply,
 };
 
-use crate::{Accounting, Server};
+use crate::{
+    accounting::ConfirmedSolution,
+    connection::SLOW_DISCONNECTS,
+    listener,
+    listener::ListenerOptions,
+    maintenance::MaintenanceWindow,
+    util,
+    Accounting,
+    Server,
+};
+
+/// Most addresses `/stats/batch` answers for in one request
+const MAX_BATCH_ADDRESSES: usize = 100;
+
+/// Path prefix of the newest API version. Versions only add fields; a change that removes or
+/// reshapes one needs a new version serving its own handlers while the old prefix keeps the old ones.
+const LATEST_API_VERSION: &str = "v1";
+
+/// Most clients subscribed to `/events` at once
+const MAX_EVENT_SUBSCRIBERS: usize = 256;
+
+/// How often `/events` pushes the pool stats
+const EVENT_STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+static EVENT_SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
 
-pub fn start(port: u16, accounting: Arc<Accounting>, server: Arc<Server>) {
+/// Longest a client may take to complete the TLS handshake
+static TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Share arrivals are roughly Poisson (variation ~1), so well above that hints at a difficulty mismatch
+const SHARE_VARIATION_THRESHOLD: f64 = 2.0;
+
+pub struct ApiTlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// Moves the admin routes to their own listener that requires client certificates
+    pub admin_client_auth: Option<AdminClientAuth>,
+}
+
+pub struct AdminClientAuth {
+    /// CA the admin client certificates must be signed by
+    pub ca_path: String,
+    pub port: u16,
+}
+
+pub fn start(
+    port: u16,
+    listener_options: ListenerOptions,
+    accounting: Arc<Accounting>,
+    server: Arc<Server>,
+    tls: Option<ApiTlsConfig>,
+) {
     task::spawn(async move {
         let current_round = path("current_round")
             .and(use_accounting(accounting.clone()))
             .then(current_round)
             .boxed();
 
-        let pool_stats = path("stats").and(use_server(server.clone())).then(pool_stats).boxed();
+        let last_solution = path("last_solution")
+            .and(use_accounting(accounting.clone()))
+            .then(last_solution)
+            .boxed();
+
+        let pool_stats = path("stats")
+            .and(use_server(server.clone()))
+            .and(use_accounting(accounting.clone()))
+            .then(pool_stats)
+            .boxed();
+
+        let events = path("events")
+            .and(use_server(server.clone()))
+            .and(use_accounting(accounting.clone()))
+            .then(events)
+            .boxed();
+
+        #[cfg(feature = "geoip")]
+        let geo_stats = path!("stats" / "geo")
+            .and(use_server(server.clone()))
+            .then(geo_stats)
+            .boxed();
+
+        let snapshot = path("snapshot")
+            .and(warp::query::<SnapshotQuery>())
+            .and(use_server(server.clone()))
+            .and(use_accounting(accounting.clone()))
+            .then(snapshot)
+            .boxed();
 
         let address_stats = path!("stats" / String)
             .and(use_server(server.clone()))
             .then(address_stats)
             .boxed();
 
+        let address_balance = path!("stats" / String / "balance")
+            .and(use_accounting(accounting.clone()))
+            .then(address_balance)
+            .boxed();
+
+        let address_shares = path!("stats" / String / "shares")
+            .and(use_accounting(accounting.clone()))
+            .then(address_shares)
+            .boxed();
+
+        let address_difficulty = path!("stats" / String / "difficulty")
+            .and(use_server(server.clone()))
+            .then(address_difficulty)
+            .boxed();
+
+        // clients on the admin listener are already authenticated by their certificate
+        let loopback_only = tls.as_ref().map_or(true, |tls| tls.admin_client_auth.is_none());
+
         let admin_current_round = path!("admin" / "current_round")
-            .and(remote())
+            .and(admin_access(loopback_only))
             .and(use_accounting(accounting.clone()))
             .then(admin_current_round)
             .boxed();
 
+        let admin_bans = path!("admin" / "bans")
+            .and(admin_access(loopback_only))
+            .and(use_server(server.clone()))
+            .then(admin_bans)
+            .boxed();
+
+        let admin_unban = path!("admin" / "unban" / String)
+            .and(admin_access(loopback_only))
+            .and(use_server(server.clone()))
+            .then(admin_unban)
+            .boxed();
+
+        let admin_drain = path!("admin" / "drain" / u64)
+            .and(admin_access(loopback_only))
+            .and(use_server(server.clone()))
+            .then(admin_drain)
+            .boxed();
+
+        let admin_maintenance = path!("admin" / "maintenance")
+            .and(admin_access(loopback_only))
+            .and(use_server(server.clone()))
+            .then(admin_maintenance)
+            .boxed();
+
+        let admin_schedule_maintenance = path!("admin" / "maintenance" / u64 / u64)
+            .and(admin_access(loopback_only))
+            .and(use_server(server.clone()))
+            .then(admin_schedule_maintenance)
+            .boxed();
+
+        let admin_cancel_maintenance = path!("admin" / "maintenance" / "cancel")
+            .and(admin_access(loopback_only))
+            .and(use_server(server.clone()))
+            .then(admin_cancel_maintenance)
+            .boxed();
+
+        let stats_batch = post()
+            .and(path!("stats" / "batch"))
+            .and(warp::body::content_length_limit(MAX_BATCH_ADDRESSES as u64 * 128))
+            .and(warp::body::json())
+            .and(use_server(server.clone()))
+            .and(use_accounting(accounting.clone()))
+            .then(stats_batch)
+            .boxed();
+        let stats_batch = versioned(stats_batch);
+
+        let admin_notify = post()
+            .and(path!("admin" / "connections" / "notify"))
+            .and(admin_access(loopback_only))
+            .and(warp::body::content_length_limit(MAX_MESSAGE_LEN as u64))
+            .and(warp::body::json())
+            .and(use_server(server.clone()))
+            .then(admin_notify)
+            .boxed();
+
+        let admin_payouts = post()
+            .and(
+                path!("admin" / "payouts" / "pause")
+                    .map(|| true)
+                    .or(path!("admin" / "payouts" / "resume").map(|| false))
+                    .unify(),
+            )
+            .and(admin_access(loopback_only))
+            .and(use_accounting(accounting.clone()))
+            .then(admin_payouts)
+            .boxed();
+
         let endpoints = current_round
+            .or(last_solution)
             .or(address_stats)
+            .or(address_balance)
+            .or(address_shares)
+            .or(address_difficulty)
             .or(pool_stats)
-            .or(admin_current_round)
+            .or(snapshot)
+            .or(events)
             .boxed();
 
-        let routes = get()
-            .or(head())
-            .unify()
-            .and(endpoints)
-            .with(warp::log("aleo_pool_server::api"));
-        info!("Starting API server on port {}", port);
-        serve(routes).run(([0, 0, 0, 0], port)).await;
+        // must be matched before address_stats, which would take "geo" as an address
+        #[cfg(feature = "geoip")]
+        let endpoints = geo_stats.or(endpoints).boxed();
+
+        let endpoints = versioned(endpoints);
+
+        let admin_endpoints = admin_current_round
+            .or(admin_bans)
+            .or(admin_unban)
+            .or(admin_drain)
+            .or(admin_maintenance)
+            .or(admin_schedule_maintenance)
+            .or(admin_cancel_maintenance)
+            .boxed();
+
+        let methods = get().or(head()).unify();
+        let log = warp::log("aleo_pool_server::api");
+        let bind = |port: u16| match listener::bind(([0, 0, 0, 0], port).into(), listener_options) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Unable to start the API server on port {}: {}", port, e);
+                None
+            }
+        };
+        match tls {
+            None => {
+                let routes = methods
+                    .and(endpoints.or(admin_endpoints))
+                    .or(stats_batch)
+                    .or(admin_notify)
+                    .or(admin_payouts)
+                    .with(log)
+                    .map(Reply::into_response)
+                    .boxed();
+                let listener = match bind(port) {
+                    Some(listener) => listener,
+                    None => return,
+                };
+                info!("Starting API server on port {}", port);
+                serve_listener(listener, None, routes).await;
+            }
+            Some(ApiTlsConfig {
+                cert_path,
+                key_path,
+                admin_client_auth: None,
+            }) => {
+                let routes = methods
+                    .and(endpoints.or(admin_endpoints))
+                    .or(stats_batch)
+                    .or(admin_notify)
+                    .or(admin_payouts)
+                    .with(log)
+                    .map(Reply::into_response)
+                    .boxed();
+                let acceptor = match tls_acceptor(&cert_path, &key_path, None) {
+                    Ok(acceptor) => acceptor,
+                    Err(e) => {
+                        error!("Unable to start the API server: {}", e);
+                        return;
+                    }
+                };
+                let listener = match bind(port) {
+                    Some(listener) => listener,
+                    None => return,
+                };
+                info!("Starting API server with TLS on port {}", port);
+                serve_listener(listener, Some(acceptor), routes).await;
+            }
+            Some(ApiTlsConfig {
+                cert_path,
+                key_path,
+                admin_client_auth: Some(admin),
+            }) => {
+                let routes = methods
+                    .clone()
+                    .and(endpoints)
+                    .or(stats_batch)
+                    .with(log)
+                    .map(Reply::into_response)
+                    .boxed();
+                let admin_routes = methods
+                    .and(admin_endpoints)
+                    .or(admin_notify)
+                    .or(admin_payouts)
+                    .with(log)
+                    .map(Reply::into_response)
+                    .boxed();
+                let acceptors = tls_acceptor(&cert_path, &key_path, None)
+                    .and_then(|api| Ok((api, tls_acceptor(&cert_path, &key_path, Some(&admin.ca_path))?)));
+                let (acceptor, admin_acceptor) = match acceptors {
+                    Ok(acceptors) => acceptors,
+                    Err(e) => {
+                        error!("Unable to start the API server: {}", e);
+                        return;
+                    }
+                };
+                let (listener, admin_listener) = match (bind(port), bind(admin.port)) {
+                    (Some(listener), Some(admin_listener)) => (listener, admin_listener),
+                    _ => return,
+                };
+                info!("Starting API server with TLS on port {}", port);
+                info!(
+                    "Starting admin API server with client authentication on port {}",
+                    admin.port
+                );
+                tokio::join!(
+                    serve_listener(listener, Some(acceptor), routes),
+                    serve_listener(admin_listener, Some(admin_acceptor), admin_routes),
+                );
+            }
+        }
     });
 }
 
+/// Address of the client, attached to each request by `serve_listener` as warp only knows it on
+/// the listeners it binds itself.
+#[derive(Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+/// Builds the TLS config the way warp does, requiring client certificates signed by the CA in
+/// `client_ca_path` if given.
+fn tls_acceptor(cert_path: &str, key_path: &str, client_ca_path: Option<&str>) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key in {}", key_path))?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_path)?))? {
+                roots
+                    .add(&Certificate(cert))
+                    .map_err(|e| anyhow!("Invalid CA certificate in {}: {:?}", ca_path, e))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves `routes` to the clients accepted on `listener`, over TLS if an acceptor is given. Binding
+/// the listener here rather than in warp is what lets it have the configured socket options.
+async fn serve_listener(listener: TcpListener, tls: Option<TlsAcceptor>, routes: BoxedFilter<(Response,)>) {
+    let service = warp::service(routes);
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept API connection: {}", e);
+                continue;
+            }
+        };
+        let service = service.clone();
+        let tls = tls.clone();
+        task::spawn(async move {
+            let service = service_fn(move |mut request| {
+                request.extensions_mut().insert(RemoteAddr(remote_addr));
+                service.clone().call(request)
+            });
+            let result = match tls {
+                None => Http::new().serve_connection(stream, service).await,
+                Some(tls) => match timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                    Ok(Ok(stream)) => Http::new().serve_connection(stream, service).await,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", remote_addr);
+                        return;
+                    }
+                },
+            };
+            if let Err(e) = result {
+                debug!("API connection from {} failed: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+/// Serves `endpoints` under the latest version prefix and unversioned. There is only one version
+/// so far, so both share the handlers.
+fn versioned<R: Reply + Send + 'static>(endpoints: BoxedFilter<(R,)>) -> BoxedFilter<(R,)> {
+    path(LATEST_API_VERSION)
+        .and(endpoints.clone())
+        .or(endpoints)
+        .unify()
+        .boxed()
+}
+
+/// Whether the client may use the admin routes.
+fn admin_access(loopback_only: bool) -> impl Filter<Extract = (bool,), Error = Infallible> + Clone {
+    warp::ext::optional::<RemoteAddr>()
+        .map(move |addr: Option<RemoteAddr>| !loopback_only || addr.is_some_and(|addr| addr.0.ip().is_loopback()))
+}
+
 fn use_accounting(
     accounting: Arc<Accounting>,
 ) -> impl Filter<Extract = (Arc<Accounting>,), Error = Infallible> + Clone {
@@ -63,23 +458,195 @@ fn use_server(server: Arc<Server>) -> impl Filter<Extract = (Arc<Server>,), Erro
     warp::any().map(move || server.clone())
 }
 
-async fn pool_stats(server: Arc<Server>) -> Json {
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "SnapshotQuery::default_limit")]
+    limit: usize,
+}
+
+impl SnapshotQuery {
+    fn default_limit() -> usize {
+        1000
+    }
+}
+
+/// Workers and speeds come from one read of the connection tables, shares and `current_n` from one
+/// read of the round, taken at `timestamp`, so the shares always add up to `current_n`.
+async fn snapshot(query: SnapshotQuery, server: Arc<Server>, accounting: Arc<Accounting>) -> Json {
+    let active = server.active_addresses().await;
+    let (current_n, mut shares) = accounting.round_shares_by_address().await;
+    let timestamp = util::now();
+
+    // addresses with shares in the round but no connection are included so the shares add up
+    let mut addresses = active
+        .into_iter()
+        .map(|(address, (workers, speed))| {
+            let address = address.to_string();
+            let current_round_shares = shares.remove(&address).unwrap_or(0);
+            (address, workers, speed, current_round_shares)
+        })
+        .collect::<Vec<_>>();
+    addresses.extend(
+        shares
+            .into_iter()
+            .map(|(address, current_round_shares)| (address, 0, vec![0.0; 4], current_round_shares)),
+    );
+    addresses.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let total = addresses.len();
+    let page = addresses
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit)
+        .map(|(address, workers, speed, current_round_shares)| {
+            json!({
+                "address": address,
+                "workers": workers,
+                "speed": speed,
+                "current_round_shares": current_round_shares,
+            })
+        })
+        .collect::<Vec<_>>();
+
     json(&json!({
+        "timestamp": timestamp,
+        "current_n": current_n,
+        "total": total,
+        "offset": query.offset,
+        "addresses": page,
+    }))
+}
+
+async fn pool_stats(server: Arc<Server>, accounting: Arc<Accounting>) -> Json {
+    let (speed_short, speed_long) = server.pool_window_speed();
+    let stats = json!({
         "online_addresses": server.online_addresses().await,
         "online_provers": server.online_provers().await,
-        "speed": server.pool_speed().await,
-    }))
+        "speed": server.pool_speed(),
+        "speed_short": speed_short,
+        "speed_long": speed_long,
+        "fill_ratio": accounting.fill_ratio().await,
+        "submit_latency_ms": server
+            .submit_latency()
+            .into_iter()
+            .map(|(bucket, count)| (bucket, json!(count)))
+            .collect::<serde_json::Map<_, _>>(),
+        "evicted_addresses": server.evicted_addresses(),
+        "payouts_paused": accounting.payouts_paused(),
+        "uncredited_shares": server.uncredited_shares(),
+        "slow_disconnects": SLOW_DISCONNECTS.load(Ordering::Relaxed),
+        "decode_buffer": {
+            "limit": MAX_MESSAGE_LEN,
+            "max_buffered": server.codec_stats().max_buffered(),
+            "length_limit_exceeded": server.codec_stats().length_limit_exceeded(),
+        },
+        "queue_depth": {
+            "accounting": accounting.channel_depth(),
+            "outbound": server.outbound_queue_depth().await,
+        },
+    });
+    #[cfg(feature = "db")]
+    let stats = {
+        let mut stats = stats;
+        stats["database_breaker"] = json!(accounting.database_breaker());
+        stats
+    };
+    json(&stats)
+}
+
+#[cfg(feature = "geoip")]
+async fn geo_stats(server: Arc<Server>) -> Json {
+    json(&server.geo_stats().await)
 }
 
 async fn address_stats(address: String, server: Arc<Server>) -> impl Reply {
     if let Ok(address) = address.parse::<Address<CanaryV0>>() {
         let speed = server.address_speed(address).await;
         let prover_count = server.address_prover_count(address).await;
+        let protocol_violations = server.address_protocol_violations(address).await;
+        let share_variation = server.address_share_variation(address).await;
+        let lifetime = server.lifetime_stats(&address);
         Ok(reply::with_status(
             json(&json!({
                 "online_provers": prover_count,
                 "speed": speed,
+                "protocol_violations": protocol_violations,
+                "share_variation": share_variation,
+                "share_variation_healthy": share_variation.map_or(true, |v| v <= SHARE_VARIATION_THRESHOLD),
+                "lifetime": {
+                    "accepted": lifetime.accepted,
+                    "rejected": lifetime.rejected,
+                },
+            })),
+            warp::http::StatusCode::OK,
+        ))
+    } else {
+        Ok(reply::with_status(
+            json(&json!({
+                "error": "invalid address"
             })),
+            warp::http::StatusCode::BAD_REQUEST,
+        ))
+    }
+}
+
+/// Stats of several addresses in one response, keyed by address. Invalid addresses map to null.
+async fn stats_batch(addresses: Vec<String>, server: Arc<Server>, accounting: Arc<Accounting>) -> impl Reply {
+    let parsed = match parse_batch(&addresses) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            return Ok(reply::with_status(
+                json(&json!({ "error": error })),
+                warp::http::StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let stats = server.addresses_stats(&parsed).await;
+    let (_, round_shares) = accounting.round_shares_by_address().await;
+    Ok(reply::with_status(
+        json(&batch_stats(addresses, &stats, &round_shares)),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Valid addresses of a batch, or the error if the batch is too large.
+fn parse_batch(addresses: &[String]) -> Result<Vec<Address<CanaryV0>>, String> {
+    if addresses.len() > MAX_BATCH_ADDRESSES {
+        return Err(format!("at most {} addresses per request", MAX_BATCH_ADDRESSES));
+    }
+    Ok(addresses
+        .iter()
+        .filter_map(|address| address.parse::<Address<CanaryV0>>().ok())
+        .collect())
+}
+
+fn batch_stats(
+    addresses: Vec<String>,
+    stats: &HashMap<Address<CanaryV0>, (u32, Vec<f64>)>,
+    round_shares: &HashMap<String, u64>,
+) -> serde_json::Map<String, Value> {
+    addresses
+        .into_iter()
+        .map(|address| {
+            let value = match address.parse::<Address<CanaryV0>>().ok().and_then(|a| stats.get(&a)) {
+                Some((prover_count, speed)) => json!({
+                    "online_provers": prover_count,
+                    "speed": speed,
+                    "round_shares": round_shares.get(&address).copied().unwrap_or(0),
+                }),
+                None => Value::Null,
+            };
+            (address, value)
+        })
+        .collect()
+}
+
+async fn address_difficulty(address: String, server: Arc<Server>) -> impl Reply {
+    if let Ok(address) = address.parse::<Address<CanaryV0>>() {
+        Ok(reply::with_status(
+            json(&server.address_difficulty_history(address).await),
             warp::http::StatusCode::OK,
         ))
     } else {
@@ -92,19 +659,146 @@ async fn address_stats(address: String, server: Arc<Server>) -> impl Reply {
     }
 }
 
+async fn address_shares(address: String, accounting: Arc<Accounting>) -> impl Reply {
+    if address.parse::<Address<CanaryV0>>().is_err() {
+        return Ok(reply::with_status(
+            json(&json!({
+                "error": "invalid address"
+            })),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(reply::with_status(
+        json(&accounting.address_shares(address).await),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn address_balance(address: String, accounting: Arc<Accounting>) -> impl Reply {
+    if address.parse::<Address<CanaryV0>>().is_err() {
+        return Ok(reply::with_status(
+            json(&json!({
+                "error": "invalid address"
+            })),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    match accounting.address_balance(address).await {
+        Ok(balance) => Ok(reply::with_status(json(&balance), warp::http::StatusCode::OK)),
+        Err(e) => {
+            error!("Failed to get address balance: {}", e);
+            Ok(reply::with_status(
+                json(&json!({
+                    "error": "internal error"
+                })),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+/// Counts a subscriber of `/events` until its event stream is dropped, which happens when the
+/// client goes away.
+struct EventSubscriber;
+
+impl EventSubscriber {
+    fn acquire() -> Option<Self> {
+        EVENT_SUBSCRIBERS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < MAX_EVENT_SUBSCRIBERS).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| EventSubscriber)
+    }
+}
+
+impl Drop for EventSubscriber {
+    fn drop(&mut self) {
+        EVENT_SUBSCRIBERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Server-sent events with the pool stats every `EVENT_STATS_INTERVAL` and confirmed solutions as
+/// they come in.
+async fn events(server: Arc<Server>, accounting: Arc<Accounting>) -> reply::Response {
+    let subscriber = match EventSubscriber::acquire() {
+        Some(subscriber) => subscriber,
+        None => {
+            return reply::with_status(
+                json(&json!({
+                    "error": "too many event subscribers"
+                })),
+                warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            )
+            .into_response()
+        }
+    };
+    // the speeds are the ones the server samples for /stats
+    let stats = stream::unfold(interval(EVENT_STATS_INTERVAL), move |mut ticker| {
+        let server = server.clone();
+        async move {
+            ticker.tick().await;
+            let stats = json!({
+                "online_addresses": server.online_addresses().await,
+                "online_provers": server.online_provers().await,
+                "speed": server.pool_speed(),
+            });
+            Some((stats, ticker))
+        }
+    });
+    let events = event_stream(accounting.subscribe_solutions(), stats).map(move |event| {
+        // held for as long as the client is subscribed
+        let _subscriber = &subscriber;
+        Ok::<_, Infallible>(event)
+    });
+    sse::reply(sse::keep_alive().stream(events)).into_response()
+}
+
+/// A `solution` event for each confirmed solution and a `stats` event for each item of `stats`.
+fn event_stream(
+    solutions: broadcast::Receiver<ConfirmedSolution>,
+    stats: impl Stream<Item = Value>,
+) -> impl Stream<Item = Event> {
+    let solutions = stream::unfold(solutions, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(solution) => return Some((solution, receiver)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .filter_map(|solution| async move { Event::default().event("solution").json_data(solution).ok() });
+    let stats = stats.filter_map(|stats| async move { Event::default().event("stats").json_data(stats).ok() });
+    stream::select(solutions, stats)
+}
+
+async fn last_solution(accounting: Arc<Accounting>) -> impl Reply {
+    match accounting.last_solution() {
+        Some(solution) => Ok(reply::with_status(json(&solution), warp::http::StatusCode::OK)),
+        None => Ok(reply::with_status(
+            json(&json!({
+                "error": "no solution found yet"
+            })),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
 async fn current_round(accounting: Arc<Accounting>) -> Json {
     let data = accounting.current_round().await;
 
     json(&json! ({
         "n": data["n"],
         "current_n": data["current_n"],
+        "fill_ratio": data["fill_ratio"],
+        "round_shares": data["round_shares"],
         "provers": data["provers"],
     }))
 }
 
-async fn admin_current_round(addr: Option<SocketAddr>, accounting: Arc<Accounting>) -> impl Reply {
-    let addr = addr.unwrap();
-    if addr.ip().is_loopback() {
+async fn admin_current_round(allowed: bool, accounting: Arc<Accounting>) -> impl Reply {
+    if allowed {
         let pplns = accounting.current_round().await;
         Ok(reply::with_status(json(&pplns), warp::http::StatusCode::OK))
     } else {
@@ -114,3 +808,330 @@ async fn admin_current_round(addr: Option<SocketAddr>, accounting: Arc<Accountin
         ))
     }
 }
+
+async fn admin_bans(allowed: bool, server: Arc<Server>) -> impl Reply {
+    if allowed {
+        let bans: HashMap<String, u64> = server
+            .banned_addresses()
+            .into_iter()
+            .map(|(address, remaining)| (address.to_string(), remaining.as_secs()))
+            .collect();
+        Ok(reply::with_status(json(&bans), warp::http::StatusCode::OK))
+    } else {
+        Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ))
+    }
+}
+
+async fn admin_drain(grace: u64, allowed: bool, server: Arc<Server>) -> impl Reply {
+    if !allowed {
+        return Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    let draining = server.drain_and_reconnect(Duration::from_secs(grace)).await;
+    Ok(reply::with_status(
+        json(&json!({
+            "draining": draining,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn admin_maintenance(allowed: bool, server: Arc<Server>) -> impl Reply {
+    if !allowed {
+        return Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    Ok(reply::with_status(
+        json(&json!({
+            "window": server.maintenance_window(),
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Schedules maintenance from `start` to `end`, in unix seconds.
+async fn admin_schedule_maintenance(start: u64, end: u64, allowed: bool, server: Arc<Server>) -> impl Reply {
+    if !allowed {
+        return Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    if start >= end || end <= util::now() {
+        return Ok(reply::with_status(
+            json(&json!({
+                "error": "invalid maintenance window"
+            })),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    let window = MaintenanceWindow { start, end };
+    match server.schedule_maintenance(Some(window)) {
+        Ok(()) => Ok(reply::with_status(
+            json(&json!({
+                "window": window,
+            })),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to schedule maintenance: {}", e);
+            Ok(reply::with_status(
+                json(&json!({
+                    "error": "failed to save the maintenance schedule"
+                })),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+async fn admin_cancel_maintenance(allowed: bool, server: Arc<Server>) -> impl Reply {
+    if !allowed {
+        return Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    let cancelled = server.maintenance_window().is_some();
+    if let Err(e) = server.schedule_maintenance(None) {
+        error!("Failed to cancel maintenance: {}", e);
+        return Ok(reply::with_status(
+            json(&json!({
+                "error": "failed to save the maintenance schedule"
+            })),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        ));
+    }
+    Ok(reply::with_status(
+        json(&json!({
+            "cancelled": cancelled,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn admin_payouts(paused: bool, allowed: bool, accounting: Arc<Accounting>) -> impl Reply {
+    if !allowed {
+        return Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    let was_paused = accounting.set_payouts_paused(paused);
+    Ok(reply::with_status(
+        json(&json!({
+            "paused": paused,
+            "was_paused": was_paused,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct NotifyRequest {
+    /// In the body rather than the path, where an IPv6 address would need percent-encoding
+    peer: SocketAddr,
+    job_id: String,
+    epoch_challenge: String,
+    address: Option<String>,
+    #[serde(default)]
+    clean_jobs: bool,
+}
+
+/// Sends a job to a single connection, for debugging a specific prover.
+async fn admin_notify(allowed: bool, job: NotifyRequest, server: Arc<Server>) -> impl Reply {
+    if !allowed {
+        return Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    let peer_addr = job.peer;
+    let notify = StratumMessage::Notify(job.job_id, job.epoch_challenge, job.address, job.clean_jobs);
+    if server.send_to_connection(peer_addr, notify).await {
+        info!("Sent a test job to {}", peer_addr);
+        Ok(reply::with_status(
+            json(&json!({
+                "sent": true,
+            })),
+            warp::http::StatusCode::OK,
+        ))
+    } else {
+        Ok(reply::with_status(
+            json(&json!({
+                "error": "connection not found"
+            })),
+            warp::http::StatusCode::NOT_FOUND,
+        ))
+    }
+}
+
+async fn admin_unban(address: String, allowed: bool, server: Arc<Server>) -> impl Reply {
+    if !allowed {
+        return Ok(reply::with_status(
+            json(&"Method Not Allowed"),
+            warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    if let Ok(address) = address.parse::<Address<CanaryV0>>() {
+        Ok(reply::with_status(
+            json(&json!({
+                "unbanned": server.unban_address(&address),
+            })),
+            warp::http::StatusCode::OK,
+        ))
+    } else {
+        Ok(reply::with_status(
+            json(&json!({
+                "error": "invalid address"
+            })),
+            warp::http::StatusCode::BAD_REQUEST,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn event_client_receives_a_well_formed_event() {
+        let (sender, solutions) = broadcast::channel::<ConfirmedSolution>(1);
+        drop(sender);
+        let stats = stream::iter(vec![json!({ "online_provers": 1 })]);
+        let response = sse::reply(event_stream(solutions, stats).map(Ok::<_, Infallible>)).into_response();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("event:stats\n"));
+        assert!(body.contains("data:{\"online_provers\":1}\n"));
+        assert!(body.ends_with("\n\n"));
+    }
+
+    fn tls_fixture(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Connects to an admin acceptor with the client certificate `name`, returning whether the pool
+    /// accepted it.
+    async fn admin_handshake(name: &str) -> bool {
+        use tokio_rustls::{
+            rustls::{ClientConfig, ServerName},
+            TlsConnector,
+        };
+        let acceptor = tls_acceptor(
+            &tls_fixture("server.pem"),
+            &tls_fixture("server.key"),
+            Some(&tls_fixture("ca.pem")),
+        )
+        .unwrap();
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(tls_fixture("ca.pem")).unwrap())).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let certs = rustls_pemfile::certs(&mut BufReader::new(
+            File::open(tls_fixture(&format!("{}.pem", name))).unwrap(),
+        ))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(
+            File::open(tls_fixture(&format!("{}.key", name))).unwrap(),
+        ))
+        .unwrap()
+        .remove(0);
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_single_cert(certs, PrivateKey(key))
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let (accepted, _) = tokio::join!(
+            acceptor.accept(server),
+            connector.connect(ServerName::try_from("localhost").unwrap(), client)
+        );
+        accepted.is_ok()
+    }
+
+    #[tokio::test]
+    async fn admin_client_certificates_are_checked() {
+        assert!(admin_handshake("client").await);
+        // signed by a CA the pool doesn't trust
+        assert!(!admin_handshake("other_client").await);
+    }
+
+    #[tokio::test]
+    async fn confirmed_solution_is_announced() {
+        let (sender, solutions) = broadcast::channel(1);
+        sender
+            .send(ConfirmedSolution {
+                height: 10,
+                reward: 500,
+            })
+            .unwrap();
+        drop(sender);
+        let response =
+            sse::reply(event_stream(solutions, stream::empty::<Value>()).map(Ok::<_, Infallible>)).into_response();
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("event:solution\n"));
+        assert!(body.contains("data:{\"height\":10,\"reward\":500}\n"));
+    }
+
+    #[tokio::test]
+    async fn unversioned_paths_match_the_latest_version() {
+        let stats = versioned(path("stats").and(warp::path::end()).map(|| "stats").boxed());
+        for path in ["/stats", "/v1/stats"] {
+            let response = warp::test::request().path(path).reply(&stats).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.body(), "stats");
+        }
+        let response = warp::test::request().path("/v2/stats").reply(&stats).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    fn address() -> Address<CanaryV0> {
+        Address::try_from(snarkvm::prelude::PrivateKey::<CanaryV0>::new(&mut rand::thread_rng()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn batch_stats_cover_each_address() {
+        let (online, offline) = (address(), address());
+        let stats = HashMap::from([(online, (2, vec![1.0, 2.0, 3.0, 4.0]))]);
+        let round_shares = HashMap::from([(online.to_string(), 40)]);
+        let addresses = vec![online.to_string(), offline.to_string(), "not an address".to_string()];
+
+        assert_eq!(parse_batch(&addresses).unwrap(), vec![online, offline]);
+        let result = batch_stats(addresses, &stats, &round_shares);
+        assert_eq!(
+            result[&online.to_string()],
+            json!({"online_provers": 2, "speed": [1.0, 2.0, 3.0, 4.0], "round_shares": 40})
+        );
+        assert_eq!(result[&offline.to_string()], Value::Null);
+        assert_eq!(result["not an address"], Value::Null);
+    }
+
+    #[test]
+    fn oversize_batch_is_rejected() {
+        let addresses = vec![address().to_string(); MAX_BATCH_ADDRESSES + 1];
+        assert_eq!(
+            parse_batch(&addresses).unwrap_err(),
+            "at most 100 addresses per request"
+        );
+        assert!(parse_batch(&addresses[..MAX_BATCH_ADDRESSES]).is_ok());
+    }
+}
@@ -0,0 +1,62 @@
+use std::{collections::HashSet, fs, future::Future, pin::Pin, str::FromStr};
+
+use anyhow::Error;
+use snarkvm::{console::account::Address, prelude::CanaryV0};
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// Decides whether a worker may authorize once its address has been parsed. A rejection carries a
+/// short reason sent to the prover.
+pub trait Authenticator: Send + Sync {
+    fn authenticate<'a>(
+        &'a self,
+        address: &'a Address<CanaryV0>,
+        worker_name: &'a str,
+        password: &'a str,
+    ) -> AuthFuture<'a>;
+}
+
+/// Accepts every worker with a valid address.
+pub struct AcceptAll;
+
+impl Authenticator for AcceptAll {
+    fn authenticate<'a>(&'a self, _: &'a Address<CanaryV0>, _: &'a str, _: &'a str) -> AuthFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Accepts only the addresses listed in a file, one per line. Empty lines and lines starting with
+/// `#` are skipped.
+pub struct Allowlist {
+    addresses: HashSet<Address<CanaryV0>>,
+}
+
+impl Allowlist {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut addresses = HashSet::new();
+        for line in fs::read_to_string(path)?.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let address =
+                Address::<CanaryV0>::from_str(line).map_err(|e| anyhow::anyhow!("invalid address {}: {}", line, e))?;
+            addresses.insert(address);
+        }
+        Ok(Self { addresses })
+    }
+
+    pub fn address_count(&self) -> usize {
+        self.addresses.len()
+    }
+}
+
+impl Authenticator for Allowlist {
+    fn authenticate<'a>(&'a self, address: &'a Address<CanaryV0>, _: &'a str, _: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            match self.addresses.contains(address) {
+                true => Ok(()),
+                false => Err("Address not allowed".to_string()),
+            }
+        })
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use snarkvm::{console::account::Address, prelude::CanaryV0};
+use tracing::{info, warn};
+
+#[derive(Clone)]
+pub struct AutoBanConfig {
+    /// Reject ratio above which an address gets banned
+    pub reject_ratio: f64,
+    /// Minimum number of submits in the window before the ratio is considered
+    pub min_samples: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
+
+/// Bans addresses whose submits are mostly rejected.
+pub struct AutoBan {
+    config: AutoBanConfig,
+    samples: Mutex<HashMap<Address<CanaryV0>, VecDeque<(Instant, bool)>>>,
+    banned: Mutex<HashMap<Address<CanaryV0>, Instant>>,
+}
+
+impl AutoBan {
+    pub fn new(config: AutoBanConfig) -> Self {
+        Self {
+            config,
+            samples: Default::default(),
+            banned: Default::default(),
+        }
+    }
+
+    /// Records a submit result and returns true if the address got banned by it.
+    pub fn record(&self, address: Address<CanaryV0>, accepted: bool) -> bool {
+        let mut samples = self.samples.lock();
+        let queue = samples.entry(address).or_default();
+        queue.push_back((Instant::now(), accepted));
+        while queue.front().is_some_and(|t| t.0.elapsed() > self.config.window) {
+            queue.pop_front();
+        }
+        if queue.len() < self.config.min_samples {
+            return false;
+        }
+        let rejected = queue.iter().filter(|(_, accepted)| !accepted).count();
+        let ratio = rejected as f64 / queue.len() as f64;
+        if ratio <= self.config.reject_ratio {
+            return false;
+        }
+        samples.remove(&address);
+        drop(samples);
+        warn!(
+            "Banning {} for {:?}: {:.1}% of recent submits rejected",
+            address,
+            self.config.cooldown,
+            ratio * 100.0
+        );
+        self.banned
+            .lock()
+            .insert(address, Instant::now() + self.config.cooldown);
+        true
+    }
+
+    /// Drops sample windows of addresses that haven't submitted recently.
+    pub fn prune(&self) {
+        self.samples
+            .lock()
+            .retain(|_, queue| queue.back().is_some_and(|t| t.0.elapsed() <= self.config.window));
+    }
+
+    pub fn is_banned(&self, address: &Address<CanaryV0>) -> bool {
+        let mut banned = self.banned.lock();
+        match banned.get(address) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                info!("Ban of {} expired", address);
+                banned.remove(address);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn unban(&self, address: &Address<CanaryV0>) -> bool {
+        self.banned.lock().remove(address).is_some()
+    }
+
+    /// Currently banned addresses with the remaining ban time.
+    pub fn banned(&self) -> HashMap<Address<CanaryV0>, Duration> {
+        let now = Instant::now();
+        let mut banned = self.banned.lock();
+        banned.retain(|_, until| *until > now);
+        banned
+            .iter()
+            .map(|(address, until)| (*address, until.duration_since(now)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use snarkvm::console::account::PrivateKey;
+
+    use super::*;
+
+    fn address() -> Address<CanaryV0> {
+        Address::try_from(PrivateKey::<CanaryV0>::new(&mut thread_rng()).unwrap()).unwrap()
+    }
+
+    fn auto_ban(cooldown: Duration) -> AutoBan {
+        AutoBan::new(AutoBanConfig {
+            reject_ratio: 0.5,
+            min_samples: 10,
+            window: Duration::from_secs(60),
+            cooldown,
+        })
+    }
+
+    #[test]
+    fn high_reject_address_is_banned() {
+        let auto_ban = auto_ban(Duration::from_secs(60));
+        let address = address();
+        let banned = (0..10).map(|i| auto_ban.record(address, i < 2)).collect::<Vec<_>>();
+        assert!(!banned[..9].contains(&true));
+        assert!(banned[9]);
+        assert!(auto_ban.is_banned(&address));
+        assert!(auto_ban.banned().contains_key(&address));
+        assert!(auto_ban.unban(&address));
+        assert!(!auto_ban.is_banned(&address));
+    }
+
+    #[test]
+    fn low_reject_address_is_not_banned() {
+        let auto_ban = auto_ban(Duration::from_secs(60));
+        let address = address();
+        for i in 0..20 {
+            assert!(!auto_ban.record(address, i % 4 != 0));
+        }
+        assert!(!auto_ban.is_banned(&address));
+    }
+
+    #[test]
+    fn ban_expires_after_the_cooldown() {
+        let auto_ban = auto_ban(Duration::from_millis(20));
+        let address = address();
+        for _ in 0..10 {
+            auto_ban.record(address, false);
+        }
+        assert!(auto_ban.is_banned(&address));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!auto_ban.is_banned(&address));
+        assert!(auto_ban.banned().is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    fs::{create_dir_all, rename, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+use blake2::{Blake2s256, Digest};
+use snarkvm::{
+    prelude::{CanaryV0, FromBytes, ToBytes},
+    synthesizer::{CoinbaseProvingKey, CoinbaseVerifyingKey},
+};
+
+use crate::accounting::state_dir;
+
+/// Version of the cache layout and of the snarkvm revision the keys were derived with. Bump it
+/// when upgrading snarkvm, so keys derived by the old revision are derived again.
+const CACHE_VERSION: u32 = 1;
+
+const VERIFYING_KEY_FILE: &str = "coinbase_verifying_key";
+const PROVING_KEY_FILE: &str = "coinbase_proving_key";
+
+/// Caches of the coinbase keys, so they don't have to be derived from the SRS on every start.
+/// Each file holds the cache version and the puzzle degree as little endian `u32`s, a hash of the
+/// key bytes and then the key.
+fn path(name: &str) -> Result<PathBuf, Error> {
+    Ok(state_dir()?.join(name))
+}
+
+/// The key in the file, `None` if there is none or it was cached by another version, for another
+/// degree or is damaged.
+fn read_key<K: FromBytes>(path: &Path, degree: u32) -> Result<Option<K>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    if header[..4] != CACHE_VERSION.to_le_bytes() || header[4..] != degree.to_le_bytes() {
+        return Ok(None);
+    }
+    let mut hash = [0u8; 32];
+    reader.read_exact(&mut hash)?;
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    if Blake2s256::digest(&bytes)[..] != hash {
+        return Ok(None);
+    }
+    Ok(Some(K::read_le(&bytes[..])?))
+}
+
+fn write_key<K: ToBytes>(path: &Path, degree: u32, key: &K) -> Result<(), Error> {
+    let bytes = key.to_bytes_le()?;
+    // written to a temporary file first so a crash never leaves a truncated key behind
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&CACHE_VERSION.to_le_bytes())?;
+    writer.write_all(&degree.to_le_bytes())?;
+    writer.write_all(&Blake2s256::digest(&bytes))?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    rename(tmp_path, path)?;
+    Ok(())
+}
+
+pub fn load_verifying_key(degree: u32) -> Result<Option<CoinbaseVerifyingKey<CanaryV0>>, Error> {
+    read_key(&path(VERIFYING_KEY_FILE)?, degree)
+}
+
+pub fn load_proving_key(degree: u32) -> Result<Option<CoinbaseProvingKey<CanaryV0>>, Error> {
+    read_key(&path(PROVING_KEY_FILE)?, degree)
+}
+
+pub fn save_verifying_key(degree: u32, verifying_key: &CoinbaseVerifyingKey<CanaryV0>) -> Result<(), Error> {
+    create_dir_all(state_dir()?)?;
+    write_key(&path(VERIFYING_KEY_FILE)?, degree, verifying_key)
+}
+
+pub fn save_proving_key(degree: u32, proving_key: &CoinbaseProvingKey<CanaryV0>) -> Result<(), Error> {
+    create_dir_all(state_dir()?)?;
+    write_key(&path(PROVING_KEY_FILE)?, degree, proving_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, remove_file, write};
+
+    use snarkvm::synthesizer::{CoinbasePuzzle, PuzzleConfig, UniversalSRS};
+
+    use super::*;
+
+    const DEGREE: u32 = (1 << 5) - 1;
+
+    fn derive() -> CoinbasePuzzle<CanaryV0> {
+        let srs = UniversalSRS::<CanaryV0>::load().unwrap();
+        CoinbasePuzzle::trim(&srs, PuzzleConfig { degree: DEGREE }).unwrap()
+    }
+
+    fn cached_verifying_key(path: &Path, degree: u32) -> Option<CoinbaseVerifyingKey<CanaryV0>> {
+        read_key(path, degree).unwrap()
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("coinbase_key_test_{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let coinbase_puzzle = derive();
+        let verifying_key = coinbase_puzzle.coinbase_verifying_key();
+        let path = temp_path();
+        write_key(&path, DEGREE, verifying_key).unwrap();
+        let loaded = cached_verifying_key(&path, DEGREE).unwrap();
+        assert_eq!(loaded.to_bytes_le().unwrap(), verifying_key.to_bytes_le().unwrap());
+
+        let proving_key = match &coinbase_puzzle {
+            CoinbasePuzzle::Prover(proving_key) => proving_key,
+            CoinbasePuzzle::Verifier(_) => panic!("trimming derives the proving key"),
+        };
+        write_key(&path, DEGREE, proving_key.as_ref()).unwrap();
+        let loaded: CoinbaseProvingKey<CanaryV0> = read_key(&path, DEGREE).unwrap().unwrap();
+        assert_eq!(loaded.to_bytes_le().unwrap(), proving_key.to_bytes_le().unwrap());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn mismatched_key_is_derived_again() {
+        let coinbase_puzzle = derive();
+        let path = temp_path();
+        write_key(&path, DEGREE, coinbase_puzzle.coinbase_verifying_key()).unwrap();
+        assert!(cached_verifying_key(&path, DEGREE + 1).is_none());
+
+        let mut bytes = read(&path).unwrap();
+        bytes[..4].copy_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+        write(&path, &bytes).unwrap();
+        assert!(cached_verifying_key(&path, DEGREE).is_none());
+
+        bytes[..4].copy_from_slice(&CACHE_VERSION.to_le_bytes());
+        *bytes.last_mut().unwrap() ^= 1;
+        write(&path, &bytes).unwrap();
+        assert!(cached_verifying_key(&path, DEGREE).is_none());
+        remove_file(path).unwrap();
+    }
+}
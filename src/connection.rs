@@ -1,176 +1,1017 @@
 use std::{
-    net::SocketAddr,
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use aleo_stratum::{
-    codec::{BoxedType, ResponseParams, StratumCodec},
+    codec::{BoxedType, CodecStats, ResponseParams, StratumCodec},
     message::StratumMessage,
 };
-use anyhow::{anyhow, Result};
-use futures_util::SinkExt;
+use blake2::{Blake2s256, Digest};
+use cache::Cache;
+use clap::ValueEnum;
+use futures_util::{stream::SplitStream, SinkExt};
+use json_rpc_types::{Error, ErrorCode, Id};
+use parking_lot::{Mutex, RwLock};
 use semver::Version;
-use snarkvm::{
-    console::account::Address,
-    prelude::{Environment, FromBytes, CanaryV0},
-};
-use snarkvm_algorithms::polycommit::kzg10::{KZGCommitment, KZGProof};
+use snarkvm::{console::account::Address, prelude::CanaryV0};
 use tokio::{
     net::TcpStream,
-    sync::mpsc::{channel, Sender},
+    sync::mpsc::{channel, Receiver, Sender},
     task,
-    time::timeout,
+    task::JoinHandle,
+    time::{sleep, timeout},
 };
 use tokio_stream::StreamExt;
 use tokio_util::codec::Framed;
-use tracing::{error, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    auth::Authenticator,
+    error::{PoolError, PoolResult},
+    server::ServerMessage,
+    submit::decode_submit,
+};
 
-use crate::server::ServerMessage;
+/// The job most recently broadcast to provers, sent to new connections right after they subscribe
+#[derive(Clone)]
+pub struct CurrentJob {
+    pub job_id: String,
+    pub epoch_challenge: String,
+    pub address: Option<String>,
+}
 
 pub struct Connection {
     user_agent: String,
     address: Option<Address<CanaryV0>>,
     version: Version,
-    last_received: Option<Instant>,
+    extranonce: Option<Vec<u8>>,
+}
+
+/// Where a connection is in its lifecycle. `Connection::run` drives a connection through these
+/// in order; any state but `Closing` may also go straight to `Closing`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Waiting for `mining.subscribe`
+    Subscribing,
+    /// Subscribed, waiting for `mining.authorize`
+    Authorizing,
+    /// Authorized and exchanging jobs and shares
+    Active,
+    /// No longer reading from the prover, outbound work is being stopped
+    Draining,
+    /// Reporting the disconnect to the server
+    Closing,
+}
+
+impl ConnectionState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::Subscribing => "subscribing",
+            ConnectionState::Authorizing => "authorizing",
+            ConnectionState::Active => "active",
+            ConnectionState::Draining => "draining",
+            ConnectionState::Closing => "closing",
+        }
+    }
+
+    pub fn can_transition(&self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (*self, next),
+            (Subscribing, Authorizing) | (Authorizing, Active) | (Active, Draining) | (Draining, Closing)
+        ) || (*self != Closing && next == Closing)
+    }
+
+    pub fn transition(&mut self, next: ConnectionState) -> PoolResult<()> {
+        if !self.can_transition(next) {
+            return Err(PoolError::InvalidTransition(self.name(), next.name()));
+        }
+        *self = next;
+        Ok(())
+    }
+
+    /// Whether the prover may send the message in this state. Policies may still turn an accepted
+    /// message down, e.g. a submit before authorizing.
+    pub fn accepts(&self, message: &StratumMessage) -> bool {
+        use ConnectionState::*;
+        match message {
+            StratumMessage::Subscribe(..) => *self == Subscribing,
+            StratumMessage::Authorize(..) | StratumMessage::Submit(..) => matches!(self, Authorizing | Active),
+            StratumMessage::GetStats(..) | StratumMessage::Response(..) | StratumMessage::Unknown(..) => {
+                *self == Active
+            }
+            StratumMessage::SetTarget(..)
+            | StratumMessage::Notify(..)
+            | StratumMessage::Reconnect(..)
+            | StratumMessage::ShowMessage(..) => false,
+        }
+    }
+}
+
+/// A connection's state together with what the state owns, so each step takes the stream or
+/// writer over from the one before.
+enum Stage {
+    Subscribing(Framed<TcpStream, StratumCodec>),
+    Authorizing(Framed<TcpStream, StratumCodec>),
+    Active(Framed<TcpStream, StratumCodec>, Receiver<StratumMessage>),
+    Draining(JoinHandle<()>),
+    Closing,
+}
+
+impl Stage {
+    fn state(&self) -> ConnectionState {
+        match self {
+            Stage::Subscribing(..) => ConnectionState::Subscribing,
+            Stage::Authorizing(..) => ConnectionState::Authorizing,
+            Stage::Active(..) => ConnectionState::Active,
+            Stage::Draining(..) => ConnectionState::Draining,
+            Stage::Closing => ConnectionState::Closing,
+        }
+    }
+}
+
+/// What to do with a `mining.submit` received before `mining.authorize`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EarlySubmitPolicy {
+    /// Drop the connection.
+    Disconnect,
+    /// Respond with an "unauthorized" error and keep waiting for authorization.
+    Reject,
+    /// Hold the submit and process it once the prover is authorized.
+    Buffer,
+}
+
+/// What to do with a `mining.authorize` received after the connection is authorized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReauthorizePolicy {
+    /// Respond with an error and keep the current address.
+    Reject,
+    /// Authorize again and switch the connection to the new address.
+    Allow,
+}
+
+/// What to do with a `mining.submit` sent without an id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IdlessSubmitPolicy {
+    /// Ids are required for submits; drop the connection.
+    Reject,
+    /// Process the submit but don't send a response.
+    Silent,
+}
+
+/// What to do with a request for a method not defined by the protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum UnknownMethodPolicy {
+    /// Count it as a protocol violation.
+    Violation,
+    /// Respond with a "method not found" error and keep the connection.
+    Respond,
+    /// Fail decoding and drop the connection.
+    Strict,
+}
+
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    pub idless_submit: IdlessSubmitPolicy,
+    pub unknown_method: UnknownMethodPolicy,
+    pub early_submit: EarlySubmitPolicy,
+    pub reauthorize: ReauthorizePolicy,
+    /// Number of unexpected messages tolerated before the connection is dropped
+    pub max_protocol_violations: u32,
+    /// Number of malformed submits tolerated within `decode_error_window`
+    pub max_decode_errors: u32,
+    /// Window after which the malformed submit count starts over
+    pub decode_error_window: Duration,
+    /// Length in bytes of the per-connection nonce prefix, 0 to disable
+    pub extranonce_len: usize,
+    /// Reject submits whose nonce doesn't start with the connection's extranonce
+    pub validate_extranonce: bool,
+    /// Minimum time between repeated warnings of the same kind for a connection
+    pub log_sample_interval: Duration,
+    /// Collapse queued targets and jobs to the latest one when the prover falls behind
+    pub coalesce_outbound: bool,
+    /// How long to hold a submit response so later ones are written with it, `None` to write
+    /// responses as soon as they are queued
+    pub ack_batch_delay: Option<Duration>,
+    /// Most messages the writer collects into one batch. A connection whose pushed messages still
+    /// fill a whole batch after coalescing can't keep up and is dropped; responses don't count.
+    pub max_outbound_batch: usize,
+    /// Consulted on authorize after the address has been parsed
+    pub authenticator: Arc<dyn Authenticator>,
+    /// Error sent to miners with an unsupported protocol version. `{version}`, `{min}` and `{max}`
+    /// are replaced with the requested and the supported versions.
+    pub unsupported_version_message: String,
+    /// How long the connection stays open after the unsupported version error so the miner reads it
+    pub unsupported_version_grace: Duration,
+    /// Decode buffer figures of all prover connections
+    pub codec_stats: Arc<CodecStats>,
+}
+
+/// Rate limits a repeated warning: the first occurrence is logged, later ones are counted and
+/// reported together once the interval has passed.
+struct LogSampler {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+impl LogSampler {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_logged: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns the number of occurrences suppressed since the last log if this one should be logged.
+    fn sample(&mut self) -> Option<u64> {
+        if self.last_logged.is_some_and(|t| t.elapsed() < self.interval) {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last_logged = Some(Instant::now());
+        Some(std::mem::take(&mut self.suppressed))
+    }
+
+    /// Returns the occurrences not reported yet, if any, so they aren't lost when the connection ends.
+    fn flush(&mut self) -> Option<u64> {
+        match std::mem::take(&mut self.suppressed) {
+            0 => None,
+            suppressed => Some(suppressed),
+        }
+    }
+}
+
+/// Share results of a connection as reported to the prover by `mining.get_stats`.
+#[derive(Default)]
+struct ConnectionStats {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    /// 0 until a target has been sent
+    target: AtomicU64,
+}
+
+impl ConnectionStats {
+    /// Counts the outcome of a response to a submit, answers to resends and to other requests are
+    /// left out by the caller.
+    fn record(&self, msg: &StratumMessage, answers_submit: bool) {
+        match msg {
+            StratumMessage::Response(_, Some(ResponseParams::Bool(true)), None) if answers_submit => {
+                self.accepted.fetch_add(1, Ordering::Relaxed);
+            }
+            StratumMessage::Response(_, None, Some(error))
+                if answers_submit && (20..=25).contains(&error.code.code()) =>
+            {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+            }
+            StratumMessage::SetTarget(target) => self.target.store(*target, Ordering::Relaxed),
+            _ => {}
+        }
+    }
+
+    fn to_response(&self, id: Id) -> StratumMessage {
+        let target = match self.target.load(Ordering::Relaxed) {
+            0 => None,
+            target => Some(target),
+        };
+        let params: Vec<Box<dyn BoxedType>> = vec![
+            Box::new(Some(self.accepted.load(Ordering::Relaxed))),
+            Box::new(Some(self.rejected.load(Ordering::Relaxed))),
+            Box::new(target),
+        ];
+        StratumMessage::Response(id, Some(ResponseParams::Array(params)), None)
+    }
+}
+
+/// Counts errors in fixed windows, so sparse errors never add up to a disconnect.
+struct ErrorWindow {
+    window: Duration,
+    started: Instant,
+    count: u32,
+}
+
+impl ErrorWindow {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Records an error and returns the number of errors in the current window.
+    fn record(&mut self) -> u32 {
+        if self.started.elapsed() >= self.window {
+            self.started = Instant::now();
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count
+    }
+}
+
+/// Limits the number of connections per IP that have subscribed but not yet authorized.
+pub struct AuthorizeSlots {
+    limit: u32,
+    pending: Mutex<HashMap<IpAddr, u32>>,
+}
+
+impl AuthorizeSlots {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            pending: Default::default(),
+        }
+    }
+
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<AuthorizeSlot> {
+        let mut pending = self.pending.lock();
+        let count = pending.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(AuthorizeSlot {
+            slots: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// Released when the connection authorizes or goes away.
+pub struct AuthorizeSlot {
+    slots: Arc<AuthorizeSlots>,
+    ip: IpAddr,
+}
+
+impl Drop for AuthorizeSlot {
+    fn drop(&mut self) {
+        let mut pending = self.slots.pending.lock();
+        if let Some(count) = pending.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SubmitOutcome {
+    /// Not answered yet
+    Pending,
+    Accepted,
+}
+
+/// Recent submits of a connection by hash, so exact resends get the answer of the original instead
+/// of being credited twice. Rejected submits are forgotten, so their resends are checked again.
+struct RecentSubmits {
+    outcomes: Cache<[u8; 32], SubmitOutcome>,
+    /// (request id, submit hash, whether it is a resend that gets the original's answer)
+    awaited: VecDeque<(Id, [u8; 32], bool)>,
+}
+
+impl RecentSubmits {
+    fn new() -> Self {
+        Self {
+            outcomes: Cache::new(SUBMIT_DEDUP_TTL),
+            awaited: VecDeque::new(),
+        }
+    }
+
+    fn await_answer(&mut self, id: Id, hash: [u8; 32], resend: bool) {
+        if self.awaited.len() >= MAX_AWAITED_SUBMITS {
+            self.awaited.pop_front();
+        }
+        self.awaited.push_back((id, hash, resend));
+    }
+
+    /// Records a submit and returns the outcome of an earlier one with the same hash. Resends of a
+    /// pending submit are answered along with it.
+    fn record(&mut self, hash: [u8; 32], id: Option<&Id>) -> Option<SubmitOutcome> {
+        let outcome = self.outcomes.get(hash);
+        if outcome.is_none() {
+            self.outcomes.set(hash, SubmitOutcome::Pending);
+        }
+        if let (Some(id), None | Some(SubmitOutcome::Pending)) = (id, outcome) {
+            self.await_answer(id.clone(), hash, outcome.is_some());
+        }
+        outcome
+    }
+
+    /// Takes note of an outgoing response. Returns the answers to resends if it answers a
+    /// submit, `None` otherwise.
+    fn answered(&mut self, msg: &StratumMessage) -> Option<Vec<StratumMessage>> {
+        let (id, params, error) = match msg {
+            StratumMessage::Response(id, params, error) => (id, params, error),
+            _ => return None,
+        };
+        let position = self
+            .awaited
+            .iter()
+            .position(|(awaited, _, resend)| !resend && awaited == id)?;
+        let (_, hash, _) = self.awaited.remove(position)?;
+        let accepted = matches!(params, Some(ResponseParams::Bool(true)));
+        if accepted {
+            self.outcomes.set(hash, SubmitOutcome::Accepted);
+        } else {
+            self.outcomes.remove(&hash);
+        }
+        let mut answers = vec![];
+        self.awaited.retain(|(id, awaited_hash, resend)| {
+            if !resend || *awaited_hash != hash {
+                return true;
+            }
+            answers.push(match accepted {
+                true => StratumMessage::Response(id.clone(), Some(ResponseParams::Bool(true)), None),
+                false => StratumMessage::Response(id.clone(), None, error.clone()),
+            });
+            false
+        });
+        Some(answers)
+    }
+}
+
+/// What a subscription left behind, so a prover that reconnects with its session id picks up
+/// the same extranonce and difficulty.
+#[derive(Clone)]
+pub struct Session {
+    address: Option<Address<CanaryV0>>,
+    target: Option<u64>,
+    extranonce: Option<Vec<u8>>,
+    last_seen: Instant,
+    /// Held by a live connection, which keeps it from expiring or being resumed elsewhere
+    connected: bool,
+}
+
+pub struct SessionStore {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Default::default(),
+        }
+    }
+
+    /// Returns the session if it is known, hasn't expired and no live connection holds it. A
+    /// second connection can't take over the extranonce of one that is still mining.
+    fn resume(&self, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock();
+        let session = sessions
+            .get_mut(id)
+            .filter(|s| !s.connected && s.last_seen.elapsed() < self.ttl)?;
+        session.connected = true;
+        session.last_seen = Instant::now();
+        Some(session.clone())
+    }
+
+    /// Issues a new session id, dropping expired sessions on the way.
+    fn create(&self, extranonce: Option<Vec<u8>>) -> String {
+        let mut sessions = self.sessions.lock();
+        sessions.retain(|_, s| s.connected || s.last_seen.elapsed() < self.ttl);
+        let mut id = hex::encode(rand::random::<[u8; 8]>());
+        while sessions.contains_key(&id) {
+            id = hex::encode(rand::random::<[u8; 8]>());
+        }
+        sessions.insert(
+            id.clone(),
+            Session {
+                address: None,
+                target: None,
+                extranonce,
+                last_seen: Instant::now(),
+                connected: true,
+            },
+        );
+        id
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Session)) {
+        if let Some(session) = self.sessions.lock().get_mut(id) {
+            f(session);
+            session.last_seen = Instant::now();
+        }
+    }
+
+    /// Lets the session be resumed, it expires counting from now.
+    fn release(&self, id: &str) {
+        self.update(id, |session| session.connected = false);
+    }
 }
 
 static PEER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 static PEER_COMM_TIMEOUT: Duration = Duration::from_secs(180);
+static SUBMIT_DEDUP_TTL: Duration = Duration::from_secs(60);
+/// Submits whose answer a connection keeps waiting for, older ones are forgotten
+static MAX_AWAITED_SUBMITS: usize = 1024;
+/// Wait hint in seconds sent to provers when the server has gone away
+static SERVER_GONE_RECONNECT_WAIT: u64 = 30;
+static SERVER_GONE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+static MAX_EARLY_SUBMITS: usize = 4;
+
+/// Refused connections waiting to be told why, further ones are closed right away
+static MAX_PENDING_REFUSALS: usize = 256;
+static PENDING_REFUSALS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of connections dropped because they couldn't keep up with their outbound messages
+pub static SLOW_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+
+static MAX_WORKER_NAME_LEN: usize = 128;
+static MAX_USER_AGENT_LEN: usize = 64;
+
+/// Keepalives and extensions some miners send that are safe to ignore
+static BENIGN_METHODS: [&str; 3] = ["mining.ping", "mining.configure", "mining.extranonce.subscribe"];
 
 static MIN_SUPPORTED_VERSION: Version = Version::new(2, 0, 0);
 static MAX_SUPPORTED_VERSION: Version = Version::new(2, 0, 0);
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub async fn init(
         stream: TcpStream,
         peer_addr: SocketAddr,
         server_sender: Sender<ServerMessage>,
         pool_address: Address<CanaryV0>,
+        authorize_slots: Arc<AuthorizeSlots>,
+        sessions: Arc<SessionStore>,
+        current_job: Arc<RwLock<Option<CurrentJob>>>,
+        config: ConnectionConfig,
+        extranonce: Option<Vec<u8>>,
     ) {
-        task::spawn(Connection::run(stream, peer_addr, server_sender, pool_address));
+        task::spawn(Connection::run(
+            stream,
+            peer_addr,
+            server_sender,
+            pool_address,
+            authorize_slots,
+            sessions,
+            current_job,
+            config,
+            extranonce,
+        ));
+    }
+
+    /// Answers the subscribe of a connection the pool won't serve with an error, then closes it.
+    pub fn refuse(stream: TcpStream, peer_addr: SocketAddr, reason: &'static str) {
+        if PENDING_REFUSALS.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING_REFUSALS {
+            PENDING_REFUSALS.fetch_sub(1, Ordering::Relaxed);
+            debug!("Closed connection from {:?} without an answer: {}", peer_addr, reason);
+            return;
+        }
+        task::spawn(async move {
+            let mut framed = Framed::new(stream, StratumCodec::default());
+            if let Ok(Some(Ok(StratumMessage::Subscribe(id, ..)))) =
+                timeout(PEER_HANDSHAKE_TIMEOUT, framed.next()).await
+            {
+                let error = Error::with_custom_msg(ErrorCode::from_code(25), reason);
+                if let Err(e) = framed.send(StratumMessage::Response(id, None, Some(error))).await {
+                    debug!("Failed to send refusal to peer {:?}: {}", peer_addr, e);
+                }
+            }
+            debug!("Refused connection from {:?}: {}", peer_addr, reason);
+            PENDING_REFUSALS.fetch_sub(1, Ordering::Relaxed);
+        });
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn run(
         stream: TcpStream,
         peer_addr: SocketAddr,
         server_sender: Sender<ServerMessage>,
         pool_address: Address<CanaryV0>,
+        authorize_slots: Arc<AuthorizeSlots>,
+        sessions: Arc<SessionStore>,
+        current_job: Arc<RwLock<Option<CurrentJob>>>,
+        config: ConnectionConfig,
+        extranonce: Option<Vec<u8>>,
     ) {
-        let mut framed = Framed::new(stream, StratumCodec::default());
-
-        let (sender, mut receiver) = channel(1024);
+        let codec = StratumCodec::default()
+            .strict(config.unknown_method == UnknownMethodPolicy::Strict)
+            .with_stats(config.codec_stats.clone());
 
         let mut conn = Connection {
             user_agent: "Unknown".to_string(),
             address: None,
             version: Version::new(0, 0, 0),
-            last_received: None,
+            extranonce,
         };
 
-        // Handshake
+        let mut session_id = None;
+        let mut resumed = None;
+        let mut sent_job = None;
+        let mut hashrate = None;
+        let mut early_submits = VecDeque::new();
 
-        if let Ok((user_agent, version)) = Connection::handshake(&mut framed, pool_address.to_string()).await {
-            conn.user_agent = user_agent;
-            conn.version = version;
-        } else {
-            if let Err(e) = server_sender.send(ServerMessage::ProverDisconnected(peer_addr)).await {
-                error!("Failed to send ProverDisconnected message to server: {}", e);
-            }
-            return;
+        let mut stage = Stage::Subscribing(Framed::new(stream, codec));
+        loop {
+            let mut state = stage.state();
+            let next = match stage {
+                Stage::Subscribing(mut framed) => {
+                    match Connection::handshake(
+                        &mut framed,
+                        pool_address.to_string(),
+                        &sessions,
+                        &current_job,
+                        &config,
+                        conn.extranonce.clone(),
+                    )
+                    .await
+                    {
+                        Ok((user_agent, version, id, session, job_id, reported_hashrate)) => {
+                            conn.user_agent = user_agent;
+                            conn.version = version;
+                            sent_job = job_id;
+                            hashrate = reported_hashrate;
+                            if let Some(session) = &session {
+                                conn.extranonce = session.extranonce.clone();
+                            }
+                            session_id = Some(id);
+                            resumed = session;
+                            Stage::Authorizing(framed)
+                        }
+                        Err(_) => Stage::Closing,
+                    }
+                }
+                Stage::Authorizing(mut framed) => {
+                    match authorize_slots.acquire(peer_addr.ip()) {
+                        Some(authorize_slot) => {
+                            let authorized =
+                                Connection::authorize(&mut framed, config.early_submit, config.authenticator.as_ref())
+                                    .await;
+                            drop(authorize_slot);
+                            match authorized {
+                                Ok((address, worker, submits)) => {
+                                    conn.address = Some(address);
+                                    early_submits = submits;
+                                    // the difficulty only carries over if the session resumes for the same address
+                                    let resumed_target = resumed
+                                        .take()
+                                        .filter(|session| session.address == Some(address))
+                                        .and_then(|session| session.target);
+                                    if let Some(session_id) = &session_id {
+                                        sessions.update(session_id, |session| {
+                                            if session.address != Some(address) {
+                                                session.target = None;
+                                            }
+                                            session.address = Some(address);
+                                        });
+                                    }
+                                    let (sender, receiver) = channel(1024);
+                                    if let Err(e) = server_sender
+                                        .send(ServerMessage::ProverAuthenticated(
+                                            peer_addr,
+                                            address,
+                                            worker,
+                                            sender,
+                                            resumed_target,
+                                            hashrate.take(),
+                                            sent_job.take(),
+                                        ))
+                                        .await
+                                    {
+                                        error!("Failed to send ProverAuthenticated message to server: {}", e);
+                                        Stage::Closing
+                                    } else {
+                                        info!("Peer {:?} authenticated as {}", peer_addr, address);
+                                        Stage::Active(framed, receiver)
+                                    }
+                                }
+                                Err(_) => Stage::Closing,
+                            }
+                        }
+                        None => {
+                            warn!(
+                                "Too many unauthorized connections from {}, dropping {:?}",
+                                peer_addr.ip(),
+                                peer_addr
+                            );
+                            Stage::Closing
+                        }
+                    }
+                }
+                Stage::Active(framed, receiver) => Stage::Draining(
+                    conn.serve(
+                        framed,
+                        receiver,
+                        std::mem::take(&mut early_submits),
+                        peer_addr,
+                        &server_sender,
+                        &config,
+                        sessions.clone(),
+                        session_id.clone().unwrap_or_default(),
+                    )
+                    .await,
+                ),
+                Stage::Draining(writer) => {
+                    writer.abort();
+                    Stage::Closing
+                }
+                Stage::Closing if server_sender.is_closed() => break,
+                Stage::Closing => {
+                    if let Err(e) = server_sender.send(ServerMessage::ProverDisconnected(peer_addr)).await {
+                        error!("Failed to send ProverDisconnected message to server: {}", e);
+                    }
+                    break;
+                }
+            };
+            stage = match state.transition(next.state()) {
+                Ok(()) => next,
+                Err(e) => {
+                    error!("Peer {:?}: {}", peer_addr, e);
+                    Stage::Closing
+                }
+            };
         }
-
-        if let Ok(address) = Connection::authorize(&mut framed).await {
-            conn.address = Some(address);
-            if let Err(e) = server_sender
-                .send(ServerMessage::ProverAuthenticated(
-                    peer_addr,
-                    conn.address.unwrap(),
-                    sender,
-                ))
-                .await
-            {
-                error!("Failed to send ProverAuthenticated message to server: {}", e);
-            }
-        } else {
-            if let Err(e) = server_sender.send(ServerMessage::ProverDisconnected(peer_addr)).await {
-                error!("Failed to send ProverDisconnected message to server: {}", e);
-            }
-            return;
+        if let Some(session_id) = &session_id {
+            sessions.release(session_id);
         }
+    }
 
-        conn.last_received = Some(Instant::now());
-
-        info!("Peer {:?} authenticated as {}", peer_addr, conn.address.unwrap());
+    /// Serves an authorized prover until it disconnects, times out or is dropped by the server.
+    /// Returns the writer task, which is still running if reading stopped first.
+    #[allow(clippy::too_many_arguments)]
+    async fn serve(
+        &self,
+        framed: Framed<TcpStream, StratumCodec>,
+        mut receiver: Receiver<StratumMessage>,
+        mut early_submits: VecDeque<StratumMessage>,
+        peer_addr: SocketAddr,
+        server_sender: &Sender<ServerMessage>,
+        config: &ConnectionConfig,
+        sessions: Arc<SessionStore>,
+        session_id: String,
+    ) -> JoinHandle<()> {
+        let recent_submits = Arc::new(Mutex::new(RecentSubmits::new()));
+        let mut protocol_violations = 0u32;
+        let mut violation_log = LogSampler::new(config.log_sample_interval);
+        let mut nonce_reject_log = LogSampler::new(config.log_sample_interval);
+        let mut decode_errors = ErrorWindow::new(config.decode_error_window);
+        let mut decode_buffer = Vec::new();
+        let mut decode_error_log = LogSampler::new(config.log_sample_interval);
+        let mut server_gone = false;
+        let mut address = self.address.expect("connections are served once authorized");
 
-        loop {
-            tokio::select! {
-                Some(msg) = receiver.recv() => {
-                    if let Some(instant) = conn.last_received {
-                        if instant.elapsed() > PEER_COMM_TIMEOUT {
-                            warn!("Peer {:?} timed out", peer_addr);
-                            break;
+        // writes run in their own task so job updates aren't held up by inbound processing
+        let (mut sink, mut stream) = futures_util::StreamExt::split(framed);
+        let (reply_sender, mut replies) = channel(1024);
+        // the latest job sent to the prover, until its first submit arrives
+        let latest_job: Arc<Mutex<Option<(String, Instant)>>> = Default::default();
+        let stats = Arc::new(ConnectionStats::default());
+        let coalesce_outbound = config.coalesce_outbound;
+        let ack_batch_delay = config.ack_batch_delay;
+        // a single message is never a backlog
+        let max_outbound_batch = config.max_outbound_batch.max(2);
+        let mut writer = task::spawn({
+            let recent_submits = recent_submits.clone();
+            let latest_job = latest_job.clone();
+            let stats = stats.clone();
+            let sessions = sessions.clone();
+            let session_id = session_id.clone();
+            async move {
+                loop {
+                    let mut batch = tokio::select! {
+                        msg = receiver.recv() => match msg {
+                            Some(msg) if coalesce_outbound => {
+                                let mut batch = vec![msg];
+                                Connection::drain_into(&mut batch, &mut receiver, max_outbound_batch);
+                                Connection::coalesce(batch)
+                            }
+                            Some(msg) => vec![msg],
+                            None => {
+                                info!("Peer {:?} dropped by server", peer_addr);
+                                break;
+                            }
+                        },
+                        Some(msg) = replies.recv() => vec![msg],
+                    };
+                    if let Some(delay) = ack_batch_delay {
+                        if batch.iter().any(|msg| matches!(msg, StratumMessage::Response(..))) {
+                            sleep(delay).await;
+                            Connection::drain_into(&mut batch, &mut replies, max_outbound_batch);
+                            Connection::drain_into(&mut batch, &mut receiver, max_outbound_batch);
+                            if coalesce_outbound {
+                                batch = Connection::coalesce(batch);
+                            }
+                        }
+                    }
+                    if Connection::too_slow(&batch, max_outbound_batch) {
+                        warn!(
+                            "Peer {:?} can't keep up with {} queued messages, disconnecting",
+                            peer_addr,
+                            batch.len()
+                        );
+                        SLOW_DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                    // the whole batch goes out in one flush
+                    for msg in batch {
+                        if let StratumMessage::Notify(job_id, _, _, clean_jobs) = &msg {
+                            if *clean_jobs {
+                                recent_submits.lock().outcomes.clear();
+                            } else {
+                                recent_submits.lock().outcomes.purge_expired();
+                            }
+                            latest_job.lock().replace((job_id.clone(), Instant::now()));
+                        }
+                        if let StratumMessage::SetTarget(target) = &msg {
+                            sessions.update(&session_id, |session| session.target = Some(*target));
+                        }
+                        let resend_answers = recent_submits.lock().answered(&msg);
+                        stats.record(&msg, resend_answers.is_some());
+                        trace!("Sending message {} to peer {:?}", msg.name(), peer_addr);
+                        if let Err(e) = sink.feed(msg).await {
+                            error!("Failed to send message to peer {:?}: {:?}", peer_addr, e);
+                        }
+                        for answer in resend_answers.into_iter().flatten() {
+                            if let Err(e) = sink.feed(answer).await {
+                                error!("Failed to send message to peer {:?}: {:?}", peer_addr, e);
+                            }
                         }
                     }
-                    trace!("Sending message {} to peer {:?}", msg.name(), peer_addr);
-                    if let Err(e) = framed.send(msg).await {
+                    if let Err(e) = sink.flush().await {
                         error!("Failed to send message to peer {:?}: {:?}", peer_addr, e);
                     }
+                }
+            }
+        });
+
+        loop {
+            tokio::select! {
+                _ = &mut writer => {
+                    break;
                 },
-                result = framed.next() => match result {
+                result = Connection::next_message(&mut early_submits, &mut stream) => match result {
                     Some(Ok(msg)) => {
                         trace!("Received message {} from peer {:?}", msg.name(), peer_addr);
-                        conn.last_received = Some(Instant::now());
                         match msg {
                             StratumMessage::Submit(id, _worker_name, job_id, nonce, commitment, proof) => {
-                                let job_bytes = hex::decode(job_id.clone());
-                                if job_bytes.is_err() {
-                                    warn!("Failed to decode job_id {} from peer {:?}", job_id, peer_addr);
+                                if id.is_none() && config.idless_submit == IdlessSubmitPolicy::Reject {
+                                    warn!("Peer {:?} sent a submit without id", peer_addr);
                                     break;
                                 }
-                                if job_bytes.clone().unwrap().len() != 4 {
-                                    warn!("Invalid job_id {} from peer {:?}", job_id, peer_addr);
-                                    break;
+                                let submit_hash = Connection::submit_hash(&job_id, &nonce, &commitment, &proof);
+                                let outcome = recent_submits.lock().record(submit_hash, id.as_ref());
+                                match outcome {
+                                    Some(SubmitOutcome::Accepted) => {
+                                        debug!("Acknowledging repeated submit from peer {:?} again", peer_addr);
+                                        if let Some(id) = id {
+                                            if let Err(e) = reply_sender.send(StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None)).await {
+                                                error!("Failed to queue message to peer {:?}: {:?}", peer_addr, e);
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                    Some(SubmitOutcome::Pending) => {
+                                        debug!("Repeated submit from peer {:?} gets the answer to the original", peer_addr);
+                                        continue;
+                                    }
+                                    None => {}
                                 }
-                                let epoch_number = u32::from_le_bytes(job_bytes.unwrap().try_into().unwrap());
-                                let nonce_bytes = hex::decode(nonce.clone());
-                                if nonce_bytes.is_err() {
-                                    warn!("Failed to decode nonce {} from peer {:?}", nonce, peer_addr);
-                                    break;
+                                let latency = {
+                                    let mut latest_job = latest_job.lock();
+                                    match latest_job.as_ref() {
+                                        Some((latest_job_id, sent)) if *latest_job_id == job_id => {
+                                            let latency = sent.elapsed();
+                                            *latest_job = None;
+                                            Some(latency)
+                                        }
+                                        _ => None,
+                                    }
+                                };
+                                if let Some(latency) = latency {
+                                    if let Err(e) = server_sender.send(ServerMessage::SubmitLatency(latency)).await {
+                                        error!("Failed to send SubmitLatency message to server: {}", e);
+                                        server_gone = true;
+                                        break;
+                                    }
                                 }
-                                let nonce = u64::from_le_bytes(nonce_bytes.unwrap().try_into().unwrap());
-                                let commitment_bytes = hex::decode(commitment.clone());
-                                if commitment_bytes.is_err() {
-                                    warn!("Failed to decode commitment {} from peer {:?}", commitment, peer_addr);
-                                    break;
+                                let (nonce_bytes, commitment, proof) = match decode_submit(&mut decode_buffer, &nonce, &commitment, &proof) {
+                                    Ok(decoded) => decoded,
+                                    Err(reason) => {
+                                        // usually a miner bug rather than abuse; only a sustained rate drops the connection
+                                        let errors = decode_errors.record();
+                                        if errors > config.max_decode_errors {
+                                            warn!("Dropping peer {:?} after {} malformed submits", peer_addr, errors);
+                                            break;
+                                        }
+                                        if let Some(suppressed) = decode_error_log.sample() {
+                                            warn!(
+                                                "Malformed submit from peer {:?}: {} ({}/{}, {} more since last report)",
+                                                peer_addr, reason, errors, config.max_decode_errors, suppressed
+                                            );
+                                        }
+                                        if let Some(id) = id {
+                                            let error = Error::with_custom_msg(ErrorCode::from_code(20), "Malformed share");
+                                            if let Err(e) = reply_sender.send(StratumMessage::Response(id, None, Some(error))).await {
+                                                error!("Failed to queue message to peer {:?}: {:?}", peer_addr, e);
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                };
+                                if let (true, Some(extranonce)) = (config.validate_extranonce, &self.extranonce) {
+                                    if !nonce_bytes.starts_with(extranonce) {
+                                        if let Some(suppressed) = nonce_reject_log.sample() {
+                                            warn!(
+                                                "Nonce {} from peer {:?} doesn't match the extranonce ({} more since last report)",
+                                                nonce, peer_addr, suppressed
+                                            );
+                                        }
+                                        if let Some(id) = id {
+                                            let error = Error::with_custom_msg(ErrorCode::from_code(20), "Invalid nonce");
+                                            if let Err(e) = reply_sender.send(StratumMessage::Response(id, None, Some(error))).await {
+                                                error!("Failed to queue message to peer {:?}: {:?}", peer_addr, e);
+                                            }
+                                        }
+                                        continue;
+                                    }
                                 }
-                                let commitment = KZGCommitment::<<CanaryV0 as Environment>::PairingCurve>::from_bytes_le(&commitment_bytes.unwrap()[..]);
-                                if commitment.is_err() {
-                                    warn!("Invalid commitment from peer {:?}", peer_addr);
+                                let nonce = u64::from_le_bytes(nonce_bytes);
+                                if let Err(e) = server_sender.send(ServerMessage::ProverSubmit(id, peer_addr, job_id, nonce, commitment, proof)).await {
+                                    error!("Failed to send ProverSubmit message to server: {}", e);
+                                    server_gone = true;
                                     break;
                                 }
-                                let proof_bytes = hex::decode(proof.clone());
-                                if proof_bytes.is_err() {
-                                warn!("Failed to decode proof {} from peer {:?}", proof, peer_addr);
-                                    break;
+                            }
+                            StratumMessage::Response(..) => {
+                                trace!("Ignoring response from peer {:?}", peer_addr);
+                            }
+                            StratumMessage::Authorize(id, worker_name, password) => {
+                                let response = match Connection::repeated_authorize(config.reauthorize, address, &worker_name, &password, config.authenticator.as_ref()).await {
+                                    Ok(Some(new_address)) => {
+                                        if let Err(e) = server_sender.send(ServerMessage::ProverReauthorized(peer_addr, new_address, worker_name)).await {
+                                            error!("Failed to send ProverReauthorized message to server: {}", e);
+                                            server_gone = true;
+                                            break;
+                                        }
+                                        sessions.update(&session_id, |session| session.address = Some(new_address));
+                                        address = new_address;
+                                        StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None)
+                                    }
+                                    Ok(None) => StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None),
+                                    Err(error) => {
+                                        debug!("Refusing repeated authorize from peer {:?}: {:?}", peer_addr, error);
+                                        StratumMessage::Response(id, None, Some(error))
+                                    }
+                                };
+                                if let Err(e) = reply_sender.send(response).await {
+                                    error!("Failed to queue message to peer {:?}: {:?}", peer_addr, e);
                                 }
-                                let proof = KZGProof::<<CanaryV0 as Environment>::PairingCurve>::from_bytes_le(&proof_bytes.unwrap());
-                                if proof.is_err() {
-                                    warn!("Invalid proof from peer {:?}", peer_addr);
-                                    break;
+                            }
+                            StratumMessage::GetStats(Some(id)) => {
+                                if let Err(e) = reply_sender.send(stats.to_response(id)).await {
+                                    error!("Failed to queue message to peer {:?}: {:?}", peer_addr, e);
                                 }
-                                if let Err(e) = server_sender.send(ServerMessage::ProverSubmit(id, peer_addr, epoch_number, nonce, commitment.unwrap(), proof.unwrap())).await {
-                                    error!("Failed to send ProverSubmit message to server: {}", e);
+                            }
+                            StratumMessage::GetStats(None) => {
+                                trace!("Ignoring stats notification from peer {:?}", peer_addr);
+                            }
+                            StratumMessage::Unknown(_, method) if BENIGN_METHODS.contains(&method.as_str()) => {
+                                debug!("Ignoring {} from peer {:?}", method, peer_addr);
+                            }
+                            StratumMessage::Unknown(id, method) if config.unknown_method == UnknownMethodPolicy::Respond => {
+                                debug!("Peer {:?} called unknown method {}", peer_addr, method);
+                                if let Some(id) = id {
+                                    let error = Error::with_custom_msg(ErrorCode::from_code(-32601), "Method not found");
+                                    if let Err(e) = reply_sender.send(StratumMessage::Response(id, None, Some(error))).await {
+                                        error!("Failed to queue message to peer {:?}: {:?}", peer_addr, e);
+                                    }
                                 }
                             }
                             _ => {
-                                warn!("Received unexpected message from peer {:?}: {:?}", peer_addr, msg.name());
-                                break;
+                                protocol_violations += 1;
+                                if let Some(suppressed) = violation_log.sample() {
+                                    warn!(
+                                        "Received unexpected message from peer {:?}: {:?} ({}/{}, {} more since last report)",
+                                        peer_addr, msg.name(), protocol_violations, config.max_protocol_violations, suppressed
+                                    );
+                                }
+                                if let Err(e) = server_sender.send(ServerMessage::ProtocolViolation(address)).await {
+                                    error!("Failed to send ProtocolViolation message to server: {}", e);
+                                    server_gone = true;
+                                    break;
+                                }
+                                if protocol_violations > config.max_protocol_violations {
+                                    break;
+                                }
                             }
                         }
                     }
                     Some(Err(e)) => {
-                        warn!("Failed to read message from peer: {:?}", e);
+                        warn!("Dropping peer {:?} after a read error: {}", peer_addr, e);
                         break;
                     }
                     None => {
@@ -184,113 +1025,1340 @@ impl Connection {
                 },
             }
         }
-        if let Err(e) = server_sender.send(ServerMessage::ProverDisconnected(peer_addr)).await {
-            error!("Failed to send ProverDisconnected message to server: {}", e);
+        for (log, what) in [
+            (&mut violation_log, "unexpected messages"),
+            (&mut nonce_reject_log, "nonces not matching the extranonce"),
+            (&mut decode_error_log, "malformed submits"),
+        ] {
+            if let Some(suppressed) = log.flush() {
+                warn!(
+                    "Peer {:?} sent {} more {} since last report",
+                    peer_addr, suppressed, what
+                );
+            }
+        }
+        if server_gone {
+            warn!("Server is gone, asking peer {:?} to reconnect", peer_addr);
+            let reconnect = StratumMessage::Reconnect(None, None, SERVER_GONE_RECONNECT_WAIT);
+            if reply_sender.send(reconnect).await.is_ok() {
+                // give the writer a moment to deliver it before the connection is closed
+                let _ = timeout(SERVER_GONE_FLUSH_TIMEOUT, &mut writer).await;
+            }
+        }
+        writer
+    }
+
+    /// Moves queued messages into the batch until it holds `max` messages.
+    fn drain_into(batch: &mut Vec<StratumMessage>, receiver: &mut Receiver<StratumMessage>, max: usize) {
+        while batch.len() < max {
+            match receiver.try_recv() {
+                Ok(msg) => batch.push(msg),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Whether a batch shows the prover can't keep up. Responses answer the prover's own requests
+    /// and can't be collapsed, so only the pushed messages left after coalescing count.
+    fn too_slow(batch: &[StratumMessage], max: usize) -> bool {
+        let pushed = batch
+            .iter()
+            .filter(|msg| !matches!(msg, StratumMessage::Response(..)))
+            .count();
+        pushed >= max
+    }
+
+    /// Keeps only the latest target and job of a backlog of outbound messages. Everything else,
+    /// responses in particular, is kept in order.
+    fn coalesce(batch: Vec<StratumMessage>) -> Vec<StratumMessage> {
+        let last_target = batch
+            .iter()
+            .rposition(|msg| matches!(msg, StratumMessage::SetTarget(..)));
+        let last_notify = batch.iter().rposition(|msg| matches!(msg, StratumMessage::Notify(..)));
+        // a dropped clean job still invalidates the older ones
+        let clean_jobs = batch
+            .iter()
+            .any(|msg| matches!(msg, StratumMessage::Notify(_, _, _, true)));
+        batch
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, msg)| match msg {
+                StratumMessage::SetTarget(..) if Some(i) != last_target => None,
+                StratumMessage::Notify(..) if Some(i) != last_notify => None,
+                StratumMessage::Notify(job_id, epoch_challenge, address, _) => {
+                    Some(StratumMessage::Notify(job_id, epoch_challenge, address, clean_jobs))
+                }
+                msg => Some(msg),
+            })
+            .collect()
+    }
+
+    /// Checks an authorize received after the connection is authorized. Returns the address to
+    /// switch to or the reason it was refused.
+    async fn reauthorize(
+        worker_name: &str,
+        password: &str,
+        authenticator: &dyn Authenticator,
+    ) -> Result<Address<CanaryV0>, String> {
+        if !Connection::valid_worker_name(worker_name) {
+            return Err("Invalid worker name".to_string());
+        }
+        let address = Address::<CanaryV0>::from_str(worker_name).map_err(|_| "Invalid address".to_string())?;
+        authenticator.authenticate(&address, worker_name, password).await?;
+        Ok(address)
+    }
+
+    /// Handles an authorize on an already authorized connection under `policy`. Returns the new
+    /// address if the connection switches to one, or the error to respond with.
+    async fn repeated_authorize(
+        policy: ReauthorizePolicy,
+        current: Address<CanaryV0>,
+        worker_name: &str,
+        password: &str,
+        authenticator: &dyn Authenticator,
+    ) -> Result<Option<Address<CanaryV0>>, Error<()>> {
+        if policy == ReauthorizePolicy::Reject {
+            return Err(Error::with_custom_msg(ErrorCode::from_code(20), "Already authorized"));
+        }
+        match Connection::reauthorize(worker_name, password, authenticator).await {
+            Ok(address) if address == current => Ok(None),
+            Ok(address) => Ok(Some(address)),
+            Err(reason) => Err(Error::with_custom_msg(ErrorCode::from_code(24), &reason)),
+        }
+    }
+
+    /// The error for a protocol version outside the supported range, `None` if it is supported.
+    fn unsupported_version(version: &Version, message: &str) -> Option<String> {
+        if *version >= MIN_SUPPORTED_VERSION && *version <= MAX_SUPPORTED_VERSION {
+            return None;
+        }
+        Some(
+            message
+                .replace("{version}", &version.to_string())
+                .replace("{min}", &MIN_SUPPORTED_VERSION.to_string())
+                .replace("{max}", &MAX_SUPPORTED_VERSION.to_string()),
+        )
+    }
+
+    fn valid_worker_name(worker_name: &str) -> bool {
+        !worker_name.is_empty()
+            && worker_name.len() <= MAX_WORKER_NAME_LEN
+            && worker_name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+    }
+
+    /// Drops non-printable characters and truncates, as the user agent is only used in logs.
+    fn sanitize_user_agent(user_agent: &str) -> String {
+        user_agent
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_USER_AGENT_LEN)
+            .collect()
+    }
+
+    fn submit_hash(job_id: &str, nonce: &str, commitment: &str, proof: &str) -> [u8; 32] {
+        let mut hasher = Blake2s256::new();
+        for field in [job_id, nonce, commitment, proof] {
+            hasher.update(field.as_bytes());
+            hasher.update(b"\n");
         }
+        hasher.finalize().into()
     }
 
+    /// Returns the prover's user agent and protocol version, its session, the job sent along and
+    /// the hashrate it expects to reach, if it gave one.
+    #[allow(clippy::type_complexity)]
     pub async fn handshake(
         framed: &mut Framed<TcpStream, StratumCodec>,
         pool_address: String,
-    ) -> Result<(String, Version)> {
+        sessions: &SessionStore,
+        current_job: &RwLock<Option<CurrentJob>>,
+        config: &ConnectionConfig,
+        extranonce: Option<Vec<u8>>,
+    ) -> PoolResult<(String, Version, String, Option<Session>, Option<String>, Option<f64>)> {
         let peer_addr = framed.get_ref().peer_addr()?;
         match timeout(PEER_HANDSHAKE_TIMEOUT, framed.next()).await {
             Ok(Some(Ok(message))) => {
                 trace!("Received message {} from peer {:?}", message.name(), peer_addr);
                 match message {
-                    StratumMessage::Subscribe(id, user_agent, protocol_version, _) => {
+                    StratumMessage::Subscribe(id, user_agent, protocol_version, session_id, hashrate) => {
                         let split: Vec<&str> = protocol_version.split('/').collect();
                         if split.len() != 2 {
                             warn!(
                                 "Invalid protocol version {} from peer {:?}",
                                 protocol_version, peer_addr
                             );
-                            return Err(anyhow!("Invalid protocol version"));
+                            return Err(PoolError::InvalidProtocolVersion(protocol_version));
                         }
                         if split[0] != "AleoStratum" {
                             warn!("Invalid protocol name {} from peer {:?}", split[0], peer_addr);
-                            return Err(anyhow!("Invalid protocol name"));
+                            return Err(PoolError::InvalidProtocolName(split[0].to_string()));
                         }
                         let version = Version::parse(split[1]).map_err(|e| {
                             warn!(
                                 "Invalid protocol version {} from peer {:?}: {:?}",
                                 split[1], peer_addr, e
                             );
-                            e
+                            PoolError::InvalidProtocolVersion(split[1].to_string())
                         })?;
-                        if version < MIN_SUPPORTED_VERSION || version > MAX_SUPPORTED_VERSION {
+                        if let Some(message) =
+                            Connection::unsupported_version(&version, &config.unsupported_version_message)
+                        {
                             warn!("Unsupported protocol version {} from peer {:?}", version, peer_addr);
-                            return Err(anyhow!("Unsupported protocol version"));
+                            let error = Error::with_custom_msg(ErrorCode::from_code(20), &message);
+                            if framed
+                                .send(StratumMessage::Response(id, None, Some(error)))
+                                .await
+                                .is_ok()
+                            {
+                                // returns early if the miner hangs up first
+                                let _ = timeout(config.unsupported_version_grace, framed.next()).await;
+                            }
+                            return Err(PoolError::UnsupportedProtocolVersion(version));
                         }
+                        let resumed = session_id.as_deref().and_then(|id| sessions.resume(id));
+                        let (session_id, extranonce) = match (session_id, &resumed) {
+                            (Some(session_id), Some(session)) => {
+                                debug!("Peer {:?} resumed session {}", peer_addr, session_id);
+                                (session_id, session.extranonce.clone())
+                            }
+                            _ => (sessions.create(extranonce.clone()), extranonce),
+                        };
                         let response_params: Vec<Box<dyn BoxedType>> = vec![
-                            Box::new(Option::<String>::None),
-                            Box::new(Option::<String>::None),
+                            Box::new(Some(session_id.clone())),
+                            Box::new(extranonce.as_ref().map(hex::encode)),
                             Box::new(Some(pool_address)),
                         ];
-                        framed
+                        let mut sent = framed
                             .send(StratumMessage::Response(
                                 id,
                                 Some(ResponseParams::Array(response_params)),
                                 None,
                             ))
-                            .await?;
-                        Ok((user_agent, version))
+                            .await;
+                        // the prover can start on the current job while it authorizes
+                        let job = current_job.read().clone();
+                        let job_id = job.as_ref().map(|job| job.job_id.clone());
+                        if let (true, Some(job)) = (sent.is_ok(), job) {
+                            sent = framed
+                                .send(StratumMessage::Notify(
+                                    job.job_id,
+                                    job.epoch_challenge,
+                                    job.address,
+                                    true,
+                                ))
+                                .await;
+                        }
+                        if sent.is_err() {
+                            // the connection never got the session, another one may resume it
+                            sessions.release(&session_id);
+                        }
+                        sent?;
+                        Ok((
+                            Connection::sanitize_user_agent(&user_agent),
+                            version,
+                            session_id,
+                            resumed,
+                            job_id,
+                            hashrate,
+                        ))
                     }
                     _ => {
                         warn!("Peer {:?} sent {} before handshake", peer_addr, message.name());
-                        Err(anyhow!("Unexpected message before handshake"))
+                        Err(PoolError::UnexpectedMessage(
+                            message.name(),
+                            ConnectionState::Subscribing.name(),
+                        ))
                     }
                 }
             }
             Ok(Some(Err(e))) => {
                 warn!("Error reading from peer {:?}: {}", peer_addr, e);
-                Err(anyhow!("Error reading from peer"))
+                Err(e.into())
             }
             Ok(None) => {
                 warn!("Peer {:?} disconnected before authorization", peer_addr);
-                Err(anyhow!("Peer disconnected before authorization"))
+                Err(PoolError::Disconnected("handshake"))
             }
             Err(e) => {
                 warn!("Peer {:?} timed out on handshake: {}", peer_addr, e);
-                Err(anyhow!("Peer timed out on handshake"))
+                Err(PoolError::Timeout("handshake"))
             }
         }
     }
 
-    pub async fn authorize(framed: &mut Framed<TcpStream, StratumCodec>) -> Result<Address<CanaryV0>> {
+    /// Returns the prover's address, its worker name and the submits buffered until it authorized.
+    pub async fn authorize(
+        framed: &mut Framed<TcpStream, StratumCodec>,
+        early_submit: EarlySubmitPolicy,
+        authenticator: &dyn Authenticator,
+    ) -> PoolResult<(Address<CanaryV0>, String, VecDeque<StratumMessage>)> {
         let peer_addr = framed.get_ref().peer_addr()?;
-        match timeout(PEER_HANDSHAKE_TIMEOUT, framed.next()).await {
-            Ok(Some(Ok(message))) => {
-                trace!("Received message {} from peer {:?}", message.name(), peer_addr);
-                match message {
-                    StratumMessage::Authorize(id, address, _) => {
-                        let address = Address::<CanaryV0>::from_str(address.as_str()).map_err(|e| {
-                            warn!("Invalid address {} from peer {:?}: {:?}", address, peer_addr, e);
-                            e
-                        })?;
-                        framed
-                            .send(StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None))
-                            .await?;
-                        Ok(address)
-                    }
-                    _ => {
+        let deadline = Instant::now() + PEER_HANDSHAKE_TIMEOUT;
+        let mut early_submits = VecDeque::new();
+        loop {
+            match timeout(deadline.saturating_duration_since(Instant::now()), framed.next()).await {
+                Ok(Some(Ok(message))) => {
+                    trace!("Received message {} from peer {:?}", message.name(), peer_addr);
+                    if !ConnectionState::Authorizing.accepts(&message) {
                         warn!("Peer {:?} sent {} before authorizing", peer_addr, message.name());
-                        Err(anyhow!("Unexpected message before authorization"))
+                        return Err(PoolError::UnexpectedMessage(
+                            message.name(),
+                            ConnectionState::Authorizing.name(),
+                        ));
+                    }
+                    match message {
+                        StratumMessage::Authorize(id, worker_name, password) => {
+                            if !Connection::valid_worker_name(&worker_name) {
+                                warn!(
+                                    "Invalid worker name {:?} from peer {:?}",
+                                    worker_name.chars().take(MAX_WORKER_NAME_LEN).collect::<String>(),
+                                    peer_addr
+                                );
+                                return Err(PoolError::InvalidWorkerName);
+                            }
+                            let address = Address::<CanaryV0>::from_str(worker_name.as_str()).map_err(|e| {
+                                warn!("Invalid address {} from peer {:?}: {:?}", worker_name, peer_addr, e);
+                                PoolError::InvalidAddress(worker_name.clone())
+                            })?;
+                            if let Err(reason) = authenticator.authenticate(&address, &worker_name, &password).await {
+                                warn!("Peer {:?} failed authentication as {}: {}", peer_addr, address, reason);
+                                let error = Error::with_custom_msg(ErrorCode::from_code(24), &reason);
+                                framed.send(StratumMessage::Response(id, None, Some(error))).await?;
+                                return Err(PoolError::Unauthorized(reason));
+                            }
+                            framed
+                                .send(StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None))
+                                .await?;
+                            return Ok((address, worker_name, early_submits));
+                        }
+                        StratumMessage::Submit(..)
+                            if early_submit == EarlySubmitPolicy::Buffer && early_submits.len() < MAX_EARLY_SUBMITS =>
+                        {
+                            debug!("Buffering submit from peer {:?} until it authorizes", peer_addr);
+                            early_submits.push_back(message);
+                        }
+                        StratumMessage::Submit(id, ..) if early_submit != EarlySubmitPolicy::Disconnect => {
+                            debug!("Rejecting submit from peer {:?} before authorization", peer_addr);
+                            if let Some(id) = id {
+                                let error = Error::with_custom_msg(ErrorCode::from_code(24), "Not authorized yet");
+                                framed.send(StratumMessage::Response(id, None, Some(error))).await?;
+                            }
+                        }
+                        // submits under the disconnect policy
+                        _ => {
+                            warn!("Peer {:?} sent {} before authorizing", peer_addr, message.name());
+                            return Err(PoolError::UnexpectedMessage(
+                                message.name(),
+                                ConnectionState::Authorizing.name(),
+                            ));
+                        }
                     }
                 }
+                Ok(Some(Err(e))) => {
+                    warn!("Error reading from peer {:?}: {}", peer_addr, e);
+                    return Err(e.into());
+                }
+                Ok(None) => {
+                    warn!("Peer {:?} disconnected before authorization", peer_addr);
+                    return Err(PoolError::Disconnected("authorization"));
+                }
+                Err(e) => {
+                    warn!("Peer {:?} timed out on authorize: {}", peer_addr, e);
+                    return Err(PoolError::Timeout("authorization"));
+                }
             }
-            Ok(Some(Err(e))) => {
-                warn!("Error reading from peer {:?}: {}", peer_addr, e);
-                Err(anyhow!("Error reading from peer"))
+        }
+    }
+
+    /// Yields submits buffered during authorization before reading from the peer.
+    async fn next_message(
+        early_submits: &mut VecDeque<StratumMessage>,
+        stream: &mut SplitStream<Framed<TcpStream, StratumCodec>>,
+    ) -> Option<std::io::Result<StratumMessage>> {
+        match early_submits.pop_front() {
+            Some(message) => Some(Ok(message)),
+            None => stream.next().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use snarkvm::console::account::PrivateKey;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+    use crate::auth::{AcceptAll, AuthFuture};
+
+    /// Accepts workers sending the password.
+    struct Password(&'static str);
+
+    impl Authenticator for Password {
+        fn authenticate<'a>(&'a self, _: &'a Address<CanaryV0>, _: &'a str, password: &'a str) -> AuthFuture<'a> {
+            Box::pin(async move {
+                match password == self.0 {
+                    true => Ok(()),
+                    false => Err("Wrong password".to_string()),
+                }
+            })
+        }
+    }
+
+    /// Authorizes `worker` with `password` over a loopback connection.
+    async fn authorize_with(
+        worker: &str,
+        password: &str,
+        authenticator: &dyn Authenticator,
+    ) -> PoolResult<Address<CanaryV0>> {
+        let (mut client, mut server) = loopback().await;
+        send_and_hang_up(&mut client, &[&authorize_frame(worker, password)]).await;
+        let authorized = Connection::authorize(&mut server, EarlySubmitPolicy::Reject, authenticator).await;
+        authorized.map(|(address, ..)| address)
+    }
+
+    #[tokio::test]
+    async fn default_authenticator_accepts_valid_addresses() {
+        let worker = worker_address();
+        let address = authorize_with(&worker, "", &AcceptAll).await.unwrap();
+        assert_eq!(address.to_string(), worker);
+        assert!(matches!(
+            authorize_with("aleo1invalid", "", &AcceptAll).await,
+            Err(PoolError::InvalidAddress(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn custom_authenticator_accepts_and_rejects() {
+        let worker = worker_address();
+        let authenticator = Password("secret");
+        assert!(authorize_with(&worker, "secret", &authenticator).await.is_ok());
+        assert!(matches!(
+            authorize_with(&worker, "guess", &authenticator).await,
+            Err(PoolError::Unauthorized(reason)) if reason == "Wrong password"
+        ));
+    }
+
+    fn worker_address() -> String {
+        let private_key = PrivateKey::<CanaryV0>::new(&mut thread_rng()).unwrap();
+        Address::try_from(private_key).unwrap().to_string()
+    }
+
+    #[test]
+    fn excess_connections_waiting_to_authorize_are_refused() {
+        let slots = Arc::new(AuthorizeSlots::new(2));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = slots.acquire(ip).unwrap();
+        let _second = slots.acquire(ip).unwrap();
+        assert!(slots.acquire(ip).is_none());
+        assert!(slots.acquire("10.0.0.2".parse().unwrap()).is_some());
+        // the first one authorizes
+        drop(first);
+        assert!(slots.acquire(ip).is_some());
+    }
+
+    /// A prover socket and the pool's end of the connection.
+    async fn loopback() -> (TcpStream, Framed<TcpStream, StratumCodec>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, Framed::new(server, StratumCodec::default()))
+    }
+
+    /// Sends `frames` from the prover, then stops sending.
+    async fn send_and_hang_up(client: &mut TcpStream, frames: &[&str]) {
+        for frame in frames {
+            client.write_all(format!("{}\n", frame).as_bytes()).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+    }
+
+    async fn handshake_error(frames: &[&str]) -> PoolError {
+        let (mut client, mut server) = loopback().await;
+        send_and_hang_up(&mut client, frames).await;
+        let sessions = SessionStore::new(Duration::from_secs(60));
+        let current_job = RwLock::new(None);
+        match Connection::handshake(
+            &mut server,
+            "pool".to_string(),
+            &sessions,
+            &current_job,
+            &test_config(),
+            None,
+        )
+        .await
+        {
+            Err(e) => e,
+            Ok(_) => panic!("the handshake succeeded"),
+        }
+    }
+
+    async fn authorize_error(frames: &[&str], authenticator: &dyn Authenticator) -> PoolError {
+        let (mut client, mut server) = loopback().await;
+        send_and_hang_up(&mut client, frames).await;
+        match Connection::authorize(&mut server, EarlySubmitPolicy::Reject, authenticator).await {
+            Err(e) => e,
+            Ok(_) => panic!("the authorization succeeded"),
+        }
+    }
+
+    fn subscribe_frame(protocol: &str) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"mining.subscribe","params":["test","{}",null]}}"#,
+            protocol
+        )
+    }
+
+    fn authorize_frame(worker: &str, password: &str) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":2,"method":"mining.authorize","params":["{}","{}"]}}"#,
+            worker, password
+        )
+    }
+
+    #[tokio::test]
+    async fn handshake_failures() {
+        assert!(matches!(
+            handshake_error(&[&subscribe_frame("FooStratum/2.0.0")]).await,
+            PoolError::InvalidProtocolName(name) if name == "FooStratum"
+        ));
+        assert!(matches!(
+            handshake_error(&[&subscribe_frame("AleoStratum")]).await,
+            PoolError::InvalidProtocolVersion(version) if version == "AleoStratum"
+        ));
+        assert!(matches!(
+            handshake_error(&[&subscribe_frame("AleoStratum/two")]).await,
+            PoolError::InvalidProtocolVersion(version) if version == "two"
+        ));
+        assert!(matches!(
+            handshake_error(&[&subscribe_frame("AleoStratum/1.0.0")]).await,
+            PoolError::UnsupportedProtocolVersion(version) if version == Version::new(1, 0, 0)
+        ));
+        assert!(matches!(
+            handshake_error(&[&authorize_frame(&worker_address(), "")]).await,
+            PoolError::UnexpectedMessage("mining.authorize", "subscribing")
+        ));
+        assert!(matches!(
+            handshake_error(&[]).await,
+            PoolError::Disconnected("handshake")
+        ));
+        assert!(matches!(handshake_error(&["not json"]).await, PoolError::Io(_)));
+    }
+
+    #[tokio::test]
+    async fn authorize_failures() {
+        assert!(matches!(
+            authorize_error(&[&authorize_frame("", "")], &AcceptAll).await,
+            PoolError::InvalidWorkerName
+        ));
+        assert!(matches!(
+            authorize_error(&[&authorize_frame("aleo1invalid", "")], &AcceptAll).await,
+            PoolError::InvalidAddress(address) if address == "aleo1invalid"
+        ));
+        assert!(matches!(
+            authorize_error(&[&authorize_frame(&worker_address(), "guess")], &Password("secret")).await,
+            PoolError::Unauthorized(reason) if reason == "Wrong password"
+        ));
+        assert!(matches!(
+            authorize_error(&[&subscribe_frame("AleoStratum/2.0.0")], &AcceptAll).await,
+            PoolError::UnexpectedMessage("mining.subscribe", "authorizing")
+        ));
+        assert!(matches!(
+            authorize_error(&[], &AcceptAll).await,
+            PoolError::Disconnected("authorization")
+        ));
+    }
+
+    fn test_config() -> ConnectionConfig {
+        ConnectionConfig {
+            idless_submit: IdlessSubmitPolicy::Reject,
+            unknown_method: UnknownMethodPolicy::Violation,
+            early_submit: EarlySubmitPolicy::Reject,
+            reauthorize: ReauthorizePolicy::Reject,
+            max_protocol_violations: 3,
+            max_decode_errors: 3,
+            decode_error_window: Duration::from_secs(60),
+            extranonce_len: 0,
+            validate_extranonce: false,
+            log_sample_interval: Duration::from_secs(60),
+            coalesce_outbound: true,
+            ack_batch_delay: None,
+            max_outbound_batch: 64,
+            authenticator: Arc::new(AcceptAll),
+            unsupported_version_message: String::new(),
+            unsupported_version_grace: Duration::ZERO,
+            codec_stats: Default::default(),
+        }
+    }
+
+    /// Runs a connection with `config` and authorizes a prover on it. Returns the prover's end, what
+    /// the connection sends the server and the server's way to reach the prover.
+    async fn authorized_prover(
+        config: ConnectionConfig,
+    ) -> (
+        Framed<TcpStream, StratumCodec>,
+        Receiver<ServerMessage>,
+        Sender<StratumMessage>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        let (server_sender, mut server_receiver) = channel(16);
+        task::spawn(Connection::run(
+            stream,
+            peer_addr,
+            server_sender,
+            Address::from_str(&worker_address()).unwrap(),
+            Arc::new(AuthorizeSlots::new(1)),
+            Arc::new(SessionStore::new(Duration::from_secs(60))),
+            Default::default(),
+            config,
+            None,
+        ));
+        let mut client = Framed::new(client, StratumCodec::default());
+        let subscribe = StratumMessage::Subscribe(
+            Id::Num(1),
+            "test".to_string(),
+            "AleoStratum/2.0.0".to_string(),
+            None,
+            None,
+        );
+        client.send(subscribe).await.unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(Id::Num(1), Some(_), None)))
+        ));
+        client
+            .send(StratumMessage::Authorize(Id::Num(2), worker_address(), String::new()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(
+                Id::Num(2),
+                Some(ResponseParams::Bool(true)),
+                None
+            )))
+        ));
+        let prover = match server_receiver.recv().await {
+            Some(ServerMessage::ProverAuthenticated(_, _, _, prover, ..)) => prover,
+            _ => panic!("expected the prover to be authenticated"),
+        };
+        (client, server_receiver, prover)
+    }
+
+    #[tokio::test]
+    async fn unknown_method_is_answered_and_the_peer_stays_connected() {
+        let config = ConnectionConfig {
+            unknown_method: UnknownMethodPolicy::Respond,
+            ..test_config()
+        };
+        let (mut client, mut server_receiver, _prover) = authorized_prover(config).await;
+        for id in [3, 4] {
+            let request = format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"mining.unknown\",\"params\":[]}}\n",
+                id
+            );
+            client.get_mut().write_all(request.as_bytes()).await.unwrap();
+            match client.next().await {
+                Some(Ok(StratumMessage::Response(Id::Num(response_id), None, Some(error)))) => {
+                    assert_eq!(response_id, id);
+                    assert_eq!(error.code.code(), -32601);
+                }
+                _ => panic!("expected a method not found error"),
             }
-            Ok(None) => {
-                warn!("Peer {:?} disconnected before authorization", peer_addr);
-                Err(anyhow!("Peer disconnected before authorization"))
+        }
+        // neither call counted as a protocol violation
+        assert!(server_receiver.try_recv().is_err());
+    }
+
+    /// A submit with a `nonce` that isn't hex.
+    fn malformed_submit(id: Option<Id>, nonce: &str) -> StratumMessage {
+        let field = || "00".to_string();
+        StratumMessage::Submit(id, worker_address(), field(), nonce.to_string(), field(), field())
+    }
+
+    #[tokio::test]
+    async fn submit_without_id_drops_the_connection_by_default() {
+        let (mut client, _server_receiver, _) = authorized_prover(test_config()).await;
+        client.send(malformed_submit(None, "zz")).await.unwrap();
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn submit_without_id_gets_no_response_when_silent() {
+        let config = ConnectionConfig {
+            idless_submit: IdlessSubmitPolicy::Silent,
+            ..test_config()
+        };
+        let (mut client, _server_receiver, _prover) = authorized_prover(config).await;
+        client.send(malformed_submit(None, "zz")).await.unwrap();
+        client.send(malformed_submit(Some(Id::Num(5)), "yy")).await.unwrap();
+        // the first answer is to the submit with an id
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(Id::Num(5), None, Some(_))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn benign_messages_are_tolerated_and_repeated_violations_disconnect() {
+        let (mut client, mut server_receiver, _prover) = authorized_prover(test_config()).await;
+        for id in 3..8 {
+            client
+                .send(StratumMessage::Unknown(Some(Id::Num(id)), "mining.ping".to_string()))
+                .await
+                .unwrap();
+        }
+        // still connected, and nothing counted
+        client.send(StratumMessage::GetStats(Some(Id::Num(8)))).await.unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(Id::Num(8), Some(_), None)))
+        ));
+        assert!(server_receiver.try_recv().is_err());
+
+        for id in 9..13 {
+            client
+                .send(StratumMessage::Unknown(Some(Id::Num(id)), "mining.bogus".to_string()))
+                .await
+                .unwrap();
+        }
+        assert!(client.next().await.is_none());
+        for _ in 0..4 {
+            assert!(matches!(
+                server_receiver.recv().await,
+                Some(ServerMessage::ProtocolViolation(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_response_carries_the_extranonce() {
+        let (mut client, mut server) = loopback().await;
+        let frame =
+            r#"{"jsonrpc":"2.0","id":1,"method":"mining.subscribe","params":["test","AleoStratum/2.0.0",null]}"#;
+        send_and_hang_up(&mut client, &[frame]).await;
+        let sessions = SessionStore::new(Duration::from_secs(60));
+        let current_job = RwLock::new(None);
+        Connection::handshake(
+            &mut server,
+            "pool".to_string(),
+            &sessions,
+            &current_job,
+            &test_config(),
+            Some(vec![0xab, 0xcd]),
+        )
+        .await
+        .unwrap();
+        drop(server);
+        let mut client = Framed::new(client, StratumCodec::default());
+        match client.next().await {
+            Some(Ok(StratumMessage::Response(Id::Num(1), Some(ResponseParams::Array(params)), None))) => {
+                assert_eq!(params[1].downcast_ref::<String>().unwrap(), "abcd");
             }
-            Err(e) => {
-                warn!("Peer {:?} timed out on authorize: {}", peer_addr, e);
-                Err(anyhow!("Peer timed out on authorize"))
+            _ => panic!("expected the subscribe response"),
+        }
+    }
+
+    #[test]
+    fn worker_names_are_validated() {
+        assert!(Connection::valid_worker_name(&worker_address()));
+        assert!(Connection::valid_worker_name("rig-1.gpu_0"));
+        assert!(!Connection::valid_worker_name(&"a".repeat(MAX_WORKER_NAME_LEN + 1)));
+        assert!(!Connection::valid_worker_name("rig\n1"));
+        assert!(!Connection::valid_worker_name("rig\u{1b}[31m"));
+        assert!(!Connection::valid_worker_name(""));
+    }
+
+    #[test]
+    fn user_agents_are_sanitized() {
+        assert_eq!(Connection::sanitize_user_agent("miner/1.0"), "miner/1.0");
+        assert_eq!(Connection::sanitize_user_agent("miner\r\n/1.0\u{7}"), "miner/1.0");
+        let long = "a".repeat(MAX_USER_AGENT_LEN * 2);
+        assert_eq!(Connection::sanitize_user_agent(&long).len(), MAX_USER_AGENT_LEN);
+    }
+
+    const EARLY_SUBMIT_FRAME: &str =
+        r#"{"jsonrpc":"2.0","id":7,"method":"mining.submit","params":["w","00","00","00","00"]}"#;
+
+    #[tokio::test]
+    async fn early_submit_is_buffered_until_authorized() {
+        let (mut client, mut server) = loopback().await;
+        let authorize = authorize_frame(&worker_address(), "");
+        send_and_hang_up(&mut client, &[EARLY_SUBMIT_FRAME, &authorize]).await;
+        let (_, _, early_submits) = Connection::authorize(&mut server, EarlySubmitPolicy::Buffer, &AcceptAll)
+            .await
+            .unwrap();
+        assert_eq!(early_submits.len(), 1);
+        assert!(matches!(early_submits[0], StratumMessage::Submit(Some(Id::Num(7)), ..)));
+        // it is handled first once the connection is active
+        let (_, mut stream) = futures_util::StreamExt::split(server);
+        let mut early_submits = early_submits;
+        assert!(matches!(
+            Connection::next_message(&mut early_submits, &mut stream).await,
+            Some(Ok(StratumMessage::Submit(Some(Id::Num(7)), ..)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn early_submit_is_soft_rejected() {
+        let (mut client, mut server) = loopback().await;
+        let authorize = authorize_frame(&worker_address(), "");
+        send_and_hang_up(&mut client, &[EARLY_SUBMIT_FRAME, &authorize]).await;
+        let (_, _, early_submits) = Connection::authorize(&mut server, EarlySubmitPolicy::Reject, &AcceptAll)
+            .await
+            .unwrap();
+        assert!(early_submits.is_empty());
+        drop(server);
+        let mut client = Framed::new(client, StratumCodec::default());
+        match client.next().await {
+            Some(Ok(StratumMessage::Response(Id::Num(7), None, Some(error)))) => {
+                assert_eq!(error.code.code(), 24);
+            }
+            _ => panic!("expected the submit to be rejected"),
+        }
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(
+                Id::Num(2),
+                Some(ResponseParams::Bool(true)),
+                None
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn early_submit_disconnects_when_configured() {
+        let (mut client, mut server) = loopback().await;
+        let authorize = authorize_frame(&worker_address(), "");
+        send_and_hang_up(&mut client, &[EARLY_SUBMIT_FRAME, &authorize]).await;
+        assert!(matches!(
+            Connection::authorize(&mut server, EarlySubmitPolicy::Disconnect, &AcceptAll).await,
+            Err(PoolError::UnexpectedMessage(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_inbound_message_does_not_delay_notify() {
+        let (mut client, _server_receiver, prover) = authorized_prover(test_config()).await;
+        // the start of a message whose rest never arrives keeps the read half waiting
+        let partial = format!(
+            r#"{{"jsonrpc":"2.0","id":3,"method":"mining.submit","params":["{}"#,
+            "0".repeat(2048)
+        );
+        client.get_mut().write_all(partial.as_bytes()).await.unwrap();
+        prover
+            .send(StratumMessage::Notify("job".to_string(), "00".to_string(), None, true))
+            .await
+            .unwrap();
+        assert!(matches!(
+            timeout(Duration::from_secs(1), client.next()).await,
+            Ok(Some(Ok(StratumMessage::Notify(job_id, ..)))) if job_id == "job"
+        ));
+    }
+
+    #[test]
+    fn burst_is_sampled() {
+        let mut log = LogSampler::new(Duration::from_secs(60));
+        let logged = (0..1000).filter(|_| log.sample().is_some()).count();
+        assert_eq!(logged, 1);
+        assert_eq!(log.flush(), Some(999));
+        assert_eq!(log.flush(), None);
+    }
+
+    #[tokio::test]
+    async fn stalled_prover_gets_only_the_latest_target() {
+        let (sender, mut receiver) = channel(16);
+        for target in 1..=5 {
+            sender.send(StratumMessage::SetTarget(target)).await.unwrap();
+        }
+        let ack = StratumMessage::Response(Id::Num(7), Some(ResponseParams::Bool(true)), None);
+        sender.send(ack).await.unwrap();
+
+        // what the writer finds queued once the prover reads again
+        let mut batch = vec![receiver.recv().await.unwrap()];
+        Connection::drain_into(&mut batch, &mut receiver, 16);
+        let batch = Connection::coalesce(batch);
+        assert_eq!(batch.len(), 2);
+        assert!(matches!(batch[0], StratumMessage::SetTarget(5)));
+        assert!(matches!(batch[1], StratumMessage::Response(Id::Num(7), ..)));
+    }
+
+    #[tokio::test]
+    async fn first_submit_for_the_latest_job_records_its_latency() {
+        let (mut client, mut server_receiver, prover) = authorized_prover(test_config()).await;
+        let notify = |job_id: &str| StratumMessage::Notify(job_id.to_string(), "00".to_string(), None, false);
+        let submit = |id, job_id: &str, nonce: &str| {
+            let field = || "00".to_string();
+            StratumMessage::Submit(
+                Some(Id::Num(id)),
+                worker_address(),
+                job_id.to_string(),
+                nonce.to_string(),
+                field(),
+                field(),
+            )
+        };
+
+        prover.send(notify("01")).await.unwrap();
+        assert!(matches!(client.next().await, Some(Ok(StratumMessage::Notify(..)))));
+        client.send(submit(3, "01", "zz")).await.unwrap();
+        match server_receiver.recv().await {
+            Some(ServerMessage::SubmitLatency(latency)) => assert!(latency < Duration::from_secs(1)),
+            _ => panic!("expected the submit latency"),
+        }
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(Id::Num(3), ..)))
+        ));
+
+        // a submit for a job older than the latest one says nothing about picking up jobs
+        prover.send(notify("02")).await.unwrap();
+        assert!(matches!(client.next().await, Some(Ok(StratumMessage::Notify(..)))));
+        client.send(submit(4, "01", "yy")).await.unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(Id::Num(4), ..)))
+        ));
+        while let Ok(message) = server_receiver.try_recv() {
+            assert!(!matches!(message, ServerMessage::SubmitLatency(..)));
+        }
+    }
+
+    /// Subscribes with `session_id`, returning the session id the pool answered with and the
+    /// session it resumed.
+    async fn resume_with(sessions: &SessionStore, session_id: &str) -> (String, Option<Session>) {
+        let (client, mut server) = loopback().await;
+        let mut client = Framed::new(client, StratumCodec::default());
+        let subscribe = StratumMessage::Subscribe(
+            Id::Num(1),
+            "test".to_string(),
+            "AleoStratum/2.0.0".to_string(),
+            Some(session_id.to_string()),
+            None,
+        );
+        client.send(subscribe).await.unwrap();
+        let current_job = RwLock::new(None);
+        let (_, _, issued, resumed, _, _) = Connection::handshake(
+            &mut server,
+            "pool".to_string(),
+            sessions,
+            &current_job,
+            &test_config(),
+            Some(vec![0xff]),
+        )
+        .await
+        .unwrap();
+        match client.next().await {
+            Some(Ok(StratumMessage::Response(Id::Num(1), Some(ResponseParams::Array(params)), None))) => {
+                assert_eq!(params[0].downcast_ref::<String>().unwrap(), &issued);
+            }
+            _ => panic!("expected the subscribe response"),
+        }
+        (issued, resumed)
+    }
+
+    #[tokio::test]
+    async fn known_session_is_resumed() {
+        let sessions = SessionStore::new(Duration::from_secs(60));
+        let address = Address::from_str(&worker_address()).unwrap();
+        let id = sessions.create(Some(vec![0x01, 0x02]));
+        sessions.update(&id, |session| {
+            session.address = Some(address);
+            session.target = Some(1000);
+        });
+        sessions.release(&id);
+
+        let (issued, resumed) = resume_with(&sessions, &id).await;
+        assert_eq!(issued, id);
+        let resumed = resumed.unwrap();
+        assert_eq!(resumed.address, Some(address));
+        assert_eq!(resumed.target, Some(1000));
+        assert_eq!(resumed.extranonce, Some(vec![0x01, 0x02]));
+    }
+
+    #[tokio::test]
+    async fn unknown_session_starts_fresh() {
+        let sessions = SessionStore::new(Duration::from_secs(60));
+        let (issued, resumed) = resume_with(&sessions, "0123456789abcdef").await;
+        assert!(resumed.is_none());
+        assert_ne!(issued, "0123456789abcdef");
+        assert!(sessions.sessions.lock().contains_key(&issued));
+    }
+
+    #[test]
+    fn valid_transitions() {
+        let mut state = ConnectionState::Subscribing;
+        for next in [
+            ConnectionState::Authorizing,
+            ConnectionState::Active,
+            ConnectionState::Draining,
+            ConnectionState::Closing,
+        ] {
+            state.transition(next).unwrap();
+            assert_eq!(state, next);
+        }
+        for state in [
+            ConnectionState::Subscribing,
+            ConnectionState::Authorizing,
+            ConnectionState::Active,
+            ConnectionState::Draining,
+        ] {
+            assert!(state.can_transition(ConnectionState::Closing));
+        }
+    }
+
+    #[test]
+    fn invalid_transitions() {
+        let mut state = ConnectionState::Subscribing;
+        assert!(state.transition(ConnectionState::Active).is_err());
+        assert_eq!(state, ConnectionState::Subscribing);
+        assert!(!ConnectionState::Active.can_transition(ConnectionState::Authorizing));
+        assert!(!ConnectionState::Closing.can_transition(ConnectionState::Closing));
+    }
+
+    #[test]
+    fn accepted_messages() {
+        let submit = StratumMessage::Submit(
+            Some(Id::Num(1)),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+        );
+        assert!(!ConnectionState::Subscribing.accepts(&submit));
+        assert!(ConnectionState::Authorizing.accepts(&submit));
+        assert!(ConnectionState::Active.accepts(&submit));
+
+        let subscribe = StratumMessage::Subscribe(Id::Num(1), String::new(), String::new(), None, None);
+        assert!(ConnectionState::Subscribing.accepts(&subscribe));
+        assert!(!ConnectionState::Authorizing.accepts(&subscribe));
+        assert!(!ConnectionState::Active.accepts(&subscribe));
+
+        let get_stats = StratumMessage::GetStats(Some(Id::Num(1)));
+        assert!(!ConnectionState::Authorizing.accepts(&get_stats));
+        assert!(ConnectionState::Active.accepts(&get_stats));
+
+        // server to prover only
+        assert!(!ConnectionState::Active.accepts(&StratumMessage::SetTarget(1)));
+    }
+
+    #[tokio::test]
+    async fn refused_prover_gets_the_reason() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        Connection::refuse(stream, peer_addr, "Pool under maintenance");
+
+        let mut client = Framed::new(client, StratumCodec::default());
+        client
+            .get_mut()
+            .write_all(format!("{}\n", subscribe_frame("AleoStratum/2.0.0")).as_bytes())
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(StratumMessage::Response(Id::Num(1), None, Some(error)))) => {
+                assert_eq!(error.code.code(), 25);
+                assert_eq!(
+                    serde_json::to_value(&error).unwrap()["message"],
+                    "Pool under maintenance"
+                );
+            }
+            _ => panic!("expected the refusal"),
+        }
+        assert!(client.next().await.is_none());
+    }
+
+    /// Sends `count` malformed submits with distinct nonces and ids from `first_id`, expecting each
+    /// to be answered.
+    async fn send_malformed(client: &mut Framed<TcpStream, StratumCodec>, first_id: u64, count: u64) {
+        for id in first_id..first_id + count {
+            client
+                .send(malformed_submit(Some(Id::Num(id)), &format!("z{}", id)))
+                .await
+                .unwrap();
+            match client.next().await {
+                Some(Ok(StratumMessage::Response(Id::Num(response_id), None, Some(error)))) => {
+                    assert_eq!(response_id, id);
+                    assert_eq!(error.code.code(), 20);
+                }
+                _ => panic!("expected a malformed share error"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sparse_malformed_submits_are_tolerated() {
+        let config = ConnectionConfig {
+            decode_error_window: Duration::from_millis(200),
+            ..test_config()
+        };
+        let (mut client, _server_receiver, _prover) = authorized_prover(config).await;
+        send_malformed(&mut client, 3, 3).await;
+        sleep(Duration::from_millis(300)).await;
+        // a new window starts counting from zero
+        send_malformed(&mut client, 6, 3).await;
+    }
+
+    #[tokio::test]
+    async fn sustained_malformed_submits_disconnect() {
+        let (mut client, _server_receiver, _prover) = authorized_prover(test_config()).await;
+        send_malformed(&mut client, 3, 3).await;
+        client.send(malformed_submit(Some(Id::Num(6)), "z6")).await.unwrap();
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rapid_acks_are_written_together_and_a_lone_ack_promptly() {
+        let delay = Duration::from_millis(100);
+        let ack = |id| StratumMessage::Response(Id::Num(id), Some(ResponseParams::Bool(true)), None);
+        let (mut client, _server_receiver, prover) = authorized_prover(ConnectionConfig {
+            ack_batch_delay: Some(delay),
+            ..test_config()
+        })
+        .await;
+
+        let start = Instant::now();
+        prover.send(ack(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        prover.send(ack(2)).await.unwrap();
+        prover.send(ack(3)).await.unwrap();
+        // the first ack waits for the later ones, which then follow without a gap
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Response(Id::Num(1), ..)))
+        ));
+        assert!(start.elapsed() >= delay);
+        for id in [2, 3] {
+            let next = timeout(Duration::from_millis(10), client.next()).await.unwrap();
+            assert!(matches!(next, Some(Ok(StratumMessage::Response(Id::Num(i), ..))) if i == id));
+        }
+
+        let start = Instant::now();
+        prover.send(ack(4)).await.unwrap();
+        let lone = timeout(delay * 3, client.next()).await.unwrap();
+        assert!(matches!(lone, Some(Ok(StratumMessage::Response(Id::Num(4), ..)))));
+        assert!(start.elapsed() < delay * 3);
+    }
+
+    #[test]
+    fn stats_count_only_answers_to_submits() {
+        let stats = ConnectionStats::default();
+        let accepted = StratumMessage::Response(Id::Num(1), Some(ResponseParams::Bool(true)), None);
+        let rejected = StratumMessage::Response(
+            Id::Num(2),
+            None,
+            Some(Error::with_custom_msg(ErrorCode::from_code(23), "")),
+        );
+        stats.record(&accepted, true);
+        stats.record(&accepted, false);
+        stats.record(&rejected, true);
+        stats.record(&StratumMessage::SetTarget(50), false);
+        match stats.to_response(Id::Num(9)) {
+            StratumMessage::Response(Id::Num(9), Some(ResponseParams::Array(params)), None) => {
+                let values = params
+                    .iter()
+                    .map(|p| *p.downcast_ref::<Option<u64>>().unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(values, vec![Some(1), Some(1), Some(50)]);
+            }
+            _ => panic!("expected the stats"),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_stats_returns_the_current_counters() {
+        let (mut client, _server_receiver, prover) = authorized_prover(test_config()).await;
+        prover.send(StratumMessage::SetTarget(50)).await.unwrap();
+        assert!(matches!(client.next().await, Some(Ok(StratumMessage::SetTarget(50)))));
+        // answered with a malformed share error
+        send_malformed(&mut client, 3, 1).await;
+
+        client.send(StratumMessage::GetStats(Some(Id::Num(9)))).await.unwrap();
+        match client.next().await {
+            Some(Ok(StratumMessage::Response(Id::Num(9), Some(ResponseParams::Array(params)), None))) => {
+                let values = params
+                    .iter()
+                    .map(|p| *p.downcast_ref::<Option<u64>>().unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(values, vec![Some(0), Some(1), Some(50)]);
             }
+            _ => panic!("expected the stats"),
         }
     }
+
+    #[tokio::test]
+    async fn closed_server_channel_shuts_the_connection_down() {
+        let (mut client, server_receiver, _prover) = authorized_prover(test_config()).await;
+        drop(server_receiver);
+        // a protocol violation has to be reported to the server
+        client
+            .send(StratumMessage::Unknown(Some(Id::Num(5)), "mining.bogus".to_string()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(StratumMessage::Reconnect(None, None, wait))) if wait == SERVER_GONE_RECONNECT_WAIT
+        ));
+        let closed = timeout(Duration::from_secs(5), client.next()).await.unwrap();
+        assert!(matches!(closed, None | Some(Err(_))));
+    }
+
+    /// Hashrate hint of a subscribe with `params` after the user agent and protocol version.
+    async fn subscribe_hashrate(params: &str) -> Option<f64> {
+        let (mut client, mut server) = loopback().await;
+        let frame = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"mining.subscribe","params":["test","AleoStratum/2.0.0",{}]}}"#,
+            params
+        );
+        send_and_hang_up(&mut client, &[&frame]).await;
+        let sessions = SessionStore::new(Duration::from_secs(60));
+        let current_job = RwLock::new(None);
+        let handshake = Connection::handshake(
+            &mut server,
+            "pool".to_string(),
+            &sessions,
+            &current_job,
+            &test_config(),
+            None,
+        )
+        .await;
+        handshake.unwrap().5
+    }
+
+    #[tokio::test]
+    async fn subscribe_carries_the_hashrate_hint() {
+        assert_eq!(subscribe_hashrate("null,2500").await, Some(2500.0));
+        assert_eq!(subscribe_hashrate("null").await, None);
+        assert_eq!(subscribe_hashrate(r#"null,"fast""#).await, None);
+    }
+
+    #[test]
+    fn backlog_of_acks_is_not_too_slow() {
+        let ack = |id| StratumMessage::Response(Id::Num(id), Some(ResponseParams::Bool(true)), None);
+        let mut batch = (0..8).map(ack).collect::<Vec<_>>();
+        batch.extend((0..8).map(|i| StratumMessage::Notify(format!("{:08x}", i), String::new(), None, false)));
+        batch.extend((0..8).map(StratumMessage::SetTarget));
+
+        let batch = Connection::coalesce(batch);
+        assert_eq!(batch.len(), 10);
+        assert!(!Connection::too_slow(&batch, 3));
+
+        // a prover that never drains piles up messages coalescing can't collapse
+        let batch = (0..4)
+            .map(|_| StratumMessage::ShowMessage(String::new()))
+            .collect::<Vec<_>>();
+        assert!(Connection::too_slow(&Connection::coalesce(batch), 3));
+    }
+
+    /// Subscribes over a loopback connection and returns what the prover received.
+    async fn subscribe_with(job: Option<CurrentJob>) -> Vec<StratumMessage> {
+        let (client, mut server) = loopback().await;
+        let mut client = Framed::new(client, StratumCodec::default());
+        let subscribe = StratumMessage::Subscribe(
+            Id::Num(1),
+            "test".to_string(),
+            "AleoStratum/2.0.0".to_string(),
+            None,
+            None,
+        );
+        client.send(subscribe).await.unwrap();
+        let sessions = SessionStore::new(Duration::from_secs(60));
+        let current_job = RwLock::new(job);
+        Connection::handshake(
+            &mut server,
+            "pool".to_string(),
+            &sessions,
+            &current_job,
+            &test_config(),
+            None,
+        )
+        .await
+        .unwrap();
+        drop(server);
+        let mut received = Vec::new();
+        while let Some(message) = client.next().await {
+            received.push(message.unwrap());
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn subscribe_is_followed_by_the_current_job() {
+        let job = CurrentJob {
+            job_id: "01000000".to_string(),
+            epoch_challenge: "challenge".to_string(),
+            address: Some("pool".to_string()),
+        };
+        let received = subscribe_with(Some(job)).await;
+        assert_eq!(received.len(), 2);
+        assert!(matches!(
+            received[0],
+            StratumMessage::Response(Id::Num(1), Some(_), None)
+        ));
+        match &received[1] {
+            StratumMessage::Notify(job_id, epoch_challenge, _, true) => {
+                assert_eq!(job_id, "01000000");
+                assert_eq!(epoch_challenge, "challenge");
+            }
+            other => panic!("expected the current job, got {}", other.name()),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_sends_no_job_before_the_first_broadcast() {
+        let received = subscribe_with(None).await;
+        assert_eq!(received.len(), 1);
+        assert!(matches!(
+            received[0],
+            StratumMessage::Response(Id::Num(1), Some(_), None)
+        ));
+    }
+
+    #[test]
+    fn unsupported_versions_get_the_supported_range() {
+        let message = "Version {version} unsupported, use {min} to {max}";
+        assert_eq!(
+            Connection::unsupported_version(&Version::new(1, 0, 0), message).as_deref(),
+            Some("Version 1.0.0 unsupported, use 2.0.0 to 2.0.0")
+        );
+        assert_eq!(
+            Connection::unsupported_version(&Version::new(3, 1, 0), message).as_deref(),
+            Some("Version 3.1.0 unsupported, use 2.0.0 to 2.0.0")
+        );
+        assert_eq!(Connection::unsupported_version(&Version::new(2, 0, 0), message), None);
+    }
+
+    #[tokio::test]
+    async fn repeated_authorize_is_rejected_by_default() {
+        let current = Address::from_str(&worker_address()).unwrap();
+        let worker = worker_address();
+        let error = Connection::repeated_authorize(ReauthorizePolicy::Reject, current, &worker, "", &AcceptAll)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code.code(), 20);
+    }
+
+    #[tokio::test]
+    async fn repeated_authorize_switches_address_when_allowed() {
+        let current = Address::from_str(&worker_address()).unwrap();
+        let worker = worker_address();
+        let switched = Connection::repeated_authorize(ReauthorizePolicy::Allow, current, &worker, "", &AcceptAll)
+            .await
+            .unwrap();
+        assert_eq!(switched.map(|address| address.to_string()), Some(worker));
+        let same =
+            Connection::repeated_authorize(ReauthorizePolicy::Allow, current, &current.to_string(), "", &AcceptAll)
+                .await
+                .unwrap();
+        assert!(same.is_none());
+        let error = Connection::repeated_authorize(ReauthorizePolicy::Allow, current, "aleo1invalid", "", &AcceptAll)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code.code(), 24);
+    }
 }
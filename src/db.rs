@@ -1,6 +1,12 @@
-use std::{collections::HashMap, env};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use deadpool_postgres::{
     ClientWrapper,
     Config,
@@ -13,12 +19,121 @@ use deadpool_postgres::{
     RecyclingMethod,
     Runtime,
 };
-use snarkvm::prelude::{PuzzleCommitment, CanaryV0};
-use tokio_postgres::NoTls;
-use tracing::warn;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio_postgres::{error::SqlState, NoTls};
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Calls go through
+    Closed,
+    /// Calls fail right away until the cooldown has passed
+    Open,
+    /// The cooldown has passed, the next call tests whether the database is back
+    HalfOpen,
+}
+
+#[derive(Serialize)]
+pub struct BreakerStats {
+    pub state: BreakerState,
+    /// Calls failed without reaching the database
+    pub short_circuited: u64,
+}
+
+/// Fails database calls fast for a cooldown after repeated failures, so callers don't pile up on an
+/// unresponsive database.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    /// (consecutive failures, when the breaker opened)
+    state: Mutex<(u32, Option<Instant>)>,
+    short_circuited: AtomicU64,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new((0, None)),
+            short_circuited: AtomicU64::new(0),
+        }
+    }
+
+    fn state(&self) -> BreakerState {
+        match self.state.lock().1 {
+            None => BreakerState::Closed,
+            Some(opened) if opened.elapsed() < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether a call may go through. Only one call is let through to test a half-open breaker.
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock();
+        match state.1 {
+            None => true,
+            Some(opened) if opened.elapsed() < self.cooldown => {
+                self.short_circuited.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Some(_) => {
+                // stays open while the test call is running
+                state.1 = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut state = self.state.lock();
+        if success {
+            if state.1.is_some() {
+                info!("Database is responding again, closing the circuit breaker");
+            }
+            *state = (0, None);
+            return;
+        }
+        state.0 += 1;
+        if state.1.is_some() || state.0 >= self.threshold {
+            warn!(
+                "Opening the database circuit breaker for {:?} after {} failures",
+                self.cooldown, state.0
+            );
+            state.1 = Some(Instant::now());
+        }
+    }
+}
+
+/// SQLSTATE classes of errors that come from the database's condition rather than the data:
+/// connection exceptions, transaction rollbacks, insufficient resources, operator intervention and
+/// system errors
+static TRANSIENT_ERROR_CLASSES: [&str; 5] = ["08", "40", "53", "57", "58"];
+
+/// Whether a failed call may succeed when retried. Errors without a SQLSTATE didn't reach the
+/// database, e.g. a lost connection or the open circuit breaker.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<tokio_postgres::Error>().and_then(|e| e.code()) {
+        Some(code) => TRANSIENT_ERROR_CLASSES
+            .iter()
+            .any(|class| code.code().starts_with(class)),
+        None => true,
+    }
+}
+
+/// Whether a call failed because the row it inserts exists already.
+pub fn is_duplicate(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(|e| e.code())
+        .is_some_and(|code| *code == SqlState::UNIQUE_VIOLATION)
+}
 
 pub struct DB {
     connection_pool: Pool,
+    breaker: CircuitBreaker,
 }
 
 impl DB {
@@ -62,36 +177,64 @@ impl DB {
         .runtime(Runtime::Tokio1)
         .build()
         .expect("Failed to create database connection pool");
-        DB { connection_pool: pool }
+        let breaker_threshold = env::var("DB_BREAKER_THRESHOLD")
+            .map(|threshold| threshold.parse::<u32>().expect("Invalid database breaker threshold"))
+            .unwrap_or(5);
+        let breaker_cooldown = env::var("DB_BREAKER_COOLDOWN")
+            .map(|cooldown| cooldown.parse::<u64>().expect("Invalid database breaker cooldown"))
+            .unwrap_or(30);
+        DB {
+            connection_pool: pool,
+            breaker: CircuitBreaker::new(breaker_threshold, Duration::from_secs(breaker_cooldown)),
+        }
     }
 
-    pub async fn save_solution(
-        &self,
-        commitment: PuzzleCommitment<CanaryV0>,
-        shares: HashMap<String, u64>,
-    ) -> Result<()> {
-        let mut conn = self.connection_pool.get().await?;
-        let transaction = conn.transaction().await?;
-
-        let solution_id: i32 = transaction
-            .query_one(
-                "INSERT INTO solution (commitment) VALUES ($1) RETURNING id",
-                &[&commitment.to_string()],
-            )
-            .await?
-            .try_get("id")?;
-
-        let stmt = transaction
-            .prepare_cached("INSERT INTO share (solution_id, address, share) VALUES ($1, $2, $3)")
-            .await?;
-        for (address, share) in shares {
-            transaction
-                .query(&stmt, &[&solution_id, &address, &(share as i64)])
-                .await?;
+    pub fn breaker_stats(&self) -> BreakerStats {
+        BreakerStats {
+            state: self.breaker.state(),
+            short_circuited: self.breaker.short_circuited.load(Ordering::Relaxed),
         }
+    }
+
+    async fn guarded<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        if !self.breaker.allow() {
+            bail!("Database circuit breaker is open");
+        }
+        let result = call.await;
+        // an error about the data means the database is responding
+        self.breaker.record(match &result {
+            Ok(_) => true,
+            Err(e) => !is_transient(e),
+        });
+        result
+    }
+
+    pub async fn save_solution(&self, commitment: &str, shares: &HashMap<String, u64>) -> Result<()> {
+        self.guarded(async {
+            let mut conn = self.connection_pool.get().await?;
+            let transaction = conn.transaction().await?;
 
-        transaction.commit().await?;
-        Ok(())
+            let solution_id: i32 = transaction
+                .query_one(
+                    "INSERT INTO solution (commitment) VALUES ($1) RETURNING id",
+                    &[&commitment],
+                )
+                .await?
+                .try_get("id")?;
+
+            let stmt = transaction
+                .prepare_cached("INSERT INTO share (solution_id, address, share) VALUES ($1, $2, $3)")
+                .await?;
+            for (address, share) in shares {
+                transaction
+                    .query(&stmt, &[&solution_id, address, &(*share as i64)])
+                    .await?;
+            }
+
+            transaction.commit().await?;
+            Ok(())
+        })
+        .await
     }
 
     pub async fn set_solution_valid(
@@ -101,41 +244,66 @@ impl DB {
         height: Option<u32>,
         reward: Option<u64>,
     ) -> Result<()> {
-        let mut conn = self.connection_pool.get().await?;
-        let transaction = conn.transaction().await?;
-        let stmt = transaction
-            .prepare_cached("UPDATE solution SET valid = $1, checked = checked + 1 WHERE commitment = $2")
-            .await?;
-        transaction.query(&stmt, &[&valid, commitment]).await?;
-        if valid {
-            transaction
-                .query(
-                    "UPDATE solution SET height = $1, reward = $2 WHERE commitment = $3",
-                    &[&(height.unwrap() as i64), &(reward.unwrap() as i64), commitment],
-                )
+        self.guarded(async {
+            let mut conn = self.connection_pool.get().await?;
+            let transaction = conn.transaction().await?;
+            let stmt = transaction
+                .prepare_cached("UPDATE solution SET valid = $1, checked = checked + 1 WHERE commitment = $2")
                 .await?;
-        }
-        transaction.commit().await?;
-        Ok(())
+            transaction.query(&stmt, &[&valid, commitment]).await?;
+            if valid {
+                transaction
+                    .query(
+                        "UPDATE solution SET height = $1, reward = $2 WHERE commitment = $3",
+                        &[&(height.unwrap() as i64), &(reward.unwrap() as i64), commitment],
+                    )
+                    .await?;
+            }
+            transaction.commit().await?;
+            Ok(())
+        })
+        .await
     }
 
     pub async fn get_should_pay_solutions(&self) -> Result<Vec<(i32, String)>> {
-        let conn = self.connection_pool.get().await?;
-        let stmt = conn
-            .prepare_cached(
-                "SELECT * FROM solution WHERE paid = false AND ((valid = false AND checked < 3) OR valid = true) \
-                 ORDER BY id",
-            )
-            .await?;
-        let rows = conn.query(&stmt, &[]).await?;
-        Ok(rows
-            .into_iter()
-            .map(|row| {
-                let id: i32 = row.get("id");
-                let commitment: String = row.get("commitment");
-                (id, commitment)
-            })
-            .collect())
+        self.guarded(async {
+            let conn = self.connection_pool.get().await?;
+            let stmt = conn
+                .prepare_cached(
+                    "SELECT * FROM solution WHERE paid = false AND ((valid = false AND checked < 3) OR valid = true) \
+                     ORDER BY id",
+                )
+                .await?;
+            let rows = conn.query(&stmt, &[]).await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| {
+                    let id: i32 = row.get("id");
+                    let commitment: String = row.get("commitment");
+                    (id, commitment)
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// Returns (unpaid, paid) of the address, zeros if it has never been paid.
+    pub async fn get_balance(&self, address: &str) -> Result<(u64, u64)> {
+        self.guarded(async {
+            let conn = self.connection_pool.get().await?;
+            let stmt = conn
+                .prepare_cached("SELECT unpaid, paid FROM balance WHERE address = $1")
+                .await?;
+            match conn.query_opt(&stmt, &[&address]).await? {
+                Some(row) => {
+                    let unpaid: i64 = row.get("unpaid");
+                    let paid: i64 = row.get("paid");
+                    Ok((unpaid as u64, paid as u64))
+                }
+                None => Ok((0, 0)),
+            }
+        })
+        .await
     }
 
     // pub async fn set_checked_blocks(&self, latest_height: u32) -> Result<()> {
@@ -149,9 +317,12 @@ impl DB {
     // }
 
     pub async fn pay_solution(&self, solution_id: i32) -> Result<()> {
-        let conn = self.connection_pool.get().await?;
-        let stmt = conn.prepare("CALL pay_solution($1)").await?;
-        conn.query(&stmt, &[&solution_id]).await?;
-        Ok(())
+        self.guarded(async {
+            let conn = self.connection_pool.get().await?;
+            let stmt = conn.prepare("CALL pay_solution($1)").await?;
+            conn.query(&stmt, &[&solution_id]).await?;
+            Ok(())
+        })
+        .await
     }
 }
@@ -0,0 +1,130 @@
+use std::io;
+
+use semver::Version;
+use thiserror::Error;
+
+pub type PoolResult<T> = Result<T, PoolError>;
+
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("peer timed out on {0}")]
+    Timeout(&'static str),
+
+    #[error("peer disconnected before {0}")]
+    Disconnected(&'static str),
+
+    #[error("unexpected message {0} before {1}")]
+    UnexpectedMessage(&'static str, &'static str),
+
+    #[error("invalid connection state transition from {0} to {1}")]
+    InvalidTransition(&'static str, &'static str),
+
+    #[error("invalid protocol version {0}")]
+    InvalidProtocolVersion(String),
+
+    #[error("invalid protocol name {0}")]
+    InvalidProtocolName(String),
+
+    #[error("unsupported protocol version {0}")]
+    UnsupportedProtocolVersion(Version),
+
+    #[error("invalid worker name")]
+    InvalidWorkerName,
+
+    #[error("invalid address {0}")]
+    InvalidAddress(String),
+
+    #[error("authorization rejected: {0}")]
+    Unauthorized(String),
+
+    #[cfg(feature = "db")]
+    #[error("solution check request failed: {0}")]
+    SolutionCheck(#[from] reqwest::Error),
+
+    #[cfg(feature = "db")]
+    #[error("all {0} solution check endpoints failed")]
+    SolutionCheckUnavailable(usize),
+
+    #[cfg(feature = "db")]
+    #[error("only {0} solution check endpoints agree, quorum is {1}")]
+    SolutionCheckQuorum(usize, usize),
+
+    #[cfg(feature = "db")]
+    #[error("invalid solution check response: missing {0}")]
+    InvalidSolutionResponse(&'static str),
+
+    #[cfg(feature = "db")]
+    #[error("database error: {0}")]
+    Database(#[from] anyhow::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        for (error, message) in [
+            (PoolError::Timeout("handshake"), "peer timed out on handshake"),
+            (
+                PoolError::Disconnected("authorization"),
+                "peer disconnected before authorization",
+            ),
+            (
+                PoolError::UnexpectedMessage("mining.submit", "subscribing"),
+                "unexpected message mining.submit before subscribing",
+            ),
+            (
+                PoolError::InvalidTransition("closing", "active"),
+                "invalid connection state transition from closing to active",
+            ),
+            (
+                PoolError::InvalidProtocolVersion("two".to_string()),
+                "invalid protocol version two",
+            ),
+            (
+                PoolError::InvalidProtocolName("Foo".to_string()),
+                "invalid protocol name Foo",
+            ),
+            (
+                PoolError::UnsupportedProtocolVersion(Version::new(1, 0, 0)),
+                "unsupported protocol version 1.0.0",
+            ),
+            (PoolError::InvalidWorkerName, "invalid worker name"),
+            (PoolError::InvalidAddress("aleo1".to_string()), "invalid address aleo1"),
+            (
+                PoolError::Unauthorized("Wrong password".to_string()),
+                "authorization rejected: Wrong password",
+            ),
+        ] {
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn io_errors_convert() {
+        let error = PoolError::from(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+        assert!(matches!(&error, PoolError::Io(e) if e.kind() == io::ErrorKind::BrokenPipe));
+        assert_eq!(error.to_string(), "I/O error: gone");
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn solution_check_messages() {
+        assert_eq!(
+            PoolError::SolutionCheckUnavailable(3).to_string(),
+            "all 3 solution check endpoints failed"
+        );
+        assert_eq!(
+            PoolError::SolutionCheckQuorum(1, 2).to_string(),
+            "only 1 solution check endpoints agree, quorum is 2"
+        );
+        assert_eq!(
+            PoolError::InvalidSolutionResponse("height").to_string(),
+            "invalid solution check response: missing height"
+        );
+    }
+}
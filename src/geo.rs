@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::anyhow;
+use maxminddb::{geoip2, Reader};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+pub trait GeoLookup: Send + Sync {
+    fn region(&self, ip: IpAddr) -> Option<String>;
+}
+
+pub struct MaxMindLookup {
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMindLookup {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            reader: Reader::open_readfile(path)?,
+        })
+    }
+}
+
+impl GeoLookup for MaxMindLookup {
+    fn region(&self, ip: IpAddr) -> Option<String> {
+        let country = self.reader.lookup::<geoip2::Country>(ip).ok()?;
+        country
+            .country
+            .and_then(|c| c.iso_code)
+            .or_else(|| country.continent.and_then(|c| c.code))
+            .map(|code| code.to_string())
+    }
+}
+
+pub struct GeoStats {
+    lookup: Box<dyn GeoLookup>,
+    regions: RwLock<HashMap<SocketAddr, String>>,
+}
+
+impl GeoStats {
+    pub fn init() -> anyhow::Result<GeoStats> {
+        let path =
+            env::var("GEOIP_DATABASE").map_err(|_| anyhow!("No GeoIP database path defined in GEOIP_DATABASE"))?;
+        let lookup =
+            MaxMindLookup::open(&path).map_err(|e| anyhow!("Failed to open GeoIP database {}: {}", path, e))?;
+        Ok(GeoStats::with_lookup(Box::new(lookup)))
+    }
+
+    pub fn with_lookup(lookup: Box<dyn GeoLookup>) -> GeoStats {
+        GeoStats {
+            lookup,
+            regions: Default::default(),
+        }
+    }
+
+    fn is_local(ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => GeoStats::is_local(IpAddr::V4(ip)),
+                // fc00::/7 unique local, fe80::/10 link local
+                None => {
+                    ip.is_loopback()
+                        || ip.is_unspecified()
+                        || (ip.segments()[0] & 0xfe00) == 0xfc00
+                        || (ip.segments()[0] & 0xffc0) == 0xfe80
+                }
+            },
+        }
+    }
+
+    pub fn region(&self, ip: IpAddr) -> String {
+        if GeoStats::is_local(ip) {
+            return "local".to_string();
+        }
+        self.lookup.region(ip).unwrap_or_else(|| {
+            debug!("Unable to resolve region for {}", ip);
+            "unknown".to_string()
+        })
+    }
+
+    pub async fn connected(&self, peer_addr: SocketAddr) {
+        let region = self.region(peer_addr.ip());
+        debug!("Peer {} resolved to region {}", peer_addr, region);
+        self.regions.write().await.insert(peer_addr, region);
+    }
+
+    pub async fn disconnected(&self, peer_addr: SocketAddr) {
+        self.regions.write().await.remove(&peer_addr);
+    }
+
+    pub async fn counts(&self) -> HashMap<String, u32> {
+        let mut counts = HashMap::new();
+        for region in self.regions.read().await.values() {
+            *counts.entry(region.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StubLookup;
+
+    impl GeoLookup for StubLookup {
+        fn region(&self, ip: IpAddr) -> Option<String> {
+            match ip {
+                IpAddr::V4(ip) if ip.octets()[0] == 1 => Some("AU".to_string()),
+                IpAddr::V4(ip) if ip.octets()[0] == 8 => Some("US".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_bucketed_by_region() {
+        let geo = GeoStats::with_lookup(Box::new(StubLookup));
+        for peer in [
+            "1.1.1.1:1",
+            "1.0.0.1:2",
+            "8.8.8.8:3",
+            "9.9.9.9:4",
+            "127.0.0.1:5",
+            "192.168.1.2:6",
+        ] {
+            geo.connected(peer.parse().unwrap()).await;
+        }
+        geo.disconnected("1.0.0.1:2".parse().unwrap()).await;
+
+        let counts = geo.counts().await;
+        assert_eq!(counts.get("AU"), Some(&1));
+        assert_eq!(counts.get("US"), Some(&1));
+        assert_eq!(counts.get("unknown"), Some(&1));
+        assert_eq!(counts.get("local"), Some(&2));
+    }
+}
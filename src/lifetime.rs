@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, rename, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use snarkvm::{console::account::Address, prelude::CanaryV0};
+use tokio::{sync::Mutex, task};
+use tracing::{error, info};
+
+use crate::accounting::state_dir;
+
+/// Share results of an address since it first connected.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LifetimeStats {
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+/// Lifetime stats of every address. When persisted they are written behind to the state
+/// directory and survive restarts.
+pub struct LifetimeStore {
+    stats: RwLock<HashMap<String, LifetimeStats>>,
+    /// Where the stats are persisted, `None` to keep them in memory only
+    path: Option<PathBuf>,
+    /// Keeps saves from writing the temporary file at the same time
+    saving: Mutex<()>,
+}
+
+impl LifetimeStore {
+    pub fn load(persist: bool) -> Self {
+        let path = match persist {
+            true => state_dir()
+                .map(|dir| Some(dir.join("lifetime_stats.json")))
+                .unwrap_or_else(|e| {
+                    error!("Failed to load lifetime stats: {}", e);
+                    None
+                }),
+            false => None,
+        };
+        LifetimeStore::load_from(path)
+    }
+
+    fn load_from(path: Option<PathBuf>) -> Self {
+        let stats = match &path {
+            Some(path) => LifetimeStore::load_stats(path).unwrap_or_else(|e| {
+                error!("Failed to load lifetime stats: {}", e);
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+        if !stats.is_empty() {
+            info!("Loaded lifetime stats of {} addresses", stats.len());
+        }
+        Self {
+            stats: RwLock::new(stats),
+            path,
+            saving: Mutex::new(()),
+        }
+    }
+
+    fn load_stats(path: &Path) -> Result<HashMap<String, LifetimeStats>, Error> {
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn persisted(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn record(&self, address: &Address<CanaryV0>, accepted: bool) {
+        let mut stats = self.stats.write();
+        let stats = stats.entry(address.to_string()).or_default();
+        if accepted {
+            stats.accepted += 1;
+        } else {
+            stats.rejected += 1;
+        }
+    }
+
+    pub fn get(&self, address: &Address<CanaryV0>) -> LifetimeStats {
+        self.stats.read().get(&address.to_string()).copied().unwrap_or_default()
+    }
+
+    /// Writes the stats to disk if they are persisted. Works on a snapshot, so recording isn't
+    /// held up by the write, which runs off the async runtime.
+    pub async fn save(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let _saving = self.saving.lock().await;
+        let stats = self.stats.read().clone();
+        task::spawn_blocking(move || LifetimeStore::write(&path, &stats)).await?
+    }
+
+    fn write(path: &Path, stats: &HashMap<String, LifetimeStats>) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        // written to a temporary file first so a crash never leaves a truncated file behind
+        let tmp_path = path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, stats)?;
+        writer.flush()?;
+        rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use snarkvm::console::account::PrivateKey;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lifetime_counts_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("aleo-pool-lifetime-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("lifetime_stats.json");
+        let address = Address::try_from(PrivateKey::<CanaryV0>::new(&mut thread_rng()).unwrap()).unwrap();
+
+        let store = LifetimeStore::load_from(Some(path.clone()));
+        store.record(&address, true);
+        store.record(&address, true);
+        store.record(&address, false);
+        store.save().await.unwrap();
+        drop(store);
+
+        let store = LifetimeStore::load_from(Some(path));
+        let stats = store.get(&address);
+        assert_eq!((stats.accepted, stats.rejected), (2, 1));
+        // in-memory stores start over
+        assert_eq!(LifetimeStore::load_from(None).get(&address).accepted, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+use std::{io, net::SocketAddr};
+
+use socket2::{Domain, Socket, Type};
+use tokio::net::TcpListener;
+
+/// Socket options set on listeners before they are bound.
+#[derive(Clone, Copy)]
+pub struct ListenerOptions {
+    /// Allows rebinding right away while connections of a previous process linger in TIME_WAIT
+    pub reuse_address: bool,
+    /// Lets several processes accept on the same port, with the kernel spreading connections
+    /// between them
+    pub reuse_port: bool,
+}
+
+pub fn bind(addr: SocketAddr, options: ListenerOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(options.reuse_address)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(options.reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rebinds_right_after_closing_with_reuse_address() {
+        let options = ListenerOptions {
+            reuse_address: true,
+            reuse_port: false,
+        };
+        let listener = bind("127.0.0.1:0".parse().unwrap(), options).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // closing the accepted side first leaves it in TIME_WAIT
+        drop(listener.accept().await.unwrap());
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+        drop(client);
+        drop(listener);
+
+        let listener = bind(addr, options).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+}
@@ -1,30 +1,57 @@
+// without the HTTP API most of the stats accessors have no caller
+#![cfg_attr(not(feature = "api"), allow(dead_code))]
+
 mod accounting;
+#[cfg(feature = "api")]
 mod api;
+mod auth;
+mod ban;
+mod coinbase_key;
 mod connection;
+mod error;
+mod lifetime;
+mod listener;
+mod maintenance;
+mod metrics;
 mod server;
+mod submit;
+mod upstream;
+mod util;
 mod validator_peer;
 
 #[cfg(feature = "db")]
 mod db;
+#[cfg(feature = "geoip")]
+mod geo;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use clap::Parser;
 use futures::stream::StreamExt;
 use rand::seq::SliceRandom;
 use signal_hook::consts::{SIGABRT, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGTSTP, SIGUSR1};
 use signal_hook_tokio::Signals;
-use snarkvm::{console::account::Address, prelude::CanaryV0};
+use snarkvm::{
+    console::account::Address,
+    prelude::{CanaryV0, Network},
+};
 use tokio::sync::mpsc::Sender;
 use tracing::{debug, error, info, warn};
 use tracing_log::{log, LogTracer};
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter};
 
+#[cfg(feature = "api")]
+use crate::api::{AdminClientAuth, ApiTlsConfig};
 use crate::validator_peer::Node;
 use crate::{
-    accounting::{Accounting, AccountingMessage},
+    accounting::{Accounting, AccountingMessage, PayoutScheme, StateFormat},
+    auth::{AcceptAll, Allowlist, Authenticator},
+    ban::AutoBanConfig,
+    connection::{ConnectionConfig, EarlySubmitPolicy, IdlessSubmitPolicy, ReauthorizePolicy, UnknownMethodPolicy},
+    listener::ListenerOptions,
     //    operator_peer::Node,
-    server::{Server, ServerMessage},
+    server::{AutoNConfig, Server, ServerConfig, ServerMessage, ShareValue},
+    upstream::{Upstream, UpstreamConfig},
 };
 
 #[derive(Debug, Parser)]
@@ -35,7 +62,7 @@ struct Opt {
     validator: Option<String>,
 
     /// Mining pool address
-    #[clap(short, long)]
+    #[clap(short, long, value_parser = parse_pool_address)]
     address: Address<CanaryV0>,
 
     /// Port to listen for incoming provers
@@ -43,9 +70,251 @@ struct Opt {
     port: u16,
 
     /// API port
+    #[cfg(feature = "api")]
     #[clap(short, long = "api-port")]
     api_port: u16,
 
+    /// Serve Prometheus metrics on this port, independent of the API
+    #[clap(long = "metrics-port")]
+    metrics_port: Option<u16>,
+
+    /// Export per-address and per-IP metrics for this many of the fastest addresses and IPs, the
+    /// others are summed up under one label. 0 to export none
+    #[clap(long = "metrics-top-addresses", default_value_t = 0)]
+    metrics_top_addresses: usize,
+
+    /// Maximum connections per IP that have subscribed but not yet authorized
+    #[clap(long = "max-pending-authorize", default_value_t = 4)]
+    max_pending_authorize: u32,
+
+    /// Interval in seconds between pool speed recalculations
+    #[clap(long = "pool-speed-interval", default_value_t = 10)]
+    pool_speed_interval: u64,
+
+    /// Window in seconds of the short term pool speed
+    #[clap(long = "speed-short-window", default_value_t = 60)]
+    speed_short_window: u64,
+
+    /// Window in seconds of the long term pool speed
+    #[clap(long = "speed-long-window", default_value_t = 3600)]
+    speed_long_window: u64,
+
+    /// Size the PPLNS window to this many seconds of pool work instead of following the network
+    /// proof target
+    #[clap(long = "auto-n-window")]
+    auto_n_window: Option<u64>,
+
+    /// Lower bound of the automatically sized PPLNS window
+    #[clap(long = "auto-n-min", default_value_t = 1 << 20)]
+    auto_n_min: u64,
+
+    /// Upper bound of the automatically sized PPLNS window
+    #[clap(long = "auto-n-max", default_value_t = u64::MAX >> 8)]
+    auto_n_max: u64,
+
+    /// Largest relative change of the automatically sized PPLNS window per minute. Sizing starts
+    /// once --speed-long-window has passed and follows the speed over it; at 0.1, n takes about 7
+    /// minutes to double
+    #[clap(long = "auto-n-max-step", default_value_t = 0.1)]
+    auto_n_max_step: f64,
+
+    /// How to handle submits sent without an id
+    #[clap(long = "idless-submit", value_enum, default_value_t = IdlessSubmitPolicy::Reject)]
+    idless_submit: IdlessSubmitPolicy,
+
+    /// How to handle requests for methods not defined by the protocol
+    #[clap(long = "unknown-method", value_enum, default_value_t = UnknownMethodPolicy::Violation)]
+    unknown_method: UnknownMethodPolicy,
+
+    /// How to handle submits received before the prover has authorized
+    #[clap(long = "early-submit", value_enum, default_value_t = EarlySubmitPolicy::Reject)]
+    early_submit: EarlySubmitPolicy,
+
+    /// How to handle an authorize on a connection that is already authorized
+    #[clap(long = "reauthorize", value_enum, default_value_t = ReauthorizePolicy::Reject)]
+    reauthorize: ReauthorizePolicy,
+
+    /// Number of unexpected messages tolerated before a prover is disconnected
+    #[clap(long = "max-protocol-violations", default_value_t = 3)]
+    max_protocol_violations: u32,
+
+    /// Number of malformed submits tolerated per window before a prover is disconnected
+    #[clap(long = "max-decode-errors", default_value_t = 10)]
+    max_decode_errors: u32,
+
+    /// Length in seconds of the malformed submit window
+    #[clap(long = "decode-error-window", default_value_t = 600)]
+    decode_error_window: u64,
+
+    /// Length in bytes of the per-connection nonce prefix sent at subscribe, 0 to disable
+    #[clap(long = "extranonce-len", default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=4))]
+    extranonce_len: u8,
+
+    /// Reject submits whose nonce doesn't start with the connection's extranonce
+    #[clap(long = "validate-extranonce")]
+    validate_extranonce: bool,
+
+    /// Minimum interval in seconds between repeated reject warnings for a connection
+    #[clap(long = "log-sample-interval", default_value_t = 10)]
+    log_sample_interval: u64,
+
+    /// Milliseconds to hold a submit response so responses following shortly after are written
+    /// together, 0 to write each right away
+    #[clap(long = "ack-batch-delay", default_value_t = 0)]
+    ack_batch_delay: u64,
+
+    /// File of addresses allowed to authorize, one per line. Any valid address may authorize
+    /// without it
+    #[clap(long = "auth-allowlist")]
+    auth_allowlist: Option<String>,
+
+    /// Error sent to miners with an unsupported protocol version. `{version}`, `{min}` and `{max}`
+    /// are replaced with the requested and the supported versions
+    #[clap(
+        long = "unsupported-version-message",
+        default_value = "Unsupported protocol version {version}, supported are {min} to {max}"
+    )]
+    unsupported_version_message: String,
+
+    /// Seconds a miner with an unsupported protocol version stays connected after the error
+    #[clap(long = "unsupported-version-grace", default_value_t = 1)]
+    unsupported_version_grace: u64,
+
+    /// Most outbound messages written to a prover in one batch. Provers whose jobs, targets and
+    /// notices still fill a batch after coalescing are disconnected as too slow
+    #[clap(long = "max-outbound-batch", default_value_t = 256)]
+    max_outbound_batch: usize,
+
+    /// Don't set SO_REUSEADDR on the listeners, so a restart fails while old connections linger
+    #[clap(long = "no-reuse-address")]
+    no_reuse_address: bool,
+
+    /// Set SO_REUSEPORT on the listeners so several pool processes can share the ports
+    #[clap(long = "reuse-port")]
+    reuse_port: bool,
+
+    /// Send every queued target and job to slow provers instead of only the latest
+    #[clap(long = "no-outbound-coalescing")]
+    no_outbound_coalescing: bool,
+
+    /// Skip local proof verification and trust the validator to reject invalid solutions
+    #[clap(long = "no-verify-proofs")]
+    no_verify_proofs: bool,
+
+    /// How accepted shares are valued for PPLNS; `difficulty` needs proof verification
+    #[clap(long = "share-value", value_enum, default_value_t = ShareValue::Difficulty)]
+    share_value: ShareValue,
+
+    /// How strongly a prover's target is lowered when most of its submits miss the target, 0 to disable
+    #[clap(long = "reject-difficulty-weight", default_value_t = 0.5)]
+    reject_difficulty_weight: f64,
+
+    /// Shares credited per connection and epoch, further ones are acknowledged without credit
+    #[clap(long = "max-epoch-shares", default_value_t = 100_000)]
+    max_epoch_shares: u32,
+
+    /// Lowest difficulty a share must reach regardless of the prover's target, 0 for no minimum
+    #[clap(long = "min-share-difficulty", default_value_t = 0)]
+    min_share_difficulty: u64,
+
+    /// Log every PPLNS change as it happens so a crash loses none since the last state save
+    #[clap(long = "share-log")]
+    share_log: bool,
+
+    /// How solution rewards are split between provers
+    #[clap(long = "payout-scheme", value_enum, default_value_t = PayoutScheme::Pplns)]
+    payout_scheme: PayoutScheme,
+
+    /// Largest value a single share is credited with in PPLNS
+    #[clap(long = "max-share-value", default_value_t = u64::MAX >> 16)]
+    max_share_value: u64,
+
+    /// Starting target of new connections
+    #[clap(long = "probation-target", default_value_t = 512)]
+    probation_target: u64,
+
+    /// Number of shares a new connection submits at the probation target before vardiff engages
+    #[clap(long = "probation-shares", default_value_t = 0)]
+    probation_shares: u32,
+
+    /// Highest hashrate in proofs per second a prover may report at subscribe to seed its target, 0
+    /// to ignore reported hashrates
+    #[clap(long = "max-reported-hashrate", default_value_t = 1_000_000.0)]
+    max_reported_hashrate: f64,
+
+    /// Keep accepted and rejected share counts of every address in the state directory so they
+    /// survive restarts
+    #[clap(long = "persist-lifetime-stats")]
+    persist_lifetime_stats: bool,
+
+    /// Seconds an address without connections keeps its live stats
+    #[clap(long = "address-idle-ttl", default_value_t = 86400)]
+    address_idle_ttl: u64,
+
+    /// Ban an address when more than this ratio of its recent submits are rejected
+    #[clap(long = "ban-reject-ratio", default_value_t = 0.5)]
+    ban_reject_ratio: f64,
+
+    /// Minimum number of recent submits before the reject ratio is considered
+    #[clap(long = "ban-min-samples", default_value_t = 20)]
+    ban_min_samples: usize,
+
+    /// Window in seconds over which the reject ratio is computed
+    #[clap(long = "ban-window", default_value_t = 600)]
+    ban_window: u64,
+
+    /// Ban duration in seconds
+    #[clap(long = "ban-cooldown", default_value_t = 1800)]
+    ban_cooldown: u64,
+
+    /// Certificate chain to serve the API over TLS
+    #[cfg(feature = "api")]
+    #[clap(long = "api-tls-cert", requires = "api_tls_key")]
+    api_tls_cert: Option<String>,
+
+    /// Private key for the API certificate
+    #[cfg(feature = "api")]
+    #[clap(long = "api-tls-key", requires = "api_tls_cert")]
+    api_tls_key: Option<String>,
+
+    /// Serve the admin routes on --admin-port, only to clients with a certificate signed by this CA
+    #[cfg(feature = "api")]
+    #[clap(long = "admin-client-ca", requires_all = ["api_tls_cert", "admin_port"])]
+    admin_client_ca: Option<String>,
+
+    /// Port of the client-authenticated admin API
+    #[cfg(feature = "api")]
+    #[clap(long = "admin-port", requires = "admin_client_ca")]
+    admin_port: Option<u16>,
+
+    /// Upstream stratum pool (host:port) to send provers to while the validator delivers no work
+    #[clap(long = "upstream", requires = "upstream_worker")]
+    upstream: Option<String>,
+
+    /// Worker name to authorize with at the upstream pool
+    #[clap(long = "upstream-worker")]
+    upstream_worker: Option<String>,
+
+    /// Password to authorize with at the upstream pool
+    #[clap(long = "upstream-password", default_value = "x")]
+    upstream_password: String,
+
+    /// Seconds without a new epoch challenge before provers are switched to the upstream pool
+    #[clap(long = "upstream-idle-timeout", default_value_t = 300)]
+    upstream_idle_timeout: u64,
+
+    /// Seed the current round from a JSON file of {address: shares} exported by another pool
+    #[clap(long = "import-state")]
+    import_state: Option<String>,
+
+    /// PPLNS window size to check --import-state against when the state doesn't have one yet
+    #[clap(long = "import-n", requires = "import_state")]
+    import_n: Option<u64>,
+
+    /// Format of the persisted PPLNS state
+    #[clap(long = "state-format", value_enum, default_value_t = StateFormat::Binary)]
+    state_format: StateFormat,
+
     /// Enable debug logging
     #[clap(short, long)]
     debug: bool,
@@ -123,15 +392,163 @@ async fn main() {
 
     let address = opt.address;
 
-    let accounting = Accounting::init();
+    let accounting = match Accounting::init(opt.state_format, opt.max_share_value, opt.payout_scheme, opt.share_log) {
+        Ok(accounting) => accounting,
+        Err(e) => {
+            error!("Failed to initialize accounting: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(path) = &opt.import_state {
+        if let Err(e) = accounting.import_state(path, opt.import_n).await {
+            error!("Failed to import state: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if opt.auto_n_window.is_some() && (opt.auto_n_min > opt.auto_n_max || !(opt.auto_n_max_step >= 0.0)) {
+        error!("Invalid auto n bounds: min must not exceed max and the step must not be negative");
+        std::process::exit(1);
+    }
+
+    let auto_n_initial = accounting.n().await;
+
+    let authenticator: Arc<dyn Authenticator> = match &opt.auth_allowlist {
+        Some(path) => match Allowlist::load(path) {
+            Ok(allowlist) => {
+                info!("Loaded {} allowed addresses", allowlist.address_count());
+                Arc::new(allowlist)
+            }
+            Err(e) => {
+                error!("Failed to load the authorization allowlist: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Arc::new(AcceptAll),
+    };
 
     let node = Node::init(validator);
 
-    let server = Server::init(port, address, node.sender(), accounting.sender()).await;
+    let listener_options = ListenerOptions {
+        reuse_address: !opt.no_reuse_address,
+        reuse_port: opt.reuse_port,
+    };
+
+    let config = ServerConfig {
+        listener: listener_options,
+        max_pending_authorize: opt.max_pending_authorize,
+        pool_speed_interval: Duration::from_secs(opt.pool_speed_interval),
+        speed_short_window: Duration::from_secs(opt.speed_short_window),
+        speed_long_window: Duration::from_secs(opt.speed_long_window),
+        connection: ConnectionConfig {
+            idless_submit: opt.idless_submit,
+            unknown_method: opt.unknown_method,
+            early_submit: opt.early_submit,
+            reauthorize: opt.reauthorize,
+            max_protocol_violations: opt.max_protocol_violations,
+            max_decode_errors: opt.max_decode_errors,
+            decode_error_window: Duration::from_secs(opt.decode_error_window),
+            extranonce_len: opt.extranonce_len as usize,
+            validate_extranonce: opt.validate_extranonce,
+            log_sample_interval: Duration::from_secs(opt.log_sample_interval),
+            coalesce_outbound: !opt.no_outbound_coalescing,
+            ack_batch_delay: (opt.ack_batch_delay > 0).then(|| Duration::from_millis(opt.ack_batch_delay)),
+            max_outbound_batch: opt.max_outbound_batch,
+            authenticator,
+            unsupported_version_message: opt.unsupported_version_message,
+            unsupported_version_grace: Duration::from_secs(opt.unsupported_version_grace),
+            codec_stats: Default::default(),
+        },
+        verify_proofs: !opt.no_verify_proofs,
+        share_value: opt.share_value,
+        reject_difficulty_weight: opt.reject_difficulty_weight,
+        upstream_idle_timeout: Duration::from_secs(opt.upstream_idle_timeout),
+        address_idle_ttl: Duration::from_secs(opt.address_idle_ttl),
+        probation_target: opt.probation_target,
+        probation_shares: opt.probation_shares,
+        max_reported_hashrate: opt.max_reported_hashrate,
+        max_epoch_shares: opt.max_epoch_shares,
+        min_share_difficulty: opt.min_share_difficulty,
+        auto_n: opt.auto_n_window.map(|window| AutoNConfig {
+            window: Duration::from_secs(window),
+            min: opt.auto_n_min,
+            max: opt.auto_n_max,
+            max_step: opt.auto_n_max_step,
+            initial: auto_n_initial,
+        }),
+        persist_lifetime_stats: opt.persist_lifetime_stats,
+        auto_ban: AutoBanConfig {
+            reject_ratio: opt.ban_reject_ratio,
+            min_samples: opt.ban_min_samples,
+            window: Duration::from_secs(opt.ban_window),
+            cooldown: Duration::from_secs(opt.ban_cooldown),
+        },
+    };
+
+    let upstream = opt.upstream.zip(opt.upstream_worker).map(|(address, worker_name)| {
+        Upstream::init(UpstreamConfig {
+            address,
+            worker_name,
+            password: opt.upstream_password,
+        })
+    });
+
+    let server = match Server::init(
+        port,
+        address,
+        node.sender(),
+        accounting.sender(),
+        upstream.as_ref().map(|upstream| upstream.sender()),
+        config,
+    )
+    .await
+    {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to start the server: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     validator_peer::start(node, server.sender());
 
-    api::start(opt.api_port, accounting.clone(), server.clone());
+    if let Some(upstream) = upstream {
+        upstream::start(upstream, server.sender());
+    }
+
+    #[cfg(feature = "api")]
+    {
+        let api_tls = match (opt.api_tls_cert, opt.api_tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(ApiTlsConfig {
+                cert_path,
+                key_path,
+                admin_client_auth: opt
+                    .admin_client_ca
+                    .zip(opt.admin_port)
+                    .map(|(ca_path, port)| AdminClientAuth { ca_path, port }),
+            }),
+            _ => None,
+        };
+
+        api::start(
+            opt.api_port,
+            listener_options,
+            accounting.clone(),
+            server.clone(),
+            api_tls,
+        );
+    }
+
+    if let Some(port) = opt.metrics_port {
+        metrics::start(
+            port,
+            listener_options,
+            opt.metrics_top_addresses,
+            server.clone(),
+            accounting.clone(),
+        );
+    }
 
     match Signals::new([SIGABRT, SIGTERM, SIGHUP, SIGINT, SIGQUIT, SIGUSR1, SIGTSTP]) {
         Ok(signals) => {
@@ -146,6 +563,38 @@ async fn main() {
     std::future::pending::<()>().await;
 }
 
+/// Human readable part of the addresses of the network the pool runs on
+const ADDRESS_PREFIX: &str = "aleo";
+
+/// Parses the pool address, rejecting anything that wouldn't be sent to provers verbatim.
+fn parse_pool_address(address: &str) -> Result<Address<CanaryV0>, String> {
+    // bech32 puts the network's prefix before the last 1
+    match address.rsplit_once('1') {
+        Some((prefix, _)) if prefix.eq_ignore_ascii_case(ADDRESS_PREFIX) => {}
+        Some((prefix, _)) if !prefix.is_empty() && prefix.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            return Err(format!(
+                "{} is an address of another network, {} addresses start with {}1",
+                address,
+                CanaryV0::NAME,
+                ADDRESS_PREFIX
+            ));
+        }
+        _ => {
+            return Err(format!(
+                "{} is not an Aleo address, they start with {}1",
+                address, ADDRESS_PREFIX
+            ))
+        }
+    }
+    let parsed = address
+        .parse::<Address<CanaryV0>>()
+        .map_err(|e| format!("{} is not a valid Aleo address: {}", address, e))?;
+    if parsed.to_string() != address {
+        return Err(format!("{} is not in canonical form, expected {}", address, parsed));
+    }
+    Ok(parsed)
+}
+
 async fn handle_signals(mut signals: Signals, accounting: Arc<Accounting>, server_sender: Sender<ServerMessage>) {
     while let Some(signal) = signals.next().await {
         info!("Received signal: {:?}", signal);
@@ -175,3 +624,55 @@ async fn handle_signals(mut signals: Signals, accounting: Arc<Accounting>, serve
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use snarkvm::console::account::PrivateKey;
+
+    use super::*;
+
+    fn address() -> String {
+        let private_key = PrivateKey::<CanaryV0>::new(&mut thread_rng()).unwrap();
+        Address::try_from(private_key).unwrap().to_string()
+    }
+
+    #[test]
+    fn valid_pool_address() {
+        let address = address();
+        assert_eq!(parse_pool_address(&address).unwrap().to_string(), address);
+    }
+
+    #[test]
+    fn pool_address_of_another_network() {
+        let address = address().replacen(ADDRESS_PREFIX, "other", 1);
+        assert!(parse_pool_address(&address).unwrap_err().contains("another network"));
+    }
+
+    #[test]
+    fn malformed_pool_address() {
+        let address = address();
+        assert!(parse_pool_address("").is_err());
+        assert!(parse_pool_address("not an address").is_err());
+        assert!(parse_pool_address(&address[..address.len() - 1]).is_err());
+        assert!(parse_pool_address(&address.to_uppercase()).is_err());
+    }
+
+    /// Checks the pool still builds without the HTTP API. It builds the whole crate again in its own
+    /// target directory, so it only runs when asked for with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn builds_without_default_features() {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        let status = std::process::Command::new(env!("CARGO"))
+            .args(["check", "--no-default-features", "--manifest-path"])
+            .arg(format!("{}/Cargo.toml", manifest_dir))
+            .env(
+                "CARGO_TARGET_DIR",
+                format!("{}/target/no-default-features", manifest_dir),
+            )
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    fs::{create_dir_all, remove_file, File},
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::Error;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{accounting::state_dir, util::now};
+
+/// Planned downtime, in unix seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl MaintenanceWindow {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.start <= now && now < self.end
+    }
+}
+
+/// The scheduled maintenance window. It is kept next to the PPLNS state so a restart honours it.
+pub struct Maintenance {
+    window: RwLock<Option<MaintenanceWindow>>,
+}
+
+impl Maintenance {
+    pub fn load() -> Self {
+        let window = match Maintenance::load_window() {
+            Ok(window) => window,
+            Err(e) => {
+                error!("Failed to load the maintenance schedule: {}", e);
+                None
+            }
+        };
+        if let Some(window) = window {
+            info!("Maintenance scheduled from {} to {}", window.start, window.end);
+        }
+        Self {
+            window: RwLock::new(window),
+        }
+    }
+
+    fn path() -> Result<PathBuf, Error> {
+        Ok(state_dir()?.join("maintenance.json"))
+    }
+
+    fn load_window() -> Result<Option<MaintenanceWindow>, Error> {
+        let path = Maintenance::path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(BufReader::new(File::open(path)?))?))
+    }
+
+    /// Replaces the schedule, `None` cancels it.
+    pub fn schedule(&self, window: Option<MaintenanceWindow>) -> Result<(), Error> {
+        let path = Maintenance::path()?;
+        match &window {
+            Some(window) => {
+                create_dir_all(state_dir()?)?;
+                let mut writer = BufWriter::new(File::create(path)?);
+                serde_json::to_writer(&mut writer, window)?;
+                writer.flush()?;
+            }
+            None if path.exists() => remove_file(path)?,
+            None => {}
+        }
+        *self.window.write() = window;
+        Ok(())
+    }
+
+    pub fn window(&self) -> Option<MaintenanceWindow> {
+        *self.window.read()
+    }
+
+    /// The window if it is in progress.
+    pub fn active(&self) -> Option<MaintenanceWindow> {
+        self.window().filter(|window| window.is_active(now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_is_active_from_start_to_end() {
+        let window = MaintenanceWindow { start: 100, end: 200 };
+        assert!(!window.is_active(99));
+        assert!(window.is_active(100));
+        assert!(window.is_active(199));
+        assert!(!window.is_active(200));
+    }
+
+    #[test]
+    fn only_a_window_in_progress_is_active() {
+        let maintenance = |start: u64, end: u64| Maintenance {
+            window: RwLock::new(Some(MaintenanceWindow { start, end })),
+        };
+        let now = now();
+        assert!(maintenance(now - 60, now + 60).active().is_some());
+        assert!(maintenance(now + 60, now + 120).active().is_none());
+        assert!(maintenance(now - 120, now - 60).active().is_none());
+        let unscheduled = Maintenance {
+            window: RwLock::new(None),
+        };
+        assert!(unscheduled.active().is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    fmt::Write as _,
+    future::Future,
+    io,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task,
+    time::timeout,
+};
+use tracing::{debug, error, info};
+
+use crate::{connection::SLOW_DISCONNECTS, listener, listener::ListenerOptions, Accounting, Server};
+
+static REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+static MAX_REQUEST_LEN: usize = 8192;
+
+/// Pool speed windows in the order `Server::pool_speed` returns them
+static SPEED_WINDOWS: [&str; 4] = ["5m", "15m", "30m", "1h"];
+
+/// Label of the addresses and IPs outside the top ones
+static OTHER_ENTRIES: &str = "other";
+
+/// Serves `/metrics` in the Prometheus text format from a bare TCP listener, so scraping works
+/// without the API. With `top_addresses` above 0, the addresses and IPs with the highest speed
+/// get their own series.
+pub fn start(
+    port: u16,
+    listener_options: ListenerOptions,
+    top_addresses: usize,
+    server: Arc<Server>,
+    accounting: Arc<Accounting>,
+) {
+    task::spawn(async move {
+        let listener = match listener::bind(([0, 0, 0, 0], port).into(), listener_options) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Unable to start the metrics server: {}", e);
+                return;
+            }
+        };
+        info!("Starting metrics server on port {}", port);
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    let server = server.clone();
+                    let accounting = accounting.clone();
+                    task::spawn(async move {
+                        if let Err(e) = respond(stream, || render(top_addresses, &server, &accounting)).await {
+                            debug!("Metrics request from {} failed: {}", peer_addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Error accepting metrics connection: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Answers a request for `/metrics` with the body `render` returns.
+async fn respond<F: Future<Output = String>>(mut stream: TcpStream, render: impl FnOnce() -> F) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+        }
+        let read = timeout(REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request_line = request.split(|byte| *byte == b'\r').next().unwrap_or_default();
+    let (status, body) = if request_line.starts_with(b"GET /metrics ") || request_line.starts_with(b"GET /metrics?") {
+        ("200 OK", render().await)
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// Speed and prover count samples of the `top` entries by 5 minute speed, as (key, provers,
+/// speed). The other entries are summed up under one label so the number of series stays bounded.
+fn top_samples(
+    label: &str,
+    mut entries: Vec<(String, f64, f64)>,
+    top: usize,
+) -> (Vec<(String, f64)>, Vec<(String, f64)>) {
+    entries.sort_unstable_by(|a, b| b.2.total_cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    let others = entries.split_off(top.min(entries.len()));
+    entries.push((
+        OTHER_ENTRIES.to_string(),
+        others.iter().map(|(_, provers, _)| provers).sum(),
+        others.iter().map(|(_, _, speed)| speed).sum(),
+    ));
+    entries
+        .into_iter()
+        .map(|(key, provers, speed)| {
+            let labels = format!("{{{}=\"{}\"}}", label, key);
+            ((labels.clone(), speed), (labels, provers))
+        })
+        .unzip()
+}
+
+async fn render(top_addresses: usize, server: &Server, accounting: &Accounting) -> String {
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "aleo_pool_online_provers",
+        "gauge",
+        "Authenticated prover connections",
+        vec![(String::new(), server.online_provers().await as f64)],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_online_addresses",
+        "gauge",
+        "Addresses with at least one prover connection",
+        vec![(String::new(), server.online_addresses().await as f64)],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_speed",
+        "gauge",
+        "Pool speed over the window",
+        SPEED_WINDOWS
+            .iter()
+            .zip(server.pool_speed())
+            .map(|(window, speed)| (format!("{{window=\"{}\"}}", window), speed))
+            .collect(),
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_round_fill_ratio",
+        "gauge",
+        "How much of the PPLNS window is filled",
+        vec![(String::new(), accounting.fill_ratio().await)],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_queue_depth",
+        "gauge",
+        "Messages waiting in internal queues",
+        vec![
+            ("{queue=\"accounting\"}".to_string(), accounting.channel_depth() as f64),
+            (
+                "{queue=\"outbound\"}".to_string(),
+                server.outbound_queue_depth().await as f64,
+            ),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_evicted_addresses_total",
+        "counter",
+        "Idle addresses whose live stats were dropped",
+        vec![(String::new(), server.evicted_addresses() as f64)],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_uncredited_shares_total",
+        "counter",
+        "Accepted shares not credited because their connection hit the epoch cap",
+        vec![(String::new(), server.uncredited_shares() as f64)],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_slow_disconnects_total",
+        "counter",
+        "Connections dropped because they couldn't keep up with their outbound messages",
+        vec![(String::new(), SLOW_DISCONNECTS.load(Ordering::Relaxed) as f64)],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_decode_buffer_max_bytes",
+        "gauge",
+        "Largest number of bytes buffered for a single message",
+        vec![(String::new(), server.codec_stats().max_buffered() as f64)],
+    );
+    write_metric(
+        &mut out,
+        "aleo_pool_decode_length_limit_exceeded_total",
+        "counter",
+        "Messages rejected for exceeding the length limit",
+        vec![(String::new(), server.codec_stats().length_limit_exceeded() as f64)],
+    );
+    if top_addresses > 0 {
+        let addresses = server
+            .active_addresses()
+            .await
+            .into_iter()
+            .map(|(address, (provers, speed))| (address.to_string(), provers as f64, speed[0]))
+            .collect();
+        let (speeds, provers) = top_samples("address", addresses, top_addresses);
+        write_metric(
+            &mut out,
+            "aleo_pool_address_speed",
+            "gauge",
+            "Speed over 5 minutes of the fastest addresses, the rest summed up as \"other\"",
+            speeds,
+        );
+        write_metric(
+            &mut out,
+            "aleo_pool_address_provers",
+            "gauge",
+            "Prover connections of the fastest addresses, the rest summed up as \"other\"",
+            provers,
+        );
+        let ips = server
+            .active_ips()
+            .await
+            .into_iter()
+            .map(|(ip, (provers, speed))| (ip.to_string(), provers as f64, speed[0]))
+            .collect();
+        let (speeds, provers) = top_samples("ip", ips, top_addresses);
+        write_metric(
+            &mut out,
+            "aleo_pool_ip_speed",
+            "gauge",
+            "Speed over 5 minutes of the fastest IPs, the rest summed up as \"other\"",
+            speeds,
+        );
+        write_metric(
+            &mut out,
+            "aleo_pool_ip_provers",
+            "gauge",
+            "Prover connections of the fastest IPs, the rest summed up as \"other\"",
+            provers,
+        );
+    }
+    // the histogram buckets are cumulative in the exposition format
+    let mut total = 0;
+    let mut buckets = vec![];
+    for (bound, count) in server.submit_latency() {
+        total += count;
+        let bound = if bound == "inf" { "+Inf".to_string() } else { bound };
+        buckets.push((format!("_bucket{{le=\"{}\"}}", bound), total as f64));
+    }
+    buckets.push(("_count".to_string(), total as f64));
+    write_metric(
+        &mut out,
+        "aleo_pool_submit_latency_ms",
+        "histogram",
+        "Time from sending a job to its first submit",
+        buckets,
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn top_entries_get_own_series() {
+        let entries = (1..=5)
+            .map(|i| (format!("aleo{}", i), 1.0, i as f64 * 10.0))
+            .collect::<Vec<_>>();
+        let (speeds, provers) = top_samples("address", entries, 2);
+        assert_eq!(
+            speeds,
+            vec![
+                ("{address=\"aleo5\"}".to_string(), 50.0),
+                ("{address=\"aleo4\"}".to_string(), 40.0),
+                ("{address=\"other\"}".to_string(), 60.0),
+            ]
+        );
+        assert_eq!(provers.last().unwrap(), &("{address=\"other\"}".to_string(), 3.0));
+    }
+
+    #[test]
+    fn fewer_entries_than_top() {
+        let entries = vec![("10.0.0.1".to_string(), 2.0, 5.0)];
+        let (speeds, provers) = top_samples("ip", entries, 3);
+        assert_eq!(
+            speeds,
+            vec![
+                ("{ip=\"10.0.0.1\"}".to_string(), 5.0),
+                ("{ip=\"other\"}".to_string(), 0.0),
+            ]
+        );
+        assert_eq!(provers[0].1, 2.0);
+    }
+
+    /// Sends `request` to a metrics responder and returns the raw response.
+    async fn request_metrics(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let responder = task::spawn(respond(stream, || async {
+            let mut out = String::new();
+            write_metric(
+                &mut out,
+                "aleo_pool_online_provers",
+                "gauge",
+                "Authenticated prover connections",
+                vec![(String::new(), 3.0)],
+            );
+            out
+        }));
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        responder.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn bare_request_gets_the_exposition_format() {
+        let response = request_metrics("GET /metrics HTTP/1.1\r\nHost: pool\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(
+            body,
+            "# HELP aleo_pool_online_provers Authenticated prover connections\n# TYPE aleo_pool_online_provers \
+             gauge\naleo_pool_online_provers 3\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn other_paths_are_not_found() {
+        let response = request_metrics("GET /stats HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+}
@@ -1,93 +1,211 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::{Display, Formatter},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
-        atomic::{AtomicU32, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
         Arc,
     },
     time::{Duration, Instant},
 };
 
-use aleo_stratum::{codec::ResponseParams, message::StratumMessage};
-use anyhow::ensure;
-use blake2::Digest;
+use aleo_stratum::{
+    codec::{CodecStats, ResponseParams},
+    message::StratumMessage,
+};
+use clap::ValueEnum;
 use flurry::HashSet as FlurryHashSet;
 use json_rpc_types::{Error, ErrorCode, Id};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 use snarkos_node_router_messages::{Data, UnconfirmedSolution};
 use snarkvm::{
     console::account::Address,
     prelude::{Environment, PartialSolution, ProverSolution, Testnet3, ToBytes},
     synthesizer::{CoinbasePuzzle, EpochChallenge, PuzzleCommitment, PuzzleConfig, UniversalSRS},
 };
-use snarkvm::circuit::prelude::PrimeField;
 use snarkvm::prelude::CanaryV0;
-use snarkvm_algorithms::{
-    cfg_into_iter,
-    crypto_hash::sha256d_to_u64,
-    fft::DensePolynomial,
-    polycommit::kzg10::{KZGCommitment, KZGProof, KZG10},
-};
-use snarkvm_curves::PairingEngine;
-use snarkvm_utilities::serialize::CanonicalSerialize;
+use snarkvm_algorithms::polycommit::kzg10::{KZGCommitment, KZGProof};
 use speedometer::Speedometer;
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
     sync::{
         mpsc::{channel, Sender},
+        oneshot,
         RwLock,
     },
     task,
+    time::{sleep, timeout},
 };
 use tracing::{debug, error, info, trace, warn};
 
-use crate::{connection::Connection, validator_peer::SnarkOSMessage, AccountingMessage};
+#[cfg(feature = "geoip")]
+use crate::geo::GeoStats;
+use crate::{
+    ban::{AutoBan, AutoBanConfig},
+    coinbase_key,
+    connection::{AuthorizeSlots, Connection, ConnectionConfig, CurrentJob, SessionStore},
+    lifetime::{LifetimeStats, LifetimeStore},
+    listener,
+    listener::ListenerOptions,
+    maintenance::{Maintenance, MaintenanceWindow},
+    submit::{proof_difficulty, verify_solution},
+    upstream::UpstreamSubmit,
+    util,
+    validator_peer::SnarkOSMessage,
+    AccountingMessage,
+};
+
+/// Accepted share value over one epoch, or the part of it the prover was connected for.
+struct EpochBucket {
+    started: Instant,
+    value: u64,
+    duration: Duration,
+}
+
+impl EpochBucket {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            value: 0,
+            duration: Duration::ZERO,
+        }
+    }
+}
 
 struct ProverState {
     peer_addr: SocketAddr,
     address: Address<CanaryV0>,
-    speed_2m: Speedometer,
+    /// Worker name the prover authorized with
+    worker: String,
+    /// Vardiff measures complete epochs only, so a window never straddles a retarget
+    current_epoch: EpochBucket,
+    completed_epochs: VecDeque<EpochBucket>,
     speed_5m: Speedometer,
     speed_15m: Speedometer,
     speed_30m: Speedometer,
     speed_1h: Speedometer,
     current_target: u64,
-    next_target: u64,
+    /// Vardiff stays off until this many shares have been accepted
+    probation_shares: u32,
+    total_accepted: u32,
+    /// Shares credited in the current epoch, checked against the per-epoch cap
+    epoch_credited: u32,
+    /// Accepted shares and low difficulty rejects since the last retarget
+    accepted: u32,
+    low_difficulty_rejects: u32,
 }
 
+/// Minimum number of submits since the last retarget before the reject rate affects the target
+static MIN_RETARGET_SAMPLES: u32 = 10;
+
+/// Number of completed epochs at the same target that vardiff averages over
+static VARDIFF_EPOCHS: usize = 4;
+/// Seconds of a prover's work a share should represent
+static VARDIFF_SHARE_SECONDS: f64 = 20.0;
+
 impl ProverState {
-    pub fn new(peer_addr: SocketAddr, address: Address<CanaryV0>) -> Self {
+    pub fn new(
+        peer_addr: SocketAddr,
+        address: Address<CanaryV0>,
+        worker: String,
+        target: u64,
+        probation_shares: u32,
+    ) -> Self {
         Self {
             peer_addr,
             address,
-            speed_2m: Speedometer::init(Duration::from_secs(120)),
+            worker,
+            current_epoch: EpochBucket::new(),
+            completed_epochs: VecDeque::with_capacity(VARDIFF_EPOCHS),
             speed_5m: Speedometer::init_with_cache(Duration::from_secs(60 * 5), Duration::from_secs(30)),
             speed_15m: Speedometer::init_with_cache(Duration::from_secs(60 * 15), Duration::from_secs(30)),
             speed_30m: Speedometer::init_with_cache(Duration::from_secs(60 * 30), Duration::from_secs(30)),
             speed_1h: Speedometer::init_with_cache(Duration::from_secs(60 * 60), Duration::from_secs(30)),
-            current_target: 512,
-            next_target: 512,
+            current_target: target,
+            probation_shares,
+            total_accepted: 0,
+            epoch_credited: 0,
+            accepted: 0,
+            low_difficulty_rejects: 0,
         }
     }
 
     pub async fn add_share(&mut self, value: u64) {
         let now = Instant::now();
-        self.speed_2m.event(value).await;
+        self.current_epoch.value += value;
         self.speed_5m.event(value).await;
         self.speed_15m.event(value).await;
         self.speed_30m.event(value).await;
         self.speed_1h.event(value).await;
-        self.next_target = ((self.speed_2m.speed().await * 20.0) as u64).max(1);
+        self.accepted += 1;
+        self.total_accepted = self.total_accepted.saturating_add(1);
         debug!("add_share took {} us", now.elapsed().as_micros());
     }
 
-    pub async fn next_target(&mut self) -> u64 {
-        if self.next_target < ((self.current_target as f64) * 0.9) as u64
-            || self.next_target > ((self.current_target as f64) * 1.1) as u64
+    /// Counts a share towards the epoch cap, returns false if the cap is already reached.
+    pub fn count_epoch_share(&mut self, cap: u32) -> bool {
+        if self.epoch_credited >= cap {
+            return false;
+        }
+        self.epoch_credited += 1;
+        true
+    }
+
+    /// Starts counting towards the epoch cap from zero, called at each epoch boundary.
+    pub fn reset_epoch_cap(&mut self) {
+        self.epoch_credited = 0;
+    }
+
+    pub fn record_low_difficulty_reject(&mut self) {
+        self.low_difficulty_rejects += 1;
+    }
+
+    /// Closes the current epoch bucket and returns the vardiff target from the completed ones:
+    /// `VARDIFF_SHARE_SECONDS` worth of the prover's share value.
+    fn vardiff_target(&mut self) -> u64 {
+        let mut epoch = std::mem::replace(&mut self.current_epoch, EpochBucket::new());
+        epoch.duration = epoch.started.elapsed();
+        if self.completed_epochs.len() == VARDIFF_EPOCHS {
+            self.completed_epochs.pop_front();
+        }
+        self.completed_epochs.push_back(epoch);
+        let value: u64 = self.completed_epochs.iter().map(|epoch| epoch.value).sum();
+        let duration: Duration = self.completed_epochs.iter().map(|epoch| epoch.duration).sum();
+        if duration.is_zero() {
+            return 1;
+        }
+        ((value as f64 / duration.as_secs_f64() * VARDIFF_SHARE_SECONDS) as u64).max(1)
+    }
+
+    /// Called at each epoch boundary. Returns the target for the next job and the reason if it
+    /// changed. When low difficulty rejects dominate, the vardiff target is lowered by
+    /// `reject_weight` times the reject rate.
+    pub async fn next_target(&mut self, reject_weight: f64) -> (u64, &'static str) {
+        let mut next_target = self.vardiff_target();
+        if self.total_accepted < self.probation_shares {
+            self.accepted = 0;
+            self.low_difficulty_rejects = 0;
+            return (self.current_target, "probation");
+        }
+        let mut reason = "vardiff";
+        let samples = self.accepted + self.low_difficulty_rejects;
+        if reject_weight > 0.0 && samples >= MIN_RETARGET_SAMPLES && self.low_difficulty_rejects > self.accepted {
+            let reject_rate = self.low_difficulty_rejects as f64 / samples as f64;
+            next_target = ((self.current_target.min(next_target) as f64 * (1.0 - reject_weight * reject_rate).max(0.0))
+                as u64)
+                .max(1);
+            reason = "reject rate";
+        }
+        self.accepted = 0;
+        self.low_difficulty_rejects = 0;
+        if next_target < ((self.current_target as f64) * 0.9) as u64
+            || next_target > ((self.current_target as f64) * 1.1) as u64
         {
-            self.current_target = self.next_target;
+            self.current_target = next_target;
+            // epochs at the old target no longer describe the cadence at the new one
+            self.completed_epochs.clear();
         }
-        self.current_target
+        (self.current_target, reason)
     }
 
     pub fn current_target(&self) -> u64 {
@@ -98,6 +216,19 @@ impl ProverState {
         self.address
     }
 
+    pub fn worker(&self) -> &str {
+        &self.worker
+    }
+
+    pub fn set_address(&mut self, address: Address<CanaryV0>, worker: String) {
+        self.address = address;
+        self.worker = worker;
+    }
+
+    pub async fn share_variation(&self) -> Option<f64> {
+        self.speed_15m.interval_variation().await
+    }
+
     // noinspection DuplicatedCode
     pub async fn speed(&mut self) -> Vec<f64> {
         vec![
@@ -128,18 +259,23 @@ struct PoolState {
     speed_15m: Speedometer,
     speed_30m: Speedometer,
     speed_1h: Speedometer,
+    /// Configurable windows to tell momentary dips from sustained drops
+    speed_short: Speedometer,
+    speed_long: Speedometer,
     current_global_target_modifier: f64,
     next_global_target_modifier: f64,
 }
 
 impl PoolState {
-    pub fn new() -> Self {
+    pub fn new(short_window: Duration, long_window: Duration) -> Self {
         Self {
             speed_1m: Speedometer::init(Duration::from_secs(60)),
             speed_5m: Speedometer::init_with_cache(Duration::from_secs(60 * 5), Duration::from_secs(30)),
             speed_15m: Speedometer::init_with_cache(Duration::from_secs(60 * 15), Duration::from_secs(30)),
             speed_30m: Speedometer::init_with_cache(Duration::from_secs(60 * 30), Duration::from_secs(30)),
             speed_1h: Speedometer::init_with_cache(Duration::from_secs(60 * 60), Duration::from_secs(30)),
+            speed_short: Speedometer::init_with_cache(short_window, short_window / 120),
+            speed_long: Speedometer::init_with_cache(long_window, long_window / 120),
             current_global_target_modifier: 1.0,
             next_global_target_modifier: 1.0,
         }
@@ -152,6 +288,8 @@ impl PoolState {
         self.speed_15m.event(value).await;
         self.speed_30m.event(value).await;
         self.speed_1h.event(value).await;
+        self.speed_short.event(value).await;
+        self.speed_long.event(value).await;
         self.next_global_target_modifier = (self.speed_1m.speed().await / 200.0).max(1f64);
         // todo: make adjustable through admin api
         debug!("pool state add_share took {} us", now.elapsed().as_micros());
@@ -181,23 +319,91 @@ impl PoolState {
             self.speed_1h.speed().await,
         ]
     }
+
+    pub async fn window_speed(&mut self) -> [f64; 2] {
+        [self.speed_short.speed().await, self.speed_long.speed().await]
+    }
+}
+
+/// Pool speeds recomputed in the background, so reading them doesn't touch the pool state.
+#[derive(Default)]
+struct PoolSpeedCache {
+    speed: [AtomicU64; 4],
+    window_speed: [AtomicU64; 2],
+}
+
+impl PoolSpeedCache {
+    /// Recomputes the speeds from `pool_state` every `interval`.
+    fn spawn_refresh(self: Arc<Self>, pool_state: Arc<RwLock<PoolState>>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        task::spawn(async move {
+            loop {
+                ticker.tick().await;
+                let mut state = pool_state.write().await;
+                let speed = state.speed().await;
+                let window_speed = state.window_speed().await;
+                drop(state);
+                for (speed, cached) in speed.iter().zip(&self.speed) {
+                    cached.store(speed.to_bits(), Ordering::Relaxed);
+                }
+                for (speed, cached) in window_speed.iter().zip(&self.window_speed) {
+                    cached.store(speed.to_bits(), Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    fn speed(&self) -> Vec<f64> {
+        self.speed
+            .iter()
+            .map(|speed| f64::from_bits(speed.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Speed over the short and long windows.
+    fn window_speed(&self) -> (f64, f64) {
+        let [short, long] = &self.window_speed;
+        (
+            f64::from_bits(short.load(Ordering::Relaxed)),
+            f64::from_bits(long.load(Ordering::Relaxed)),
+        )
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum ServerMessage {
     ProverConnected(TcpStream, SocketAddr),
-    ProverAuthenticated(SocketAddr, Address<CanaryV0>, Sender<StratumMessage>),
+    /// (peer_addr, address, worker, sender, resumed target, reported hashrate, job sent on subscribe)
+    ProverAuthenticated(
+        SocketAddr,
+        Address<CanaryV0>,
+        String,
+        Sender<StratumMessage>,
+        Option<u64>,
+        Option<f64>,
+        Option<String>,
+    ),
+    /// An authorized prover authorized again for another address
+    /// (peer_addr, address, worker)
+    ProverReauthorized(SocketAddr, Address<CanaryV0>, String),
     ProverDisconnected(SocketAddr),
+    ProtocolViolation(Address<CanaryV0>),
+    /// Delay between a job being sent to a prover and its first submit for it
+    SubmitLatency(Duration),
+    /// (id, peer_addr, job_id, nonce, commitment, proof)
     ProverSubmit(
-        Id,
+        Option<Id>,
         SocketAddr,
-        u32,
+        String,
         u64,
         KZGCommitment<<CanaryV0 as Environment>::PairingCurve>,
         KZGProof<<CanaryV0 as Environment>::PairingCurve>,
     ),
     NewEpochChallenge(EpochChallenge<CanaryV0>, u64),
+    /// (job_id, epoch_challenge, upstream pool address, clean_jobs)
+    UpstreamJob(String, String, String, bool),
+    UpstreamTarget(u64),
     Exit,
 }
 
@@ -206,9 +412,14 @@ impl ServerMessage {
         match self {
             ServerMessage::ProverConnected(..) => "ProverConnected",
             ServerMessage::ProverAuthenticated(..) => "ProverAuthenticated",
+            ServerMessage::ProverReauthorized(..) => "ProverReauthorized",
             ServerMessage::ProverDisconnected(..) => "ProverDisconnected",
+            ServerMessage::ProtocolViolation(..) => "ProtocolViolation",
+            ServerMessage::SubmitLatency(..) => "SubmitLatency",
             ServerMessage::ProverSubmit(..) => "ProverSubmit",
             ServerMessage::NewEpochChallenge(..) => "NewEpochChallenge",
+            ServerMessage::UpstreamJob(..) => "UpstreamJob",
+            ServerMessage::UpstreamTarget(..) => "UpstreamTarget",
             ServerMessage::Exit => "Exit",
         }
     }
@@ -220,6 +431,446 @@ impl Display for ServerMessage {
     }
 }
 
+#[derive(Clone)]
+pub struct DifficultyChange {
+    timestamp: u64,
+    old_target: u64,
+    new_target: u64,
+    reason: &'static str,
+}
+
+/// Appends a change to an address's history, dropping the oldest once `DIFFICULTY_HISTORY_LEN`
+/// are kept.
+fn push_difficulty_change(history: &mut VecDeque<DifficultyChange>, change: DifficultyChange) {
+    if history.len() >= DIFFICULTY_HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(change);
+}
+
+/// Serialized with each target followed by its `human_difficulty`.
+impl Serialize for DifficultyChange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut change = serializer.serialize_struct("DifficultyChange", 6)?;
+        change.serialize_field("timestamp", &self.timestamp)?;
+        change.serialize_field("old_target", &self.old_target)?;
+        change.serialize_field("old_difficulty", &human_difficulty(self.old_target))?;
+        change.serialize_field("new_target", &self.new_target)?;
+        change.serialize_field("new_difficulty", &human_difficulty(self.new_target))?;
+        change.serialize_field("reason", self.reason)?;
+        change.end()
+    }
+}
+
+/// A proof meets a target with a probability of about `1 / target`, so the target is also the
+/// number of proofs expected per share. Rendered with an SI prefix, e.g. `1.50 M`.
+pub fn human_difficulty(target: u64) -> String {
+    const PREFIXES: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+    if target < 1000 {
+        return target.to_string();
+    }
+    let mut value = target as f64;
+    let mut prefix = "";
+    for next in PREFIXES {
+        // anything from 999.995 up would be shown as 1000.00
+        if value < 999.995 {
+            break;
+        }
+        value /= 1000.0;
+        prefix = next;
+    }
+    format!("{:.2} {}", value, prefix)
+}
+
+static DIFFICULTY_HISTORY_LEN: usize = 32;
+
+/// Degree of the coinbase puzzle proofs are verified against
+const COINBASE_PUZZLE_DEGREE: u32 = (1 << 13) - 1;
+
+/// How long before a maintenance window starts it is announced in the log
+static MAINTENANCE_WARNING: Duration = Duration::from_secs(600);
+/// Time provers get to act on the reconnect request when maintenance starts
+static MAINTENANCE_DRAIN_GRACE: Duration = Duration::from_secs(30);
+
+/// Number of recent jobs whose ids are recognized in submits
+static ISSUED_JOBS_LEN: usize = 8;
+
+/// How long a prover waits for the upstream pool to answer a forwarded submit
+static UPSTREAM_SUBMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A job sent to provers; submits are routed by the job they name rather than by the side
+/// currently active, so those sent around a switch still reach the pool that issued the job.
+#[derive(Clone, PartialEq, Eq)]
+enum IssuedJob {
+    /// Local job, its id is the hex of the epoch number
+    Epoch(u32),
+    /// Upstream pool job, its id is passed on as the upstream pool sent it
+    Upstream(String),
+}
+
+impl IssuedJob {
+    fn has_id(&self, job_id: &str) -> bool {
+        match self {
+            IssuedJob::Epoch(epoch_number) => {
+                let mut bytes = [0u8; 4];
+                hex::decode_to_slice(job_id, &mut bytes).is_ok() && u32::from_le_bytes(bytes) == *epoch_number
+            }
+            IssuedJob::Upstream(id) => id == job_id,
+        }
+    }
+}
+
+/// The last `ISSUED_JOBS_LEN` jobs sent to provers, oldest first.
+#[derive(Default)]
+struct IssuedJobs(VecDeque<IssuedJob>);
+
+impl IssuedJobs {
+    fn issue(&mut self, job: IssuedJob) {
+        if self.0.contains(&job) {
+            return;
+        }
+        if self.0.len() >= ISSUED_JOBS_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back(job);
+    }
+
+    /// The job a submit names, `None` if it was never issued or has been evicted since.
+    fn find(&self, job_id: &str) -> Option<IssuedJob> {
+        self.0.iter().rev().find(|job| job.has_id(job_id)).cloned()
+    }
+}
+
+/// How long a disconnected prover can resume its session
+static SESSION_TTL: Duration = Duration::from_secs(600);
+
+/// Upper bounds in milliseconds of the submit latency histogram buckets, the last one is open
+static SUBMIT_LATENCY_BUCKETS: [u64; 7] = [100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Default)]
+struct LatencyHistogram {
+    counts: [AtomicU64; 8],
+}
+
+impl LatencyHistogram {
+    fn record(&self, latency: Duration) {
+        let millis = latency.as_millis() as u64;
+        let bucket = SUBMIT_LATENCY_BUCKETS
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(SUBMIT_LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Vec<(String, u64)> {
+        SUBMIT_LATENCY_BUCKETS
+            .iter()
+            .map(|bound| bound.to_string())
+            .chain(std::iter::once("inf".to_string()))
+            .zip(self.counts.iter().map(|count| count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// How an accepted share is valued for PPLNS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ShareValue {
+    /// The target assigned to the prover.
+    Target,
+    /// The difficulty the proof achieved, capped at twice the network proof target.
+    Difficulty,
+}
+
+impl ShareValue {
+    /// Value credited for a proof of `proof_difficulty` submitted at `prover_target`.
+    fn credit(self, prover_target: u64, proof_difficulty: u64, proof_target: u64) -> u64 {
+        match self {
+            ShareValue::Target => prover_target,
+            ShareValue::Difficulty => proof_difficulty.min(proof_target.saturating_mul(2)),
+        }
+    }
+}
+
+/// Sizes PPLNS `n` so the window covers a timespan of work at the current pool speed.
+///
+/// The speed is taken over the long speed window, so sizing starts once that window has filled
+/// and follows the pool speed with its lag. On top of that `n` moves by at most `max_step` per
+/// minute; at 0.1 it takes about 7 minutes to double or halve.
+#[derive(Clone)]
+pub struct AutoNConfig {
+    pub window: Duration,
+    pub min: u64,
+    pub max: u64,
+    /// Largest relative change of `n` per update
+    pub max_step: f64,
+    /// `n` of the persisted state to continue from, 0 if there is none
+    pub initial: u64,
+}
+
+impl AutoNConfig {
+    /// The `n` to move to from `current` at the given pool speed in share value per second.
+    pub fn next_n(&self, current: u64, speed: f64) -> u64 {
+        let desired = (speed * self.window.as_secs_f64()).clamp(self.min as f64, self.max as f64);
+        if current == 0 {
+            return desired as u64;
+        }
+        let current = current as f64;
+        let step = current * self.max_step;
+        desired
+            .clamp(current - step, current + step)
+            .clamp(self.min as f64, self.max as f64) as u64
+    }
+}
+
+pub struct ServerConfig {
+    pub listener: ListenerOptions,
+    /// Maximum number of connections per IP that may sit between subscribe and authorize
+    pub max_pending_authorize: u32,
+    /// How often the aggregate pool speed served by the API is recomputed
+    pub pool_speed_interval: Duration,
+    /// Windows of the short and long term pool speed
+    pub speed_short_window: Duration,
+    pub speed_long_window: Duration,
+    pub connection: ConnectionConfig,
+    pub auto_ban: AutoBanConfig,
+    /// Verify share proofs locally; when disabled the validator is trusted to reject invalid solutions
+    pub verify_proofs: bool,
+    /// Only honoured as `Difficulty` when proofs are verified
+    pub share_value: ShareValue,
+    /// How strongly a dominating low difficulty reject rate lowers a prover's target, 0 to disable
+    pub reject_difficulty_weight: f64,
+    /// How long the validator may go without a new epoch challenge before provers are switched to
+    /// the upstream pool, if one is configured
+    pub upstream_idle_timeout: Duration,
+    /// Target of new connections, kept until they have submitted `probation_shares` shares
+    pub probation_target: u64,
+    pub probation_shares: u32,
+    /// Highest hashrate in proofs per second a prover may report to seed its target, 0 to ignore
+    /// reported hashrates
+    pub max_reported_hashrate: f64,
+    /// Shares credited per connection and epoch, further ones are acknowledged without credit
+    pub max_epoch_shares: u32,
+    /// Lowest difficulty a share must reach whatever the connection's target, up to the network
+    /// proof target
+    pub min_share_difficulty: u64,
+    /// How long an address without connections keeps its live stats
+    pub address_idle_ttl: Duration,
+    /// Size `n` from the pool speed instead of the network proof target
+    pub auto_n: Option<AutoNConfig>,
+    /// Keep per-address lifetime share counts across restarts
+    pub persist_lifetime_stats: bool,
+}
+
+/// Starting target for a prover expecting `hashrate` proofs per second, unless the hashrate isn't
+/// plausible.
+fn reported_target(hashrate: f64, max_reported_hashrate: f64) -> Option<u64> {
+    if hashrate > 0.0 && hashrate <= max_reported_hashrate {
+        Some(((hashrate * VARDIFF_SHARE_SECONDS) as u64).max(1))
+    } else {
+        None
+    }
+}
+
+/// Records a share's result and answers the submit, unless it came without an id.
+#[allow(clippy::too_many_arguments)]
+async fn send_result(
+    sender: &Sender<StratumMessage>,
+    id: Option<Id>,
+    auto_ban: &AutoBan,
+    lifetime_stats: &LifetimeStore,
+    address: Option<Address<CanaryV0>>,
+    result: bool,
+    error_code: Option<ErrorCode>,
+    desc: Option<String>,
+) {
+    // stale shares are expected around epoch changes and don't count against the prover
+    if let Some(address) = address {
+        lifetime_stats.record(&address, result);
+        if result || error_code.map(|code| code.code()) != Some(21) {
+            auto_ban.record(address, result);
+        }
+    }
+    // id-less submits don't get a response
+    let id = match id {
+        Some(id) => id,
+        None => return,
+    };
+    if result {
+        if let Err(e) = sender
+            .send(StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None))
+            .await
+        {
+            error!("Error sending result to prover: {}", e);
+        }
+    } else if let Err(e) = sender
+        .send(StratumMessage::Response(
+            id,
+            None,
+            Some(Error::with_custom_msg(error_code.unwrap(), desc.unwrap().as_str())),
+        ))
+        .await
+    {
+        error!("Error sending result to prover: {}", e);
+    }
+}
+
+/// The verifier shares are checked with, loaded only if proof verification is enabled. Fails if
+/// it is enabled but the verifier can't be loaded.
+fn verifier<T>(verify_proofs: bool, load: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<Option<T>> {
+    if !verify_proofs {
+        warn!("Proof verification is disabled, shares are only checked against the difficulty target");
+        return Ok(None);
+    }
+    match load() {
+        Ok(verifier) => Ok(Some(verifier)),
+        Err(e) => Err(anyhow::anyhow!(
+            "Proof verification is enabled but the coinbase verifying key could not be loaded: {}",
+            e
+        )),
+    }
+}
+
+/// Extranonces are handed out sequentially so concurrent connections never share one.
+fn next_extranonce(counter: &AtomicU64, len: usize) -> Option<Vec<u8>> {
+    if len == 0 {
+        return None;
+    }
+    let counter = counter.fetch_add(1, Ordering::SeqCst);
+    Some(counter.to_le_bytes()[..len].to_vec())
+}
+
+/// Lowest difficulty a share must reach: the connection's target, raised to the pool minimum but
+/// never above the network proof target, as a solution to the network target always counts.
+fn difficulty_floor(target: u64, min_share_difficulty: u64, proof_target: u64) -> u64 {
+    target.max(min_share_difficulty).min(proof_target)
+}
+
+/// Spreads the reconnects of `count` provers over `grace` so they don't all come back at once.
+fn staggered_wait(grace: Duration, i: usize, count: usize) -> u64 {
+    grace.as_secs() * i as u64 / count as u64
+}
+
+/// Sends every prover a reconnect request with the wait returned for its position and
+/// closes the connections once the grace period has passed.
+async fn reconnect_all(
+    authenticated_provers: &Arc<RwLock<HashMap<SocketAddr, Sender<StratumMessage>>>>,
+    grace: Duration,
+    wait_seconds: impl Fn(usize, usize) -> u64,
+) -> usize {
+    let mut provers = authenticated_provers
+        .read()
+        .await
+        .iter()
+        .map(|(peer_addr, sender)| (*peer_addr, sender.clone()))
+        .collect::<Vec<_>>();
+    provers.sort_unstable_by_key(|(peer_addr, _)| *peer_addr);
+    let count = provers.len();
+    for (i, (peer_addr, sender)) in provers.iter().enumerate() {
+        let wait_seconds = wait_seconds(i, count);
+        if let Err(e) = sender.send(StratumMessage::Reconnect(None, None, wait_seconds)).await {
+            error!("Failed to send reconnect to prover {}: {}", peer_addr, e);
+        }
+    }
+    info!("Draining {} connections over {:?}", count, grace);
+
+    let authenticated_provers = authenticated_provers.clone();
+    task::spawn(async move {
+        sleep(grace).await;
+        let mut authenticated_provers = authenticated_provers.write().await;
+        for (peer_addr, sender) in provers {
+            // only close the connection we asked to reconnect, not a new one from the same address
+            if authenticated_provers
+                .get(&peer_addr)
+                .is_some_and(|current| current.same_channel(&sender))
+            {
+                authenticated_provers.remove(&peer_addr);
+                debug!("Closed drained connection {}", peer_addr);
+            }
+        }
+    });
+    count
+}
+
+/// Asks every prover to come back once the maintenance window is over.
+async fn drain_for_maintenance(
+    authenticated_provers: &Arc<RwLock<HashMap<SocketAddr, Sender<StratumMessage>>>>,
+    window: MaintenanceWindow,
+    now: u64,
+) -> usize {
+    let wait_seconds = window.end.saturating_sub(now);
+    reconnect_all(authenticated_provers, MAINTENANCE_DRAIN_GRACE, |_, _| wait_seconds).await
+}
+
+/// Addresses with live stats that have no connections and haven't been seen for `ttl`.
+/// An address with stats but no sighting, e.g. one left from before a restart, is idle too.
+fn find_idle(
+    ttl: Duration,
+    connections: &HashMap<Address<CanaryV0>, HashSet<SocketAddr>>,
+    last_seen: &HashMap<Address<CanaryV0>, Instant>,
+    difficulty_history: &HashMap<Address<CanaryV0>, VecDeque<DifficultyChange>>,
+    protocol_violations: &HashMap<Address<CanaryV0>, u64>,
+) -> HashSet<Address<CanaryV0>> {
+    last_seen
+        .keys()
+        .chain(difficulty_history.keys())
+        .chain(protocol_violations.keys())
+        .filter(|address| {
+            !connections.contains_key(*address)
+                && last_seen
+                    .get(*address)
+                    .map_or(true, |last_seen| last_seen.elapsed() > ttl)
+        })
+        .copied()
+        .collect()
+}
+
+/// Drops the live stats of idle addresses and returns how many there were.
+fn evict_idle(
+    ttl: Duration,
+    connections: &HashMap<Address<CanaryV0>, HashSet<SocketAddr>>,
+    last_seen: &mut HashMap<Address<CanaryV0>, Instant>,
+    difficulty_history: &mut HashMap<Address<CanaryV0>, VecDeque<DifficultyChange>>,
+    protocol_violations: &mut HashMap<Address<CanaryV0>, u64>,
+) -> usize {
+    let idle = find_idle(ttl, connections, last_seen, difficulty_history, protocol_violations);
+    for address in &idle {
+        last_seen.remove(address);
+        difficulty_history.remove(address);
+        protocol_violations.remove(address);
+    }
+    idle.len()
+}
+
+/// Logs a prover's new target with fields that can be filtered on.
+fn log_difficulty_change(peer_addr: SocketAddr, address: Address<CanaryV0>, worker: &str, change: &DifficultyChange) {
+    info!(
+        peer = %peer_addr,
+        address = %address,
+        worker,
+        target = change.new_target,
+        old_target = change.old_target,
+        reason = change.reason,
+        "Prover difficulty set"
+    );
+}
+
+/// Sends a message to one authenticated connection. Returns false if there is no such connection.
+async fn send_to_connection(
+    authenticated_provers: &RwLock<HashMap<SocketAddr, Sender<StratumMessage>>>,
+    peer_addr: SocketAddr,
+    msg: StratumMessage,
+) -> bool {
+    let sender = match authenticated_provers.read().await.get(&peer_addr) {
+        Some(sender) => sender.clone(),
+        None => return false,
+    };
+    if let Err(e) = sender.send(msg).await {
+        error!("Error sending message to prover {}: {}", peer_addr, e);
+        return false;
+    }
+    true
+}
+
 pub struct Server {
     sender: Sender<ServerMessage>,
     validator_sender: Arc<Sender<SnarkOSMessage>>,
@@ -228,13 +879,58 @@ pub struct Server {
     connected_provers: RwLock<HashSet<SocketAddr>>,
     authenticated_provers: Arc<RwLock<HashMap<SocketAddr, Sender<StratumMessage>>>>,
     pool_state: Arc<RwLock<PoolState>>,
-    prover_states: Arc<RwLock<HashMap<SocketAddr, RwLock<ProverState>>>>,
+    prover_states: Arc<RwLock<HashMap<SocketAddr, Arc<RwLock<ProverState>>>>>,
     prover_address_connections: Arc<RwLock<HashMap<Address<CanaryV0>, HashSet<SocketAddr>>>>,
-    coinbase_puzzle: CoinbasePuzzle<CanaryV0>,
+    coinbase_puzzle: Option<CoinbasePuzzle<CanaryV0>>,
     latest_epoch_number: AtomicU32,
     latest_epoch_challenge: Arc<RwLock<Option<EpochChallenge<CanaryV0>>>>,
     latest_proof_target: AtomicU64,
     nonce_seen: Arc<FlurryHashSet<u64>>,
+    authorize_slots: Arc<AuthorizeSlots>,
+    sessions: Arc<SessionStore>,
+    pool_speed: Arc<PoolSpeedCache>,
+    connection_config: ConnectionConfig,
+    auto_ban: Arc<AutoBan>,
+    lifetime_stats: Arc<LifetimeStore>,
+    /// Protocol violations per address, evicted with the other stats once the address is idle
+    protocol_violations: RwLock<HashMap<Address<CanaryV0>, u64>>,
+    extranonce_counter: AtomicU64,
+    /// Recent difficulty changes per address, evicted once the address is idle
+    difficulty_history: RwLock<HashMap<Address<CanaryV0>, VecDeque<DifficultyChange>>>,
+    /// When each address last connected or disconnected
+    address_last_seen: RwLock<HashMap<Address<CanaryV0>, Instant>>,
+    address_idle_ttl: Duration,
+    evicted_addresses: AtomicU64,
+    probation_target: u64,
+    probation_shares: u32,
+    max_reported_hashrate: f64,
+    max_epoch_shares: u32,
+    min_share_difficulty: u64,
+    uncredited_shares: Arc<AtomicU64>,
+    auto_n: Option<AutoNConfig>,
+    /// Last `n` set by auto sizing, starting from the persisted one
+    current_n: AtomicU64,
+    maintenance: Maintenance,
+    /// Start of the last maintenance window that was announced and started
+    maintenance_warned: AtomicU64,
+    maintenance_started: AtomicU64,
+    reject_difficulty_weight: f64,
+    share_value: ShareValue,
+    upstream_sender: Option<Sender<UpstreamSubmit>>,
+    upstream_idle_timeout: Duration,
+    /// Provers are mining upstream jobs because the validator went idle
+    upstream_active: AtomicBool,
+    /// (job_id, epoch_challenge, upstream pool address)
+    upstream_job: RwLock<Option<(String, String, String)>>,
+    /// Shared with connections so they can send it before authorization
+    current_job: Arc<parking_lot::RwLock<Option<CurrentJob>>>,
+    upstream_target: AtomicU64,
+    last_epoch_challenge: RwLock<Instant>,
+    /// Most recent jobs sent to provers
+    issued_jobs: RwLock<IssuedJobs>,
+    submit_latency: LatencyHistogram,
+    #[cfg(feature = "geoip")]
+    geo_stats: GeoStats,
 }
 
 impl Server {
@@ -243,28 +939,25 @@ impl Server {
         address: Address<CanaryV0>,
         validator_sender: Arc<Sender<SnarkOSMessage>>,
         accounting_sender: Sender<AccountingMessage>,
-    ) -> Arc<Server> {
+        upstream_sender: Option<Sender<UpstreamSubmit>>,
+        config: ServerConfig,
+    ) -> anyhow::Result<Arc<Server>> {
         let (sender, mut receiver) = channel(1024);
 
-        let (_, listener) = match TcpListener::bind(format!("0.0.0.0:{}", port)).await {
-            Ok(listener) => {
-                let local_ip = listener.local_addr().expect("Could not get local ip");
-                info!("Listening on {}", local_ip);
-                (local_ip, listener)
-            }
-            Err(e) => {
-                panic!("Unable to start the server: {:?}", e);
-            }
-        };
+        let listener = listener::bind(([0, 0, 0, 0], port).into(), config.listener)
+            .map_err(|e| anyhow::anyhow!("Unable to start the server: {:?}", e))?;
+        let local_ip = listener.local_addr().expect("Could not get local ip");
+        info!("Listening on {}", local_ip);
 
-        info!("Initializing universal SRS");
-        let srs = UniversalSRS::<CanaryV0>::load().expect("Failed to load SRS");
-        info!("Universal SRS initialized");
+        // an unverified proof could claim any difficulty
+        let share_value = if config.share_value == ShareValue::Difficulty && !config.verify_proofs {
+            warn!("Proof verification is disabled, shares are valued at the prover target");
+            ShareValue::Target
+        } else {
+            config.share_value
+        };
 
-        info!("Initializing coinbase verifying key");
-        let coinbase_puzzle = CoinbasePuzzle::<CanaryV0>::trim(&srs, PuzzleConfig { degree: (1 << 13) - 1 })
-            .expect("Failed to load coinbase verifying key");
-        info!("Coinbase verifying key initialized");
+        let coinbase_puzzle = verifier(config.verify_proofs, Server::load_coinbase_puzzle)?;
 
         let server = Arc::new(Server {
             sender,
@@ -273,7 +966,10 @@ impl Server {
             pool_address: address,
             connected_provers: Default::default(),
             authenticated_provers: Default::default(),
-            pool_state: Arc::new(RwLock::new(PoolState::new())),
+            pool_state: Arc::new(RwLock::new(PoolState::new(
+                config.speed_short_window,
+                config.speed_long_window,
+            ))),
             prover_states: Default::default(),
             prover_address_connections: Default::default(),
             coinbase_puzzle,
@@ -281,6 +977,42 @@ impl Server {
             latest_epoch_challenge: Default::default(),
             latest_proof_target: AtomicU64::new(u64::MAX),
             nonce_seen: Arc::new(FlurryHashSet::with_capacity(10 << 20)),
+            authorize_slots: Arc::new(AuthorizeSlots::new(config.max_pending_authorize)),
+            sessions: Arc::new(SessionStore::new(SESSION_TTL)),
+            pool_speed: Default::default(),
+            connection_config: config.connection.clone(),
+            auto_ban: Arc::new(AutoBan::new(config.auto_ban.clone())),
+            lifetime_stats: Arc::new(LifetimeStore::load(config.persist_lifetime_stats)),
+            protocol_violations: Default::default(),
+            extranonce_counter: AtomicU64::new(rand::random()),
+            difficulty_history: Default::default(),
+            address_last_seen: Default::default(),
+            address_idle_ttl: config.address_idle_ttl,
+            evicted_addresses: AtomicU64::new(0),
+            probation_target: config.probation_target,
+            probation_shares: config.probation_shares,
+            max_reported_hashrate: config.max_reported_hashrate,
+            max_epoch_shares: config.max_epoch_shares,
+            min_share_difficulty: config.min_share_difficulty,
+            uncredited_shares: Default::default(),
+            auto_n: config.auto_n.clone(),
+            current_n: AtomicU64::new(config.auto_n.as_ref().map_or(0, |auto_n| auto_n.initial)),
+            maintenance: Maintenance::load(),
+            maintenance_warned: AtomicU64::new(0),
+            maintenance_started: AtomicU64::new(0),
+            reject_difficulty_weight: config.reject_difficulty_weight,
+            share_value,
+            upstream_sender,
+            upstream_idle_timeout: config.upstream_idle_timeout,
+            upstream_active: AtomicBool::new(false),
+            upstream_job: Default::default(),
+            current_job: Default::default(),
+            upstream_target: AtomicU64::new(512),
+            last_epoch_challenge: RwLock::new(Instant::now()),
+            issued_jobs: Default::default(),
+            submit_latency: Default::default(),
+            #[cfg(feature = "geoip")]
+            geo_stats: GeoStats::init()?,
         });
 
         // clear nonce
@@ -295,6 +1027,93 @@ impl Server {
             });
         }
 
+        // recompute pool speed
+        server
+            .pool_speed
+            .clone()
+            .spawn_refresh(server.pool_state.clone(), config.pool_speed_interval);
+
+        // enforce bans
+        {
+            let server = server.clone();
+            let mut ticker = tokio::time::interval(Duration::from_secs(5));
+            task::spawn(async move {
+                loop {
+                    ticker.tick().await;
+                    server.auto_ban.prune();
+                    for address in server.auto_ban.banned().keys() {
+                        server.disconnect_address(address).await;
+                    }
+                }
+            });
+        }
+
+        // write lifetime stats behind
+        if server.lifetime_stats.persisted() {
+            let server = server.clone();
+            let mut ticker = tokio::time::interval(Duration::from_secs(60));
+            task::spawn(async move {
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = server.lifetime_stats.save().await {
+                        error!("Failed to save lifetime stats: {}", e);
+                    }
+                }
+            });
+        }
+
+        // evict stats of addresses that went away
+        {
+            let server = server.clone();
+            let mut ticker = tokio::time::interval(Duration::from_secs(60));
+            task::spawn(async move {
+                loop {
+                    ticker.tick().await;
+                    let evicted = server.evict_idle_addresses().await;
+                    if evicted > 0 {
+                        debug!("Evicted stats of {} idle addresses", evicted);
+                    }
+                }
+            });
+        }
+
+        // size n from the pool speed, once the long speed window has filled
+        if server.auto_n.is_some() {
+            let server = server.clone();
+            let start = tokio::time::Instant::now() + config.speed_long_window;
+            let mut ticker = tokio::time::interval_at(start, Duration::from_secs(60));
+            task::spawn(async move {
+                loop {
+                    ticker.tick().await;
+                    server.update_auto_n().await;
+                }
+            });
+        }
+
+        // announce and enforce maintenance windows
+        {
+            let server = server.clone();
+            let mut ticker = tokio::time::interval(Duration::from_secs(5));
+            task::spawn(async move {
+                loop {
+                    ticker.tick().await;
+                    server.check_maintenance().await;
+                }
+            });
+        }
+
+        // fail over to the upstream pool
+        if server.upstream_sender.is_some() {
+            let server = server.clone();
+            let mut ticker = tokio::time::interval(Duration::from_secs(5));
+            task::spawn(async move {
+                loop {
+                    ticker.tick().await;
+                    server.check_upstream_failover().await;
+                }
+            });
+        }
+
         let s = server.clone();
         task::spawn(async move {
             loop {
@@ -323,7 +1142,62 @@ impl Server {
             }
         });
 
-        server
+        Ok(server)
+    }
+
+    /// Only the verifying key is needed. The keys are cached in the state directory after they
+    /// have been derived once, the proving key for when only its cache survived.
+    fn load_coinbase_puzzle() -> anyhow::Result<CoinbasePuzzle<CanaryV0>> {
+        match coinbase_key::load_verifying_key(COINBASE_PUZZLE_DEGREE) {
+            Ok(Some(verifying_key)) => {
+                info!("Loaded cached coinbase verifying key");
+                return Ok(CoinbasePuzzle::Verifier(Arc::new(verifying_key)));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to load cached coinbase verifying key: {}", e),
+        }
+        match coinbase_key::load_proving_key(COINBASE_PUZZLE_DEGREE) {
+            Ok(Some(proving_key)) => {
+                info!("Loaded cached coinbase proving key");
+                let coinbase_puzzle = CoinbasePuzzle::Prover(Arc::new(proving_key));
+                if let Err(e) =
+                    coinbase_key::save_verifying_key(COINBASE_PUZZLE_DEGREE, coinbase_puzzle.coinbase_verifying_key())
+                {
+                    warn!("Failed to cache coinbase verifying key: {}", e);
+                }
+                return Ok(coinbase_puzzle);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to load cached coinbase proving key: {}", e),
+        }
+
+        info!("Initializing universal SRS");
+        let srs = UniversalSRS::<CanaryV0>::load()?;
+        info!("Universal SRS initialized");
+
+        info!("Initializing coinbase keys");
+        let coinbase_puzzle = CoinbasePuzzle::<CanaryV0>::trim(
+            &srs,
+            PuzzleConfig {
+                degree: COINBASE_PUZZLE_DEGREE,
+            },
+        )?;
+        info!("Coinbase keys initialized");
+        if let Err(e) =
+            coinbase_key::save_verifying_key(COINBASE_PUZZLE_DEGREE, coinbase_puzzle.coinbase_verifying_key())
+        {
+            warn!("Failed to cache coinbase verifying key: {}", e);
+        }
+        if let CoinbasePuzzle::Prover(proving_key) = &coinbase_puzzle {
+            if let Err(e) = coinbase_key::save_proving_key(COINBASE_PUZZLE_DEGREE, proving_key) {
+                warn!("Failed to cache coinbase proving key: {}", e);
+            }
+        }
+        Ok(coinbase_puzzle)
+    }
+
+    fn next_extranonce(&self) -> Option<Vec<u8>> {
+        next_extranonce(&self.extranonce_counter, self.connection_config.extranonce_len)
     }
 
     fn seen_nonce(nonce_seen: Arc<FlurryHashSet<u64>>, nonce: u64) -> bool {
@@ -342,18 +1216,68 @@ impl Server {
         trace!("Received message: {}", msg);
         match msg {
             ServerMessage::ProverConnected(stream, peer_addr) => {
+                if self.maintenance.active().is_some() {
+                    Connection::refuse(stream, peer_addr, "Pool under maintenance");
+                    return;
+                }
                 self.connected_provers.write().await.insert(peer_addr);
-                Connection::init(stream, peer_addr, self.sender.clone(), self.pool_address).await;
+                #[cfg(feature = "geoip")]
+                self.geo_stats.connected(peer_addr).await;
+                Connection::init(
+                    stream,
+                    peer_addr,
+                    self.sender.clone(),
+                    self.pool_address,
+                    self.authorize_slots.clone(),
+                    self.sessions.clone(),
+                    self.current_job.clone(),
+                    self.connection_config.clone(),
+                    self.next_extranonce(),
+                )
+                .await;
             }
-            ServerMessage::ProverAuthenticated(peer_addr, address, sender) => {
+            ServerMessage::ProverAuthenticated(
+                peer_addr,
+                address,
+                worker,
+                sender,
+                resumed_target,
+                hashrate,
+                sent_job,
+            ) => {
+                if self.auto_ban.is_banned(&address) {
+                    // dropping the sender closes the connection
+                    info!("Refusing banned address {} from {}", address, peer_addr);
+                    return;
+                }
+                let reported_target =
+                    hashrate.and_then(|hashrate| reported_target(hashrate, self.max_reported_hashrate));
+                // resumed sessions have already been through probation
+                let (target, probation_shares, reason) = match (resumed_target, reported_target) {
+                    (Some(target), _) => (target, 0, "resumed"),
+                    (None, Some(target)) => (target, self.probation_shares, "reported hashrate"),
+                    (None, None) => {
+                        if let Some(hashrate) = hashrate {
+                            debug!("Ignoring implausible hashrate {} reported by {}", hashrate, peer_addr);
+                        }
+                        (self.probation_target, self.probation_shares, "initial")
+                    }
+                };
+                // shares below the floor would be rejected, so don't hand out a target below it
+                let target = difficulty_floor(
+                    target,
+                    self.min_share_difficulty,
+                    self.latest_proof_target.load(Ordering::SeqCst),
+                );
                 self.authenticated_provers
                     .write()
                     .await
                     .insert(peer_addr, sender.clone());
-                self.prover_states
-                    .write()
-                    .await
-                    .insert(peer_addr, ProverState::new(peer_addr, address).into());
+                self.prover_states.write().await.insert(
+                    peer_addr,
+                    Arc::new(ProverState::new(peer_addr, address, worker.clone(), target, probation_shares).into()),
+                );
+                self.address_last_seen.write().await.insert(address, Instant::now());
                 let mut pac_write = self.prover_address_connections.write().await;
                 if let Some(address) = pac_write.get_mut(&address) {
                     address.insert(peer_addr);
@@ -361,10 +1285,23 @@ impl Server {
                     pac_write.insert(address, HashSet::from([peer_addr]));
                 }
                 drop(pac_write);
-                if let Err(e) = sender.send(StratumMessage::SetTarget(512)).await {
+                self.record_difficulty_change(peer_addr, address, &worker, 0, target, reason)
+                    .await;
+                if let Err(e) = sender.send(StratumMessage::SetTarget(target)).await {
                     error!("Error sending initial target to prover: {}", e);
                 }
-                if let Some(epoch_challenge) = self.latest_epoch_challenge.read().await.as_ref() {
+                // the prover already works on the job it got on subscribe unless a newer one came since
+                let current_job_id = self.current_job.read().as_ref().map(|job| job.job_id.clone());
+                if sent_job.is_some() && sent_job == current_job_id {
+                    if self.upstream_active.load(Ordering::SeqCst) {
+                        let target = self.upstream_target.load(Ordering::SeqCst);
+                        if let Err(e) = sender.send(StratumMessage::SetTarget(target)).await {
+                            error!("Error sending upstream target to prover {}: {}", peer_addr, e);
+                        }
+                    }
+                } else if self.upstream_active.load(Ordering::SeqCst) {
+                    self.send_upstream_job(&peer_addr, &sender, true).await;
+                } else if let Some(epoch_challenge) = self.latest_epoch_challenge.read().await.as_ref() {
                     let job_id = hex::encode(self.latest_epoch_number.load(Ordering::SeqCst).to_le_bytes());
                     if let Err(e) = sender
                         .send(StratumMessage::Notify(
@@ -388,6 +1325,9 @@ impl Server {
                     Some(state) => Some(state.read().await.address()),
                     None => None,
                 };
+                if let Some(address) = address {
+                    self.address_last_seen.write().await.insert(address, Instant::now());
+                }
                 if address.is_some() {
                     let mut pac_write = self.prover_address_connections.write().await;
                     let pac = pac_write.get_mut(&address.unwrap());
@@ -400,37 +1340,92 @@ impl Server {
                 }
                 self.connected_provers.write().await.remove(&peer_addr);
                 self.authenticated_provers.write().await.remove(&peer_addr);
+                #[cfg(feature = "geoip")]
+                self.geo_stats.disconnected(peer_addr).await;
             }
-            ServerMessage::NewEpochChallenge(epoch_challenge, proof_target) => {
-                let latest_epoch = self.latest_epoch_number.load(Ordering::SeqCst);
-                if latest_epoch < epoch_challenge.epoch_number()
-                    || (epoch_challenge.epoch_number() == 0 && latest_epoch == 0)
-                {
-                    info!("New epoch challenge: {}", epoch_challenge.epoch_number());
-                    self.latest_epoch_number
-                        .store(epoch_challenge.epoch_number(), Ordering::SeqCst);
-                    self.latest_epoch_challenge
-                        .write()
+            ServerMessage::ProverReauthorized(peer_addr, address, worker) => {
+                if self.auto_ban.is_banned(&address) {
+                    info!("Disconnecting {} switching to banned address {}", peer_addr, address);
+                    self.authenticated_provers.write().await.remove(&peer_addr);
+                    return;
+                }
+                // the connection keeps its difficulty, stats and epoch cap, only the address changes
+                let old_address = match self.prover_states.read().await.get(&peer_addr) {
+                    Some(state) => {
+                        let mut state = state.write().await;
+                        let old_address = state.address();
+                        state.set_address(address, worker);
+                        old_address
+                    }
+                    None => return,
+                };
+                if old_address == address {
+                    return;
+                }
+                let now = Instant::now();
+                let mut address_last_seen = self.address_last_seen.write().await;
+                address_last_seen.insert(old_address, now);
+                address_last_seen.insert(address, now);
+                drop(address_last_seen);
+                let mut pac_write = self.prover_address_connections.write().await;
+                if let Some(peer_addrs) = pac_write.get_mut(&old_address) {
+                    peer_addrs.remove(&peer_addr);
+                    if peer_addrs.is_empty() {
+                        pac_write.remove(&old_address);
+                    }
+                }
+                pac_write.entry(address).or_default().insert(peer_addr);
+                info!("Peer {} switched from {} to {}", peer_addr, old_address, address);
+            }
+            ServerMessage::ProtocolViolation(address) => {
+                *self.protocol_violations.write().await.entry(address).or_insert(0) += 1;
+            }
+            ServerMessage::SubmitLatency(latency) => {
+                self.submit_latency.record(latency);
+            }
+            ServerMessage::NewEpochChallenge(epoch_challenge, proof_target) => {
+                *self.last_epoch_challenge.write().await = Instant::now();
+                let leaving_upstream = self.upstream_active.swap(false, Ordering::SeqCst);
+                if leaving_upstream {
+                    info!("Validator is delivering work again, switching provers back from the upstream pool");
+                }
+                let latest_epoch = self.latest_epoch_number.load(Ordering::SeqCst);
+                if latest_epoch < epoch_challenge.epoch_number()
+                    || (epoch_challenge.epoch_number() == 0 && latest_epoch == 0)
+                {
+                    info!("New epoch challenge: {}", epoch_challenge.epoch_number());
+                    self.latest_epoch_number
+                        .store(epoch_challenge.epoch_number(), Ordering::SeqCst);
+                    self.latest_epoch_challenge
+                        .write()
                         .await
                         .replace(epoch_challenge.clone());
                     self.clear_nonce();
+                    self.issue_job(IssuedJob::Epoch(epoch_challenge.epoch_number())).await;
                 }
                 if epoch_challenge.epoch_number() < latest_epoch {
                     return;
                 }
                 info!("Updating target to {}", proof_target);
                 self.latest_proof_target.store(proof_target, Ordering::SeqCst);
-                if let Err(e) = self
-                    .accounting_sender
-                    .send(AccountingMessage::SetN(proof_target * 5))
-                    .await
-                {
-                    error!("Error sending accounting message: {}", e);
+                if self.auto_n.is_none() {
+                    if let Err(e) = self
+                        .accounting_sender
+                        .send(AccountingMessage::SetN(proof_target * 5))
+                        .await
+                    {
+                        error!("Error sending accounting message: {}", e);
+                    }
                 }
                 let global_difficulty_modifier = self.pool_state.write().await.next_global_target_modifier().await;
                 debug!("Global difficulty modifier: {}", global_difficulty_modifier);
                 let job_id = hex::encode(epoch_challenge.epoch_number().to_le_bytes());
                 let epoch_challenge_hex = hex::encode(epoch_challenge.to_bytes_le().unwrap());
+                self.current_job.write().replace(CurrentJob {
+                    job_id: job_id.clone(),
+                    epoch_challenge: epoch_challenge_hex.clone(),
+                    address: None,
+                });
                 for (peer_addr, sender) in self.authenticated_provers.read().await.clone().iter() {
                     let states = self.prover_states.read().await;
                     let prover_state = match states.get(peer_addr) {
@@ -442,14 +1437,36 @@ impl Server {
                     };
 
                     let prover_display = format!("{}", prover_state.read().await);
+                    let address = prover_state.read().await.address();
+                    let worker = prover_state.read().await.worker().to_string();
                     let current_difficulty = prover_state.read().await.current_target();
-                    let mut next_difficulty =
-                        (prover_state.write().await.next_target().await as f64 * global_difficulty_modifier) as u64;
+                    prover_state.write().await.reset_epoch_cap();
+                    let (next_target, mut reason) = prover_state
+                        .write()
+                        .await
+                        .next_target(self.reject_difficulty_weight)
+                        .await;
+                    let mut next_difficulty = (next_target as f64 * global_difficulty_modifier) as u64;
                     drop(states);
+                    if next_difficulty < self.min_share_difficulty {
+                        next_difficulty = self.min_share_difficulty;
+                        reason = "raised to minimum share difficulty";
+                    }
                     if next_difficulty > proof_target {
                         next_difficulty = proof_target;
+                        reason = "capped to proof target";
                     }
-                    if current_difficulty != next_difficulty {
+                    // provers were on the upstream pool's target
+                    if current_difficulty != next_difficulty || leaving_upstream {
+                        self.record_difficulty_change(
+                            *peer_addr,
+                            address,
+                            &worker,
+                            current_difficulty,
+                            next_difficulty,
+                            reason,
+                        )
+                        .await;
                         if let Err(e) = sender.send(StratumMessage::SetTarget(next_difficulty)).await {
                             error!("Error sending difficulty target to prover {}: {}", prover_display, e);
                         }
@@ -467,7 +1484,29 @@ impl Server {
                     }
                 }
             }
-            ServerMessage::ProverSubmit(id, peer_addr, epoch_number, nonce, commitment, proof) => {
+            ServerMessage::UpstreamJob(job_id, epoch_challenge, address, clean_jobs) => {
+                *self.upstream_job.write().await = Some((job_id, epoch_challenge, address));
+                if self.upstream_active.load(Ordering::SeqCst) {
+                    self.use_upstream_job().await;
+                    for (peer_addr, sender) in self.authenticated_provers.read().await.iter() {
+                        self.send_upstream_job(peer_addr, sender, clean_jobs).await;
+                    }
+                }
+            }
+            ServerMessage::UpstreamTarget(target) => {
+                self.upstream_target.store(target, Ordering::SeqCst);
+            }
+            ServerMessage::ProverSubmit(id, peer_addr, job_id, nonce, commitment, proof) => {
+                let job = self.issued_jobs.read().await.find(&job_id);
+                let epoch_number = match job {
+                    Some(IssuedJob::Upstream(_)) => {
+                        self.forward_submit(id, peer_addr, job_id, nonce, commitment, proof)
+                            .await;
+                        return;
+                    }
+                    Some(IssuedJob::Epoch(epoch_number)) => Some(epoch_number),
+                    None => None,
+                };
                 let prover_states = self.prover_states.clone();
                 let pool_state = self.pool_state.clone();
                 let authenticated_provers = self.authenticated_provers.clone();
@@ -480,32 +1519,13 @@ impl Server {
                 let global_proof_target = self.latest_proof_target.load(Ordering::SeqCst);
                 let pool_address = self.pool_address;
                 let coinbase_puzzle = self.coinbase_puzzle.clone();
+                let auto_ban = self.auto_ban.clone();
+                let lifetime_stats = self.lifetime_stats.clone();
+                let share_value = self.share_value;
+                let max_epoch_shares = self.max_epoch_shares;
+                let min_share_difficulty = self.min_share_difficulty;
+                let uncredited_shares = self.uncredited_shares.clone();
                 task::spawn(async move {
-                    async fn send_result(
-                        sender: &Sender<StratumMessage>,
-                        id: Id,
-                        result: bool,
-                        error_code: Option<ErrorCode>,
-                        desc: Option<String>,
-                    ) {
-                        if result {
-                            if let Err(e) = sender
-                                .send(StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None))
-                                .await
-                            {
-                                error!("Error sending result to prover: {}", e);
-                            }
-                        } else if let Err(e) = sender
-                            .send(StratumMessage::Response(
-                                id,
-                                None,
-                                Some(Error::with_custom_msg(error_code.unwrap(), desc.unwrap().as_str())),
-                            ))
-                            .await
-                        {
-                            error!("Error sending result to prover: {}", e);
-                        }
-                    }
                     let provers = authenticated_provers.read().await;
                     let states = prover_states.read().await;
                     let sender = match provers.get(&peer_addr) {
@@ -522,6 +1542,9 @@ impl Server {
                             send_result(
                                 sender,
                                 id,
+                                &auto_ban,
+                                &lifetime_stats,
+                                None,
                                 false,
                                 Some(ErrorCode::from_code(24)),
                                 Some("Unknown prover".to_string()),
@@ -531,6 +1554,7 @@ impl Server {
                         }
                     };
                     let prover_display = format!("{}", prover_state.read().await);
+                    let address = prover_state.read().await.address();
                     let epoch_challenge = match latest_epoch_challenge.read().await.clone() {
                         Some(template) => template,
                         None => {
@@ -541,6 +1565,9 @@ impl Server {
                             send_result(
                                 sender,
                                 id,
+                                &auto_ban,
+                                &lifetime_stats,
+                                Some(address),
                                 false,
                                 Some(ErrorCode::from_code(21)),
                                 Some("No epoch challenge".to_string()),
@@ -549,6 +1576,27 @@ impl Server {
                             return;
                         }
                     };
+                    let epoch_number = match epoch_number {
+                        Some(epoch_number) => epoch_number,
+                        None => {
+                            warn!(
+                                "Received solution from prover {} for job {} that was never issued",
+                                prover_display, job_id
+                            );
+                            send_result(
+                                sender,
+                                id,
+                                &auto_ban,
+                                &lifetime_stats,
+                                Some(address),
+                                false,
+                                Some(ErrorCode::from_code(20)),
+                                Some("Invalid job".to_string()),
+                            )
+                            .await;
+                            return;
+                        }
+                    };
                     if epoch_number != latest_epoch_number {
                         info!(
                             "Received stale solution from prover {} with epoch number: {} (expected {})",
@@ -557,6 +1605,9 @@ impl Server {
                         send_result(
                             sender,
                             id,
+                            &auto_ban,
+                            &lifetime_stats,
+                            Some(address),
                             false,
                             Some(ErrorCode::from_code(21)),
                             Some("Stale solution".to_string()),
@@ -569,6 +1620,9 @@ impl Server {
                         send_result(
                             sender,
                             id,
+                            &auto_ban,
+                            &lifetime_stats,
+                            Some(address),
                             false,
                             Some(ErrorCode::from_code(22)),
                             Some("Duplicate nonce".to_string()),
@@ -581,13 +1635,16 @@ impl Server {
                     if prover_target > global_proof_target {
                         prover_target = global_proof_target;
                     }
-                    let proof_difficulty = match &commitment.to_bytes_le() {
-                        Ok(bytes) => u64::MAX / sha256d_to_u64(bytes),
+                    let proof_difficulty = match proof_difficulty(&commitment) {
+                        Ok(difficulty) => difficulty,
                         Err(e) => {
                             warn!("Received invalid solution from prover {}: {}", prover_display, e);
                             send_result(
                                 sender,
                                 id,
+                                &auto_ban,
+                                &lifetime_stats,
+                                Some(address),
                                 false,
                                 Some(ErrorCode::from_code(23)),
                                 Some("Invalid solution".to_string()),
@@ -596,14 +1653,19 @@ impl Server {
                             return;
                         }
                     };
-                    if proof_difficulty < prover_target {
+                    let difficulty_floor = difficulty_floor(prover_target, min_share_difficulty, global_proof_target);
+                    if proof_difficulty < difficulty_floor {
+                        prover_state.write().await.record_low_difficulty_reject();
                         warn!(
                             "Received solution with difficulty {} from prover {} (expected {})",
-                            proof_difficulty, prover_display, prover_target
+                            proof_difficulty, prover_display, difficulty_floor
                         );
                         send_result(
                             sender,
                             id,
+                            &auto_ban,
+                            &lifetime_stats,
+                            Some(address),
                             false,
                             Some(ErrorCode::from_code(23)),
                             Some("Difficulty target not met".to_string()),
@@ -611,78 +1673,53 @@ impl Server {
                         .await;
                         return;
                     }
-                    debug!("Verifying solution from prover {}", prover_display);
-                    let polynomial = match prover_polynomial(&epoch_challenge, pool_address, nonce) {
-                        Ok(polynomial) => polynomial,
-                        Err(e) => {
-                            warn!(
-                                "Failed to construct prover polynomial from prover {}: {}",
-                                prover_display, e
-                            );
+                    if let Some(coinbase_puzzle) = coinbase_puzzle {
+                        debug!("Verifying solution from prover {}", prover_display);
+                        if let Err(e) = verify_solution(
+                            &coinbase_puzzle,
+                            &epoch_challenge,
+                            pool_address,
+                            nonce,
+                            &commitment,
+                            &proof,
+                        ) {
+                            warn!("Failed to verify solution from prover {}: {}", prover_display, e);
                             send_result(
                                 sender,
                                 id,
+                                &auto_ban,
+                                &lifetime_stats,
+                                Some(address),
                                 false,
                                 Some(ErrorCode::from_code(20)),
-                                Some("Invalid polynomial".to_string()),
-                            )
-                            .await;
-                            return;
-                        }
-                    };
-                    let point = match hash_commitment(&commitment) {
-                        Ok(point) => point,
-                        Err(e) => {
-                            warn!("Failed to hash commitment from prover {}: {}", prover_display, e);
-                            send_result(
-                                sender,
-                                id,
-                                false,
-                                Some(ErrorCode::from_code(20)),
-                                Some("Invalid commitment".to_string()),
-                            )
-                            .await;
-                            return;
-                        }
-                    };
-                    let product_eval_at_point =
-                        polynomial.evaluate(point) * epoch_challenge.epoch_polynomial().evaluate(point);
-                    match KZG10::check(
-                        coinbase_puzzle.coinbase_verifying_key(),
-                        &commitment,
-                        point,
-                        product_eval_at_point,
-                        &proof,
-                    ) {
-                        Ok(true) => {
-                            debug!("Verified proof from prover {}", prover_display);
-                        }
-                        _ => {
-                            warn!("Failed to verify proof from prover {}", prover_display);
-                            send_result(
-                                sender,
-                                id,
-                                false,
-                                Some(ErrorCode::from_code(20)),
-                                Some("Invalid proof".to_string()),
+                                Some(e.message().to_string()),
                             )
                             .await;
                             return;
                         }
+                        debug!("Verified proof from prover {}", prover_display);
                     }
 
+                    // past the cap shares still count towards speed and vardiff and solutions are still
+                    // submitted, they are just not credited
                     prover_state.write().await.add_share(prover_target).await;
                     pool_state.write().await.add_share(prover_target).await;
-                    if let Err(e) = accounting_sender
-                        .send(AccountingMessage::NewShare(
-                            prover_state.read().await.address().to_string(),
-                            proof_difficulty.min(global_proof_target * 2),
-                        ))
-                        .await
-                    {
-                        error!("Failed to send accounting message: {}", e);
+                    if prover_state.write().await.count_epoch_share(max_epoch_shares) {
+                        let value = share_value.credit(prover_target, proof_difficulty, global_proof_target);
+                        if let Err(e) = accounting_sender
+                            .send(AccountingMessage::NewShare(
+                                prover_state.read().await.address().to_string(),
+                                value,
+                            ))
+                            .await
+                        {
+                            error!("Failed to send accounting message: {}", e);
+                        }
+                    } else {
+                        uncredited_shares.fetch_add(1, Ordering::Relaxed);
+                        debug!("Not crediting share from prover {} over the epoch cap", prover_display);
                     }
-                    send_result(sender, id, true, None, None).await;
+                    send_result(sender, id, &auto_ban, &lifetime_stats, Some(address), true, None, None).await;
                     drop(provers);
                     drop(states);
                     debug!(
@@ -710,7 +1747,10 @@ impl Server {
                         }
                         if let Err(e) = {
                             accounting_sender
-                                .send(AccountingMessage::NewSolution(PuzzleCommitment::new(commitment)))
+                                .send(AccountingMessage::NewSolution(
+                                    PuzzleCommitment::new(commitment),
+                                    address.to_string(),
+                                ))
                                 .await
                         } {
                             error!("Failed to send accounting message: {}", e);
@@ -718,7 +1758,11 @@ impl Server {
                     }
                 });
             }
-            ServerMessage::Exit => {}
+            ServerMessage::Exit => {
+                if let Err(e) = self.lifetime_stats.save().await {
+                    error!("Failed to save lifetime stats: {}", e);
+                }
+            }
         }
     }
 
@@ -726,12 +1770,346 @@ impl Server {
         self.authenticated_provers.read().await.len() as u32
     }
 
+    /// Number of messages queued on outbound prover channels across all connections.
+    pub async fn outbound_queue_depth(&self) -> usize {
+        self.authenticated_provers
+            .read()
+            .await
+            .values()
+            .map(util::channel_depth)
+            .sum()
+    }
+
     pub async fn online_addresses(&self) -> u32 {
         self.prover_address_connections.read().await.len() as u32
     }
 
-    pub async fn pool_speed(&self) -> Vec<f64> {
-        self.pool_state.write().await.speed().await
+    /// Addresses without connections that haven't been seen for the idle TTL.
+    pub async fn idle_addresses(&self) -> Vec<Address<CanaryV0>> {
+        let connections = self.prover_address_connections.read().await;
+        let last_seen = self.address_last_seen.read().await;
+        let difficulty_history = self.difficulty_history.read().await;
+        let protocol_violations = self.protocol_violations.read().await;
+        find_idle(
+            self.address_idle_ttl,
+            &connections,
+            &last_seen,
+            &difficulty_history,
+            &protocol_violations,
+        )
+        .into_iter()
+        .collect()
+    }
+
+    /// Drops the live stats of idle addresses. Their shares stay with accounting.
+    pub async fn evict_idle_addresses(&self) -> usize {
+        // decided under the same locks the stats are dropped under, so an address that reconnects
+        // in between is kept
+        let connections = self.prover_address_connections.read().await;
+        let mut last_seen = self.address_last_seen.write().await;
+        let mut difficulty_history = self.difficulty_history.write().await;
+        let mut protocol_violations = self.protocol_violations.write().await;
+        let evicted = evict_idle(
+            self.address_idle_ttl,
+            &connections,
+            &mut last_seen,
+            &mut difficulty_history,
+            &mut protocol_violations,
+        );
+        self.evicted_addresses.fetch_add(evicted as u64, Ordering::Relaxed);
+        evicted
+    }
+
+    /// Decode buffer figures of the prover connections.
+    pub fn codec_stats(&self) -> &CodecStats {
+        &self.connection_config.codec_stats
+    }
+
+    /// Accepted shares that weren't credited because their connection hit the epoch cap.
+    pub fn uncredited_shares(&self) -> u64 {
+        self.uncredited_shares.load(Ordering::Relaxed)
+    }
+
+    /// Total number of addresses evicted by `evict_idle_addresses`.
+    pub fn evicted_addresses(&self) -> u64 {
+        self.evicted_addresses.load(Ordering::Relaxed)
+    }
+
+    /// Count of first submits per job by latency bucket, keyed by the bucket's upper bound in ms.
+    pub fn submit_latency(&self) -> Vec<(String, u64)> {
+        self.submit_latency.snapshot()
+    }
+
+    /// Pool speed over the short and long windows.
+    pub fn pool_window_speed(&self) -> (f64, f64) {
+        self.pool_speed.window_speed()
+    }
+
+    /// Moves `n` towards the pool's work over the configured window.
+    async fn update_auto_n(&self) {
+        let auto_n = match &self.auto_n {
+            Some(auto_n) => auto_n,
+            None => return,
+        };
+        let (_, speed) = self.pool_window_speed();
+        if speed <= 0.0 {
+            // nothing to size from, keep the current n rather than shrinking it to the minimum
+            return;
+        }
+        let current = self.current_n.load(Ordering::Relaxed);
+        let n = auto_n.next_n(current, speed);
+        if n == current {
+            return;
+        }
+        debug!("Auto sizing n from {} to {} at pool speed {}", current, n, speed);
+        if let Err(e) = self.accounting_sender.send(AccountingMessage::SetN(n)).await {
+            error!("Error sending accounting message: {}", e);
+            return;
+        }
+        self.current_n.store(n, Ordering::Relaxed);
+    }
+
+    pub fn pool_speed(&self) -> Vec<f64> {
+        self.pool_speed.speed()
+    }
+
+    async fn record_difficulty_change(
+        &self,
+        peer_addr: SocketAddr,
+        address: Address<CanaryV0>,
+        worker: &str,
+        old_target: u64,
+        new_target: u64,
+        reason: &'static str,
+    ) {
+        let change = DifficultyChange {
+            timestamp: util::now(),
+            old_target,
+            new_target,
+            reason,
+        };
+        log_difficulty_change(peer_addr, address, worker, &change);
+        let mut history = self.difficulty_history.write().await;
+        push_difficulty_change(history.entry(address).or_default(), change);
+    }
+
+    pub async fn address_difficulty_history(&self, address: Address<CanaryV0>) -> Vec<DifficultyChange> {
+        self.difficulty_history
+            .read()
+            .await
+            .get(&address)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Closes all connections of the address by dropping their senders.
+    pub async fn disconnect_address(&self, address: &Address<CanaryV0>) {
+        let peer_addrs = match self.prover_address_connections.read().await.get(address) {
+            Some(peer_addrs) => peer_addrs.clone(),
+            None => return,
+        };
+        let mut authenticated_provers = self.authenticated_provers.write().await;
+        for peer_addr in peer_addrs {
+            if authenticated_provers.remove(&peer_addr).is_some() {
+                info!("Disconnecting {} ({})", peer_addr, address);
+            }
+        }
+    }
+
+    /// Sends a message to a single connection. Returns false if there is no such connection.
+    pub async fn send_to_connection(&self, peer_addr: SocketAddr, msg: StratumMessage) -> bool {
+        send_to_connection(&self.authenticated_provers, peer_addr, msg).await
+    }
+
+    async fn check_upstream_failover(&self) {
+        if self.upstream_active.load(Ordering::SeqCst)
+            || self.last_epoch_challenge.read().await.elapsed() < self.upstream_idle_timeout
+            || self.upstream_job.read().await.is_none()
+        {
+            return;
+        }
+        warn!(
+            "No epoch challenge from the validator for {:?}, switching provers to the upstream pool",
+            self.upstream_idle_timeout
+        );
+        self.upstream_active.store(true, Ordering::SeqCst);
+        self.use_upstream_job().await;
+        for (peer_addr, sender) in self.authenticated_provers.read().await.iter() {
+            self.send_upstream_job(peer_addr, sender, true).await;
+        }
+    }
+
+    /// Makes the upstream job the one new connections start on.
+    async fn use_upstream_job(&self) {
+        if let Some((job_id, epoch_challenge, address)) = self.upstream_job.read().await.clone() {
+            self.issue_job(IssuedJob::Upstream(job_id.clone())).await;
+            self.current_job.write().replace(CurrentJob {
+                job_id,
+                epoch_challenge,
+                address: Some(address),
+            });
+        }
+    }
+
+    async fn send_upstream_job(&self, peer_addr: &SocketAddr, sender: &Sender<StratumMessage>, clean_jobs: bool) {
+        let (job_id, epoch_challenge, address) = match self.upstream_job.read().await.clone() {
+            Some(job) => job,
+            None => return,
+        };
+        let target = self.upstream_target.load(Ordering::SeqCst);
+        if let Err(e) = sender.send(StratumMessage::SetTarget(target)).await {
+            error!("Error sending upstream target to prover {}: {}", peer_addr, e);
+        }
+        if let Err(e) = sender
+            .send(StratumMessage::Notify(
+                job_id,
+                epoch_challenge,
+                Some(address),
+                clean_jobs,
+            ))
+            .await
+        {
+            error!("Error sending upstream job to prover {}: {}", peer_addr, e);
+        }
+    }
+
+    /// Remembers a job sent to provers so submits for it are recognized.
+    async fn issue_job(&self, job: IssuedJob) {
+        self.issued_jobs.write().await.issue(job);
+    }
+
+    /// Passes a submit for an upstream job on to the upstream pool and answers the prover with its
+    /// result. These shares are not credited.
+    async fn forward_submit(
+        &self,
+        id: Option<Id>,
+        peer_addr: SocketAddr,
+        job_id: String,
+        nonce: u64,
+        commitment: KZGCommitment<<CanaryV0 as Environment>::PairingCurve>,
+        proof: KZGProof<<CanaryV0 as Environment>::PairingCurve>,
+    ) {
+        let sender = match self.authenticated_provers.read().await.get(&peer_addr) {
+            Some(sender) => sender.clone(),
+            None => {
+                error!("Sender not found for peer: {}", peer_addr);
+                return;
+            }
+        };
+        let upstream_sender = self.upstream_sender.clone();
+        task::spawn(async move {
+            let (result_sender, result_receiver) = oneshot::channel();
+            let submit = match (commitment.to_bytes_le(), proof.to_bytes_le()) {
+                (Ok(commitment), Ok(proof)) => UpstreamSubmit {
+                    job_id,
+                    nonce: hex::encode(nonce.to_le_bytes()),
+                    commitment: hex::encode(commitment),
+                    proof: hex::encode(proof),
+                    result: result_sender,
+                },
+                _ => {
+                    warn!("Failed to serialize submit from prover {} for upstream", peer_addr);
+                    return;
+                }
+            };
+            let forwarded = match &upstream_sender {
+                Some(upstream_sender) => upstream_sender.send(submit).await.is_ok(),
+                None => false,
+            };
+            let result = match forwarded {
+                true => match timeout(UPSTREAM_SUBMIT_TIMEOUT, result_receiver).await {
+                    Ok(Ok(result)) => result,
+                    _ => Err(Error::with_custom_msg(ErrorCode::from_code(20), "Upstream unavailable")),
+                },
+                false => Err(Error::with_custom_msg(ErrorCode::from_code(20), "Upstream unavailable")),
+            };
+            debug!("Upstream answered submit from prover {}: {:?}", peer_addr, result);
+            let id = match id {
+                Some(id) => id,
+                None => return,
+            };
+            let response = match result {
+                Ok(()) => StratumMessage::Response(id, Some(ResponseParams::Bool(true)), None),
+                Err(error) => StratumMessage::Response(id, None, Some(error)),
+            };
+            if let Err(e) = sender.send(response).await {
+                error!("Error sending result to prover: {}", e);
+            }
+        });
+    }
+
+    /// Asks every connection to reconnect, with wait hints spread over the grace period to avoid a
+    /// reconnect storm, and closes the remaining ones once it has passed.
+    pub async fn drain_and_reconnect(&self, grace: Duration) -> usize {
+        reconnect_all(&self.authenticated_provers, grace, |i, count| {
+            staggered_wait(grace, i, count)
+        })
+        .await
+    }
+
+    pub fn maintenance_window(&self) -> Option<MaintenanceWindow> {
+        self.maintenance.window()
+    }
+
+    pub fn schedule_maintenance(&self, window: Option<MaintenanceWindow>) -> anyhow::Result<()> {
+        self.maintenance.schedule(window)
+    }
+
+    /// Warns ahead of a maintenance window, sends provers away when it starts and clears it once
+    /// it is over.
+    async fn check_maintenance(&self) {
+        let window = match self.maintenance.window() {
+            Some(window) => window,
+            None => return,
+        };
+        let now = util::now();
+        if now >= window.end {
+            info!("Maintenance window is over, accepting provers again");
+            if let Err(e) = self.maintenance.schedule(None) {
+                error!("Failed to clear the maintenance schedule: {}", e);
+            }
+            return;
+        }
+        if now < window.start {
+            let remaining = window.start - now;
+            if remaining <= MAINTENANCE_WARNING.as_secs()
+                && self.maintenance_warned.swap(window.start, Ordering::SeqCst) != window.start
+            {
+                warn!("Maintenance starts in {} seconds", remaining);
+                let message = format!(
+                    "Pool maintenance from {} to {} (unix time), connections are closed in {} seconds",
+                    window.start, window.end, remaining
+                );
+                let provers = self.authenticated_provers.read().await.clone();
+                for (peer_addr, sender) in provers {
+                    if let Err(e) = sender.send(StratumMessage::ShowMessage(message.clone())).await {
+                        error!("Failed to announce maintenance to prover {}: {}", peer_addr, e);
+                    }
+                }
+            }
+            return;
+        }
+        if self.maintenance_started.swap(window.start, Ordering::SeqCst) != window.start {
+            info!("Maintenance started, sending provers away until {}", window.end);
+            drain_for_maintenance(&self.authenticated_provers, window, now).await;
+        }
+    }
+
+    pub fn banned_addresses(&self) -> HashMap<Address<CanaryV0>, Duration> {
+        self.auto_ban.banned()
+    }
+
+    pub fn unban_address(&self, address: &Address<CanaryV0>) -> bool {
+        self.auto_ban.unban(address)
+    }
+
+    pub fn lifetime_stats(&self, address: &Address<CanaryV0>) -> LifetimeStats {
+        self.lifetime_stats.get(address)
+    }
+
+    #[cfg(feature = "geoip")]
+    pub async fn geo_stats(&self) -> HashMap<String, u32> {
+        self.geo_stats.counts().await
     }
 
     pub async fn address_prover_count(&self, address: Address<CanaryV0>) -> u32 {
@@ -743,6 +2121,109 @@ impl Server {
             .unwrap_or(0)
     }
 
+    pub async fn address_protocol_violations(&self, address: Address<CanaryV0>) -> u64 {
+        self.protocol_violations
+            .read()
+            .await
+            .get(&address)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The prover states of the connections of each address, or of every online one, taken under a
+    /// single read of the connection tables. The tables are released before any state is locked.
+    async fn address_prover_states(
+        &self,
+        addresses: Option<&[Address<CanaryV0>]>,
+    ) -> HashMap<Address<CanaryV0>, Vec<Arc<RwLock<ProverState>>>> {
+        let prover_connections = self.prover_address_connections.read().await;
+        let prover_states = self.prover_states.read().await;
+        let states_of = |connections: &HashSet<SocketAddr>| {
+            connections
+                .iter()
+                .filter_map(|connection| prover_states.get(connection).cloned())
+                .collect::<Vec<_>>()
+        };
+        match addresses {
+            Some(addresses) => addresses
+                .iter()
+                .map(|address| {
+                    let states = prover_connections.get(address).map(states_of).unwrap_or_default();
+                    (*address, states)
+                })
+                .collect(),
+            None => prover_connections
+                .iter()
+                .map(|(address, connections)| (*address, states_of(connections)))
+                .collect(),
+        }
+    }
+
+    /// Worker count and summed speed of the prover states.
+    async fn workers_speed(states: &[Arc<RwLock<ProverState>>]) -> (u32, Vec<f64>) {
+        let mut speed = vec![0.0, 0.0, 0.0, 0.0];
+        for state in states {
+            state
+                .write()
+                .await
+                .speed()
+                .await
+                .iter()
+                .zip(speed.iter_mut())
+                .for_each(|(s, speed)| *speed += s);
+        }
+        (states.len() as u32, speed)
+    }
+
+    /// Connected worker count and speed of every online address.
+    pub async fn active_addresses(&self) -> HashMap<Address<CanaryV0>, (u32, Vec<f64>)> {
+        let mut active = HashMap::new();
+        for (address, states) in self.address_prover_states(None).await {
+            active.insert(address, Server::workers_speed(&states).await);
+        }
+        active
+    }
+
+    /// Connected worker count and speed of every IP with an authenticated prover.
+    pub async fn active_ips(&self) -> HashMap<IpAddr, (u32, Vec<f64>)> {
+        let mut ip_states = HashMap::<IpAddr, Vec<_>>::new();
+        for (peer_addr, state) in self.prover_states.read().await.iter() {
+            ip_states.entry(peer_addr.ip()).or_default().push(state.clone());
+        }
+        let mut active = HashMap::with_capacity(ip_states.len());
+        for (ip, states) in ip_states {
+            active.insert(ip, Server::workers_speed(&states).await);
+        }
+        active
+    }
+
+    /// Prover count and speed of each of the addresses.
+    pub async fn addresses_stats(
+        &self,
+        addresses: &[Address<CanaryV0>],
+    ) -> HashMap<Address<CanaryV0>, (u32, Vec<f64>)> {
+        let mut stats = HashMap::with_capacity(addresses.len());
+        for (address, states) in self.address_prover_states(Some(addresses)).await {
+            stats.insert(address, Server::workers_speed(&states).await);
+        }
+        stats
+    }
+
+    /// Highest share interval variation among the connections of the address.
+    pub async fn address_share_variation(&self, address: Address<CanaryV0>) -> Option<f64> {
+        let prover_connections = self.prover_address_connections.read().await.get(&address)?.clone();
+        let prover_states = self.prover_states.read().await;
+        let mut variation: Option<f64> = None;
+        for prover_connection in prover_connections {
+            if let Some(prover_state) = prover_states.get(&prover_connection) {
+                if let Some(v) = prover_state.read().await.share_variation().await {
+                    variation = Some(variation.map_or(v, |max| max.max(v)));
+                }
+            }
+        }
+        variation
+    }
+
     pub async fn address_speed(&self, address: Address<CanaryV0>) -> Vec<f64> {
         let mut speed = vec![0.0, 0.0, 0.0, 0.0];
         let prover_connections_lock = self.prover_address_connections.read().await;
@@ -767,51 +2248,575 @@ impl Server {
     }
 }
 
-fn prover_polynomial(
-    epoch_challenge: &EpochChallenge<CanaryV0>,
-    address: Address<CanaryV0>,
-    nonce: u64,
-) -> anyhow::Result<DensePolynomial<<<CanaryV0 as Environment>::PairingCurve as PairingEngine>::Fr>> {
-    let input = {
-        let mut bytes = [0u8; 76];
-        bytes[..4].copy_from_slice(&epoch_challenge.epoch_number().to_bytes_le()?);
-        bytes[4..36].copy_from_slice(&epoch_challenge.epoch_block_hash().to_bytes_le()?);
-        bytes[36..68].copy_from_slice(&address.to_bytes_le()?);
-        bytes[68..].copy_from_slice(&nonce.to_le_bytes());
-        bytes
-    };
-    Ok(hash_to_polynomial::<
-        <<CanaryV0 as Environment>::PairingCurve as PairingEngine>::Fr,
-    >(&input, epoch_challenge.degree()))
-}
-
-fn hash_to_polynomial<F: PrimeField>(input: &[u8], degree: u32) -> DensePolynomial<F> {
-    // Hash the input into coefficients.
-    let coefficients = hash_to_coefficients(input, degree + 1);
-    // Construct the polynomial from the coefficients.
-    DensePolynomial::from_coefficients_vec(coefficients)
-}
-
-fn hash_to_coefficients<F: PrimeField>(input: &[u8], num_coefficients: u32) -> Vec<F> {
-    // Hash the input.
-    let hash = blake2::Blake2s256::digest(input);
-    // Hash with a counter and return the coefficients.
-    cfg_into_iter!(0..num_coefficients)
-        .map(|counter| {
-            let mut input_with_counter = [0u8; 36];
-            input_with_counter[..32].copy_from_slice(&hash);
-            input_with_counter[32..].copy_from_slice(&counter.to_le_bytes());
-            F::from_bytes_le_mod_order(&blake2::Blake2b512::digest(input_with_counter))
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use snarkvm::console::account::PrivateKey;
+
+    use super::*;
+
+    fn address() -> Address<CanaryV0> {
+        Address::try_from(PrivateKey::<CanaryV0>::new(&mut thread_rng()).unwrap()).unwrap()
+    }
+
+    fn prover_state() -> ProverState {
+        let peer_addr = "127.0.0.1:4040".parse().unwrap();
+        ProverState::new(peer_addr, address(), "worker".to_string(), 100, 0)
+    }
+
+    #[test]
+    fn floor_is_the_higher_of_target_and_minimum() {
+        assert_eq!(difficulty_floor(100, 500, 10_000), 500);
+        assert_eq!(difficulty_floor(1_000, 500, 10_000), 1_000);
+    }
+
+    #[test]
+    fn floor_never_exceeds_the_proof_target() {
+        assert_eq!(difficulty_floor(100, 500, 300), 300);
+        assert_eq!(difficulty_floor(1_000, 500, 300), 300);
+    }
+
+    #[tokio::test]
+    async fn pool_speed_is_read_from_the_cache() {
+        let pool_state = Arc::new(RwLock::new(PoolState::new(
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        )));
+        pool_state.write().await.add_share(1_000).await;
+        let cache = Arc::new(PoolSpeedCache::default());
+        // the refresh waits for the pool state, the reads must not
+        let state = pool_state.write().await;
+        cache
+            .clone()
+            .spawn_refresh(pool_state.clone(), Duration::from_millis(20));
+        let reads = (0..100)
+            .map(|_| {
+                let cache = cache.clone();
+                task::spawn(async move { cache.speed() })
+            })
+            .collect::<Vec<_>>();
+        for read in reads {
+            assert_eq!(read.await.unwrap(), vec![0.0; 4]);
+        }
+        drop(state);
+        sleep(Duration::from_millis(50)).await;
+        assert!(cache.speed().iter().all(|speed| *speed > 0.0));
+        assert!(cache.window_speed().0 > 0.0);
+    }
+
+    fn auto_ban() -> AutoBan {
+        AutoBan::new(AutoBanConfig {
+            reject_ratio: 0.5,
+            min_samples: 10,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
         })
-        .collect()
-}
+    }
 
-fn hash_commitment<E: PairingEngine>(commitment: &KZGCommitment<E>) -> anyhow::Result<E::Fr> {
-    // Convert the commitment into bytes.
-    let mut bytes = Vec::with_capacity(96);
-    commitment.serialize_uncompressed(&mut bytes)?;
-    ensure!(bytes.len() == 96, "Invalid commitment byte length for hashing");
+    #[tokio::test]
+    async fn submit_with_id_is_answered_to_its_id() {
+        let (sender, mut receiver) = channel(4);
+        let lifetime_stats = LifetimeStore::load(false);
+        let address = address();
+        send_result(
+            &sender,
+            Some(Id::Num(7)),
+            &auto_ban(),
+            &lifetime_stats,
+            Some(address),
+            true,
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(StratumMessage::Response(
+                Id::Num(7),
+                Some(ResponseParams::Bool(true)),
+                None
+            ))
+        ));
+        assert_eq!(lifetime_stats.get(&address).accepted, 1);
+    }
 
-    // Return the hash of the commitment.
-    Ok(E::Fr::from_bytes_le_mod_order(&blake2::Blake2b512::digest(&bytes)))
+    #[tokio::test]
+    async fn submit_without_id_is_counted_but_not_answered() {
+        let (sender, mut receiver) = channel(4);
+        let lifetime_stats = LifetimeStore::load(false);
+        let address = address();
+        let code = Some(ErrorCode::from_code(23));
+        let desc = Some("Low difficulty".to_string());
+        send_result(
+            &sender,
+            None,
+            &auto_ban(),
+            &lifetime_stats,
+            Some(address),
+            false,
+            code,
+            desc,
+        )
+        .await;
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(lifetime_stats.get(&address).rejected, 1);
+    }
+
+    #[test]
+    fn connections_get_distinct_extranonces() {
+        let counter = AtomicU64::new(u64::MAX - 10);
+        let extranonces = (0..1_000)
+            .map(|_| next_extranonce(&counter, 2).unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(extranonces.len(), 1_000);
+        assert!(extranonces.iter().all(|extranonce| extranonce.len() == 2));
+        assert_eq!(next_extranonce(&counter, 0), None);
+    }
+
+    #[test]
+    fn verification_without_a_key_fails_at_startup() {
+        let error = verifier::<()>(true, || Err(anyhow::anyhow!("missing parameters"))).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Proof verification is enabled but the coinbase verifying key could not be loaded: missing parameters"
+        );
+    }
+
+    #[test]
+    fn verification_with_a_key_starts() {
+        assert_eq!(verifier(true, || Ok(7)).unwrap(), Some(7));
+        // the key isn't loaded when verification is disabled
+        assert_eq!(verifier::<u32>(false, || panic!("loaded")).unwrap(), None);
+    }
+
+    #[test]
+    fn difficulty_history_keeps_the_latest_changes_in_order() {
+        let mut history = VecDeque::new();
+        let changes = DIFFICULTY_HISTORY_LEN as u64 + 3;
+        for target in 1..=changes {
+            let change = DifficultyChange {
+                timestamp: target,
+                old_target: target * 100,
+                new_target: (target + 1) * 100,
+                reason: "vardiff",
+            };
+            push_difficulty_change(&mut history, change);
+        }
+        assert_eq!(history.len(), DIFFICULTY_HISTORY_LEN);
+        assert_eq!(history.front().unwrap().old_target, 400);
+        assert_eq!(history.back().unwrap().new_target, (changes + 1) * 100);
+        // each change starts where the previous one ended
+        for (previous, next) in history.iter().zip(history.iter().skip(1)) {
+            assert_eq!(previous.new_target, next.old_target);
+            assert!(previous.timestamp < next.timestamp);
+        }
+    }
+
+    #[tokio::test]
+    async fn even_shares_vary_little_and_bursts_vary_a_lot() {
+        let start = Instant::now() - Duration::from_secs(60);
+        let even = prover_state();
+        for i in 0..10 {
+            even.speed_15m.event_at(1, start + Duration::from_secs(i * 5)).await;
+        }
+        assert!(even.share_variation().await.unwrap() < 0.01);
+
+        let bursty = prover_state();
+        for i in 0..10 {
+            let at = start + Duration::from_millis(i * 10);
+            bursty.speed_15m.event_at(1, at).await;
+        }
+        bursty.speed_15m.event_at(1, start + Duration::from_secs(50)).await;
+        // above the threshold the API flags as unhealthy
+        assert!(bursty.share_variation().await.unwrap() > 2.0);
+    }
+
+    #[test]
+    fn reconnect_waits_are_staggered_over_the_grace() {
+        let grace = Duration::from_secs(60);
+        let waits = (0..4).map(|i| staggered_wait(grace, i, 4)).collect::<Vec<_>>();
+        assert_eq!(waits, vec![0, 15, 30, 45]);
+    }
+
+    #[tokio::test]
+    async fn drain_sends_reconnects_and_closes_after_the_grace() {
+        let authenticated_provers: Arc<RwLock<HashMap<SocketAddr, Sender<StratumMessage>>>> = Default::default();
+        let mut receivers = vec![];
+        for port in [4001, 4002, 4003] {
+            let (sender, receiver) = channel(4);
+            authenticated_provers
+                .write()
+                .await
+                .insert(SocketAddr::from(([127, 0, 0, 1], port)), sender);
+            receivers.push(receiver);
+        }
+        let grace = Duration::from_millis(100);
+        let draining = reconnect_all(&authenticated_provers, grace, |i, _| i as u64 * 10).await;
+        assert_eq!(draining, 3);
+        let mut waits = vec![];
+        for receiver in &mut receivers {
+            match receiver.recv().await {
+                Some(StratumMessage::Reconnect(None, None, wait_seconds)) => waits.push(wait_seconds),
+                _ => panic!("expected a reconnect"),
+            }
+        }
+        assert_eq!(waits, vec![0, 10, 20]);
+        assert_eq!(authenticated_provers.read().await.len(), 3);
+
+        sleep(grace * 3).await;
+        assert!(authenticated_provers.read().await.is_empty());
+        // dropping the senders is what closes the connections
+        for receiver in &mut receivers {
+            assert!(receiver.recv().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn dominating_rejects_lower_the_target() {
+        let mut clean = prover_state();
+        let mut rejecting = prover_state();
+        for _ in 0..5 {
+            clean.add_share(100).await;
+            rejecting.add_share(100).await;
+        }
+        for _ in 0..10 {
+            rejecting.record_low_difficulty_reject();
+        }
+
+        let (clean_target, clean_reason) = clean.next_target(0.5).await;
+        assert_ne!(clean_reason, "reject rate");
+        assert!(clean_target >= 100);
+        let (rejecting_target, rejecting_reason) = rejecting.next_target(0.5).await;
+        assert_eq!(rejecting_reason, "reject rate");
+        // two thirds of the submits missed, so the target drops by a third
+        assert_eq!(rejecting_target, 66);
+    }
+
+    fn epoch_job_id(epoch_number: u32) -> String {
+        hex::encode(epoch_number.to_le_bytes())
+    }
+
+    #[test]
+    fn submit_goes_to_the_pool_that_issued_its_job() {
+        let mut issued = IssuedJobs::default();
+        issued.issue(IssuedJob::Upstream("upstream-1".to_string()));
+        // switched back to local jobs, the upstream job is still being worked on
+        issued.issue(IssuedJob::Epoch(5));
+        issued.issue(IssuedJob::Epoch(6));
+        assert!(matches!(issued.find("upstream-1"), Some(IssuedJob::Upstream(id)) if id == "upstream-1"));
+        assert!(matches!(issued.find(&epoch_job_id(5)), Some(IssuedJob::Epoch(5))));
+        assert!(issued.find(&epoch_job_id(7)).is_none());
+    }
+
+    #[test]
+    fn evicted_jobs_are_not_recognized() {
+        let mut issued = IssuedJobs::default();
+        issued.issue(IssuedJob::Upstream("upstream-1".to_string()));
+        for epoch_number in 0..ISSUED_JOBS_LEN as u32 - 1 {
+            issued.issue(IssuedJob::Epoch(epoch_number));
+        }
+        // issuing a known job again doesn't push anything out
+        issued.issue(IssuedJob::Epoch(0));
+        assert!(issued.find("upstream-1").is_some());
+        issued.issue(IssuedJob::Epoch(100));
+        assert!(issued.find("upstream-1").is_none());
+        assert!(matches!(issued.find(&epoch_job_id(0)), Some(IssuedJob::Epoch(0))));
+    }
+
+    #[tokio::test]
+    async fn short_window_reacts_faster_to_a_burst() {
+        let mut pool_state = PoolState::new(Duration::from_secs(1), Duration::from_secs(3600));
+        for _ in 0..100 {
+            pool_state.add_share(10).await;
+        }
+        let [short, long] = pool_state.window_speed().await;
+        assert!(short > long * 100.0);
+
+        // the burst has left the short window but still counts in the long one
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let [short, long] = pool_state.window_speed().await;
+        assert_eq!(short, 0.0);
+        assert!(long > 0.0);
+    }
+
+    #[test]
+    fn share_value_follows_the_mode() {
+        // (proof difficulty, credited at the target, credited at the difficulty)
+        for (proof_difficulty, target_value, difficulty_value) in [
+            (500, 100, 500),
+            (100, 100, 100),
+            (5_000, 100, 2_000),
+            (u64::MAX, 100, 2_000),
+        ] {
+            assert_eq!(ShareValue::Target.credit(100, proof_difficulty, 1_000), target_value);
+            // capped at twice the proof target
+            assert_eq!(
+                ShareValue::Difficulty.credit(100, proof_difficulty, 1_000),
+                difficulty_value
+            );
+        }
+    }
+
+    #[test]
+    fn idle_address_is_evicted_and_keeps_its_shares() {
+        let ttl = Duration::from_secs(60);
+        let (idle, recent, connected, orphan) = (address(), address(), address(), address());
+        let long_ago = Instant::now() - Duration::from_secs(120);
+        let connections = HashMap::from([(connected, HashSet::from(["127.0.0.1:4040".parse().unwrap()]))]);
+        let mut last_seen = HashMap::from([(idle, long_ago), (recent, Instant::now()), (connected, long_ago)]);
+        let change = || DifficultyChange {
+            timestamp: 0,
+            old_target: 100,
+            new_target: 200,
+            reason: "vardiff",
+        };
+        let mut difficulty_history = HashMap::from([
+            (idle, VecDeque::from([change()])),
+            (connected, VecDeque::from([change()])),
+            (orphan, VecDeque::from([change()])),
+        ]);
+        let mut protocol_violations = HashMap::from([(idle, 3), (recent, 1)]);
+        let lifetime_stats = LifetimeStore::load(false);
+        lifetime_stats.record(&idle, true);
+        lifetime_stats.record(&idle, false);
+
+        let evicted = evict_idle(
+            ttl,
+            &connections,
+            &mut last_seen,
+            &mut difficulty_history,
+            &mut protocol_violations,
+        );
+
+        assert_eq!(evicted, 2);
+        assert!(!last_seen.contains_key(&idle));
+        assert!(!difficulty_history.contains_key(&idle));
+        assert!(!difficulty_history.contains_key(&orphan));
+        assert!(!protocol_violations.contains_key(&idle));
+        assert!(last_seen.contains_key(&recent) && protocol_violations.contains_key(&recent));
+        assert!(last_seen.contains_key(&connected) && difficulty_history.contains_key(&connected));
+        let shares = lifetime_stats.get(&idle);
+        assert_eq!((shares.accepted, shares.rejected), (1, 1));
+    }
+
+    /// Ends the prover's current epoch as if it had credited `value` over the last 20 seconds.
+    fn fill_epoch(state: &mut ProverState, value: u64) {
+        state.current_epoch.value = value;
+        state.current_epoch.started = Instant::now() - Duration::from_secs(20);
+    }
+
+    #[tokio::test]
+    async fn retarget_starts_a_fresh_cadence() {
+        let mut state = prover_state();
+        fill_epoch(&mut state, 1_000);
+        let (target, _) = state.next_target(0.0).await;
+        assert!((990..=1_000).contains(&target));
+        assert!(state.completed_epochs.is_empty());
+
+        // the same hashrate at the new target is no reason for another change
+        fill_epoch(&mut state, 1_000);
+        assert_eq!(state.next_target(0.0).await.0, target);
+        assert_eq!(state.completed_epochs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn new_prover_keeps_the_probation_target_until_enough_shares() {
+        let peer_addr = "127.0.0.1:4040".parse().unwrap();
+        let mut state = ProverState::new(peer_addr, address(), "worker".to_string(), 100, 3);
+        for _ in 0..2 {
+            state.add_share(100).await;
+            fill_epoch(&mut state, 1_000);
+            assert_eq!(state.next_target(0.0).await, (100, "probation"));
+        }
+
+        state.add_share(100).await;
+        fill_epoch(&mut state, 1_000);
+        let (target, reason) = state.next_target(0.0).await;
+        assert_eq!(reason, "vardiff");
+        assert!(target > 100);
+    }
+
+    #[tokio::test]
+    async fn maintenance_drain_asks_provers_back_after_the_window() {
+        let authenticated_provers: Arc<RwLock<HashMap<SocketAddr, Sender<StratumMessage>>>> = Default::default();
+        let mut receivers = vec![];
+        for port in [4001, 4002] {
+            let (sender, receiver) = channel(4);
+            authenticated_provers
+                .write()
+                .await
+                .insert(SocketAddr::from(([127, 0, 0, 1], port)), sender);
+            receivers.push(receiver);
+        }
+        let window = MaintenanceWindow {
+            start: 1_000,
+            end: 4_600,
+        };
+        assert_eq!(drain_for_maintenance(&authenticated_provers, window, 1_000).await, 2);
+        for receiver in &mut receivers {
+            assert!(matches!(
+                receiver.recv().await,
+                Some(StratumMessage::Reconnect(None, None, 3_600))
+            ));
+        }
+    }
+
+    /// Collects what a test logs.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn difficulty_is_logged_at_authorize_and_retarget() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let peer_addr = "127.0.0.1:4040".parse().unwrap();
+        let address = address();
+        tracing::subscriber::with_default(subscriber, || {
+            for (old_target, new_target, reason) in [(0, 512, "initial"), (512, 1024, "vardiff")] {
+                let change = DifficultyChange {
+                    timestamp: 0,
+                    old_target,
+                    new_target,
+                    reason,
+                };
+                log_difficulty_change(peer_addr, address, "rig1", &change);
+            }
+        });
+
+        let logs = String::from_utf8(logs.0.lock().clone()).unwrap();
+        let lines = logs.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        for (line, target) in lines.iter().zip(["target=512", "target=1024"]) {
+            assert!(line.contains("Prover difficulty set"));
+            assert!(line.contains(&format!("address={}", address)));
+            assert!(line.contains("worker=\"rig1\""));
+            assert!(line.contains(target));
+        }
+    }
+
+    #[test]
+    fn epoch_cap_stops_credit_until_the_next_epoch() {
+        let mut state = prover_state();
+        for _ in 0..3 {
+            assert!(state.count_epoch_share(3));
+        }
+        assert!(!state.count_epoch_share(3));
+        assert!(!state.count_epoch_share(3));
+
+        state.reset_epoch_cap();
+        assert!(state.count_epoch_share(3));
+    }
+
+    #[tokio::test]
+    async fn test_job_reaches_only_the_targeted_connection() {
+        let authenticated_provers: RwLock<HashMap<SocketAddr, Sender<StratumMessage>>> = Default::default();
+        let targeted = SocketAddr::from(([127, 0, 0, 1], 4001));
+        let other = SocketAddr::from(([127, 0, 0, 1], 4002));
+        let (sender, mut targeted_receiver) = channel(4);
+        authenticated_provers.write().await.insert(targeted, sender);
+        let (sender, mut other_receiver) = channel(4);
+        authenticated_provers.write().await.insert(other, sender);
+
+        let job = || StratumMessage::Notify("test".to_string(), "challenge".to_string(), None, true);
+        assert!(send_to_connection(&authenticated_provers, targeted, job()).await);
+        assert!(matches!(
+            targeted_receiver.try_recv(),
+            Ok(StratumMessage::Notify(job_id, ..)) if job_id == "test"
+        ));
+        assert!(other_receiver.try_recv().is_err());
+
+        let unknown = SocketAddr::from(([127, 0, 0, 1], 4003));
+        assert!(!send_to_connection(&authenticated_provers, unknown, job()).await);
+    }
+
+    #[test]
+    fn auto_n_follows_the_pool_speed_in_bounded_steps() {
+        let auto_n = AutoNConfig {
+            window: Duration::from_secs(100),
+            min: 1_000,
+            max: 100_000,
+            max_step: 0.1,
+            initial: 0,
+        };
+        // the first size is taken as is
+        assert_eq!(auto_n.next_n(0, 50.0), 5_000);
+        // a rising speed grows n by at most a tenth per update
+        let mut n = 5_000;
+        let mut sizes = vec![];
+        for _ in 0..3 {
+            n = auto_n.next_n(n, 200.0);
+            sizes.push(n);
+        }
+        assert_eq!(sizes, vec![5_500, 6_050, 6_655]);
+        // and never past the bounds
+        assert_eq!(auto_n.next_n(99_000, 10_000.0), 100_000);
+        assert_eq!(auto_n.next_n(1_050, 0.1), 1_000);
+        assert_eq!(auto_n.next_n(0, 0.1), 1_000);
+    }
+
+    #[test]
+    fn only_recently_issued_jobs_are_accepted() {
+        let mut issued = IssuedJobs::default();
+        for epoch_number in 0..ISSUED_JOBS_LEN as u32 {
+            issued.issue(IssuedJob::Epoch(epoch_number));
+        }
+        assert!(matches!(issued.find(&epoch_job_id(3)), Some(IssuedJob::Epoch(3))));
+        // a well formed id the pool never sent
+        assert!(issued.find(&epoch_job_id(1_000)).is_none());
+
+        issued.issue(IssuedJob::Epoch(ISSUED_JOBS_LEN as u32));
+        assert!(issued.find(&epoch_job_id(0)).is_none());
+        assert!(issued.find(&epoch_job_id(1)).is_some());
+    }
+
+    #[test]
+    fn initial_target_follows_the_reported_hashrate() {
+        let max = 1_000_000.0;
+        let target = reported_target(1_000.0, max).unwrap();
+        assert_eq!(target, (1_000.0 * VARDIFF_SHARE_SECONDS) as u64);
+        assert_eq!(reported_target(4_000.0, max), Some(target * 4));
+        assert_eq!(reported_target(0.01, max), Some(1));
+    }
+
+    #[test]
+    fn implausible_reported_hashrate_is_ignored() {
+        for hashrate in [0.0, -5.0, f64::NAN, 2_000_000.0] {
+            assert_eq!(reported_target(hashrate, 1_000_000.0), None);
+        }
+        assert_eq!(reported_target(1_000.0, 0.0), None);
+    }
+
+    #[test]
+    fn human_difficulty_at_unit_boundaries() {
+        for (target, expected) in [
+            (0, "0"),
+            (999, "999"),
+            (1_000, "1.00 K"),
+            (1_500, "1.50 K"),
+            (999_994, "999.99 K"),
+            (999_999, "1.00 M"),
+            (1_000_000, "1.00 M"),
+            (123_456_789, "123.46 M"),
+            (u64::MAX, "18.45 E"),
+        ] {
+            assert_eq!(human_difficulty(target), expected, "{}", target);
+        }
+    }
 }
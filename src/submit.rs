@@ -0,0 +1,185 @@
+//! Decoding and verification of submitted solutions. Free of server state, so `benches/submit.rs`
+//! includes this file as is.
+
+use std::fmt::{Display, Formatter};
+
+use anyhow::ensure;
+use blake2::Digest;
+use snarkvm::{
+    circuit::prelude::PrimeField,
+    console::account::Address,
+    prelude::{CanaryV0, Environment, FromBytes, ToBytes},
+    synthesizer::{CoinbasePuzzle, EpochChallenge},
+};
+use snarkvm_algorithms::{
+    cfg_into_iter,
+    crypto_hash::sha256d_to_u64,
+    fft::DensePolynomial,
+    polycommit::kzg10::{KZGCommitment, KZGProof, KZG10},
+};
+use snarkvm_curves::PairingEngine;
+use snarkvm_utilities::serialize::CanonicalSerialize;
+
+type Curve = <CanaryV0 as Environment>::PairingCurve;
+
+pub type DecodedSubmit = ([u8; 8], KZGCommitment<Curve>, KZGProof<Curve>);
+
+/// Decodes hex into `buffer`, reusing its allocation.
+fn decode_hex_into(buffer: &mut Vec<u8>, data: &str) -> Result<(), hex::FromHexError> {
+    buffer.resize(data.len() / 2, 0);
+    hex::decode_to_slice(data, buffer)
+}
+
+/// Decodes the hex fields of a submit into (nonce bytes, commitment, proof), using `buffer` for
+/// the variable length fields. The job id is left to the server, as upstream jobs have ids of
+/// their own format.
+pub fn decode_submit(
+    buffer: &mut Vec<u8>,
+    nonce: &str,
+    commitment: &str,
+    proof: &str,
+) -> Result<DecodedSubmit, &'static str> {
+    let mut nonce_bytes = [0u8; 8];
+    hex::decode_to_slice(nonce, &mut nonce_bytes).map_err(|_| "invalid nonce")?;
+    decode_hex_into(buffer, commitment).map_err(|_| "undecodable commitment")?;
+    let commitment = KZGCommitment::from_bytes_le(&buffer[..]).map_err(|_| "invalid commitment")?;
+    decode_hex_into(buffer, proof).map_err(|_| "undecodable proof")?;
+    let proof = KZGProof::from_bytes_le(&buffer[..]).map_err(|_| "invalid proof")?;
+    Ok((nonce_bytes, commitment, proof))
+}
+
+/// Difficulty the commitment of a solution reaches.
+pub fn proof_difficulty(commitment: &KZGCommitment<Curve>) -> anyhow::Result<u64> {
+    Ok(u64::MAX / sha256d_to_u64(&commitment.to_bytes_le()?))
+}
+
+/// Why a solution failed verification.
+#[derive(Debug)]
+pub enum VerifyError {
+    Polynomial(anyhow::Error),
+    Commitment(anyhow::Error),
+    Proof,
+}
+
+impl VerifyError {
+    /// Error message sent to the prover.
+    pub fn message(&self) -> &'static str {
+        match self {
+            VerifyError::Polynomial(_) => "Invalid polynomial",
+            VerifyError::Commitment(_) => "Invalid commitment",
+            VerifyError::Proof => "Invalid proof",
+        }
+    }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Polynomial(e) => write!(f, "failed to construct prover polynomial: {}", e),
+            VerifyError::Commitment(e) => write!(f, "failed to hash commitment: {}", e),
+            VerifyError::Proof => write!(f, "proof doesn't verify"),
+        }
+    }
+}
+
+/// Checks the proof of a solution to the epoch challenge, mined for `address` with `nonce`.
+pub fn verify_solution(
+    coinbase_puzzle: &CoinbasePuzzle<CanaryV0>,
+    epoch_challenge: &EpochChallenge<CanaryV0>,
+    address: Address<CanaryV0>,
+    nonce: u64,
+    commitment: &KZGCommitment<Curve>,
+    proof: &KZGProof<Curve>,
+) -> Result<(), VerifyError> {
+    let polynomial = prover_polynomial(epoch_challenge, address, nonce).map_err(VerifyError::Polynomial)?;
+    let point = hash_commitment(commitment).map_err(VerifyError::Commitment)?;
+    let product_eval_at_point = polynomial.evaluate(point) * epoch_challenge.epoch_polynomial().evaluate(point);
+    match KZG10::check(
+        coinbase_puzzle.coinbase_verifying_key(),
+        commitment,
+        point,
+        product_eval_at_point,
+        proof,
+    ) {
+        Ok(true) => Ok(()),
+        _ => Err(VerifyError::Proof),
+    }
+}
+
+fn prover_polynomial(
+    epoch_challenge: &EpochChallenge<CanaryV0>,
+    address: Address<CanaryV0>,
+    nonce: u64,
+) -> anyhow::Result<DensePolynomial<<Curve as PairingEngine>::Fr>> {
+    let input = {
+        let mut bytes = [0u8; 76];
+        bytes[..4].copy_from_slice(&epoch_challenge.epoch_number().to_bytes_le()?);
+        bytes[4..36].copy_from_slice(&epoch_challenge.epoch_block_hash().to_bytes_le()?);
+        bytes[36..68].copy_from_slice(&address.to_bytes_le()?);
+        bytes[68..].copy_from_slice(&nonce.to_le_bytes());
+        bytes
+    };
+    Ok(hash_to_polynomial::<<Curve as PairingEngine>::Fr>(
+        &input,
+        epoch_challenge.degree(),
+    ))
+}
+
+fn hash_to_polynomial<F: PrimeField>(input: &[u8], degree: u32) -> DensePolynomial<F> {
+    // Hash the input into coefficients.
+    let coefficients = hash_to_coefficients(input, degree + 1);
+    // Construct the polynomial from the coefficients.
+    DensePolynomial::from_coefficients_vec(coefficients)
+}
+
+fn hash_to_coefficients<F: PrimeField>(input: &[u8], num_coefficients: u32) -> Vec<F> {
+    // Hash the input.
+    let hash = blake2::Blake2s256::digest(input);
+    // Hash with a counter and return the coefficients.
+    cfg_into_iter!(0..num_coefficients)
+        .map(|counter| {
+            let mut input_with_counter = [0u8; 36];
+            input_with_counter[..32].copy_from_slice(&hash);
+            input_with_counter[32..].copy_from_slice(&counter.to_le_bytes());
+            F::from_bytes_le_mod_order(&blake2::Blake2b512::digest(input_with_counter))
+        })
+        .collect()
+}
+
+fn hash_commitment<E: PairingEngine>(commitment: &KZGCommitment<E>) -> anyhow::Result<E::Fr> {
+    // Convert the commitment into bytes.
+    let mut bytes = Vec::with_capacity(96);
+    commitment.serialize_uncompressed(&mut bytes)?;
+    ensure!(bytes.len() == 96, "Invalid commitment byte length for hashing");
+
+    // Return the hash of the commitment.
+    Ok(E::Fr::from_bytes_le_mod_order(&blake2::Blake2b512::digest(&bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_buffer_decodes_like_a_fresh_allocation() {
+        let mut buffer = Vec::new();
+        // shorter values after longer ones must not keep stale bytes
+        for data in ["00112233445566778899aabbccddeeff", "deadbeef", "", "0a"] {
+            decode_hex_into(&mut buffer, data).unwrap();
+            assert_eq!(buffer, hex::decode(data).unwrap());
+        }
+        for data in ["abc", "zz"] {
+            assert_eq!(decode_hex_into(&mut buffer, data).is_err(), hex::decode(data).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_submit_fields_are_named() {
+        let mut buffer = Vec::new();
+        assert_eq!(decode_submit(&mut buffer, "zz", "", "").err(), Some("invalid nonce"));
+        assert_eq!(
+            decode_submit(&mut buffer, "0001020304050607", "zz", "").err(),
+            Some("undecodable commitment")
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use aleo_stratum::{
+    codec::{ResponseParams, StratumCodec},
+    message::StratumMessage,
+};
+use futures_util::sink::SinkExt;
+use json_rpc_types::{Error, ErrorCode, Id};
+use tokio::{
+    net::TcpStream,
+    sync::{
+        mpsc,
+        mpsc::{Receiver, Sender},
+        oneshot,
+        Mutex,
+    },
+    task,
+    time::{sleep, timeout},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    error::{PoolError, PoolResult},
+    ServerMessage,
+};
+
+static UPSTREAM_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct UpstreamConfig {
+    /// host:port of the upstream stratum server
+    pub address: String,
+    pub worker_name: String,
+    pub password: String,
+}
+
+/// A prover's submit for an upstream job, with the job id as the upstream pool sent it.
+pub struct UpstreamSubmit {
+    pub job_id: String,
+    pub nonce: String,
+    pub commitment: String,
+    pub proof: String,
+    /// Receives the upstream pool's answer, dropped if the connection to it is lost first
+    pub result: oneshot::Sender<Result<(), Error<()>>>,
+}
+
+/// Stratum client connection to an upstream pool, used when the validator stops delivering work.
+pub struct Upstream {
+    config: UpstreamConfig,
+    sender: Sender<UpstreamSubmit>,
+    receiver: Arc<Mutex<Receiver<UpstreamSubmit>>>,
+}
+
+impl Upstream {
+    pub fn init(config: UpstreamConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(1024);
+        Self {
+            config,
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    /// Submits sent here are forwarded upstream with the configured worker name.
+    pub fn sender(&self) -> Sender<UpstreamSubmit> {
+        self.sender.clone()
+    }
+}
+
+pub fn start(upstream: Upstream, server_sender: Sender<ServerMessage>) {
+    task::spawn(async move {
+        let config = upstream.config;
+        let receiver = &mut *upstream.receiver.lock().await;
+        loop {
+            info!("Connecting to upstream pool {}...", config.address);
+            match timeout(UPSTREAM_HANDSHAKE_TIMEOUT, TcpStream::connect(&config.address)).await {
+                Ok(Ok(socket)) => {
+                    let mut framed = Framed::new(socket, StratumCodec::default());
+                    match handshake(&mut framed, &config).await {
+                        Ok(address) => {
+                            info!(
+                                "Authorized to upstream pool {} as {}",
+                                config.address, config.worker_name
+                            );
+                            run(&mut framed, &config, address, receiver, &server_sender).await;
+                        }
+                        Err(e) => error!("Upstream handshake failed: {}", e),
+                    }
+                }
+                Ok(Err(e)) => error!("Failed to connect to upstream pool: {}", e),
+                Err(_) => error!("Timed out connecting to upstream pool"),
+            }
+            sleep(Duration::from_secs(5)).await;
+        }
+    });
+}
+
+/// Subscribes and authorizes, returning the upstream pool address from the subscribe response.
+async fn handshake(
+    framed: &mut Framed<TcpStream, StratumCodec>,
+    config: &UpstreamConfig,
+) -> PoolResult<Option<String>> {
+    framed
+        .send(StratumMessage::Subscribe(
+            Id::Num(0),
+            format!("aleo-pool-server/{}", env!("CARGO_PKG_VERSION")),
+            "AleoStratum/2.0.0".to_string(),
+            None,
+            None,
+        ))
+        .await?;
+    let address = match next_response(framed, "subscribe").await? {
+        // a decoded null is a `None::<String>`, a string is a plain `String`
+        StratumMessage::Response(_, Some(ResponseParams::Array(params)), None) => {
+            params.get(2).and_then(|param| param.downcast_ref::<String>()).cloned()
+        }
+        message => return Err(PoolError::UnexpectedMessage(message.name(), "subscribe response")),
+    };
+    framed
+        .send(StratumMessage::Authorize(
+            Id::Num(1),
+            config.worker_name.clone(),
+            config.password.clone(),
+        ))
+        .await?;
+    match next_response(framed, "authorize").await? {
+        StratumMessage::Response(_, Some(ResponseParams::Bool(true)), None) => Ok(address),
+        message => Err(PoolError::UnexpectedMessage(message.name(), "authorize response")),
+    }
+}
+
+async fn next_response(
+    framed: &mut Framed<TcpStream, StratumCodec>,
+    stage: &'static str,
+) -> PoolResult<StratumMessage> {
+    match timeout(UPSTREAM_HANDSHAKE_TIMEOUT, framed.next()).await {
+        Ok(Some(Ok(message))) => Ok(message),
+        Ok(Some(Err(e))) => Err(e.into()),
+        Ok(None) => Err(PoolError::Disconnected(stage)),
+        Err(_) => Err(PoolError::Timeout(stage)),
+    }
+}
+
+async fn run(
+    framed: &mut Framed<TcpStream, StratumCodec>,
+    config: &UpstreamConfig,
+    address: Option<String>,
+    receiver: &mut Receiver<UpstreamSubmit>,
+    server_sender: &Sender<ServerMessage>,
+) {
+    let mut next_id = 2u64;
+    // submits waiting for their answer, by request id
+    let mut pending = HashMap::<u64, oneshot::Sender<Result<(), Error<()>>>>::new();
+    loop {
+        tokio::select! {
+            Some(submit) = receiver.recv() => {
+                // the prover was answered already if nobody waits for the result
+                if submit.result.is_closed() {
+                    continue;
+                }
+                pending.retain(|_, result| !result.is_closed());
+                next_id += 1;
+                let message = StratumMessage::Submit(
+                    Some(Id::Num(next_id)),
+                    config.worker_name.clone(),
+                    submit.job_id,
+                    submit.nonce,
+                    submit.commitment,
+                    submit.proof,
+                );
+                trace!("Sending {} to upstream", message.name());
+                if let Err(e) = framed.send(message).await {
+                    error!("Failed to send message to upstream: {}", e);
+                    continue;
+                }
+                pending.insert(next_id, submit.result);
+            }
+            result = framed.next() => match result {
+                Some(Ok(message)) => match message {
+                    StratumMessage::SetTarget(target) => {
+                        debug!("Upstream target set to {}", target);
+                        if let Err(e) = server_sender.send(ServerMessage::UpstreamTarget(target)).await {
+                            error!("Failed to send UpstreamTarget message to server: {}", e);
+                        }
+                    }
+                    StratumMessage::Notify(job_id, epoch_challenge, job_address, clean_jobs) => {
+                        debug!("Received upstream job {}", job_id);
+                        let job_address = match job_address.or_else(|| address.clone()) {
+                            Some(job_address) => job_address,
+                            None => {
+                                warn!("Upstream job {} has no pool address, ignoring", job_id);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = server_sender
+                            .send(ServerMessage::UpstreamJob(job_id, epoch_challenge, job_address, clean_jobs))
+                            .await
+                        {
+                            error!("Failed to send UpstreamJob message to server: {}", e);
+                        }
+                    }
+                    StratumMessage::Response(id, params, error) => {
+                        let result = match (params, error) {
+                            (Some(ResponseParams::Bool(true)), _) => {
+                                debug!("Upstream accepted submit {:?}", id);
+                                Ok(())
+                            }
+                            (_, error) => {
+                                warn!("Upstream rejected submit {:?}: {:?}", id, error);
+                                Err(error.unwrap_or_else(|| Error::with_custom_msg(ErrorCode::from_code(20), "Rejected by upstream")))
+                            }
+                        };
+                        let waiting = match id {
+                            Id::Num(id) => pending.remove(&id),
+                            _ => None,
+                        };
+                        if let Some(waiting) = waiting {
+                            // the prover has been answered already when this fails
+                            let _ = waiting.send(result);
+                        }
+                    }
+                    message => {
+                        debug!("Ignoring {} from upstream", message.name());
+                    }
+                },
+                Some(Err(e)) => {
+                    error!("Failed to read message from upstream: {}", e);
+                    break;
+                }
+                None => {
+                    warn!("Upstream pool disconnected");
+                    break;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aleo_stratum::codec::BoxedType;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_returns_the_upstream_pool_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (upstream, _) = listener.accept().await.unwrap();
+        let mut upstream = Framed::new(upstream, StratumCodec::default());
+        task::spawn(async move {
+            assert!(matches!(upstream.next().await, Some(Ok(StratumMessage::Subscribe(..)))));
+            let params: Vec<Box<dyn BoxedType>> = vec![
+                Box::new(Some("session".to_string())),
+                Box::new(None::<String>),
+                Box::new(Some("aleo1pool".to_string())),
+            ];
+            let response = StratumMessage::Response(Id::Num(0), Some(ResponseParams::Array(params)), None);
+            upstream.send(response).await.unwrap();
+            assert!(matches!(upstream.next().await, Some(Ok(StratumMessage::Authorize(..)))));
+            let response = StratumMessage::Response(Id::Num(1), Some(ResponseParams::Bool(true)), None);
+            upstream.send(response).await.unwrap();
+        });
+        let config = UpstreamConfig {
+            address: String::new(),
+            worker_name: "worker".to_string(),
+            password: String::new(),
+        };
+        let mut framed = Framed::new(client, StratumCodec::default());
+        let address = handshake(&mut framed, &config).await.unwrap();
+        assert_eq!(address.as_deref(), Some("aleo1pool"));
+    }
+}
@@ -0,0 +1,38 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc::Sender;
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Number of messages queued in the channel and not received yet.
+pub fn channel_depth<T>(sender: &Sender<T>) -> usize {
+    sender.max_capacity() - sender.capacity()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn depth_follows_the_backlog() {
+        let (sender, mut receiver) = channel(16);
+        for i in 0..10 {
+            sender.send(i).await.unwrap();
+            // drained at half the rate messages are queued
+            if i % 2 == 1 {
+                receiver.recv().await.unwrap();
+            }
+        }
+        assert_eq!(channel_depth(&sender), 5);
+        while receiver.try_recv().is_ok() {}
+        assert_eq!(channel_depth(&sender), 0);
+    }
+}
@@ -0,0 +1,111 @@
+//! Replays a captured stratum session through the codec.
+//!
+//! The capture is read as raw frames, one JSON message per line as sent on the wire.
+//! Every frame is decoded, then encoded again, and either the result or the error is printed.
+
+use std::{env, fs, process::exit};
+
+use aleo_stratum::codec::StratumCodec;
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: replay <frame log>");
+            exit(2);
+        }
+    };
+    let capture = match fs::read(&path) {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", path, e);
+            exit(2);
+        }
+    };
+
+    let mut errors = 0;
+    for (line, result) in replay(&capture) {
+        match result {
+            Ok(frame) => print!("{}: {}", line, frame),
+            Err(e) => {
+                println!("{}: {}", line, e);
+                errors += 1;
+            }
+        }
+    }
+
+    if errors > 0 {
+        eprintln!("{} frame(s) failed", errors);
+        exit(1);
+    }
+}
+
+/// Decodes and encodes again every frame of `capture`. Gives the line number of each frame with
+/// the message name and the encoded frame, or the reason it failed.
+fn replay(capture: &[u8]) -> Vec<(usize, Result<String, String>)> {
+    let mut results = Vec::new();
+    for (line, frame) in capture.split(|b| *b == b'\n').enumerate() {
+        if frame.is_empty() {
+            continue;
+        }
+        // a fresh codec per frame so a bad frame can't affect the following ones
+        let mut codec = StratumCodec::default();
+        let mut src = BytesMut::from(frame);
+        src.extend_from_slice(b"\n");
+        let message = match codec.decode(&mut src) {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                results.push((line + 1, Err("incomplete frame".to_string())));
+                continue;
+            }
+            Err(e) => {
+                results.push((line + 1, Err(format!("decode error: {}", e))));
+                continue;
+            }
+        };
+        let name = message.name();
+        let mut dst = BytesMut::new();
+        let result = match codec.encode(message, &mut dst) {
+            Ok(()) => Ok(format!("{} {}", name, String::from_utf8_lossy(&dst))),
+            Err(e) => Err(format!("{} encode error: {}", name, e)),
+        };
+        results.push((line + 1, result));
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn good_capture_replays() {
+        let capture = concat!(
+            r#"{"jsonrpc":"2.0","id":1,"method":"mining.subscribe","params":["miner","AleoStratum/2.0.0",null]}"#,
+            "\n\n",
+            r#"{"jsonrpc":"2.0","id":2,"method":"mining.authorize","params":["worker","password"]}"#,
+            "\n",
+        );
+        let results = replay(capture.as_bytes());
+        assert_eq!(results.len(), 2);
+        assert!(matches!(&results[0], (1, Ok(frame)) if frame.starts_with("mining.subscribe {")));
+        assert!(matches!(&results[1], (3, Ok(frame)) if frame.starts_with("mining.authorize {")));
+    }
+
+    #[test]
+    fn corrupt_capture_reports_failures() {
+        let capture = concat!(
+            r#"{"jsonrpc":"2.0","id":1,"method":"mining.authorize","params":["worker"]}"#,
+            "\n",
+            r#"{"jsonrpc":"2.0","id":2,"method":"#,
+            "\n",
+        );
+        let results = replay(capture.as_bytes());
+        assert_eq!(results.len(), 2);
+        let error = "decode error: mining.authorize: expected 2 params, got 1".to_string();
+        assert_eq!(results[0], (1, Err(error)));
+        assert!(matches!(&results[1], (2, Err(e)) if e.starts_with("decode error: ")));
+    }
+}
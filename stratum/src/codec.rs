@@ -1,4 +1,10 @@
-use std::io;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use bytes::BytesMut;
 use downcast_rs::{impl_downcast, DowncastSync};
@@ -6,12 +12,40 @@ use erased_serde::Serialize as ErasedSerialize;
 use json_rpc_types::{Id, Request, Response, Version};
 use serde::{ser::SerializeSeq, Deserialize, Serialize};
 use serde_json::Value;
-use tokio_util::codec::{AnyDelimiterCodec, Decoder, Encoder};
+use tokio_util::codec::{AnyDelimiterCodec, AnyDelimiterCodecError, Decoder, Encoder};
 
-use crate::message::StratumMessage;
+use crate::{
+    message::StratumMessage,
+    schema::{bool_param, nullable_port_param, nullable_str_param, schema, str_param, u64_param},
+};
+
+/// Longest message accepted by the codec
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Decode buffer figures of the codecs sharing it.
+#[derive(Default)]
+pub struct CodecStats {
+    max_buffered: AtomicUsize,
+    length_limit_exceeded: AtomicU64,
+}
+
+impl CodecStats {
+    /// Largest number of bytes buffered for a message still waiting for its delimiter
+    pub fn max_buffered(&self) -> usize {
+        self.max_buffered.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages rejected for exceeding `MAX_MESSAGE_LEN`
+    pub fn length_limit_exceeded(&self) -> u64 {
+        self.length_limit_exceeded.load(Ordering::Relaxed)
+    }
+}
 
 pub struct StratumCodec {
     codec: AnyDelimiterCodec,
+    /// Fail to decode methods not defined by the protocol instead of returning `StratumMessage::Unknown`
+    strict: bool,
+    stats: Arc<CodecStats>,
 }
 
 impl Default for StratumCodec {
@@ -19,16 +53,32 @@ impl Default for StratumCodec {
         Self {
             // Notify is ~400 bytes and submit is ~1750 bytes. 4096 should be enough for all messages
             // TODO: verify again
-            codec: AnyDelimiterCodec::new_with_max_length(vec![b'\n'], vec![b'\n'], 4096),
+            codec: AnyDelimiterCodec::new_with_max_length(vec![b'\n'], vec![b'\n'], MAX_MESSAGE_LEN),
+            strict: false,
+            stats: Default::default(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct NotifyParams(String, String, Option<String>, bool);
+impl StratumCodec {
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Records into `stats`, e.g. to aggregate the figures of every connection.
+    pub fn with_stats(mut self, stats: Arc<CodecStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn stats(&self) -> &Arc<CodecStats> {
+        &self.stats
+    }
+}
 
 #[derive(Serialize, Deserialize)]
-struct SubscribeParams(String, String, Option<String>);
+struct NotifyParams(String, String, Option<String>, bool);
 
 pub trait BoxedType: ErasedSerialize + Send + DowncastSync {}
 erased_serde::serialize_trait_object!(BoxedType);
@@ -40,6 +90,8 @@ impl BoxedType for Option<String> {}
 
 pub enum ResponseParams {
     Bool(bool),
+    Str(String),
+    Num(u64),
     Array(Vec<Box<dyn BoxedType>>),
     Null,
 }
@@ -51,6 +103,8 @@ impl Serialize for ResponseParams {
     {
         match self {
             ResponseParams::Bool(b) => serializer.serialize_bool(*b),
+            ResponseParams::Str(s) => serializer.serialize_str(s),
+            ResponseParams::Num(n) => serializer.serialize_u64(*n),
             ResponseParams::Array(v) => {
                 let mut seq = serializer.serialize_seq(Some(v.len()))?;
                 for item in v {
@@ -71,6 +125,11 @@ impl<'de> Deserialize<'de> for ResponseParams {
         let value = Value::deserialize(deserializer)?;
         match value {
             Value::Bool(b) => Ok(ResponseParams::Bool(b)),
+            Value::String(s) => Ok(ResponseParams::Str(s)),
+            Value::Number(n) => match n.as_u64() {
+                Some(n) => Ok(ResponseParams::Num(n)),
+                None => Err(serde::de::Error::custom("invalid response params")),
+            },
             Value::Array(a) => {
                 let mut vec: Vec<Box<dyn BoxedType>> = Vec::new();
                 a.iter().for_each(|v| match v {
@@ -92,11 +151,19 @@ impl Encoder<StratumMessage> for StratumCodec {
 
     fn encode(&mut self, item: StratumMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let bytes = match item {
-            StratumMessage::Subscribe(id, user_agent, protocol_version, session_id) => {
+            StratumMessage::Subscribe(id, user_agent, protocol_version, session_id, hashrate) => {
+                let mut params = vec![
+                    Value::from(user_agent),
+                    Value::from(protocol_version),
+                    Value::from(session_id),
+                ];
+                if let Some(hashrate) = hashrate {
+                    params.push(Value::from(hashrate));
+                }
                 let request = Request {
                     jsonrpc: Version::V2,
                     method: "mining.subscribe",
-                    params: Some(SubscribeParams(user_agent, protocol_version, session_id)),
+                    params: Some(params),
                     id: Some(id),
                 };
                 serde_json::to_vec(&request).unwrap_or_default()
@@ -133,7 +200,43 @@ impl Encoder<StratumMessage> for StratumCodec {
                     jsonrpc: Version::V2,
                     method: "mining.submit",
                     params: Some(vec![worker_name, job_id, nonce, commitment, proof]),
-                    id: Some(id),
+                    id,
+                };
+                serde_json::to_vec(&request).unwrap_or_default()
+            }
+            StratumMessage::Reconnect(host, port, wait_seconds) => {
+                let request = Request {
+                    jsonrpc: Version::V2,
+                    method: "client.reconnect",
+                    params: Some(vec![Value::from(host), Value::from(port), Value::from(wait_seconds)]),
+                    id: None,
+                };
+                serde_json::to_vec(&request).unwrap_or_default()
+            }
+            StratumMessage::ShowMessage(message) => {
+                let request = Request {
+                    jsonrpc: Version::V2,
+                    method: "client.show_message",
+                    params: Some(vec![message]),
+                    id: None,
+                };
+                serde_json::to_vec(&request).unwrap_or_default()
+            }
+            StratumMessage::GetStats(id) => {
+                let request = Request {
+                    jsonrpc: Version::V2,
+                    method: "mining.get_stats",
+                    params: Some(Vec::<Value>::new()),
+                    id,
+                };
+                serde_json::to_vec(&request).unwrap_or_default()
+            }
+            StratumMessage::Unknown(id, method) => {
+                let request = Request {
+                    jsonrpc: Version::V2,
+                    method: method.as_str(),
+                    params: Some(Vec::<Value>::new()),
+                    id,
                 };
                 serde_json::to_vec(&request).unwrap_or_default()
             }
@@ -157,39 +260,24 @@ impl Encoder<StratumMessage> for StratumCodec {
     }
 }
 
-fn unwrap_str_value(value: &Value) -> Result<String, io::Error> {
-    match value {
-        Value::String(s) => Ok(s.clone()),
-        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Param is not str")),
-    }
-}
-
-fn unwrap_bool_value(value: &Value) -> Result<bool, io::Error> {
-    match value {
-        Value::Bool(b) => Ok(*b),
-        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Param is not bool")),
-    }
-}
-
-fn unwrap_u64_value(value: &Value) -> Result<u64, io::Error> {
-    match value {
-        Value::Number(n) => Ok(n
-            .as_u64()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Param is not u64"))?),
-        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Param is not u64")),
-    }
-}
-
 impl Decoder for StratumCodec {
     type Error = io::Error;
     type Item = StratumMessage;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let string = self
-            .codec
-            .decode(src)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let string = self.codec.decode(src).map_err(|e| match e {
+            AnyDelimiterCodecError::MaxChunkLengthExceeded => {
+                self.stats.length_limit_exceeded.fetch_add(1, Ordering::Relaxed);
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("message exceeds the length limit of {} bytes", MAX_MESSAGE_LEN),
+                )
+            }
+            AnyDelimiterCodecError::Io(e) => e,
+        })?;
         if string.is_none() {
+            // complete messages have been taken out, what's left is waiting for a delimiter
+            self.stats.max_buffered.fetch_max(src.len(), Ordering::Relaxed);
             return Ok(None);
         }
         let bytes = string.unwrap();
@@ -204,72 +292,52 @@ impl Decoder for StratumCodec {
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
             let id = request.id;
             let method = request.method.as_str();
-            let params = match request.params {
-                Some(params) => params,
-                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "No params")),
+            let params = match (request.params, schema(method)) {
+                (Some(params), Some(schema)) => {
+                    schema.validate(&params)?;
+                    params
+                }
+                (None, Some(_)) => return Err(io::Error::new(io::ErrorKind::InvalidData, "No params")),
+                // unknown methods may come without params
+                (params, None) => params.unwrap_or_default(),
             };
             match method {
-                "mining.subscribe" => {
-                    if params.len() != 3 {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid params"));
-                    }
-                    let user_agent = unwrap_str_value(&params[0])?;
-                    let protocol_version = unwrap_str_value(&params[1])?;
-                    let session_id = match &params[2] {
-                        Value::String(s) => Some(s),
-                        Value::Null => None,
-                        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid params")),
-                    };
-                    StratumMessage::Subscribe(
-                        id.unwrap_or(Id::Num(0)),
-                        user_agent,
-                        protocol_version,
-                        session_id.cloned(),
-                    )
-                }
+                "mining.subscribe" => StratumMessage::Subscribe(
+                    id.unwrap_or(Id::Num(0)),
+                    str_param(&params[0]),
+                    str_param(&params[1]),
+                    nullable_str_param(&params[2]),
+                    params.get(3).and_then(Value::as_f64),
+                ),
                 "mining.authorize" => {
-                    if params.len() != 2 {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid params"));
-                    }
-                    let worker_name = unwrap_str_value(&params[0])?;
-                    let worker_password = unwrap_str_value(&params[1])?;
-                    StratumMessage::Authorize(id.unwrap_or(Id::Num(0)), worker_name, worker_password)
-                }
-                "mining.set_target" => {
-                    if params.len() != 1 {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid params"));
-                    }
-                    let difficulty_target = unwrap_u64_value(&params[0])?;
-                    StratumMessage::SetTarget(difficulty_target)
+                    StratumMessage::Authorize(id.unwrap_or(Id::Num(0)), str_param(&params[0]), str_param(&params[1]))
                 }
-                "mining.notify" => {
-                    if params.len() != 4 {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid params"));
-                    }
-                    let job_id = unwrap_str_value(&params[0])?;
-                    let epoch_challenge = unwrap_str_value(&params[1])?;
-                    let address = match &params[2] {
-                        Value::String(s) => Some(s),
-                        Value::Null => None,
-                        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid params")),
-                    };
-                    let clean_jobs = unwrap_bool_value(&params[3])?;
-                    StratumMessage::Notify(job_id, epoch_challenge, address.cloned(), clean_jobs)
-                }
-                "mining.submit" => {
-                    if params.len() != 5 {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid params"));
-                    }
-                    let worker_name = unwrap_str_value(&params[0])?;
-                    let job_id = unwrap_str_value(&params[1])?;
-                    let nonce = unwrap_str_value(&params[2])?;
-                    let commitment = unwrap_str_value(&params[3])?;
-                    let proof = unwrap_str_value(&params[4])?;
-                    StratumMessage::Submit(id.unwrap_or(Id::Num(0)), worker_name, job_id, nonce, commitment, proof)
-                }
-                _ => {
+                "mining.set_target" => StratumMessage::SetTarget(u64_param(&params[0])),
+                "mining.notify" => StratumMessage::Notify(
+                    str_param(&params[0]),
+                    str_param(&params[1]),
+                    nullable_str_param(&params[2]),
+                    bool_param(&params[3]),
+                ),
+                "mining.submit" => StratumMessage::Submit(
+                    id,
+                    str_param(&params[0]),
+                    str_param(&params[1]),
+                    str_param(&params[2]),
+                    str_param(&params[3]),
+                    str_param(&params[4]),
+                ),
+                "client.reconnect" => StratumMessage::Reconnect(
+                    nullable_str_param(&params[0]),
+                    nullable_port_param(&params[1]),
+                    u64_param(&params[2]),
+                ),
+                "client.show_message" => StratumMessage::ShowMessage(str_param(&params[0])),
+                "mining.get_stats" => StratumMessage::GetStats(id),
+                _ if self.strict => {
                     return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown method"));
                 }
+                _ => StratumMessage::Unknown(id, method.to_string()),
             }
         } else {
             let response = serde_json::from_value::<Response<ResponseParams, ()>>(json)
@@ -283,3 +351,172 @@ impl Decoder for StratumCodec {
         Ok(Some(result))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn round_trip(message: StratumMessage) -> StratumMessage {
+        let mut codec = StratumCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(message, &mut buf).unwrap();
+        codec.decode(&mut buf).unwrap().unwrap()
+    }
+
+    /// Result of a response after a round trip, as JSON for comparison.
+    fn response_result(params: ResponseParams) -> Value {
+        match round_trip(StratumMessage::Response(Id::Num(1), Some(params), None)) {
+            StratumMessage::Response(Id::Num(1), Some(params), None) => serde_json::to_value(params).unwrap(),
+            message => panic!("unexpected {}", message.name()),
+        }
+    }
+
+    #[test]
+    fn scalar_response_params() {
+        assert_eq!(response_result(ResponseParams::Str("ok".to_string())), json!("ok"));
+        assert_eq!(response_result(ResponseParams::Num(42)), json!(42));
+    }
+
+    #[test]
+    fn existing_response_params() {
+        assert_eq!(response_result(ResponseParams::Bool(true)), json!(true));
+        assert_eq!(response_result(ResponseParams::Null), Value::Null);
+        let array: Vec<Box<dyn BoxedType>> = vec![
+            Box::new("a".to_string()),
+            Box::new(Some(7u64)),
+            Box::new(None::<String>),
+        ];
+        assert_eq!(response_result(ResponseParams::Array(array)), json!(["a", 7, null]));
+    }
+
+    fn decode(codec: &mut StratumCodec, line: &str) -> io::Result<Option<StratumMessage>> {
+        codec.decode(&mut BytesMut::from(format!("{}\n", line).as_str()))
+    }
+
+    #[test]
+    fn unknown_method() {
+        let mut codec = StratumCodec::default();
+        let message = decode(
+            &mut codec,
+            r#"{"jsonrpc":"2.0","id":3,"method":"mining.ping","params":[]}"#,
+        );
+        assert!(
+            matches!(message, Ok(Some(StratumMessage::Unknown(Some(Id::Num(3)), method))) if method == "mining.ping")
+        );
+        let message = decode(
+            &mut codec,
+            r#"{"jsonrpc":"2.0","method":"mining.extranonce.subscribe"}"#,
+        );
+        assert!(matches!(message, Ok(Some(StratumMessage::Unknown(None, _)))));
+    }
+
+    #[test]
+    fn unknown_method_in_strict_mode() {
+        let mut codec = StratumCodec::default().strict(true);
+        let message = decode(
+            &mut codec,
+            r#"{"jsonrpc":"2.0","id":3,"method":"mining.ping","params":[]}"#,
+        );
+        assert!(message.is_err());
+    }
+
+    #[test]
+    fn known_method_without_params() {
+        let mut codec = StratumCodec::default();
+        let error = decode(&mut codec, r#"{"jsonrpc":"2.0","id":1,"method":"mining.authorize"}"#).unwrap_err();
+        assert_eq!(error.to_string(), "No params");
+    }
+
+    #[test]
+    fn subscribe_response_with_an_extranonce() {
+        let params: Vec<Box<dyn BoxedType>> = vec![
+            Box::new(Some("session".to_string())),
+            Box::new(Some("abcd".to_string())),
+            Box::new(Some("pool".to_string())),
+        ];
+        assert_eq!(
+            response_result(ResponseParams::Array(params)),
+            json!(["session", "abcd", "pool"])
+        );
+    }
+
+    #[test]
+    fn notify_with_four_or_five_params() {
+        let mut codec = StratumCodec::default();
+        for line in [
+            r#"{"jsonrpc":"2.0","method":"mining.notify","params":["01000000","challenge",null,true]}"#,
+            r#"{"jsonrpc":"2.0","method":"mining.notify","params":["01000000","challenge",null,true,"extra"]}"#,
+        ] {
+            match decode(&mut codec, line).unwrap() {
+                Some(StratumMessage::Notify(job_id, epoch_challenge, None, true)) => {
+                    assert_eq!(job_id, "01000000");
+                    assert_eq!(epoch_challenge, "challenge");
+                }
+                _ => panic!("expected a notify from {}", line),
+            }
+        }
+    }
+
+    #[test]
+    fn notify_with_three_params() {
+        let mut codec = StratumCodec::default();
+        let error = decode(
+            &mut codec,
+            r#"{"jsonrpc":"2.0","method":"mining.notify","params":["01000000","challenge",null]}"#,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "mining.notify: expected at least 4 params, got 3");
+    }
+
+    #[test]
+    fn message_without_delimiter_past_the_limit() {
+        let mut codec = StratumCodec::default();
+        let mut buf = BytesMut::from(vec![b'a'; MAX_MESSAGE_LEN].as_slice());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(codec.stats().max_buffered(), MAX_MESSAGE_LEN);
+        buf.extend_from_slice(b"aaaa");
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("message exceeds the length limit of {} bytes", MAX_MESSAGE_LEN)
+        );
+        assert_eq!(codec.stats().length_limit_exceeded(), 1);
+    }
+
+    #[test]
+    fn get_stats_round_trip() {
+        assert!(matches!(
+            round_trip(StratumMessage::GetStats(Some(Id::Num(4)))),
+            StratumMessage::GetStats(Some(Id::Num(4)))
+        ));
+        assert!(matches!(
+            round_trip(StratumMessage::GetStats(None)),
+            StratumMessage::GetStats(None)
+        ));
+    }
+
+    #[test]
+    fn subscribe_with_a_hashrate_hint() {
+        let subscribe = |hashrate| {
+            StratumMessage::Subscribe(
+                Id::Num(1),
+                "miner".to_string(),
+                "AleoStratum/2.0.0".to_string(),
+                None,
+                hashrate,
+            )
+        };
+        assert!(matches!(round_trip(subscribe(Some(1500.0))), StratumMessage::Subscribe(.., Some(h)) if h == 1500.0));
+        assert!(matches!(
+            round_trip(subscribe(None)),
+            StratumMessage::Subscribe(.., None)
+        ));
+        let message = decode(
+            &mut StratumCodec::default(),
+            r#"{"jsonrpc":"2.0","id":1,"method":"mining.subscribe","params":["m","AleoStratum/2.0.0",null,"fast"]}"#,
+        );
+        assert!(matches!(message, Ok(Some(StratumMessage::Subscribe(.., None)))));
+    }
+}
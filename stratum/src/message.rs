@@ -4,8 +4,9 @@ use crate::codec::ResponseParams;
 
 pub enum StratumMessage {
     /// This first version doesn't support vhosts.
-    /// (id, user_agent, protocol_version, session_id)
-    Subscribe(Id, String, String, Option<String>),
+    /// The hashrate the prover expects to reach, in proofs per second, is an optional hint.
+    /// (id, user_agent, protocol_version, session_id, hashrate)
+    Subscribe(Id, String, String, Option<String>, Option<f64>),
 
     /// (id, worker_name, worker_password)
     Authorize(Id, String, String),
@@ -21,11 +22,30 @@ pub enum StratumMessage {
 
     /// Submit shares to the pool.
     /// See protocol specification for details about the fields.
+    /// The id is `None` if the submit was sent as a notification.
     /// (id, worker_name, job_id, nonce, commitment, proof)
-    Submit(Id, String, String, String, String, String),
+    Submit(Option<Id>, String, String, String, String, String),
+
+    /// Asks the client to reconnect, optionally to another host, after waiting.
+    /// (host, port, wait_seconds)
+    Reconnect(Option<String>, Option<u16>, u64),
+
+    /// A message for the miner's operator, e.g. announcing maintenance.
+    /// (message)
+    ShowMessage(String),
+
+    /// Asks the pool for the connection's stats, answered with [accepted, rejected, target]. Sent
+    /// without an id, it is a notification and gets no answer.
+    /// (id)
+    GetStats(Option<Id>),
 
     /// (id, result, error)
     Response(Id, Option<ResponseParams>, Option<Error<()>>),
+
+    /// A request with a method not defined by the protocol, e.g. `mining.ping`.
+    /// Params are discarded.
+    /// (id, method)
+    Unknown(Option<Id>, String),
 }
 
 impl StratumMessage {
@@ -36,7 +56,11 @@ impl StratumMessage {
             StratumMessage::SetTarget(..) => "mining.set_target",
             StratumMessage::Notify(..) => "mining.notify",
             StratumMessage::Submit(..) => "mining.submit",
+            StratumMessage::Reconnect(..) => "client.reconnect",
+            StratumMessage::ShowMessage(..) => "client.show_message",
+            StratumMessage::GetStats(..) => "mining.get_stats",
             StratumMessage::Response(..) => "mining.response",
+            StratumMessage::Unknown(..) => "unknown",
         }
     }
 }
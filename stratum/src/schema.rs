@@ -0,0 +1,220 @@
+use std::io;
+
+use serde_json::Value;
+
+/// Expected type of a single param
+#[derive(Clone, Copy)]
+pub(crate) enum Param {
+    Str,
+    NullableStr,
+    U64,
+    /// A `u16` or null
+    NullablePort,
+    Bool,
+}
+
+impl Param {
+    fn matches(self, value: &Value) -> bool {
+        match (self, value) {
+            (Param::Str | Param::NullableStr, Value::String(_)) => true,
+            (Param::NullableStr | Param::NullablePort, Value::Null) => true,
+            (Param::U64, Value::Number(n)) => n.as_u64().is_some(),
+            (Param::NullablePort, Value::Number(n)) => n.as_u64().is_some_and(|n| u16::try_from(n).is_ok()),
+            (Param::Bool, Value::Bool(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Param::Str => "a string",
+            Param::NullableStr => "a string or null",
+            Param::U64 => "an unsigned integer",
+            Param::NullablePort => "a port number or null",
+            Param::Bool => "a boolean",
+        }
+    }
+}
+
+/// Params a method is expected to carry
+pub(crate) struct MethodSchema {
+    pub method: &'static str,
+    pub params: &'static [Param],
+    /// Params after the described ones are allowed and ignored
+    pub trailing: bool,
+}
+
+static SCHEMAS: &[MethodSchema] = &[
+    // may be followed by a hashrate hint, which is ignored unless it's a number
+    MethodSchema {
+        method: "mining.subscribe",
+        params: &[Param::Str, Param::Str, Param::NullableStr],
+        trailing: true,
+    },
+    MethodSchema {
+        method: "mining.authorize",
+        params: &[Param::Str, Param::Str],
+        trailing: false,
+    },
+    MethodSchema {
+        method: "mining.set_target",
+        params: &[Param::U64],
+        trailing: false,
+    },
+    // newer revisions append fields after the first four
+    MethodSchema {
+        method: "mining.notify",
+        params: &[Param::Str, Param::Str, Param::NullableStr, Param::Bool],
+        trailing: true,
+    },
+    MethodSchema {
+        method: "mining.submit",
+        params: &[Param::Str, Param::Str, Param::Str, Param::Str, Param::Str],
+        trailing: false,
+    },
+    MethodSchema {
+        method: "client.reconnect",
+        params: &[Param::NullableStr, Param::NullablePort, Param::U64],
+        trailing: false,
+    },
+    MethodSchema {
+        method: "client.show_message",
+        params: &[Param::Str],
+        trailing: false,
+    },
+    MethodSchema {
+        method: "mining.get_stats",
+        params: &[],
+        trailing: false,
+    },
+];
+
+/// Schema of a method defined by the protocol.
+pub(crate) fn schema(method: &str) -> Option<&'static MethodSchema> {
+    SCHEMAS.iter().find(|schema| schema.method == method)
+}
+
+impl MethodSchema {
+    pub fn validate(&self, params: &[Value]) -> Result<(), io::Error> {
+        let expected = self.params.len();
+        if params.len() < expected || (!self.trailing && params.len() > expected) {
+            let at_least = if self.trailing { "at least " } else { "" };
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: expected {}{} params, got {}",
+                    self.method,
+                    at_least,
+                    expected,
+                    params.len()
+                ),
+            ));
+        }
+        for (index, (param, value)) in self.params.iter().zip(params).enumerate() {
+            if !param.matches(value) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: param {} must be {}", self.method, index, param.description()),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The accessors below only read params the schema has already validated.
+
+pub(crate) fn str_param(value: &Value) -> String {
+    value.as_str().unwrap_or_default().to_string()
+}
+
+pub(crate) fn nullable_str_param(value: &Value) -> Option<String> {
+    value.as_str().map(str::to_string)
+}
+
+pub(crate) fn u64_param(value: &Value) -> u64 {
+    value.as_u64().unwrap_or_default()
+}
+
+pub(crate) fn nullable_port_param(value: &Value) -> Option<u16> {
+    value.as_u64().and_then(|n| u16::try_from(n).ok())
+}
+
+pub(crate) fn bool_param(value: &Value) -> bool {
+    value.as_bool().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn valid(param: Param) -> Value {
+        match param {
+            Param::Str | Param::NullableStr => json!("value"),
+            Param::U64 => json!(1),
+            Param::NullablePort => json!(4040),
+            Param::Bool => json!(true),
+        }
+    }
+
+    fn error(schema: &MethodSchema, params: &[Value]) -> String {
+        schema.validate(params).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn valid_params() {
+        for schema in SCHEMAS {
+            let params = schema.params.iter().map(|param| valid(*param)).collect::<Vec<_>>();
+            assert!(schema.validate(&params).is_ok(), "{}", schema.method);
+        }
+    }
+
+    #[test]
+    fn wrong_arity() {
+        for schema in SCHEMAS {
+            let mut params = schema.params.iter().map(|param| valid(*param)).collect::<Vec<_>>();
+            if schema.trailing {
+                params.pop();
+                let expected = format!(
+                    "{}: expected at least {} params, got {}",
+                    schema.method,
+                    schema.params.len(),
+                    params.len()
+                );
+                assert_eq!(error(schema, &params), expected);
+            } else {
+                params.push(json!("extra"));
+                let expected = format!(
+                    "{}: expected {} params, got {}",
+                    schema.method,
+                    schema.params.len(),
+                    params.len()
+                );
+                assert_eq!(error(schema, &params), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_type() {
+        for schema in SCHEMAS {
+            for (index, param) in schema.params.iter().enumerate() {
+                let mut params = schema.params.iter().map(|param| valid(*param)).collect::<Vec<_>>();
+                params[index] = json!({});
+                let expected = format!("{}: param {} must be {}", schema.method, index, param.description());
+                assert_eq!(error(schema, &params), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_port() {
+        let schema = schema("client.reconnect").unwrap();
+        assert_eq!(
+            error(schema, &[Value::Null, json!(70000), json!(0)]),
+            "client.reconnect: param 1 must be a port number or null"
+        );
+    }
+}